@@ -0,0 +1,116 @@
+//! Finding ACPI tables. BOOTBOOT says where the RSDT or XSDT is, and every table starts with the
+//! same header, so finding one is a walk through a list of pointers. The tables sit in identity
+//! mapped memory. Nothing here runs AML.
+
+
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use crate::{
+    bootboot::{
+        BootBootUnpacked,
+        BOOTBOOT_INFO,
+        BOOTBOOT,
+    },
+    shell::{
+        self,
+        Command,
+    },
+    println,
+};
+
+
+/// The common header is this long
+const HEADER_LEN:usize=36;
+/// Nothing real comes close, this is just so a bad pointer doesn't make a huge slice
+const MAX_TABLE_LEN:usize=1<<20;
+
+
+static ACPI:Command=Command::new("acpi","","List the ACPI tables",acpi);
+
+
+/// One table, header included
+#[derive(Debug,Copy,Clone)]
+pub struct Table {
+    pub phys:u64,
+    pub bytes:&'static [u8],
+}
+#[allow(dead_code)]
+impl Table {
+    /// Checks the header and the checksum of the table at `phys`
+    fn at(phys:u64)->Option<Table> {
+        if phys==0 {return None}
+        let header=unsafe{core::slice::from_raw_parts(phys as *const u8,HEADER_LEN)};
+        let len=u32::from_le_bytes([header[4],header[5],header[6],header[7]]) as usize;
+        if !(HEADER_LEN..=MAX_TABLE_LEN).contains(&len) {return None}
+        let bytes=unsafe{core::slice::from_raw_parts(phys as *const u8,len)};
+        if !checksum_ok(bytes) {return None}
+        Some(Table{phys,bytes})
+    }
+    pub fn signature(&self)->&[u8] {
+        &self.bytes[0..4]
+    }
+    pub fn revision(&self)->u8 {
+        self.bytes[8]
+    }
+    pub fn oem_id(&self)->&[u8] {
+        &self.bytes[10..16]
+    }
+    /// What comes after the header
+    pub fn data(&self)->&'static [u8] {
+        &self.bytes[HEADER_LEN..]
+    }
+}
+
+
+/// All the bytes of a table add up to 0
+pub fn checksum_ok(bytes:&[u8])->bool {
+    bytes.iter().fold(0u8,|sum,byte|sum.wrapping_add(*byte))==0
+}
+/// The RSDT or XSDT, and whether it has 64 bit pointers
+fn root()->Option<(Table,bool)> {
+    let bootboot:BootBootUnpacked=unsafe{*(BOOTBOOT_INFO as *const BOOTBOOT)}.into();
+    let mut phys=unsafe{bootboot.arch.x86_64.acpi_ptr};
+    if phys==0 {return None}
+    // BOOTBOOT passes the RSDT or XSDT itself, but take the RSDP too
+    let rsdp=unsafe{core::slice::from_raw_parts(phys as *const u8,36)};
+    if &rsdp[0..8]==b"RSD PTR " {
+        let xsdt=u64::from_le_bytes(rsdp[24..32].try_into().unwrap());
+        phys=if rsdp[15]>=2&&xsdt!=0 {xsdt} else {u32::from_le_bytes(rsdp[16..20].try_into().unwrap()) as u64};
+    }
+    let table=Table::at(phys)?;
+    match table.signature() {
+        b"XSDT"=>Some((table,true)),
+        b"RSDT"=>Some((table,false)),
+        _=>None,
+    }
+}
+/// Every table the RSDT or XSDT points at whose checksum is right
+pub fn tables()->Vec<Table> {
+    let Some((root,wide))=root() else {return Vec::new()};
+    let size=if wide {8} else {4};
+    root.data().chunks_exact(size)
+        .map(|pointer|if wide {u64::from_le_bytes(pointer.try_into().unwrap())} else {u32::from_le_bytes(pointer.try_into().unwrap()) as u64})
+        .filter_map(Table::at)
+        .collect()
+}
+/// The first table with this signature
+pub fn find(signature:&[u8;4])->Option<Table> {
+    tables().into_iter().find(|table|table.signature()==signature)
+}
+
+
+pub fn init() {
+    shell::register(&[&ACPI]);
+}
+fn acpi(_args:&[&str])->Result<(),&'static str> {
+    let tables=tables();
+    if tables.is_empty() {
+        println!("No ACPI tables");
+    }
+    for table in tables {
+        let signature=core::str::from_utf8(table.signature()).unwrap_or("????");
+        let oem=core::str::from_utf8(table.oem_id()).unwrap_or("").trim();
+        println!("  {} at {:#010x}, {} bytes, revision {}, {}",signature,table.phys,table.bytes.len(),table.revision(),oem);
+    }
+    return Ok(());
+}
@@ -17,6 +17,19 @@ pub const BOOTBOOT_FB: u64 = 0xfffffffffc000000;  /* frame buffer virtual addres
 pub const BOOTBOOT_INFO: u64 = 0xffffffffffe00000;  /* bootboot struct virtual address */
 pub const BOOTBOOT_ENV: u64 = 0xffffffffffe01000;  /* environment string virtual address */
 pub const BOOTBOOT_CORE: u64 = 0xffffffffffe02000;  /* core loadable segment start */
+pub const BOOTBOOT_ENV_SIZE: usize = 4096;  /* the environment is at most one page */
+
+
+/// The environment from `bootboot/env`. It is zero terminated (or fills the whole page), and
+/// anything that isn't UTF-8 cuts it short.
+pub fn env()->&'static str {
+    let page=unsafe{core::slice::from_raw_parts(BOOTBOOT_ENV as *const u8,BOOTBOOT_ENV_SIZE)};
+    let len=page.iter().position(|b|*b==0).unwrap_or(BOOTBOOT_ENV_SIZE);
+    match core::str::from_utf8(&page[..len]) {
+        Ok(s)=>s,
+        Err(e)=>unsafe{core::str::from_utf8_unchecked(&page[..e.valid_up_to()])},
+    }
+}
 
 
 #[repr(u8)]
@@ -0,0 +1,410 @@
+//! Kernel configuration from the BOOTBOOT environment page (`bootboot/env`). It is treated like a
+//! kernel command line, one option per line:
+//! ```text
+//! screen=1280x720         # handled by BOOTBOOT itself
+//! loglevel=debug          // both comment styles work
+//! serial.port=0x2f8
+//! clock.tsc               /* a bare key means `true` */
+//! shell.prompt="\"os\"> "
+//! selftest=screen, config.parse
+//! ```
+//! Subsystems declare the options they understand as [`Opt`]s and [`register`] them during init,
+//! which checks the values given in the environment. Options can also be changed at runtime with
+//! [`set`].
+
+
+use alloc::{
+    string::{
+        String,
+        ToString,
+    },
+    vec::Vec,
+};
+use spin::Mutex;
+use core::fmt;
+use crate::{
+    bootboot,
+    warn,
+};
+
+
+lazy_static::lazy_static! {
+    static ref CONFIG:Mutex<Config>=Mutex::new(Config::parse(bootboot::env()));
+}
+
+
+/// Options BOOTBOOT reads itself. They are registered so they don't show up as unknown.
+pub static SCREEN:Opt=Opt::new("screen",Kind::Str,"","Screen resolution requested from the firmware (read by BOOTBOOT)");
+pub static KERNEL:Opt=Opt::new("kernel",Kind::Str,"kernel","Kernel file name in the initrd (read by BOOTBOOT)");
+
+
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum Kind {
+    Bool,
+    Int,
+    Str,
+    List,
+}
+impl fmt::Display for Kind {
+    fn fmt(&self,f:&mut fmt::Formatter)->fmt::Result {
+        use Kind::*;
+        f.write_str(match self {
+            Bool=>"bool",
+            Int=>"int",
+            Str=>"string",
+            List=>"list",
+        })
+    }
+}
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum ConfigError {
+    /// The value couldn't be read as the option's kind
+    BadValue(Kind),
+    UnterminatedQuote,
+    UnknownOption,
+}
+impl fmt::Display for ConfigError {
+    fn fmt(&self,f:&mut fmt::Formatter)->fmt::Result {
+        use ConfigError::*;
+        match self {
+            BadValue(kind)=>write!(f,"expected a {}",kind),
+            UnterminatedQuote=>f.write_str("unterminated quote"),
+            UnknownOption=>f.write_str("unknown option"),
+        }
+    }
+}
+
+
+/// A configuration option. `name` may end in `.*` to match a whole family of keys (`loglevel.*`).
+/// `default` is written the same way it would be in the environment.
+pub struct Opt {
+    pub name:&'static str,
+    pub kind:Kind,
+    pub default:&'static str,
+    pub help:&'static str,
+}
+impl Opt {
+    pub const fn new(name:&'static str,kind:Kind,default:&'static str,help:&'static str)->Opt {
+        Opt {name,kind,default,help}
+    }
+    pub fn matches(&self,key:&str)->bool {
+        match self.name.strip_suffix(".*") {
+            Some(prefix)=>key.len()>prefix.len()+1&&key.starts_with(prefix)&&key.as_bytes()[prefix.len()]==b'.',
+            None=>key==self.name,
+        }
+    }
+    /// The raw value from the environment, or the default if it isn't set
+    pub fn raw(&self)->String {
+        raw(self.name).unwrap_or_else(||self.default.to_string())
+    }
+    /// Falls back to the default (with a warning) if the configured value is bad.
+    pub fn get_bool(&self)->bool {
+        self.get_with(parse_bool)
+    }
+    pub fn get_int(&self)->i64 {
+        self.get_with(parse_int)
+    }
+    pub fn get_str(&self)->String {
+        self.get_with(parse_str)
+    }
+    pub fn get_list(&self)->Vec<String> {
+        self.get_with(parse_list)
+    }
+    fn get_with<T,F:Fn(&str)->Result<T,ConfigError>>(&self,parse:F)->T {
+        if let Some(raw)=raw(self.name) {
+            match parse(&raw) {
+                Ok(v)=>return v,
+                Err(e)=>warn!("Bad value `{}` for `{}`: {}",raw,self.name,e),
+            }
+        }
+        parse(self.default).expect("Option has a bad default")
+    }
+}
+
+
+/// The parsed environment. Values are kept raw (quotes and all) until someone asks for them as a
+/// particular kind.
+struct Config {
+    entries:Vec<(String,String)>,
+    options:Vec<&'static Opt>,
+}
+impl Config {
+    fn parse(source:&str)->Config {
+        let mut config=Config {
+            entries:Vec::new(),
+            options:Vec::new(),
+        };
+        let stripped=strip_comments(source);
+        for line in stripped.lines() {
+            let line=line.trim();
+            if line.len()==0 {continue}
+            let (key,value)=match line.split_once('=') {
+                Some((k,v))=>(k.trim(),v.trim()),
+                None=>(line,"true"),
+            };
+            if key.len()==0 {continue}
+            config.set(key,value);
+        }
+        return config;
+    }
+    fn set(&mut self,key:&str,value:&str) {
+        match self.entries.iter_mut().find(|(k,_)|k==key) {
+            Some(entry)=>entry.1=value.to_string(),
+            None=>self.entries.push((key.to_string(),value.to_string())),
+        }
+    }
+    fn option(&self,key:&str)->Option<&'static Opt> {
+        self.options.iter().find(|o|o.matches(key)).copied()
+    }
+}
+
+
+/// Removes `#`, `//` and `/* */` comments. Comment markers inside quotes are left alone.
+fn strip_comments(source:&str)->String {
+    let mut out=String::with_capacity(source.len());
+    let mut chars=source.chars().peekable();
+    let mut in_quote=false;
+    while let Some(c)=chars.next() {
+        if in_quote {
+            out.push(c);
+            match c {
+                '\\'=>if let Some(n)=chars.next() {out.push(n)},
+                '"'|'\n'=>in_quote=false,
+                _=>{},
+            }
+            continue;
+        }
+        match (c,chars.peek()) {
+            ('"',_)=>{
+                in_quote=true;
+                out.push(c);
+            },
+            ('#',_)|('/',Some('/'))=>{
+                while let Some(n)=chars.peek() {
+                    if *n=='\n' {break}
+                    chars.next();
+                }
+            },
+            ('/',Some('*'))=>{
+                chars.next();
+                let mut last=' ';
+                while let Some(n)=chars.next() {
+                    if last=='*'&&n=='/' {break}
+                    if n=='\n' {out.push('\n')}   // keep the line structure intact
+                    last=n;
+                }
+            },
+            _=>out.push(c),
+        }
+    }
+    return out;
+}
+pub fn parse_bool(s:&str)->Result<bool,ConfigError> {
+    match s.trim() {
+        "1"|"true"|"yes"|"on"|"y"=>Ok(true),
+        "0"|"false"|"no"|"off"|"n"=>Ok(false),
+        _=>Err(ConfigError::BadValue(Kind::Bool)),
+    }
+}
+/// Decimal, `0x` hex, `0o` octal or `0b` binary with an optional `K`, `M` or `G` (binary) suffix.
+pub fn parse_int(s:&str)->Result<i64,ConfigError> {
+    let err=ConfigError::BadValue(Kind::Int);
+    let s=s.trim().replace('_',"");
+    let (negative,s)=match s.strip_prefix('-') {
+        Some(rest)=>(true,rest),
+        None=>(false,&s[..]),
+    };
+    let (s,multiplier)=match s.as_bytes().last() {
+        Some(b'K')|Some(b'k')=>(&s[..s.len()-1],1<<10),
+        Some(b'M')|Some(b'm')=>(&s[..s.len()-1],1<<20),
+        Some(b'G')|Some(b'g')=>(&s[..s.len()-1],1<<30),
+        _=>(s,1),
+    };
+    let (digits,radix)=if let Some(d)=s.strip_prefix("0x").or(s.strip_prefix("0X")) {
+        (d,16)
+    } else if let Some(d)=s.strip_prefix("0o") {
+        (d,8)
+    } else if let Some(d)=s.strip_prefix("0b") {
+        (d,2)
+    } else {
+        (s,10)
+    };
+    // `from_str_radix` takes a sign of its own, which would make `--5` 5
+    if digits.starts_with(['-','+']) {
+        return Err(err);
+    }
+    let value=i64::from_str_radix(digits,radix).map_err(|_|err.clone())?
+        .checked_mul(multiplier).ok_or(err)?;
+    return Ok(if negative {-value} else {value});
+}
+/// Bare strings are used as-is, quoted ones have their escapes (`\"`, `\\`, `\n`, `\t`) expanded.
+pub fn parse_str(s:&str)->Result<String,ConfigError> {
+    let s=s.trim();
+    let inner=match s.strip_prefix('"') {
+        Some(rest)=>rest,
+        None=>return Ok(s.to_string()),
+    };
+    let mut out=String::with_capacity(inner.len());
+    let mut chars=inner.chars();
+    while let Some(c)=chars.next() {
+        match c {
+            '"'=>return match chars.as_str().trim() {
+                ""=>Ok(out),
+                _=>Err(ConfigError::BadValue(Kind::Str)),   // junk after the closing quote
+            },
+            '\\'=>match chars.next() {
+                Some('n')=>out.push('\n'),
+                Some('t')=>out.push('\t'),
+                Some(c)=>out.push(c),
+                None=>break,
+            },
+            c=>out.push(c),
+        }
+    }
+    return Err(ConfigError::UnterminatedQuote);
+}
+/// Separated by commas or spaces, each item may be quoted. An empty value is an empty list.
+pub fn parse_list(s:&str)->Result<Vec<String>,ConfigError> {
+    let mut items=Vec::new();
+    let mut start=0;
+    let mut in_quote=false;
+    let mut escaped=false;
+    if s.trim().len()==0 {return Ok(items)}
+    for (i,c) in s.char_indices() {
+        match c {
+            _ if escaped=>escaped=false,
+            '\\' if in_quote=>escaped=true,
+            '"'=>in_quote=!in_quote,
+            ','|' ' if !in_quote&&s[start..i].trim().len()>0=>{
+                items.push(parse_str(&s[start..i])?);
+                start=i+1;
+            },
+            ',' if !in_quote=>start=i+1,
+            _=>{},
+        }
+    }
+    if in_quote {return Err(ConfigError::UnterminatedQuote)}
+    if s[start..].trim().len()>0 {
+        items.push(parse_str(&s[start..])?);
+    }
+    return Ok(items);
+}
+fn check(kind:Kind,value:&str)->Result<(),ConfigError> {
+    match kind {
+        Kind::Bool=>parse_bool(value).map(|_|()),
+        Kind::Int=>parse_int(value).map(|_|()),
+        Kind::Str=>parse_str(value).map(|_|()),
+        Kind::List=>parse_list(value).map(|_|()),
+    }
+}
+
+
+/// The raw value of `key`, if it was set
+pub fn raw(key:&str)->Option<String> {
+    x86_64::instructions::interrupts::without_interrupts(||{
+        CONFIG.lock().entries.iter().find(|(k,_)|k==key).map(|(_,v)|v.clone())
+    })
+}
+/// All keys starting with `prefix` along with their raw values
+pub fn raw_with_prefix(prefix:&str)->Vec<(String,String)> {
+    x86_64::instructions::interrupts::without_interrupts(||{
+        CONFIG.lock().entries.iter().filter(|(k,_)|k.starts_with(prefix)).cloned().collect()
+    })
+}
+/// Declares options and warns about any that were given a value of the wrong kind.
+pub fn register(options:&[&'static Opt]) {
+    for opt in options {
+        let bad=x86_64::instructions::interrupts::without_interrupts(||{
+            let mut config=CONFIG.lock();
+            if !config.options.iter().any(|o|o.name==opt.name) {
+                config.options.push(opt);
+            }
+            config.entries.iter()
+                .filter(|(k,_)|opt.matches(k))
+                .filter_map(|(k,v)|check(opt.kind,v).err().map(|e|(k.clone(),v.clone(),e)))
+                .collect::<Vec<_>>()
+        });
+        for (key,value,err) in bad {
+            warn!("Bad value `{}` for `{}`: {}",value,key,err);
+        }
+    }
+}
+/// Changes an option at runtime. Only registered options can be set and the value has to parse as
+/// the option's kind. Subsystems that only read their options at init won't notice.
+pub fn set(key:&str,value:&str)->Result<(),ConfigError> {
+    x86_64::instructions::interrupts::without_interrupts(||{
+        let mut config=CONFIG.lock();
+        let opt=config.option(key).ok_or(ConfigError::UnknownOption)?;
+        check(opt.kind,value)?;
+        config.set(key,value);
+        return Ok(());
+    })
+}
+/// Calls `f` with every registered option and the value it is set to (if any)
+pub fn for_each_option<F:FnMut(&'static Opt,Option<&str>)>(mut f:F) {
+    let (options,entries)=x86_64::instructions::interrupts::without_interrupts(||{
+        let config=CONFIG.lock();
+        (config.options.clone(),config.entries.clone())
+    });
+    for opt in options {
+        let mut any=false;
+        for (_,value) in entries.iter().filter(|(k,_)|opt.matches(k)) {
+            f(opt,Some(value));
+            any=true;
+        }
+        if !any {f(opt,None)}
+    }
+}
+/// Warns about options in the environment nobody registered. Call this after every subsystem had a
+/// chance to register.
+pub fn warn_unknown() {
+    let unknown:Vec<String>=x86_64::instructions::interrupts::without_interrupts(||{
+        let config=CONFIG.lock();
+        config.entries.iter()
+            .filter(|(k,_)|config.option(k).is_none())
+            .map(|(k,_)|k.clone())
+            .collect()
+    });
+    for key in unknown {
+        warn!("Unknown option `{}` in the environment",key);
+    }
+}
+pub fn init() {
+    register(&[&SCREEN,&KERNEL]);
+}
+
+
+/// Comments, quoting and every kind of value
+pub fn test_parse()->Result<(),&'static str> {
+    let config=Config::parse("a=1 # one\nb=\"x # y\" // not a comment in quotes\n/* c=3\n */ d\ne=/* inline */ 5\n=7\n");
+    let keys:Vec<(&str,&str)>=config.entries.iter().map(|(k,v)|(&k[..],&v[..])).collect();
+    if keys!=[("a","1"),("b","\"x # y\""),("d","true"),("e","5")] {
+        return Err("comments or bare keys were parsed wrong");
+    }
+    if parse_str("\"say \\\"hi\\\"\\n\"")!=Ok("say \"hi\"\n".to_string())||parse_str("  bare  ")!=Ok("bare".to_string()) {
+        return Err("strings were parsed wrong");
+    }
+    if parse_str("\"open")!=Err(ConfigError::UnterminatedQuote)||parse_str("\"a\" junk")!=Err(ConfigError::BadValue(Kind::Str)) {
+        return Err("bad strings were accepted");
+    }
+    if parse_int("0x2f8")!=Ok(0x2f8)||parse_int("-42")!=Ok(-42)||parse_int("0b1010")!=Ok(10)||parse_int("16K")!=Ok(16384)||parse_int("1_000")!=Ok(1000) {
+        return Err("ints were parsed wrong");
+    }
+    if parse_int("--5").is_ok()||parse_int("-+5").is_ok()||parse_int("+5").is_ok()||parse_int("0x-5").is_ok() {
+        return Err("a second sign was accepted");
+    }
+    if parse_int("12q").is_ok()||parse_int("0x").is_ok()||parse_int("9999999999G").is_ok() {
+        return Err("bad ints were accepted");
+    }
+    if parse_bool("yes")!=Ok(true)||parse_bool(" off ")!=Ok(false)||parse_bool("maybe")!=Err(ConfigError::BadValue(Kind::Bool)) {
+        return Err("bools were parsed wrong");
+    }
+    let list=parse_list("screen, \"a, b\",,time  log").map_err(|_|"a list didn't parse")?;
+    if list!=["screen","a, b","time","log"]||parse_list("  ")!=Ok(Vec::new()) {
+        return Err("lists were parsed wrong");
+    }
+    if parse_list("a, \"b").is_ok() {
+        return Err("a list with an open quote was accepted");
+    }
+    return Ok(());
+}
@@ -0,0 +1,307 @@
+//! A VT100/xterm escape sequence parser. It follows the state machine from
+//! <https://vt100.net/emu/dec_ansi_parser> closely enough for a console, and only turns characters
+//! into [`Action`]s. What the actions mean is up to the [`Console`](super::Console).
+
+
+use embedded_graphics::pixelcolor::Rgb888;
+
+
+pub const MAX_PARAMS:usize=16;
+
+
+/// The 16 standard colours (xterm's defaults), normal then bright
+pub const PALETTE_16:[Rgb888;16]=[
+    Rgb888::new(0,0,0),
+    Rgb888::new(205,0,0),
+    Rgb888::new(0,205,0),
+    Rgb888::new(205,205,0),
+    Rgb888::new(0,0,238),
+    Rgb888::new(205,0,205),
+    Rgb888::new(0,205,205),
+    Rgb888::new(229,229,229),
+    Rgb888::new(127,127,127),
+    Rgb888::new(255,0,0),
+    Rgb888::new(0,255,0),
+    Rgb888::new(255,255,0),
+    Rgb888::new(92,92,255),
+    Rgb888::new(255,0,255),
+    Rgb888::new(0,255,255),
+    Rgb888::new(255,255,255),
+];
+
+
+/// Colour `idx` of xterm's 256 colour palette: the 16 standard colours, a 6x6x6 cube and a ramp of
+/// 24 greys.
+pub fn palette_256(idx:u8)->Rgb888 {
+    match idx {
+        0..=15=>PALETTE_16[idx as usize],
+        16..=231=>{
+            let idx=idx-16;
+            let level=|v:u8|if v==0 {0} else {55+v*40};
+            Rgb888::new(level(idx/36),level((idx/6)%6),level(idx%6))
+        },
+        _=>{
+            let v=8+(idx-232)*10;
+            Rgb888::new(v,v,v)
+        },
+    }
+}
+
+
+/// Something the console has to do
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum Action {
+    /// Draw a character
+    Print(char),
+    /// A C0 control character like `\n` or `\x08`
+    Execute(u8),
+    /// `ESC [ <private> <params> <intermediate> <final>`
+    Csi {
+        params:Params,
+        private:Option<u8>,
+        intermediate:Option<u8>,
+        final_byte:u8,
+    },
+    /// `ESC <intermediate> <final>`
+    Esc {
+        intermediate:Option<u8>,
+        final_byte:u8,
+    },
+}
+
+
+/// CSI parameters. Missing parameters are 0, and sub-parameters (`38:2:r:g:b`) are flattened so
+/// they read the same as the `;` form.
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub struct Params {
+    values:[u16;MAX_PARAMS],
+    len:usize,
+}
+impl Params {
+    const fn new()->Params {
+        Params {
+            values:[0;MAX_PARAMS],
+            len:0,
+        }
+    }
+    pub fn len(&self)->usize {
+        self.len
+    }
+    pub fn get(&self,idx:usize)->u16 {
+        if idx<self.len {self.values[idx]} else {0}
+    }
+    /// For counts and positions, where 0 and missing both mean 1
+    pub fn get_or_one(&self,idx:usize)->u16 {
+        self.get(idx).max(1)
+    }
+    pub fn iter(&self)->impl Iterator<Item=u16>+'_ {
+        self.values[..self.len].iter().copied()
+    }
+    pub fn as_slice(&self)->&[u16] {
+        &self.values[..self.len]
+    }
+}
+
+
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+enum State {
+    Ground,
+    Escape,
+    EscapeIntermediate,
+    CsiParam,
+    CsiIntermediate,
+    /// Bad CSI sequence, eat it up to the final byte
+    CsiIgnore,
+    /// OSC, DCS and friends. Nothing uses them so they are skipped up to `BEL` or `ST`.
+    String,
+    StringEscape,
+}
+
+
+pub struct Parser {
+    state:State,
+    params:Params,
+    /// A digit has been seen for the current parameter
+    param_started:bool,
+    private:Option<u8>,
+    intermediate:Option<u8>,
+}
+impl Parser {
+    pub const fn new()->Parser {
+        Parser {
+            state:State::Ground,
+            params:Params::new(),
+            param_started:false,
+            private:None,
+            intermediate:None,
+        }
+    }
+    fn clear(&mut self) {
+        self.params=Params::new();
+        self.param_started=false;
+        self.private=None;
+        self.intermediate=None;
+    }
+    fn push_param(&mut self) {
+        if self.params.len<MAX_PARAMS {
+            self.params.len+=1;
+        }
+        self.param_started=false;
+    }
+    fn csi(&mut self,final_byte:u8)->Action {
+        if self.param_started||self.params.len>0 {
+            self.push_param();
+        }
+        self.state=State::Ground;
+        Action::Csi {
+            params:self.params,
+            private:self.private,
+            intermediate:self.intermediate,
+            final_byte,
+        }
+    }
+    /// Feeds one character in and returns what to do about it, if anything
+    pub fn advance(&mut self,c:char)->Option<Action> {
+        use State::*;
+        let b=if (c as u32)<0x80 {c as u8} else {0xFF};
+        // these work from anywhere
+        match b {
+            0x18|0x1A=>{    // CAN and SUB abort the sequence
+                self.state=Ground;
+                return None;
+            },
+            0x1B=>{
+                self.state=if self.state==String {StringEscape} else {Escape};
+                self.clear();
+                return None;
+            },
+            _=>{},
+        }
+        match self.state {
+            Ground=>match b {
+                0x00..=0x1F|0x7F=>Some(Action::Execute(b)),
+                _=>Some(Action::Print(c)),
+            },
+            Escape|EscapeIntermediate=>match b {
+                0x00..=0x1F=>Some(Action::Execute(b)),
+                0x20..=0x2F=>{
+                    self.intermediate=Some(b);
+                    self.state=EscapeIntermediate;
+                    None
+                },
+                b'[' if self.state==Escape=>{
+                    self.state=CsiParam;
+                    None
+                },
+                b']'|b'P'|b'X'|b'^'|b'_' if self.state==Escape=>{
+                    self.state=String;
+                    None
+                },
+                0x30..=0x7E=>{
+                    self.state=Ground;
+                    Some(Action::Esc{intermediate:self.intermediate,final_byte:b})
+                },
+                _=>{
+                    self.state=Ground;
+                    None
+                },
+            },
+            CsiParam=>match b {
+                0x00..=0x1F=>Some(Action::Execute(b)),
+                b'0'..=b'9'=>{
+                    let idx=self.params.len.min(MAX_PARAMS-1);
+                    let v=&mut self.params.values[idx];
+                    *v=v.saturating_mul(10).saturating_add((b-b'0') as u16);
+                    self.param_started=true;
+                    None
+                },
+                b';'|b':'=>{
+                    self.push_param();
+                    None
+                },
+                b'<'..=b'?'=>{
+                    if self.params.len>0||self.param_started||self.private.is_some() {
+                        self.state=CsiIgnore;
+                    } else {
+                        self.private=Some(b);
+                    }
+                    None
+                },
+                0x20..=0x2F=>{
+                    self.intermediate=Some(b);
+                    self.state=CsiIntermediate;
+                    None
+                },
+                0x40..=0x7E=>Some(self.csi(b)),
+                _=>{
+                    self.state=CsiIgnore;
+                    None
+                },
+            },
+            CsiIntermediate=>match b {
+                0x00..=0x1F=>Some(Action::Execute(b)),
+                0x40..=0x7E=>Some(self.csi(b)),
+                _=>{
+                    self.state=CsiIgnore;
+                    None
+                },
+            },
+            CsiIgnore=>match b {
+                0x00..=0x1F=>Some(Action::Execute(b)),
+                0x40..=0x7E=>{
+                    self.state=Ground;
+                    None
+                },
+                _=>None,
+            },
+            String=>{
+                if b==0x07 {    // xterm ends OSC with BEL too
+                    self.state=Ground;
+                }
+                None
+            },
+            StringEscape=>{
+                self.state=if b==b'\\' {Ground} else {String};
+                None
+            },
+        }
+    }
+}
+
+
+/// Runs a few sequences through the parser and checks what comes out
+pub fn test_parser()->Result<(),&'static str> {
+    let mut parser=Parser::new();
+    let mut actions=[None;8];
+    let mut count=0;
+    for c in "a\x1b[1;31m\x1b]0;title\x07\x1b[?25l\x1b[;5H\x1b7é".chars() {
+        if let Some(action)=parser.advance(c) {
+            if count==actions.len() {return Err("too many actions")}
+            actions[count]=Some(action);
+            count+=1;
+        }
+    }
+    let csi=|values:&[u16],private,final_byte|{
+        let mut params=Params::new();
+        params.values[..values.len()].copy_from_slice(values);
+        params.len=values.len();
+        Some(Action::Csi{params,private,intermediate:None,final_byte})
+    };
+    let expected=[
+        Some(Action::Print('a')),
+        csi(&[1,31],None,b'm'),
+        csi(&[25],Some(b'?'),b'l'),
+        csi(&[0,5],None,b'H'),
+        Some(Action::Esc{intermediate:None,final_byte:b'7'}),
+        Some(Action::Print('é')),
+        None,
+        None,
+    ];
+    if actions!=expected {
+        return Err("the parser produced the wrong actions");
+    }
+    if palette_256(16)!=Rgb888::new(0,0,0)||palette_256(231)!=Rgb888::new(255,255,255)||palette_256(232)!=Rgb888::new(8,8,8) {
+        return Err("the 256 colour palette is wrong");
+    }
+    return Ok(());
+}
@@ -0,0 +1,269 @@
+//! Console fonts. There is the font built into the kernel (Tamzen 8x15, ASCII only) and PC Screen
+//! Fonts (PSF1 and PSF2, the format Linux's console uses) loaded from the initrd. PSF fonts can
+//! come with a Unicode table saying which characters each glyph is for.
+
+
+use alloc::{
+    vec::Vec,
+    boxed::Box,
+};
+use spin::Mutex;
+use embedded_graphics::{
+    text::Text,
+    pixelcolor::Rgb888,
+    Drawable,
+};
+use bitmap_font::{
+    tamzen::FONT_8x15,
+    TextStyle,
+};
+use crate::{
+    math::Point,
+    screen::Screen,
+    initrd,
+};
+
+
+const PSF1_MAGIC:[u8;2]=[0x36,0x04];
+const PSF1_MODE512:u8=0x01;
+const PSF1_MODEHASTAB:u8=0x02;
+const PSF1_MODESEQ:u8=0x04;
+const PSF1_SEPARATOR:u16=0xFFFF;
+const PSF1_STARTSEQ:u16=0xFFFE;
+const PSF2_MAGIC:[u8;4]=[0x72,0xb5,0x4a,0x86];
+const PSF2_HAS_UNICODE_TABLE:u32=0x01;
+const PSF2_SEPARATOR:u8=0xFF;
+const PSF2_STARTSEQ:u8=0xFE;
+
+
+lazy_static::lazy_static! {
+    /// Fonts that have been loaded, by path, so loading one again doesn't parse it again
+    static ref LOADED:Mutex<Vec<(&'static str,&'static Psf)>>=Mutex::new(Vec::new());
+}
+
+
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum FontError {
+    NotFound,
+    BadMagic,
+    /// The file ends before the header says it should
+    Truncated,
+    /// The header doesn't make sense, like a glyph size of 0
+    BadHeader,
+}
+
+
+/// A PSF font. The glyph data is borrowed straight from the initrd.
+pub struct Psf {
+    width:usize,
+    height:usize,
+    bytes_per_row:usize,
+    bytes_per_glyph:usize,
+    glyph_count:usize,
+    glyphs:&'static [u8],
+    /// `(char,glyph)` sorted by `char`. Empty if the font has no Unicode table, then glyph `n` is
+    /// for character `n`.
+    unicode:Vec<(char,u32)>,
+}
+#[allow(dead_code)]
+impl Psf {
+    pub fn parse(data:&'static [u8])->Result<Psf,FontError> {
+        if data.starts_with(&PSF2_MAGIC) {
+            Self::parse_psf2(data)
+        } else if data.starts_with(&PSF1_MAGIC) {
+            Self::parse_psf1(data)
+        } else {
+            Err(FontError::BadMagic)
+        }
+    }
+    fn parse_psf1(data:&'static [u8])->Result<Psf,FontError> {
+        if data.len()<4 {return Err(FontError::Truncated)}
+        let mode=data[2];
+        let height=data[3] as usize;
+        if height==0 {return Err(FontError::BadHeader)}
+        let glyph_count=if mode&PSF1_MODE512!=0 {512} else {256};
+        let end=4+glyph_count*height;
+        let glyphs=data.get(4..end).ok_or(FontError::Truncated)?;
+        let mut unicode=Vec::new();
+        if mode&(PSF1_MODEHASTAB|PSF1_MODESEQ)!=0 {
+            let mut glyph=0;
+            let mut in_seq=false;
+            for pair in data[end..].chunks_exact(2) {
+                if glyph>=glyph_count {break}
+                match u16::from_le_bytes([pair[0],pair[1]]) {
+                    PSF1_SEPARATOR=>{
+                        glyph+=1;
+                        in_seq=false;
+                    },
+                    PSF1_STARTSEQ=>in_seq=true,
+                    c if !in_seq=>if let Some(c)=char::from_u32(c as u32) {
+                        unicode.push((c,glyph as u32));
+                    },
+                    _=>{},  // sequences (a character plus combining marks) can't be drawn
+                }
+            }
+        }
+        return Ok(Self::new(8,height,glyph_count,glyphs,unicode));
+    }
+    fn parse_psf2(data:&'static [u8])->Result<Psf,FontError> {
+        let word=|idx:usize|->Result<u32,FontError> {
+            let bytes=data.get(idx*4..idx*4+4).ok_or(FontError::Truncated)?;
+            Ok(u32::from_le_bytes([bytes[0],bytes[1],bytes[2],bytes[3]]))
+        };
+        let header_size=word(2)? as usize;
+        let flags=word(3)?;
+        let glyph_count=word(4)? as usize;
+        let bytes_per_glyph=word(5)? as usize;
+        let height=word(6)? as usize;
+        let width=word(7)? as usize;
+        if width==0||height==0||glyph_count==0||bytes_per_glyph<height*((width+7)/8) {
+            return Err(FontError::BadHeader);
+        }
+        let end=glyph_count.checked_mul(bytes_per_glyph).and_then(|len|len.checked_add(header_size)).ok_or(FontError::BadHeader)?;
+        let glyphs=data.get(header_size..end).ok_or(FontError::Truncated)?;
+        let mut unicode=Vec::new();
+        if flags&PSF2_HAS_UNICODE_TABLE!=0 {
+            for (glyph,entry) in data[end..].split(|b|*b==PSF2_SEPARATOR).take(glyph_count).enumerate() {
+                // everything after the first STARTSEQ is sequences
+                let singles=entry.split(|b|*b==PSF2_STARTSEQ).next().unwrap_or(&[]);
+                if let Ok(chars)=core::str::from_utf8(singles) {
+                    unicode.extend(chars.chars().map(|c|(c,glyph as u32)));
+                }
+            }
+        }
+        return Ok(Self::new(width,height,glyph_count,glyphs,unicode));
+    }
+    fn new(width:usize,height:usize,glyph_count:usize,glyphs:&'static [u8],mut unicode:Vec<(char,u32)>)->Psf {
+        let bytes_per_row=(width+7)/8;
+        unicode.sort_unstable_by_key(|(c,_)|*c);
+        unicode.dedup_by_key(|(c,_)|*c);
+        Psf {
+            width,
+            height,
+            bytes_per_row,
+            bytes_per_glyph:glyphs.len()/glyph_count,
+            glyph_count,
+            glyphs,
+            unicode,
+        }
+    }
+    pub fn size(&self)->(usize,usize) {
+        (self.width,self.height)
+    }
+    pub fn glyph_count(&self)->usize {
+        self.glyph_count
+    }
+    /// Which glyph draws `c`
+    pub fn glyph_index(&self,c:char)->Option<usize> {
+        if self.unicode.is_empty() {
+            let idx=c as usize;
+            return if idx<self.glyph_count {Some(idx)} else {None};
+        }
+        self.unicode.binary_search_by_key(&c,|(c,_)|*c).ok().map(|i|self.unicode[i].1 as usize)
+    }
+    /// The bitmap of glyph `idx`, rows top to bottom, most significant bit leftmost
+    pub fn glyph(&self,idx:usize)->&'static [u8] {
+        let start=idx*self.bytes_per_glyph;
+        &self.glyphs[start..start+self.height*self.bytes_per_row]
+    }
+    fn draw(&self,screen:&mut Screen,pos:Point,idx:usize,fg:Rgb888,bg:Rgb888) {
+        let (fg,bg)=(screen.encode(fg),screen.encode(bg));
+        let glyph=self.glyph(idx);
+        for (y,row) in glyph.chunks_exact(self.bytes_per_row).enumerate() {
+            if pos.1+y>=screen.h {break}
+            let right=(pos.0+self.width).min(screen.w);
+            for (x,p) in screen.row_span(pos.1+y,pos.0..right).iter_mut().enumerate() {
+                let set=row[x/8]&(0x80>>(x%8))!=0;
+                *p=if set {fg} else {bg};
+            }
+        }
+    }
+}
+
+
+/// The font a console draws with
+#[derive(Copy,Clone)]
+pub enum Font {
+    Builtin,
+    Psf(&'static Psf),
+}
+impl Font {
+    /// Size of a character cell in pixels
+    pub fn size(&self)->(usize,usize) {
+        match self {
+            Font::Builtin=>(8,15),
+            Font::Psf(psf)=>psf.size(),
+        }
+    }
+    pub fn has_glyph(&self,c:char)->bool {
+        match self {
+            Font::Builtin=>c==' '||c.is_ascii_graphic(),
+            Font::Psf(psf)=>psf.glyph_index(c).is_some(),
+        }
+    }
+    /// Draws `c` with its top left corner at `pos`, filling the whole cell. `c` must be in the
+    /// font.
+    pub fn draw(&self,screen:&mut Screen,pos:Point,c:char,fg:Rgb888,bg:Rgb888) {
+        match self {
+            Font::Builtin=>{
+                let mut buf=[0u8;4];
+                let style=TextStyle::new_bg(&FONT_8x15,fg,bg);
+                Text::new(c.encode_utf8(&mut buf),pos.into(),style).draw(screen).unwrap();
+            },
+            Font::Psf(psf)=>if let Some(idx)=psf.glyph_index(c) {
+                psf.draw(screen,pos,idx,fg,bg);
+            },
+        }
+    }
+}
+
+
+/// Loads a PSF font from the initrd. Fonts stay loaded for good, so asking for the same path
+/// again is cheap.
+pub fn load(path:&str)->Result<Font,FontError> {
+    let mut loaded=LOADED.lock();
+    if let Some((_,psf))=loaded.iter().find(|(p,_)|*p==path) {
+        return Ok(Font::Psf(psf));
+    }
+    let data=initrd::find(path).ok_or(FontError::NotFound)?;
+    let psf:&'static Psf=Box::leak(Box::new(Psf::parse(data)?));
+    let name:&'static str=Box::leak(Box::from(path));
+    loaded.push((name,psf));
+    return Ok(Font::Psf(psf));
+}
+
+
+/// A two glyph 4x3 PSF2 font with a Unicode table
+static TEST_PSF2:[u8;32+2*3+10]=[
+    0x72,0xb5,0x4a,0x86,    // magic
+    0,0,0,0,                // version
+    32,0,0,0,               // header size
+    1,0,0,0,                // flags: has a Unicode table
+    2,0,0,0,                // glyphs
+    3,0,0,0,                // bytes per glyph
+    3,0,0,0,                // height
+    4,0,0,0,                // width
+    0xF0,0x90,0xF0,         // a box
+    0x60,0x60,0x60,         // a bar
+    b'o',0xFF,              // glyph 0 is `o`
+    0xC3,0xA9,b'|',0xFE,b'e',0xCC,0x81,0xFF,   // glyph 1 is `é` and `|`, plus a sequence
+];
+
+
+/// Parses a tiny PSF2 font and looks some characters up in it
+pub fn test_psf()->Result<(),&'static str> {
+    let psf=Psf::parse(&TEST_PSF2).map_err(|_|"the font didn't parse")?;
+    if psf.size()!=(4,3)||psf.glyph_count()!=2 {
+        return Err("wrong glyph size or count");
+    }
+    if psf.glyph_index('o')!=Some(0)||psf.glyph_index('é')!=Some(1)||psf.glyph_index('|')!=Some(1)||psf.glyph_index('e').is_some() {
+        return Err("the Unicode table was read wrong");
+    }
+    if psf.glyph(1)!=[0x60,0x60,0x60] {
+        return Err("wrong glyph bitmap");
+    }
+    if Psf::parse(&TEST_PSF2[..36]).is_ok()||Psf::parse(&TEST_PSF2[4..]).is_ok() {
+        return Err("a broken font parsed");
+    }
+    return Ok(());
+}
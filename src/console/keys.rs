@@ -0,0 +1,71 @@
+//! What the console does with the keyboard: Alt+F1..F12 switch virtual terminals, Shift+PageUp
+//! and Shift+PageDown scroll through the history, and everything else is typed into the terminal
+//! being shown.
+
+
+use crate::input::{
+    self,
+    KeyCode,
+    KeyEvent,
+};
+use super::vt;
+
+
+/// The virtual terminal Alt+`key` switches to
+fn vt_key(key:KeyCode)->Option<usize> {
+    use KeyCode::*;
+    Some(match key {
+        F1=>0,F2=>1,F3=>2,F4=>3,F5=>4,F6=>5,
+        F7=>6,F8=>7,F9=>8,F10=>9,F11=>10,F12=>11,
+        _=>return None,
+    })
+}
+/// What xterm sends for keys that don't have a character
+fn key_sequence(key:KeyCode)->Option<&'static str> {
+    use KeyCode::*;
+    Some(match key {
+        ArrowUp=>"\x1b[A",
+        ArrowDown=>"\x1b[B",
+        ArrowRight=>"\x1b[C",
+        ArrowLeft=>"\x1b[D",
+        Home=>"\x1b[H",
+        End=>"\x1b[F",
+        Insert=>"\x1b[2~",
+        Delete=>"\x1b[3~",
+        PageUp=>"\x1b[5~",
+        PageDown=>"\x1b[6~",
+        _=>return None,
+    })
+}
+
+
+/// Handles one key event
+pub fn handle(event:&KeyEvent) {
+    if !event.down {return}
+    let mods=&event.modifiers;
+    if let (true,Some(idx))=(mods.alt(),vt_key(event.code)) {
+        if idx<vt::COUNT {
+            super::_switch(idx);
+        }
+        return;
+    }
+    match event.code {
+        KeyCode::PageUp if mods.shift()=>return super::_scroll_pages(1),
+        KeyCode::PageDown if mods.shift()=>return super::_scroll_pages(-1),
+        KeyCode::ShiftLeft|KeyCode::ShiftRight|KeyCode::ControlLeft|KeyCode::ControlRight|
+            KeyCode::AltLeft|KeyCode::AltRight=>return,
+        _=>super::_scroll_pages(0),
+    }
+    if let Some(c)=event.unicode {
+        super::_input(c);
+    } else if let Some(seq)=key_sequence(event.code) {
+        super::_input_str(seq);
+    }
+}
+/// The console's keyboard task
+pub async fn run() {
+    let keys=input::subscribe();
+    loop {
+        handle(&keys.read().await);
+    }
+}
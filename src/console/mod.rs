@@ -0,0 +1,1254 @@
+use crate::{
+    math::Point,
+    warn,
+    screen::{
+        self,
+        Screen,
+        PixelFormat,
+    },
+    config::{
+        self,
+        Opt,
+        Kind,
+    },
+    bootboot::{
+        BootBootUnpacked,
+        BOOTBOOT_INFO,
+        BOOTBOOT_FB,
+        BOOTBOOT,
+    },
+};
+use spin::Mutex;
+use alloc::{
+    vec,
+    vec::Vec,
+    collections::VecDeque,
+};
+use core::{
+    fmt::{
+        self,
+        Write
+    },
+    task::{
+        Poll,
+        Waker,
+    },
+    time::Duration,
+};
+use embedded_graphics::{
+    primitives::{
+        Rectangle,
+    },
+    geometry::{
+        Size,
+        Point as EGPoint,
+    },
+    pixelcolor::{
+        Rgb888,
+    },
+};
+use vt::Terminals;
+use font::{
+    Font,
+    FontError,
+};
+use ansi::{
+    Action,
+    Params,
+    Parser,
+    PALETTE_16,
+    palette_256,
+};
+
+
+pub mod ansi;
+pub mod vt;
+pub mod unicode;
+pub mod font;
+pub mod keys;
+pub mod pointer;
+
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::console::_print(format_args!($($arg)*)));
+}
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+#[macro_export]
+macro_rules! move_up {
+    () => ($crate::console::_move_up());
+}
+#[macro_export]
+macro_rules! cursor_timer {
+    ($elapsed:expr) => ($crate::console::_cursor($elapsed));
+}
+
+
+pub static SCROLLBACK:Opt=Opt::new("console.scrollback",Kind::Int,"1000","Rows of history kept for Shift+PageUp");
+pub static FONT:Opt=Opt::new("console.font",Kind::Str,"","PSF font in the initrd to use instead of the built in one");
+pub static POINTER:Opt=Opt::new("console.pointer",Kind::Bool,"true","Draw a mouse pointer over the console (needs fb.backbuffer)");
+
+
+lazy_static::lazy_static! {
+    /// Every virtual terminal. `print!` writes to [`vt::KERNEL_VT`].
+    pub static ref CONSOLE:Mutex<Terminals>={
+        let bootboot:BootBootUnpacked=unsafe{*(BOOTBOOT_INFO as *const BOOTBOOT)}.into(); // convert raw data into not-so-raw data and an aligned rust struct
+        let format=PixelFormat::from_fb_type(bootboot.fb.fb_type).unwrap_or(PixelFormat::Argb);
+        let mut screen=Screen::new(bootboot.fb.width as usize,bootboot.fb.height as usize,bootboot.fb.scanline as usize,BOOTBOOT_FB as usize,format);
+        config::register(&[&screen::BACK_BUFFER,&screen::FLUSH_TICKS]);
+        if screen::BACK_BUFFER.get_bool() {
+            screen.enable_back_buffer();
+        }
+        config::register(&[&SCROLLBACK,&vt::LOG_VT,&vt::LOG_ECHO]);
+        let mut terminals=Terminals::new(screen);
+        for console in terminals.iter_mut() {
+            console.set_flush_ticks(screen::FLUSH_TICKS.get_int().max(0) as u32);
+            console.set_scrollback_limit(SCROLLBACK.get_int().max(0) as usize);
+        }
+        let log_vt=vt::LOG_VT.get_int();
+        if log_vt<1||terminals.set_log_vt(log_vt as usize-1).is_err() {
+            terminals.set_log_vt(vt::COUNT-1).unwrap();
+        }
+        terminals.set_log_echo(vt::LOG_ECHO.get_bool());
+        Mutex::new(terminals)
+    };
+}
+
+
+/// Starts reading the keyboard and loads the font from `console.font`. This is after logging is
+/// set up so a bad font can be complained about.
+pub fn init() {
+    crate::task::spawn("console",keys::run());
+    config::register(&[&FONT,&POINTER]);
+    if POINTER.get_bool() {
+        crate::task::spawn("pointer",pointer::run());
+    }
+    let path=FONT.get_str();
+    if path.is_empty() {return}
+    if let Err(e)=set_font(&path) {
+        warn!("Can't use font `{}`: {:?}",path,e);
+    }
+}
+/// Switches every virtual terminal to the PSF font at `path` in the initrd. An empty path is the
+/// built in font.
+pub fn set_font(path:&str)->Result<(),FontError> {
+    let font=if path.is_empty() {Font::Builtin} else {font::load(path)?};
+    x86_64::instructions::interrupts::without_interrupts(||{
+        CONSOLE.lock().set_font(font);
+    });
+    return Ok(());
+}
+
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    x86_64::instructions::interrupts::without_interrupts(||{
+        let mut terminals=CONSOLE.lock();
+        let console=terminals.kernel_vt();
+        console.write_fmt(args).unwrap();
+        console.written();
+    });
+}
+#[doc(hidden)]
+pub fn _move_up() {
+    x86_64::instructions::interrupts::without_interrupts(||{
+        let mut terminals=CONSOLE.lock();
+        let console=terminals.kernel_vt();
+        console.move_up();
+        console.written();
+    });
+}
+/// Scrolls the view by half a screen per page, into the history for positive `pages`. 0 goes back
+/// to the live screen.
+#[doc(hidden)]
+pub fn _scroll_pages(pages:isize) {
+    x86_64::instructions::interrupts::without_interrupts(||{
+        let mut terminals=CONSOLE.lock();
+        let console=terminals.active_vt();
+        if pages==0 {
+            console.scroll_view_reset();
+        } else {
+            let page=(console.size().1/2) as isize;
+            console.scroll_view(pages*page);
+        }
+        console.flush();
+    });
+}
+#[doc(hidden)]
+pub fn _flush() {
+    x86_64::instructions::interrupts::without_interrupts(||{
+        CONSOLE.lock().active_vt().flush();
+    });
+}
+#[doc(hidden)]
+pub fn _cursor(elapsed:Duration) {
+    x86_64::instructions::interrupts::without_interrupts(||{
+        let mut terminals=CONSOLE.lock();
+        let console=terminals.active_vt();
+        console.cursor_tick(elapsed);
+        console.flush_tick();
+    });
+}
+/// Shows virtual terminal `idx` (0 based)
+#[doc(hidden)]
+pub fn _switch(idx:usize) {
+    x86_64::instructions::interrupts::without_interrupts(||{
+        let _=CONSOLE.lock().switch(idx);
+    });
+}
+/// Moves the mouse pointer on the screen being shown, or hides it
+#[doc(hidden)]
+pub fn _pointer(pos:Option<Point>) {
+    x86_64::instructions::interrupts::without_interrupts(||{
+        CONSOLE.lock().active_vt().set_pointer(pos);
+    });
+}
+/// Gives typed text to the virtual terminal being shown
+#[doc(hidden)]
+pub fn _input(c:char) {
+    x86_64::instructions::interrupts::without_interrupts(||{
+        let mut terminals=CONSOLE.lock();
+        let console=terminals.active_vt();
+        console.push_input(c);
+        console.written();
+    });
+}
+/// Like [`_input`] for the escape sequences of cursor and editing keys
+#[doc(hidden)]
+pub fn _input_str(string:&str) {
+    x86_64::instructions::interrupts::without_interrupts(||{
+        CONSOLE.lock().active_vt().push_input_str(string);
+    });
+}
+/// The next character typed on virtual terminal `idx`, if there is one
+pub fn read_input(idx:usize)->Option<char> {
+    x86_64::instructions::interrupts::without_interrupts(||{
+        CONSOLE.lock().get(idx)?.read_input()
+    })
+}
+/// Waits for a character to be typed on virtual terminal `idx`. Only one task should read from a
+/// terminal at a time.
+pub async fn read_char(idx:usize)->char {
+    core::future::poll_fn(|cx|{
+        x86_64::instructions::interrupts::without_interrupts(||{
+            let mut terminals=CONSOLE.lock();
+            let console=terminals.get(idx).expect("no such virtual terminal");
+            match console.read_input() {
+                Some(c)=>Poll::Ready(c),
+                None=>{
+                    console.input_waker=Some(cx.waker().clone());
+                    Poll::Pending
+                },
+            }
+        })
+    }).await
+}
+/// Size of virtual terminal `idx` in character cells
+pub fn size(idx:usize)->(usize,usize) {
+    x86_64::instructions::interrupts::without_interrupts(||{
+        CONSOLE.lock().get(idx).map(|console|console.size()).unwrap_or((0,0))
+    })
+}
+/// Turns echoing typed characters on or off for virtual terminal `idx`
+pub fn set_echo(idx:usize,echo:bool) {
+    x86_64::instructions::interrupts::without_interrupts(||{
+        if let Some(console)=CONSOLE.lock().get(idx) {
+            console.set_echo(echo);
+        }
+    });
+}
+
+
+/// A colour as the program asked for it. Palette colours are looked up when drawing so bold can
+/// brighten them.
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum Color {
+    /// The console's default foreground or background
+    Default,
+    Indexed(u8),
+    Rgb(Rgb888),
+}
+/// What SGR (`ESC [ ... m`) sets
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub struct Attributes {
+    pub fg:Color,
+    pub bg:Color,
+    pub bold:bool,
+    pub underline:bool,
+    pub reverse:bool,
+}
+impl Attributes {
+    pub const fn new()->Attributes {
+        Attributes {
+            fg:Color::Default,
+            bg:Color::Default,
+            bold:false,
+            underline:false,
+            reverse:false,
+        }
+    }
+}
+
+
+/// One character cell of the console
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub struct Cell {
+    pub c:char,
+    pub attrs:Attributes,
+}
+impl Cell {
+    /// The right half of a wide character. The glyph is drawn by the cell to its left.
+    pub const WIDE_TAIL:char='\0';
+    pub const fn blank()->Cell {
+        Cell {
+            c:' ',
+            attrs:Attributes::new(),
+        }
+    }
+    pub fn is_wide_tail(&self)->bool {
+        self.c==Self::WIDE_TAIL
+    }
+}
+
+
+/// A text console on a [`Screen`]. Text written to it can contain VT100/xterm escape sequences
+/// (colours, cursor movement, erasing, scroll regions and so on, see [`ansi`]).
+///
+/// The console keeps every cell (character and attributes) so it can redraw itself, and rows that
+/// scroll off the top go into a scrollback history which can be viewed with [`Console::scroll_view`].
+///
+/// A console without a screen still keeps its cells. This is how the [virtual terminals](vt) that
+/// aren't being shown work: switching to one hands it the screen and it redraws everything.
+pub struct Console {
+    screen:Option<Screen>,
+    /// Size of the area the console covers, in pixels
+    width:usize,
+    height:usize,
+    font:Font,
+    /// Size of a character cell, from the font
+    cell_w:usize,
+    cell_h:usize,
+    w:usize,
+    h:usize,
+    fg:Rgb888,
+    bg:Rgb888,
+    attrs:Attributes,
+    /// The scrollback rows, oldest first, then the rows of the screen, `w` cells each. It is a ring
+    /// once the scrollback is full: scrolling moves `start` on instead of moving cells.
+    cells:Vec<Cell>,
+    /// The row of `cells` the oldest scrollback row is in
+    start:usize,
+    /// How many rows of scrollback come before the screen
+    scrollback:usize,
+    scrollback_limit:usize,
+    /// How many rows back into the scrollback we are looking. 0 is the live screen.
+    view_offset:usize,
+    /// Can be `w` after writing in the last column. The wrap happens on the next character.
+    cursor:Point,
+    /// `(cursor,attrs)` saved by `ESC 7` or `ESC [ s`
+    saved:(Point,Attributes),
+    /// First and last row that scroll, inclusive
+    scroll_top:usize,
+    scroll_bottom:usize,
+    /// A tab stop at each column where this is true
+    tabs:Vec<bool>,
+    /// How long before the visual bell ends
+    bell_delay:Duration,
+    parser:Parser,
+    cursor_visible:bool,
+    /// The cursor is currently inverted on the screen
+    cursor_drawn:bool,
+    cursor_delay:Duration,
+    flush_ticks:u32,
+    flush_delay:u32,
+    /// Characters typed while this console was shown, waiting to be read
+    input:VecDeque<char>,
+    /// Print typed characters as they come in
+    echo:bool,
+    /// The task waiting in [`read_char`]
+    input_waker:Option<Waker>,
+}
+impl Write for Console {
+    fn write_str(&mut self,string:&str)->fmt::Result {
+        if let Err(_)=self.print_str(string) {
+            Err(fmt::Error)
+        } else {
+            Ok(())
+        }
+    }
+}
+#[allow(dead_code)]
+impl Console {
+    /// How long the blinking cursor stays shown and hidden, in milliseconds
+    pub const CURSOR_ON_DELAY:u64=2750;
+    pub const CURSOR_OFF_DELAY:u64=1100;
+    pub const DEFAULT_SCROLLBACK:usize=1000;
+    pub const TAB_WIDTH:usize=8;
+    /// How long the screen flashes for the bell, in milliseconds
+    pub const BELL_DELAY:u64=330;
+    pub const INPUT_LIMIT:usize=256;
+    pub const DEFAULT_FG:Rgb888=Rgb888::new(175,175,175);
+    pub const DEFAULT_BG:Rgb888=Rgb888::new(0,0,0);
+    pub fn new(screen:Screen)->Console {
+        Self::new_colors(screen,Self::DEFAULT_FG,Self::DEFAULT_BG)
+    }
+    pub fn new_colors(screen:Screen,fg:Rgb888,bg:Rgb888)->Console {
+        let mut console=Self::detached(screen.w,screen.h,fg,bg);
+        console.screen=Some(screen);
+        return console;
+    }
+    /// A console for a `width`x`height` pixel screen, using the built in font, with nowhere to
+    /// draw. Give it a screen with [`Console::attach`].
+    pub fn detached(width:usize,height:usize,fg:Rgb888,bg:Rgb888)->Console {
+        let font=Font::Builtin;
+        let (cell_w,cell_h)=font.size();
+        let (w,h)=((width/cell_w).max(1),(height/cell_h).max(1));
+        Console {
+            width,
+            height,
+            font,
+            cell_w,
+            cell_h,
+            w,
+            h,
+            screen:None,
+            fg,
+            bg,
+            attrs:Attributes::new(),
+            cells:vec![Cell::blank();w*h],
+            start:0,
+            scrollback:0,
+            scrollback_limit:Self::DEFAULT_SCROLLBACK,
+            view_offset:0,
+            cursor:Point::zero(),
+            saved:(Point::zero(),Attributes::new()),
+            scroll_top:0,
+            scroll_bottom:h-1,
+            tabs:Self::default_tabs(w),
+            bell_delay:Duration::ZERO,
+            parser:Parser::new(),
+            cursor_visible:true,
+            cursor_drawn:false,
+            cursor_delay:Duration::from_millis(Self::CURSOR_ON_DELAY),
+            flush_ticks:0,
+            flush_delay:0,
+            input:VecDeque::new(),
+            echo:true,
+            input_waker:None,
+        }
+    }
+    /// Starts drawing to `screen`, redrawing every cell
+    pub fn attach(&mut self,screen:Screen) {
+        self.screen=Some(screen);
+        self.cursor_drawn=false;
+        self.cursor_delay=Duration::ZERO;
+        self.bell_delay=Duration::ZERO;
+        self.redraw();
+    }
+    /// Moves the mouse pointer, if this console has the screen
+    pub fn set_pointer(&mut self,pos:Option<Point>) {
+        if let Some(screen)=self.screen.as_mut() {
+            screen.set_pointer(pos);
+        }
+    }
+    /// Stops drawing and gives the screen back. The cells are kept.
+    pub fn detach(&mut self)->Option<Screen> {
+        if !self.bell_delay.is_zero() {
+            self.bell_delay=Duration::ZERO;
+            self.redraw();
+        }
+        self.hide_cursor();
+        self.flush();
+        self.screen.take()
+    }
+    pub fn font(&self)->Font {
+        self.font
+    }
+    /// Switches fonts. The grid is worked out again from the new cell size, keeping as much of
+    /// the text as fits.
+    pub fn set_font(&mut self,font:Font) {
+        let (cell_w,cell_h)=font.size();
+        self.hide_cursor();
+        self.font=font;
+        self.cell_w=cell_w;
+        self.cell_h=cell_h;
+        self.resize((self.width/cell_w).max(1),(self.height/cell_h).max(1));
+    }
+    /// Changes the grid to `w`x`h` cells. Rows that would be cut off above the cursor go into the
+    /// scrollback. Scroll regions, tab stops and the view go back to their defaults.
+    fn resize(&mut self,w:usize,h:usize) {
+        let cut=(self.cursor.1+1).saturating_sub(h);
+        self.reshape(w,h,cut,self.scrollback_limit);
+        self.cursor=Point(self.cursor.0.min(w-1),self.cursor.1-cut);
+        self.saved.0=Point(self.saved.0.0.min(w-1),self.saved.0.1.min(h-1));
+        self.scroll_top=0;
+        self.scroll_bottom=h-1;
+        self.tabs=Self::default_tabs(w);
+        self.view_offset=0;
+        let bg=self.bg;
+        if let Some(screen)=self.screen.as_mut() {
+            screen.fill_rect(&Rectangle::new(EGPoint::zero(),Size::new(self.width as u32,self.height as u32)),bg);
+        }
+        self.redraw();
+    }
+    /// Lays the cells out again for a `w`x`h` screen and `limit` rows of scrollback, with the ring
+    /// starting over at the oldest row. The top `cut` rows of the screen go into the scrollback,
+    /// and rows are cut or padded to the new width.
+    fn reshape(&mut self,w:usize,h:usize,cut:usize,limit:usize) {
+        let history=(self.scrollback+cut).min(limit);
+        let first=self.scrollback+cut-history;
+        let kept=history+(self.h-cut).min(h);
+        let cols=w.min(self.w);
+        let mut cells=vec![Cell::blank();(history+h)*w];
+        for r in 0..kept {
+            let from=self.row_start(first+r);
+            let row=&mut cells[r*w..r*w+cols];
+            row.copy_from_slice(&self.cells[from..from+cols]);
+            if let Some(last)=row.last_mut() {
+                if unicode::char_width(last.c)>1 {   // lost its right half
+                    *last=Cell::blank();
+                }
+            }
+        }
+        self.cells=cells;
+        self.start=0;
+        self.scrollback=history;
+        self.scrollback_limit=limit;
+        self.w=w;
+        self.h=h;
+    }
+    /// Where row `r` starts in the cells, counting from the oldest scrollback row
+    fn row_start(&self,r:usize)->usize {
+        (self.start+r)%(self.cells.len()/self.w)*self.w
+    }
+    /// Row `y` of the screen
+    fn row(&self,y:usize)->&[Cell] {
+        let i=self.row_start(self.scrollback+y);
+        &self.cells[i..i+self.w]
+    }
+    fn row_mut(&mut self,y:usize)->&mut [Cell] {
+        let i=self.row_start(self.scrollback+y);
+        &mut self.cells[i..i+self.w]
+    }
+    /// Copies screen row `from` over row `to`
+    fn copy_row(&mut self,from:usize,to:usize) {
+        let (from,to)=(self.row_start(self.scrollback+from),self.row_start(self.scrollback+to));
+        self.cells.copy_within(from..from+self.w,to);
+    }
+    fn default_tabs(w:usize)->Vec<bool> {
+        (0..w).map(|x|x>0&&x%Self::TAB_WIDTH==0).collect()
+    }
+    pub fn is_attached(&self)->bool {
+        self.screen.is_some()
+    }
+    /// Queues a typed character for [`Console::read_input`], printing it if echo is on. The oldest
+    /// characters are dropped when nobody is reading.
+    pub fn push_input(&mut self,c:char) {
+        if self.input.len()>=Self::INPUT_LIMIT {
+            self.input.pop_front();
+        }
+        self.input.push_back(c);
+        if let Some(waker)=self.input_waker.take() {
+            waker.wake();
+        }
+        if self.echo {
+            let mut buf=[0u8;4];
+            let _=self.print_str(c.encode_utf8(&mut buf));
+        }
+    }
+    /// Queues a string, like the escape sequence for a cursor key, without echoing it
+    pub fn push_input_str(&mut self,string:&str) {
+        let echo=core::mem::replace(&mut self.echo,false);
+        for c in string.chars() {
+            self.push_input(c);
+        }
+        self.echo=echo;
+    }
+    pub fn read_input(&mut self)->Option<char> {
+        self.input.pop_front()
+    }
+    pub fn has_input(&self)->bool {
+        !self.input.is_empty()
+    }
+    pub fn set_echo(&mut self,echo:bool) {
+        self.echo=echo;
+    }
+    /// With a back buffer, flush every `ticks` timer ticks. 0 flushes after every write.
+    pub fn set_flush_ticks(&mut self,ticks:u32) {
+        self.flush_ticks=ticks;
+        self.flush_delay=ticks;
+    }
+    /// How many rows of history to keep. Extra rows are dropped oldest first.
+    pub fn set_scrollback_limit(&mut self,rows:usize) {
+        self.reshape(self.w,self.h,0,rows);
+        if self.view_offset>self.scrollback {
+            let extra=(self.view_offset-self.scrollback) as isize;
+            self.scroll_view(-extra);
+        }
+    }
+    pub fn flush(&mut self) {
+        if let Some(screen)=self.screen.as_mut() {
+            screen.flush();
+        }
+    }
+    /// Call after writing to the console. Flushes unless flushing is left to the timer.
+    pub fn written(&mut self) {
+        if self.flush_ticks==0 {
+            self.flush();
+        }
+    }
+    pub fn flush_tick(&mut self) {
+        if self.flush_delay==0 {
+            self.flush();
+            self.flush_delay=self.flush_ticks;
+        } else {
+            self.flush_delay-=1;
+        }
+    }
+    /// The default `(fg,bg)`
+    pub fn colors(&self)->(Rgb888,Rgb888) {
+        (self.fg,self.bg)
+    }
+    /// Size in character cells
+    pub fn size(&self)->(usize,usize) {
+        (self.w,self.h)
+    }
+    /// The screen is showing the live rows and not the scrollback
+    fn live(&self)->bool {
+        self.screen.is_some()&&self.view_offset==0
+    }
+    /// The screen, if it is showing the live rows
+    fn live_screen(&mut self)->Option<&mut Screen> {
+        if self.view_offset==0 {self.screen.as_mut()} else {None}
+    }
+    /// Moves the view `rows` into the scrollback (positive) or back towards the live screen
+    /// (negative) and redraws.
+    pub fn scroll_view(&mut self,rows:isize) {
+        let offset=(self.view_offset as isize+rows).max(0) as usize;
+        let offset=offset.min(self.scrollback);
+        if offset==self.view_offset {return}
+        self.hide_cursor();
+        self.view_offset=offset;
+        self.redraw();
+    }
+    /// Back to the live screen, if we were looking at the scrollback
+    pub fn scroll_view_reset(&mut self) {
+        let offset=self.view_offset as isize;
+        self.scroll_view(-offset);
+    }
+    /// Draws every row from the cells, taking the view offset into account. The cursor is drawn
+    /// over, so it is left hidden.
+    pub fn redraw(&mut self) {
+        self.cursor_drawn=false;
+        for y in 0..self.h {
+            let row=self.row_start(self.scrollback-self.view_offset+y);
+            for x in 0..self.w {
+                self.render_cell(x,y,self.cells[row+x]);
+            }
+        }
+    }
+    fn cell_rect(&self,x:usize,y:usize,w:usize,h:usize)->Rectangle {
+        Rectangle::new(
+            (Point(x,y)*Point(self.cell_w,self.cell_h)).into(),
+            Size::new((w*self.cell_w) as u32,(h*self.cell_h) as u32),
+        )
+    }
+    /// Inverts the cursor's cell column. Doing it twice puts back whatever was there.
+    fn invert_cursor(&mut self) {
+        let x=self.cursor.0.min(self.w-1);
+        let mut rect=self.cell_rect(x,self.cursor.1,1,1);
+        rect.size.width=1;
+        let screen=match self.screen.as_mut() {
+            Some(screen)=>screen,
+            None=>return,
+        };
+        let mask=screen.encode(Rgb888::new(255,255,255));
+        let (px,w)=(rect.top_left.x as usize,rect.size.width as usize);
+        for y in rect.rows() {
+            for p in screen.row_span(y as usize,px..px+w) {
+                *p^=mask;
+            }
+        }
+        self.cursor_drawn=!self.cursor_drawn;
+    }
+    /// Takes the cursor off the screen before drawing and restarts the blink
+    fn hide_cursor(&mut self) {
+        if self.cursor_drawn {
+            self.invert_cursor();
+        }
+        self.cursor_delay=Duration::ZERO;
+    }
+    /// Called every timer tick with how long the tick was
+    pub fn cursor_tick(&mut self,elapsed:Duration) {
+        if !self.bell_delay.is_zero() {
+            self.bell_delay=self.bell_delay.saturating_sub(elapsed);
+            if self.bell_delay.is_zero() {
+                self.redraw();
+            }
+            return;
+        }
+        if self.cursor_delay.is_zero() {
+            if self.cursor_drawn {
+                self.invert_cursor();
+                self.cursor_delay=Duration::from_millis(Self::CURSOR_OFF_DELAY);
+            } else if self.cursor_visible&&self.live() {
+                self.invert_cursor();
+                self.cursor_delay=Duration::from_millis(Self::CURSOR_ON_DELAY);
+            }
+        } else {
+            self.cursor_delay=self.cursor_delay.saturating_sub(elapsed);
+        }
+    }
+    pub fn println(&mut self,string:&str)->Result<(),usize> {
+        self.print(string)?;
+        self.print_str("\n")
+    }
+    pub fn print(&mut self,string:&str)->Result<(),usize> {
+        self.print_str(string)
+    }
+    pub fn println_str_format(&mut self,string:&str,fg:Rgb888,bg:Option<Rgb888>,underline:bool)->Result<(),usize> {
+        self.print_str_format(string,fg,bg,underline)?;
+        self.print_str("\n")
+    }
+    pub fn move_up(&mut self) {
+        self.hide_cursor();
+        self.scroll_up(1);
+    }
+    /// Prints with the given colours instead of the current attributes. `bg` of `None` keeps the
+    /// current background.
+    pub fn print_str_format(&mut self,string:&str,fg:Rgb888,bg:Option<Rgb888>,underline:bool)->Result<(),usize> {
+        let old=self.attrs;
+        self.attrs.fg=Color::Rgb(fg);
+        if let Some(bg)=bg {
+            self.attrs.bg=Color::Rgb(bg);
+        }
+        self.attrs.underline=underline;
+        let res=self.print_str(string);
+        self.attrs=old;
+        return res;
+    }
+    /// Prints a string, following any escape sequences in it. This can't fail, the `Result` is only
+    /// there to match the other printing functions.
+    pub fn print_str(&mut self,string:&str)->Result<(),usize> {
+        self.hide_cursor();
+        for c in string.chars() {
+            if let Some(action)=self.parser.advance(c) {
+                self.perform(action);
+            }
+        }
+        return Ok(());
+    }
+    fn perform(&mut self,action:Action) {
+        match action {
+            Action::Print(c)=>self.put_char(c),
+            Action::Execute(b)=>self.execute(b),
+            Action::Csi{params,private,intermediate:None,final_byte}=>self.csi(&params,private,final_byte),
+            Action::Esc{intermediate:None,final_byte}=>self.esc(final_byte),
+            _=>{},  // charset selection and other things we don't do
+        }
+    }
+    fn resolve(&self,color:Color,default:Rgb888,bold:bool)->Rgb888 {
+        match color {
+            Color::Default=>default,
+            Color::Indexed(i) if bold&&i<8=>PALETTE_16[i as usize+8],
+            Color::Indexed(i)=>palette_256(i),
+            Color::Rgb(c)=>c,
+        }
+    }
+    /// `(fg,bg)` for some attributes
+    fn colors_of(&self,attrs:&Attributes)->(Rgb888,Rgb888) {
+        let fg=self.resolve(attrs.fg,self.fg,attrs.bold);
+        let bg=self.resolve(attrs.bg,self.bg,false);
+        if attrs.reverse {(bg,fg)} else {(fg,bg)}
+    }
+    /// What erased cells become. Only the background of the current attributes is kept.
+    fn blank(&self)->Cell {
+        let bg=if self.attrs.reverse {
+            match self.attrs.fg {
+                Color::Default=>Color::Rgb(self.fg),
+                c=>c,
+            }
+        } else {
+            self.attrs.bg
+        };
+        Cell {
+            c:' ',
+            attrs:Attributes{bg,..Attributes::new()},
+        }
+    }
+    fn render_cell(&mut self,x:usize,y:usize,cell:Cell) {
+        if cell.is_wide_tail() {return}  // drawn with the left half
+        let (fg,bg)=self.colors_of(&cell.attrs);
+        let cols=unicode::char_width(cell.c).max(1).min(self.w-x);
+        let rect=self.cell_rect(x,y,cols,1);
+        let screen=match self.screen.as_mut() {
+            Some(screen)=>screen,
+            None=>return,
+        };
+        let start_coords=Point(x,y)*Point(self.cell_w,self.cell_h);
+        if self.font.has_glyph(cell.c) {
+            self.font.draw(screen,start_coords,cell.c,fg,bg);
+            if cols>1 {
+                let rest=Rectangle::new(rect.top_left+Size::new(self.cell_w as u32,0),Size::new(((cols-1)*self.cell_w) as u32,rect.size.height));
+                screen.fill_rect(&rest,bg);
+            }
+        } else {
+            // the replacement glyph: a box as wide as the character, if the font is big enough
+            screen.fill_rect(&rect,bg);
+            let inner=Rectangle::new(rect.top_left+Size::new(1,2),rect.size.saturating_sub(Size::new(3,4)));
+            if inner.size.width>0&&inner.size.height>0 {
+                for edge in [
+                    Rectangle::new(inner.top_left,Size::new(inner.size.width,1)),
+                    Rectangle::new(inner.top_left+Size::new(0,inner.size.height-1),Size::new(inner.size.width,1)),
+                    Rectangle::new(inner.top_left,Size::new(1,inner.size.height)),
+                    Rectangle::new(inner.top_left+Size::new(inner.size.width-1,0),Size::new(1,inner.size.height)),
+                ] {
+                    screen.fill_rect(&edge,fg);
+                }
+            }
+        }
+        if cell.attrs.underline {
+            let underline=Rectangle::new(rect.top_left+Size::new(0,rect.size.height.saturating_sub(1)),Size::new(rect.size.width,1));
+            screen.fill_rect(&underline,fg);
+        }
+    }
+    fn set_cell(&mut self,x:usize,y:usize,cell:Cell) {
+        self.row_mut(y)[x]=cell;
+        if self.live() {
+            self.render_cell(x,y,cell);
+        }
+    }
+    /// Blanks the other half of any wide character that `x..x+cols` of row `y` cuts in two
+    fn split_wide(&mut self,x:usize,y:usize,cols:usize) {
+        if x>0&&self.row(y)[x].is_wide_tail() {
+            self.set_cell(x-1,y,self.blank());
+        }
+        let end=x+cols;
+        if end<self.w&&self.row(y)[end].is_wide_tail() {
+            self.set_cell(end,y,self.blank());
+        }
+    }
+    /// Draws `c` at the cursor, wrapping by display columns. Wide characters take two cells and
+    /// zero width ones are dropped.
+    fn put_char(&mut self,c:char) {
+        let cols=unicode::char_width(c);
+        if cols==0||cols>self.w {return}
+        if self.cursor.0+cols>self.w {
+            if self.cursor.0<self.w {   // a wide character that doesn't fit in the last column
+                self.erase(self.cursor.1,self.cursor.0,self.w);
+            }
+            self.cursor.0=0;
+            self.line_feed();
+        }
+        let (x,y)=(self.cursor.0,self.cursor.1);
+        self.split_wide(x,y,cols);
+        let attrs=self.attrs;
+        if cols==2 {
+            self.row_mut(y)[x+1]=Cell{c:Cell::WIDE_TAIL,attrs};
+        }
+        self.set_cell(x,y,Cell{c,attrs});
+        self.cursor.0+=cols;
+    }
+    fn execute(&mut self,b:u8) {
+        match b {
+            0x07=>self.bell(),
+            0x08=>self.cursor.0=self.cursor.0.min(self.w-1).saturating_sub(1),
+            b'\t'=>self.tab_forward(1),
+            b'\n'|0x0B|0x0C=>{
+                self.cursor.0=0;
+                self.line_feed();
+            },
+            b'\r'=>self.cursor.0=0,
+            _=>{},
+        }
+    }
+    /// Flashes the screen for [`Console::BELL_DELAY`] milliseconds
+    pub fn bell(&mut self) {
+        if !self.live()||!self.bell_delay.is_zero() {return}
+        self.hide_cursor();
+        let (w,h)=(self.w*self.cell_w,self.h*self.cell_h);
+        let screen=self.screen.as_mut().unwrap();
+        let mask=screen.encode(Rgb888::new(255,255,255));
+        for y in 0..h {
+            for p in screen.row_span(y,0..w) {
+                *p^=mask;
+            }
+        }
+        self.bell_delay=Duration::from_millis(Self::BELL_DELAY);
+    }
+    /// Moves to the `n`th next tab stop, or the last column
+    fn tab_forward(&mut self,n:usize) {
+        let mut x=self.cursor.0.min(self.w-1);
+        for _ in 0..n {
+            x=(x+1..self.w).find(|&x|self.tabs[x]).unwrap_or(self.w-1);
+        }
+        self.cursor.0=x;
+    }
+    /// Moves to the `n`th previous tab stop, or the first column
+    fn tab_backward(&mut self,n:usize) {
+        let mut x=self.cursor.0.min(self.w-1);
+        for _ in 0..n {
+            x=(0..x).rev().find(|&x|self.tabs[x]).unwrap_or(0);
+        }
+        self.cursor.0=x;
+    }
+    /// Down a row, scrolling if we are at the bottom of the scroll region
+    fn line_feed(&mut self) {
+        if self.cursor.1==self.scroll_bottom {
+            self.scroll_up(1);
+        } else if self.cursor.1+1<self.h {
+            self.cursor.1+=1;
+        }
+    }
+    /// Up a row, scrolling down if we are at the top of the scroll region
+    fn reverse_index(&mut self) {
+        if self.cursor.1==self.scroll_top {
+            self.scroll_down(1);
+        } else if self.cursor.1>0 {
+            self.cursor.1-=1;
+        }
+    }
+    fn scroll_up(&mut self,rows:usize) {
+        self.scroll_rows_up(self.scroll_top,rows);
+    }
+    fn scroll_down(&mut self,rows:usize) {
+        self.scroll_rows_down(self.scroll_top,rows);
+    }
+    /// Makes the top row of the screen the newest scrollback row. Once the scrollback is full the
+    /// oldest row is dropped by moving the start of the ring on. The row that comes in at the
+    /// bottom of the screen is left for the caller to blank.
+    fn push_scrollback(&mut self) {
+        if self.scrollback<self.scrollback_limit {
+            // the ring only starts going round once it is full, so it can grow at the end
+            if (self.scrollback+self.h)*self.w==self.cells.len() {
+                self.cells.resize(self.cells.len()+self.w,Cell::blank());
+            }
+            self.scrollback+=1;
+        } else {
+            self.start=(self.start+1)%(self.cells.len()/self.w);
+        }
+        if self.view_offset>0 {   // keep looking at the same rows
+            self.view_offset=(self.view_offset+1).min(self.scrollback);
+        }
+    }
+    /// Scrolls rows `top..=scroll_bottom` up. Rows scrolled off the top of the screen go into the
+    /// scrollback.
+    fn scroll_rows_up(&mut self,top:usize,rows:usize) {
+        let (rows,bottom)=(rows.min(self.scroll_bottom+1-top),self.scroll_bottom);
+        if top==0 {
+            for _ in 0..rows {
+                self.push_scrollback();
+                // the rows under the scroll region went up with the rest
+                for y in (bottom+1..self.h).rev() {
+                    self.copy_row(y-1,y);
+                }
+            }
+        } else {
+            for y in top..=bottom-rows {
+                self.copy_row(y+rows,y);
+            }
+        }
+        let blank=self.blank();
+        for y in bottom+1-rows..=bottom {
+            self.row_mut(y).fill(blank);
+        }
+        let color=self.colors_of(&blank.attrs).1;
+        let (cell_h,pixels)=(self.cell_h,top*self.cell_h..(bottom+1)*self.cell_h);
+        if let Some(screen)=self.live_screen() {
+            screen.scroll_up(pixels,rows*cell_h,color);
+        }
+    }
+    /// Scrolls rows `top..=scroll_bottom` down
+    fn scroll_rows_down(&mut self,top:usize,rows:usize) {
+        let (rows,bottom)=(rows.min(self.scroll_bottom+1-top),self.scroll_bottom);
+        for y in (top+rows..=bottom).rev() {
+            self.copy_row(y-rows,y);
+        }
+        let blank=self.blank();
+        for y in top..top+rows {
+            self.row_mut(y).fill(blank);
+        }
+        let color=self.colors_of(&blank.attrs).1;
+        let (cell_h,pixels)=(self.cell_h,top*self.cell_h..(bottom+1)*self.cell_h);
+        if let Some(screen)=self.live_screen() {
+            screen.scroll_down(pixels,rows*cell_h,color);
+        }
+    }
+    /// Erases columns `x0..x1` of row `y`
+    fn erase(&mut self,y:usize,x0:usize,x1:usize) {
+        let x1=x1.min(self.w);
+        if x0>=x1 {return}
+        self.split_wide(x0,y,x1-x0);
+        let blank=self.blank();
+        self.row_mut(y)[x0..x1].fill(blank);
+        let color=self.colors_of(&blank.attrs).1;
+        let rect=self.cell_rect(x0,y,x1-x0,1);
+        if let Some(screen)=self.live_screen() {
+            screen.fill_rect(&rect,color);
+        }
+    }
+    fn erase_rows(&mut self,y0:usize,y1:usize) {
+        for y in y0..y1.min(self.h) {
+            self.erase(y,0,self.w);
+        }
+    }
+    /// Moves the cells of row `y` from column `from` onwards to column `to`. Wide characters cut
+    /// in two by where the shift starts, or pushed half off the end, are blanked.
+    fn shift_cells(&mut self,y:usize,from:usize,to:usize) {
+        let (from,to)=(from.min(self.w),to.min(self.w));
+        self.split_wide(from.min(to),y,from.saturating_sub(to));
+        let len=self.w-from.max(to);
+        self.row_mut(y).copy_within(from..from+len,to);
+        let (cell_w,cell_h)=(self.cell_w,self.cell_h);
+        if let Some(screen)=self.live_screen() {
+            let px=|c:usize|c*cell_w;
+            for py in y*cell_h..(y+1)*cell_h {
+                screen.row(py).copy_within(px(from)..px(from+len),px(to));
+            }
+        }
+        if from>to {
+            self.erase(y,to+len,self.w);
+        } else {
+            self.erase(y,from,to);
+            let last=self.row(y)[self.w-1];
+            if !last.is_wide_tail()&&unicode::char_width(last.c)>1 {
+                self.set_cell(self.w-1,y,self.blank());
+            }
+        }
+    }
+    fn set_cursor(&mut self,x:usize,y:usize) {
+        self.cursor=Point(x.min(self.w-1),y.min(self.h-1));
+    }
+    fn esc(&mut self,final_byte:u8) {
+        match final_byte {
+            b'7'=>self.saved=(self.cursor,self.attrs),
+            b'8'=>{
+                self.cursor=self.saved.0;
+                self.attrs=self.saved.1;
+            },
+            b'D'=>self.line_feed(),
+            b'E'=>{
+                self.cursor.0=0;
+                self.line_feed();
+            },
+            b'H'=>self.tabs[self.cursor.0.min(self.w-1)]=true,
+            b'M'=>self.reverse_index(),
+            b'c'=>self.reset(),
+            _=>{},
+        }
+    }
+    /// Back to the power on state, with a clear screen
+    pub fn reset(&mut self) {
+        self.hide_cursor();
+        self.attrs=Attributes::new();
+        self.saved=(Point::zero(),Attributes::new());
+        self.scroll_top=0;
+        self.scroll_bottom=self.h-1;
+        self.tabs=Self::default_tabs(self.w);
+        self.cursor_visible=true;
+        self.parser=Parser::new();
+        self.erase_rows(0,self.h);
+        self.cursor=Point::zero();
+    }
+    fn csi(&mut self,params:&Params,private:Option<u8>,final_byte:u8) {
+        let n=params.get_or_one(0) as usize;
+        let (x,y)=(self.cursor.0.min(self.w-1),self.cursor.1);
+        if let Some(b'?')=private {
+            let set=match final_byte {
+                b'h'=>true,
+                b'l'=>false,
+                _=>return,
+            };
+            for mode in params.iter() {
+                if mode==25 {
+                    self.cursor_visible=set;
+                }
+            }
+            return;
+        } else if private.is_some() {
+            return;
+        }
+        match final_byte {
+            b'A'=>self.set_cursor(x,y.saturating_sub(n).max(if y>=self.scroll_top {self.scroll_top} else {0})),
+            b'B'=>self.set_cursor(x,(y+n).min(if y<=self.scroll_bottom {self.scroll_bottom} else {self.h-1})),
+            b'C'=>self.set_cursor(x+n,y),
+            b'D'=>self.set_cursor(x.saturating_sub(n),y),
+            b'E'=>self.set_cursor(0,y+n),
+            b'F'=>self.set_cursor(0,y.saturating_sub(n)),
+            b'G'|b'`'=>self.set_cursor(n-1,y),
+            b'I'=>self.tab_forward(n),
+            b'Z'=>self.tab_backward(n),
+            b'g'=>match params.get(0) {
+                0=>self.tabs[x]=false,
+                3=>self.tabs.iter_mut().for_each(|t|*t=false),
+                _=>{},
+            },
+            b'd'=>self.set_cursor(x,n-1),
+            b'H'|b'f'=>self.set_cursor(params.get_or_one(1) as usize-1,n-1),
+            b'J'=>match params.get(0) {
+                0=>{
+                    self.erase(y,x,self.w);
+                    self.erase_rows(y+1,self.h);
+                },
+                1=>{
+                    self.erase_rows(0,y);
+                    self.erase(y,0,x+1);
+                },
+                2|3=>self.erase_rows(0,self.h),
+                _=>{},
+            },
+            b'K'=>match params.get(0) {
+                0=>self.erase(y,x,self.w),
+                1=>self.erase(y,0,x+1),
+                2=>self.erase(y,0,self.w),
+                _=>{},
+            },
+            b'X'=>self.erase(y,x,x+n),
+            b'@'=>self.shift_cells(y,x,x+n),
+            b'P'=>self.shift_cells(y,x+n,x),
+            b'L' if y>=self.scroll_top&&y<=self.scroll_bottom=>{
+                self.scroll_rows_down(y,n);
+                self.cursor.0=0;
+            },
+            b'M' if y>=self.scroll_top&&y<=self.scroll_bottom=>{
+                self.scroll_rows_up(y,n);
+                self.cursor.0=0;
+            },
+            b'S'=>self.scroll_up(n),
+            b'T'=>self.scroll_down(n),
+            b'm'=>self.sgr(params),
+            b'r'=>{
+                let top=params.get_or_one(0) as usize-1;
+                let bottom=match params.get(1) {
+                    0=>self.h,
+                    b=>(b as usize).min(self.h),
+                }-1;
+                if top<bottom {
+                    self.scroll_top=top;
+                    self.scroll_bottom=bottom;
+                    self.cursor=Point::zero();
+                }
+            },
+            b's'=>self.saved=(self.cursor,self.attrs),
+            b'u'=>{
+                self.cursor=self.saved.0;
+                self.attrs=self.saved.1;
+            },
+            _=>{},
+        }
+    }
+    /// Select Graphic Rendition. No parameters is the same as `0`.
+    fn sgr(&mut self,params:&Params) {
+        let p=params.as_slice();
+        if p.len()==0 {
+            self.attrs=Attributes::new();
+            return;
+        }
+        let mut i=0;
+        while i<p.len() {
+            match p[i] {
+                0=>self.attrs=Attributes::new(),
+                1=>self.attrs.bold=true,
+                4=>self.attrs.underline=true,
+                7=>self.attrs.reverse=true,
+                22=>self.attrs.bold=false,
+                24=>self.attrs.underline=false,
+                27=>self.attrs.reverse=false,
+                c@30..=37=>self.attrs.fg=Color::Indexed((c-30) as u8),
+                39=>self.attrs.fg=Color::Default,
+                c@40..=47=>self.attrs.bg=Color::Indexed((c-40) as u8),
+                49=>self.attrs.bg=Color::Default,
+                c@90..=97=>self.attrs.fg=Color::Indexed((c-90+8) as u8),
+                c@100..=107=>self.attrs.bg=Color::Indexed((c-100+8) as u8),
+                which@(38|48)=>{
+                    let color=match p.get(i+1) {
+                        Some(5)=>{
+                            i+=2;
+                            p.get(i).map(|c|Color::Indexed(*c as u8))
+                        },
+                        Some(2)=>{
+                            i+=4;
+                            match (p.get(i-2),p.get(i-1),p.get(i)) {
+                                (Some(r),Some(g),Some(b))=>Some(Color::Rgb(Rgb888::new(*r as u8,*g as u8,*b as u8))),
+                                _=>None,
+                            }
+                        },
+                        _=>None,
+                    };
+                    match (which,color) {
+                        (38,Some(c))=>self.attrs.fg=c,
+                        (48,Some(c))=>self.attrs.bg=c,
+                        _=>return,  // can't tell where the next parameter starts
+                    }
+                },
+                _=>{},
+            }
+            i+=1;
+        }
+    }
+}
+
+
+/// Lays text out on a console without a screen and checks where it went
+pub fn test_layout()->Result<(),&'static str> {
+    let (cell_w,cell_h)=Font::Builtin.size();
+    let mut console=Console::detached(12*cell_w,3*cell_h,Console::DEFAULT_FG,Console::DEFAULT_BG);
+    let _=console.print_str("a\tb\x08c\r\n漢字xyzé\u{301}\x07");
+    let row=|console:&Console,y:usize|->[char;12] {
+        let mut chars=[' ';12];
+        for (c,cell) in chars.iter_mut().zip(console.row(y).iter()) {
+            *c=cell.c;
+        }
+        chars
+    };
+    if row(&console,0)!=['a',' ',' ',' ',' ',' ',' ',' ','c',' ',' ',' '] {
+        return Err("tab or backspace went to the wrong column");
+    }
+    if row(&console,1)[..7]!=['漢',Cell::WIDE_TAIL,'字',Cell::WIDE_TAIL,'x','y','z'] {
+        return Err("wide characters weren't given two columns");
+    }
+    if console.cursor!=Point(8,1) {
+        return Err("the cursor didn't move by display columns");
+    }
+    // a wide character in the last column wraps, and overwriting half of one blanks the rest
+    let _=console.print_str("\x1b[2;12H字\x1b[3;2Hx");
+    if row(&console,1)[11]!=' '||console.row(2)[0].c!=' '||console.row(2)[1].c!='x' {
+        return Err("wide characters didn't wrap or split cleanly");
+    }
+    if unicode::char_width('\u{301}')!=0||unicode::char_width('가')!=2||unicode::str_width("ab漢")!=4 {
+        return Err("char_width is wrong");
+    }
+    return Ok(());
+}
+/// Scrolls a small console past its scrollback limit and with scroll regions, then makes it
+/// narrower
+pub fn test_scrollback()->Result<(),&'static str> {
+    let (cell_w,cell_h)=Font::Builtin.size();
+    let mut console=Console::detached(4*cell_w,3*cell_h,Console::DEFAULT_FG,Console::DEFAULT_BG);
+    console.set_scrollback_limit(3);
+    let _=console.print_str("1\n2\n3\n4\n5\n6\n7");
+    let rows=|console:&Console|->Vec<char> {
+        (0..console.scrollback+console.h).map(|r|console.cells[console.row_start(r)].c).collect()
+    };
+    if rows(&console)!=['2','3','4','5','6','7'] {
+        return Err("rows didn't go round the scrollback ring");
+    }
+    // only rows scrolled off the top of the screen go into the scrollback
+    let _=console.print_str("\x1b[2;3r\x1b[3;1H\n8");
+    if rows(&console)!=['2','3','4','5','7','8'] {
+        return Err("a scroll region scrolled into the scrollback");
+    }
+    let _=console.print_str("\x1b[1;2r\x1b[2;1H\n9");
+    if rows(&console)!=['3','4','5','7','9','8'] {
+        return Err("rows under a scroll region moved");
+    }
+    console.resize(2,3);
+    if rows(&console)!=['3','4','5','7','9','8']||console.cells.len()!=6*2 {
+        return Err("changing the width lost rows");
+    }
+    return Ok(());
+}
@@ -0,0 +1,18 @@
+//! The mouse pointer. It shows up the first time the mouse moves and follows it from then on,
+//! drawn over whichever virtual terminal is showing.
+
+
+use crate::{
+    input,
+    math::Point,
+};
+
+
+/// The console's mouse task
+pub async fn run() {
+    let mouse=input::subscribe_mouse();
+    loop {
+        let event=mouse.read().await;
+        super::_pointer(Some(Point(event.x,event.y)));
+    }
+}
@@ -0,0 +1,87 @@
+//! How many console columns a character takes up. This is a cut down `wcwidth`: combining marks
+//! and other zero width characters take none, East Asian wide and fullwidth characters (and most
+//! emoji) take two, everything else takes one.
+
+
+/// Ranges of characters that take no columns. They would combine with the previous character but
+/// we can't draw that, so they are dropped.
+static ZERO_WIDTH:&[(u32,u32)]=&[
+    (0x0300,0x036F),    // combining diacritical marks
+    (0x0483,0x0489),
+    (0x0591,0x05BD),
+    (0x0610,0x061A),
+    (0x064B,0x065F),
+    (0x0E31,0x0E31),
+    (0x0E34,0x0E3A),
+    (0x1AB0,0x1AFF),
+    (0x1DC0,0x1DFF),
+    (0x200B,0x200F),    // zero width space, joiners and direction marks
+    (0x202A,0x202E),
+    (0x2060,0x2064),
+    (0x20D0,0x20FF),    // combining marks for symbols
+    (0xFE00,0xFE0F),    // variation selectors
+    (0xFE20,0xFE2F),
+    (0xFEFF,0xFEFF),    // byte order mark
+    (0xE0100,0xE01EF),
+];
+/// Ranges of characters that take two columns
+static WIDE:&[(u32,u32)]=&[
+    (0x1100,0x115F),    // Hangul Jamo
+    (0x231A,0x231B),
+    (0x2329,0x232A),
+    (0x23E9,0x23EC),
+    (0x25FD,0x25FE),
+    (0x2614,0x2615),
+    (0x2E80,0x303E),    // CJK radicals, punctuation
+    (0x3041,0x33FF),    // kana, CJK symbols
+    (0x3400,0x4DBF),    // CJK extension A
+    (0x4E00,0x9FFF),    // CJK unified ideographs
+    (0xA000,0xA4CF),    // Yi
+    (0xA960,0xA97F),
+    (0xAC00,0xD7A3),    // Hangul syllables
+    (0xF900,0xFAFF),    // CJK compatibility ideographs
+    (0xFE10,0xFE19),
+    (0xFE30,0xFE6F),
+    (0xFF00,0xFF60),    // fullwidth forms
+    (0xFFE0,0xFFE6),
+    (0x1F300,0x1F64F),  // pictographs and emoticons
+    (0x1F680,0x1F6FF),
+    (0x1F900,0x1F9FF),
+    (0x1FA70,0x1FAFF),
+    (0x20000,0x2FFFD),
+    (0x30000,0x3FFFD),
+];
+
+
+fn in_table(table:&[(u32,u32)],c:u32)->bool {
+    table.binary_search_by(|&(start,end)|{
+        if end<c {
+            core::cmp::Ordering::Less
+        } else if start>c {
+            core::cmp::Ordering::Greater
+        } else {
+            core::cmp::Ordering::Equal
+        }
+    }).is_ok()
+}
+
+
+/// Columns `c` takes up: 0, 1 or 2. Control characters are 0.
+pub fn char_width(c:char)->usize {
+    let c=c as u32;
+    if c<0x20||(0x7F..0xA0).contains(&c) {
+        0
+    } else if c<0x300 {
+        1
+    } else if in_table(ZERO_WIDTH,c) {
+        0
+    } else if in_table(WIDE,c) {
+        2
+    } else {
+        1
+    }
+}
+/// Columns a string of plain text takes up. Escape sequences aren't skipped.
+pub fn str_width(string:&str)->usize {
+    string.chars().map(char_width).sum()
+}
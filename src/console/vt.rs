@@ -0,0 +1,107 @@
+//! Virtual terminals. Each one is a whole [`Console`] (cells, cursor, scrollback and typed input),
+//! but only the active one has the screen. Alt+F1..F12 switch between them.
+//!
+//! VT1 is the kernel's own console where `print!` goes, and one VT (VT12 unless `console.log_vt`
+//! says otherwise) is kept for the kernel log so a shell and the log can be looked at side by side.
+
+
+use alloc::vec::Vec;
+use crate::{
+    screen::Screen,
+    config::{
+        Opt,
+        Kind,
+    },
+};
+use super::{
+    Console,
+    font::Font,
+};
+
+
+/// How many virtual terminals there are, one per function key
+pub const COUNT:usize=12;
+/// Where `print!` goes
+pub const KERNEL_VT:usize=0;
+
+
+pub static LOG_VT:Opt=Opt::new("console.log_vt",Kind::Int,"12","Virtual terminal (1-12) the kernel log is written to");
+pub static LOG_ECHO:Opt=Opt::new("console.log_echo",Kind::Bool,"true","Also write the kernel log to VT1");
+
+
+pub struct Terminals {
+    vts:Vec<Console>,
+    active:usize,
+    log:usize,
+    log_echo:bool,
+}
+#[allow(dead_code)]
+impl Terminals {
+    /// [`COUNT`] terminals the size of `screen`, with the first one showing
+    pub fn new(screen:Screen)->Terminals {
+        let (width,height)=(screen.w,screen.h);
+        let mut vts=Vec::with_capacity(COUNT);
+        vts.push(Console::new(screen));
+        while vts.len()<COUNT {
+            vts.push(Console::detached(width,height,Console::DEFAULT_FG,Console::DEFAULT_BG));
+        }
+        Terminals {
+            vts,
+            active:KERNEL_VT,
+            log:COUNT-1,
+            log_echo:true,
+        }
+    }
+    /// Index of the terminal being shown
+    pub fn active(&self)->usize {
+        self.active
+    }
+    pub fn len(&self)->usize {
+        self.vts.len()
+    }
+    pub fn get(&mut self,idx:usize)->Option<&mut Console> {
+        self.vts.get_mut(idx)
+    }
+    pub fn active_vt(&mut self)->&mut Console {
+        &mut self.vts[self.active]
+    }
+    pub fn kernel_vt(&mut self)->&mut Console {
+        &mut self.vts[KERNEL_VT]
+    }
+    pub fn iter_mut(&mut self)->impl Iterator<Item=&mut Console> {
+        self.vts.iter_mut()
+    }
+    /// Shows terminal `idx`. The screen moves over and the terminal redraws from its cells.
+    pub fn switch(&mut self,idx:usize)->Result<(),()> {
+        if idx>=self.vts.len() {return Err(())}
+        if idx==self.active {return Ok(())}
+        let screen=self.vts[self.active].detach().ok_or(())?;
+        self.active=idx;
+        let vt=&mut self.vts[idx];
+        vt.attach(screen);
+        vt.flush();
+        return Ok(());
+    }
+    /// Changes the font of every terminal
+    pub fn set_font(&mut self,font:Font) {
+        for vt in self.vts.iter_mut() {
+            vt.set_font(font);
+        }
+        self.active_vt().flush();
+    }
+    /// Index of the terminal the kernel log goes to
+    pub fn log_vt(&self)->usize {
+        self.log
+    }
+    pub fn set_log_vt(&mut self,idx:usize)->Result<(),()> {
+        if idx>=self.vts.len() {return Err(())}
+        self.log=idx;
+        return Ok(());
+    }
+    pub fn log_echo(&self)->bool {
+        self.log_echo
+    }
+    pub fn set_log_echo(&mut self,echo:bool) {
+        self.log_echo=echo;
+    }
+}
@@ -1,3 +1,28 @@
+//! The GDT and the TSS. Every core gets its own pair: the GDT is the same everywhere, but each
+//! core needs its own TSS for the stacks it switches to. RSP0 is where the CPU goes when an
+//! interrupt, fault or system call comes in from ring 3, and has to be a stack nothing else is
+//! using at the time. Which of the GDTs a core has loaded is also how [`current_core`] tells the
+//! cores apart without asking the CPU.
+//!
+//! The segments are in the order SYSCALL and SYSRET expect: kernel code then kernel data, and
+//! user data then user code, so `STAR` can describe both pairs with one selector each.
+
+
+use alloc::{
+    boxed::Box,
+    vec,
+};
+use core::{
+    mem::size_of,
+    ptr::{
+        addr_of,
+        addr_of_mut,
+    },
+    sync::atomic::{
+        AtomicPtr,
+        Ordering,
+    },
+};
 use x86_64::{
     structures::{
         tss::TaskStateSegment,
@@ -11,52 +36,111 @@ use x86_64::{
         segmentation::{
             CS,
             SS,
+            DS,
+            ES,
             Segment,
         },
-        tables::load_tss,
+        tables::{
+            load_tss,
+            sgdt,
+        },
+    },
+    registers::model_specific::{
+        Efer,
+        EferFlags,
+        Star,
     },
+    PrivilegeLevel,
     VirtAddr,
 };
 
 
 pub const DOUBLE_FAULT_IST_INDEX:u16=0;
+/// Cores that can have tables. Cores are numbered by their APIC ID.
+pub const MAX_CORES:usize=64;
 
+pub const KERNEL_CODE:SegmentSelector=SegmentSelector::new(1,PrivilegeLevel::Ring0);
+pub const KERNEL_DATA:SegmentSelector=SegmentSelector::new(2,PrivilegeLevel::Ring0);
+pub const USER_DATA:SegmentSelector=SegmentSelector::new(3,PrivilegeLevel::Ring3);
+pub const USER_CODE:SegmentSelector=SegmentSelector::new(4,PrivilegeLevel::Ring3);
+const TSS_SELECTOR:SegmentSelector=SegmentSelector::new(5,PrivilegeLevel::Ring0);
 
-lazy_static::lazy_static! {
-    static ref TSS:TaskStateSegment={
-        let mut tss=TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize]={
-            const STACK_SIZE:usize=4096*5;
-            static mut STACK:[u8;STACK_SIZE]=[0;STACK_SIZE];
-            let stack_start=VirtAddr::from_ptr(unsafe{&STACK});
-            let stack_end=stack_start+STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
-    static ref GDT:(GlobalDescriptorTable,Selectors)={
-        let mut gdt=GlobalDescriptorTable::new();
-        let code_selector=gdt.add_entry(Descriptor::kernel_code_segment());
-        let data_selector=gdt.add_entry(Descriptor::kernel_data_segment());
-        let tss_selector=gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt,Selectors{code_selector,tss_selector,data_selector})
-    };
-}
+const DOUBLE_FAULT_STACK_SIZE:usize=4096*5;
+/// The stack RSP0 points at until something sets its own
+const KERNEL_STACK_SIZE:usize=4096*8;
 
 
-pub fn init() {
-    GDT.0.load();
-    unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        SS::set_reg(GDT.1.data_selector);   // I got #GP faults on `iretq` without this single line. that was annoying to figure out
-        load_tss(GDT.1.tss_selector);
-    }
+/// Each core's GDT, indexed by core like [`TSS`]. Only written by the core's own [`init`].
+static mut GDTS:[GlobalDescriptorTable;MAX_CORES]=[const{GlobalDescriptorTable::new()};MAX_CORES];
+/// Each core's TSS, null for cores that haven't run [`init`]
+static TSS:[AtomicPtr<TaskStateSegment>;MAX_CORES]=[const{AtomicPtr::new(core::ptr::null_mut())};MAX_CORES];
+
 
+/// The top of a new stack that is never freed
+fn leak_stack(size:usize)->VirtAddr {
+    let stack=Box::leak(vec![0u8;size].into_boxed_slice());
+    (VirtAddr::from_ptr(stack.as_ptr())+size).align_down(16u64)
+}
+/// Which core this is, from which of [`GDTS`] it loaded in [`init`] with the number `_start`
+/// worked out. A core still on the bootloader's GDT is core 0, the only one that runs kernel code
+/// before its `init`.
+pub fn current_core()->usize {
+    let first=addr_of!(GDTS) as u64;
+    let index=sgdt().base.as_u64().wrapping_sub(first)/size_of::<GlobalDescriptorTable>() as u64;
+    if index<MAX_CORES as u64 {index as usize} else {0}
 }
 
 
-struct Selectors {
-    code_selector:SegmentSelector,
-    data_selector:SegmentSelector,
-    tss_selector:SegmentSelector,
+/// Builds and loads this core's GDT and TSS, and points SYSCALL and SYSRET at the segments
+pub fn init(core:usize) {
+    assert!(core<MAX_CORES,"core {} is past MAX_CORES",core);
+    let mut tss=TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize]=leak_stack(DOUBLE_FAULT_STACK_SIZE);
+    tss.privilege_stack_table[0]=leak_stack(KERNEL_STACK_SIZE);
+    // kept as a pointer, RSP0 gets changed under the GDT's reference
+    let tss=Box::into_raw(Box::new(tss));
+    let gdt=unsafe{&mut *addr_of_mut!(GDTS[core])};
+    let selectors=[
+        gdt.add_entry(Descriptor::kernel_code_segment()),
+        gdt.add_entry(Descriptor::kernel_data_segment()),
+        gdt.add_entry(Descriptor::user_data_segment()),
+        gdt.add_entry(Descriptor::user_code_segment()),
+        gdt.add_entry(Descriptor::tss_segment(unsafe{&*tss})),
+    ];
+    debug_assert!(selectors==[KERNEL_CODE,KERNEL_DATA,USER_DATA,USER_CODE,TSS_SELECTOR]);
+    TSS[core].store(tss,Ordering::Release);
+    gdt.load();
+    unsafe {
+        CS::set_reg(KERNEL_CODE);
+        SS::set_reg(KERNEL_DATA);   // I got #GP faults on `iretq` without this single line. that was annoying to figure out
+        DS::set_reg(KERNEL_DATA);
+        ES::set_reg(KERNEL_DATA);
+        load_tss(TSS_SELECTOR);
+        Efer::update(|flags|flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+    Star::write(USER_CODE,USER_DATA,KERNEL_CODE,KERNEL_DATA).expect("the GDT isn't laid out for SYSRET");
+}
+/// Sets where this core's stack goes when something comes in from ring 3. Returns the old one.
+pub fn set_kernel_stack(top:VirtAddr)->VirtAddr {
+    let tss=TSS[current_core()].load(Ordering::Acquire);
+    assert!(!tss.is_null(),"this core has no TSS");
+    // the CPU only reads it, on the next switch from ring 3. The TSS is packed, so no references.
+    unsafe {
+        let rsp0=addr_of_mut!((*tss).privilege_stack_table) as *mut VirtAddr;
+        let old=rsp0.read_unaligned();
+        rsp0.write_unaligned(top);
+        old
+    }
+}
+/// Where `core`'s RSP0 is kept, for the system call entry, which has to find the stack itself
+pub fn kernel_stack_slot(core:usize)->*const VirtAddr {
+    let tss=TSS[core].load(Ordering::Acquire);
+    assert!(!tss.is_null(),"core {} has no TSS",core);
+    unsafe{addr_of_mut!((*tss).privilege_stack_table) as *const VirtAddr}
+}
+/// Where this core's stack goes when something comes in from ring 3
+pub fn kernel_stack()->VirtAddr {
+    let tss=TSS[current_core()].load(Ordering::Acquire);
+    assert!(!tss.is_null(),"this core has no TSS");
+    unsafe{(addr_of_mut!((*tss).privilege_stack_table) as *const VirtAddr).read_unaligned()}
 }
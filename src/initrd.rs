@@ -1,4 +1,34 @@
+//! The initial ramdisk. BOOTBOOT loads it (and inflates it if it was gzipped), so by the time we
+//! run it is a cpio archive sitting in identity mapped memory.
+
+
 use cpio_reader::{
     Entry,
     iter_files,
 };
+use crate::bootboot::{
+    BootBootUnpacked,
+    BOOTBOOT_INFO,
+    BOOTBOOT,
+};
+
+
+/// The whole archive
+pub fn data()->&'static [u8] {
+    let bootboot:BootBootUnpacked=unsafe{*(BOOTBOOT_INFO as *const BOOTBOOT)}.into();
+    if bootboot.initrd_ptr==0 {return &[]}
+    unsafe{core::slice::from_raw_parts(bootboot.initrd_ptr as *const u8,bootboot.initrd_size)}
+}
+pub fn files()->impl Iterator<Item=Entry<'static>> {
+    iter_files(data())
+}
+/// Paths in the archive can start with `./` depending on how it was made. Leading `/` and `./`
+/// are ignored on both sides.
+fn normalize(path:&str)->&str {
+    path.trim_start_matches("./").trim_start_matches('/')
+}
+/// The contents of the file at `path`
+pub fn find(path:&str)->Option<&'static [u8]> {
+    let path=normalize(path);
+    files().find(|entry|normalize(entry.name())==path).map(|entry|entry.file())
+}
@@ -0,0 +1,325 @@
+//! Keyboard layouts. A keymap says what each key types with and without Shift and AltGr; the keys
+//! that type the same thing everywhere (Enter, Tab, the numpad...) aren't in it. Layouts besides
+//! the built in ones can be loaded from the initrd, one key per line:
+//! ```text
+//! # Swiss German, starting from the German layout
+//! base de
+//! altgr
+//! Y z Z
+//! Z y Y
+//! Key1 1 + |      # key, normal, Shift, AltGr, Shift+AltGr
+//! Minus ' ? ´
+//! Equals ^ ` ~
+//! ```
+//! Keys are named like `pc_keyboard::KeyCode` (`Q`, `Key1`, `SemiColon`, `HashTilde` for the key
+//! left of Z). A character can also be written as `U+00E9`, `space` or `none`.
+
+
+use alloc::{
+    vec::Vec,
+    boxed::Box,
+    format,
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::initrd;
+use super::{
+    KeyCode,
+    Modifiers,
+    scancode::ISO_KEY,
+};
+
+
+/// The keys a keymap covers, in the order the built in layouts list them: the number row, the
+/// three letter rows (with the ISO key before Z) and the space bar
+const LAYOUT_KEYS:[KeyCode;49]={
+    use KeyCode::*;
+    [
+        BackTick,Key1,Key2,Key3,Key4,Key5,Key6,Key7,Key8,Key9,Key0,Minus,Equals,
+        Q,W,E,R,T,Y,U,I,O,P,BracketSquareLeft,BracketSquareRight,BackSlash,
+        A,S,D,F,G,H,J,K,L,SemiColon,Quote,
+        ISO_KEY,Z,X,C,V,B,N,M,Comma,Fullstop,Slash,
+        Spacebar,
+    ]
+};
+
+
+/// A layout compiled into the kernel. `normal` and `shift` have one character for every key in
+/// [`LAYOUT_KEYS`] but the space bar, `\0` for nothing.
+struct Builtin {
+    name:&'static str,
+    normal:&'static str,
+    shift:&'static str,
+    altgr:&'static [(KeyCode,char)],
+}
+static BUILTIN:&[Builtin]={
+    use KeyCode::*;
+    &[
+        Builtin {
+            name:"us",
+            normal:"`1234567890-=qwertyuiop[]\\asdfghjkl;'\\zxcvbnm,./",
+            shift:"~!@#$%^&*()_+QWERTYUIOP{}|ASDFGHJKL:\"|ZXCVBNM<>?",
+            altgr:&[],
+        },
+        Builtin {
+            name:"uk",
+            normal:"`1234567890-=qwertyuiop[]#asdfghjkl;'\\zxcvbnm,./",
+            shift:"¬!\"£$%^&*()_+QWERTYUIOP{}~ASDFGHJKL:@|ZXCVBNM<>?",
+            altgr:&[(BackTick,'¦'),(Key4,'€'),(A,'á'),(E,'é'),(I,'í'),(O,'ó'),(U,'ú')],
+        },
+        Builtin {
+            name:"de",
+            normal:"^1234567890ß´qwertzuiopü+#asdfghjklöä<yxcvbnm,.-",
+            shift:"°!\"§$%&/()=?`QWERTZUIOPÜ*'ASDFGHJKLÖÄ>YXCVBNM;:_",
+            altgr:&[(Key2,'²'),(Key3,'³'),(Key7,'{'),(Key8,'['),(Key9,']'),(Key0,'}'),(Minus,'\\'),
+                (Q,'@'),(E,'€'),(BracketSquareRight,'~'),(ISO_KEY,'|'),(M,'µ')],
+        },
+        Builtin {
+            name:"fr",
+            normal:"²&é\"'(-è_çà)=azertyuiop^$*qsdfghjklmù<wxcvbn,;:!",
+            shift:"\01234567890°+AZERTYUIOP¨£µQSDFGHJKLM%>WXCVBN?./§",
+            altgr:&[(Key2,'~'),(Key3,'#'),(Key4,'{'),(Key5,'['),(Key6,'|'),(Key7,'`'),(Key8,'\\'),
+                (Key9,'^'),(Key0,'@'),(Minus,']'),(Equals,'}'),(E,'€'),(BracketSquareRight,'¤')],
+        },
+        Builtin {
+            name:"dvorak",
+            normal:"`1234567890[]',.pyfgcrl/=\\aoeuidhtns-\\;qjkxbmwvz",
+            shift:"~!@#$%^&*(){}\"<>PYFGCRL?+|AOEUIDHTNS_|:QJKXBMWVZ",
+            altgr:&[],
+        },
+        Builtin {
+            name:"colemak",
+            normal:"`1234567890-=qwfpgjluy;[]\\arstdhneio'\\zxcvbkm,./",
+            shift:"~!@#$%^&*()_+QWFPGJLUY:{}|ARSTDHNEIO\"|ZXCVBKM<>?",
+            altgr:&[],
+        },
+    ]
+};
+
+
+lazy_static::lazy_static! {
+    /// Every keymap that has been built or loaded. Loaded ones are named by their path.
+    static ref KEYMAPS:Mutex<Vec<&'static Keymap>>=Mutex::new(BUILTIN.iter().map(|b|&*Box::leak(Box::new(Keymap::from_builtin(b)))).collect());
+}
+
+
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum KeymapError {
+    /// Not a built in layout and not a file in the initrd
+    NotFound,
+    NotText,
+    /// A line of the file couldn't be read (1-based)
+    BadLine(usize),
+    /// `base` names a layout that doesn't exist
+    UnknownBase,
+}
+
+
+/// What Ctrl does to typed characters
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum CtrlHandling {
+    /// Ctrl+C types `c`
+    Ignore,
+    /// Ctrl+letter types the control character (Ctrl+C is `\x03`), like a terminal
+    Map,
+}
+impl CtrlHandling {
+    pub fn parse(s:&str)->Option<CtrlHandling> {
+        match s {
+            "ignore"=>Some(CtrlHandling::Ignore),
+            "map"=>Some(CtrlHandling::Map),
+            _=>None,
+        }
+    }
+    pub fn name(&self)->&'static str {
+        match self {
+            CtrlHandling::Ignore=>"ignore",
+            CtrlHandling::Map=>"map",
+        }
+    }
+}
+
+
+pub struct Keymap {
+    pub name:&'static str,
+    /// `[normal,shift,altgr,shift+altgr]` for each key, `\0` for nothing
+    keys:Vec<(KeyCode,[char;4])>,
+    /// Right Alt is AltGr instead of Alt
+    pub altgr:bool,
+}
+#[allow(dead_code)]
+impl Keymap {
+    fn from_builtin(builtin:&Builtin)->Keymap {
+        let mut keys:Vec<(KeyCode,[char;4])>=LAYOUT_KEYS.iter()
+            .zip(builtin.normal.chars().zip(builtin.shift.chars()))
+            .map(|(key,(normal,shift))|(*key,[normal,shift,'\0','\0']))
+            .collect();
+        keys.push((KeyCode::Spacebar,[' ',' ','\0','\0']));
+        for (key,c) in builtin.altgr {
+            if let Some(entry)=keys.iter_mut().find(|(k,_)|k==key) {
+                entry.1[2]=*c;
+            }
+        }
+        Keymap {
+            name:builtin.name,
+            keys,
+            altgr:!builtin.altgr.is_empty(),
+        }
+    }
+    /// Reads a keymap file. `name` is what it will be called.
+    pub fn parse(name:&'static str,text:&str)->Result<Keymap,KeymapError> {
+        let mut keymap=Keymap {
+            name,
+            keys:Vec::new(),
+            altgr:false,
+        };
+        for (i,line) in text.lines().enumerate() {
+            let bad=KeymapError::BadLine(i+1);
+            let line=line.split('#').next().unwrap().trim();
+            let mut words=line.split_whitespace();
+            match words.next() {
+                None=>continue,
+                Some("altgr")=>keymap.altgr=true,
+                Some("base")=>{
+                    let base=find(words.next().ok_or(bad)?).ok_or(KeymapError::UnknownBase)?;
+                    keymap.keys=base.keys.clone();
+                    keymap.altgr|=base.altgr;
+                },
+                Some(key)=>{
+                    let key=LAYOUT_KEYS.iter().find(|k|format!("{:?}",k)==key).ok_or(bad)?;
+                    let mut chars=['\0';4];
+                    for (slot,word) in chars.iter_mut().zip(words.by_ref()) {
+                        *slot=parse_char(word).ok_or(bad)?;
+                    }
+                    if words.next().is_some() {return Err(bad)}
+                    match keymap.keys.iter_mut().find(|(k,_)|k==key) {
+                        Some(entry)=>entry.1=chars,
+                        None=>keymap.keys.push((*key,chars)),
+                    }
+                },
+            }
+        }
+        return Ok(keymap);
+    }
+    /// What `key` types with these modifiers, before Ctrl is applied
+    fn lookup(&self,key:KeyCode,mods:&Modifiers)->Option<char> {
+        let chars=&self.keys.iter().find(|(k,_)|*k==key)?.1;
+        let altgr=self.altgr&&mods.right_alt;
+        // Caps Lock only does letters, and Shift undoes it
+        let shift=mods.shift()^(mods.caps_lock&&chars[0].is_alphabetic());
+        let c=chars[shift as usize+altgr as usize*2];
+        if c=='\0' {None} else {Some(c)}
+    }
+    /// What `key` types with these modifiers, for a key press
+    pub fn translate(&self,key:KeyCode,mods:&Modifiers,ctrl:CtrlHandling)->Option<char> {
+        use KeyCode::*;
+        let numpad=mods.num_lock&&!mods.shift();
+        let c=match key {
+            Enter|NumpadEnter=>'\n',
+            Tab=>'\t',
+            Backspace=>'\x08',
+            Escape=>'\x1b',
+            NumpadSlash=>'/',
+            NumpadStar=>'*',
+            NumpadMinus=>'-',
+            NumpadPlus=>'+',
+            Numpad0 if numpad=>'0',
+            Numpad1 if numpad=>'1',
+            Numpad2 if numpad=>'2',
+            Numpad3 if numpad=>'3',
+            Numpad4 if numpad=>'4',
+            Numpad5 if numpad=>'5',
+            Numpad6 if numpad=>'6',
+            Numpad7 if numpad=>'7',
+            Numpad8 if numpad=>'8',
+            Numpad9 if numpad=>'9',
+            NumpadPeriod if numpad=>'.',
+            _=>self.lookup(key,mods)?,
+        };
+        if ctrl==CtrlHandling::Map&&mods.ctrl()&&!(self.altgr&&mods.right_alt) {
+            // by what the key types without modifiers, so Ctrl+C is where the C is
+            let base=self.lookup(key,&Modifiers::default()).unwrap_or(c);
+            match base.to_ascii_lowercase() {
+                l@'a'..='z'=>return Some((l as u8-b'a'+1) as char),
+                ' '|'@'|'2'=>return Some('\0'),
+                '['=>return Some('\x1b'),
+                '\\'=>return Some('\x1c'),
+                ']'=>return Some('\x1d'),
+                '/'|'-'=>return Some('\x1f'),
+                _=>{},
+            }
+        }
+        return Some(c);
+    }
+}
+
+
+/// `x`, `U+XXXX`, `space` or `none`
+fn parse_char(word:&str)->Option<char> {
+    match word {
+        "space"=>return Some(' '),
+        "none"=>return Some('\0'),
+        _=>{},
+    }
+    if let Some(hex)=word.strip_prefix("U+") {
+        return char::from_u32(u32::from_str_radix(hex,16).ok()?);
+    }
+    let mut chars=word.chars();
+    let c=chars.next()?;
+    if chars.next().is_some() {return None}
+    return Some(c);
+}
+
+
+/// A keymap that has already been built or loaded
+pub fn find(name:&str)->Option<&'static Keymap> {
+    without_interrupts(||KEYMAPS.lock().iter().find(|k|k.name==name).copied())
+}
+/// A built in layout by name, or a keymap file from the initrd
+pub fn get(name:&str)->Result<&'static Keymap,KeymapError> {
+    if let Some(keymap)=find(name) {
+        return Ok(keymap);
+    }
+    let data=initrd::find(name).ok_or(KeymapError::NotFound)?;
+    let text=core::str::from_utf8(data).map_err(|_|KeymapError::NotText)?;
+    let name:&'static str=Box::leak(Box::from(name));
+    let keymap:&'static Keymap=Box::leak(Box::new(Keymap::parse(name,text)?));
+    without_interrupts(||KEYMAPS.lock().push(keymap));
+    return Ok(keymap);
+}
+pub fn for_each_keymap<F:FnMut(&'static Keymap)>(mut f:F) {
+    let keymaps=without_interrupts(||KEYMAPS.lock().clone());
+    for keymap in keymaps {
+        f(keymap);
+    }
+}
+
+
+/// Checks the built in layouts and the keymap file parser
+pub fn test_keymaps()->Result<(),&'static str> {
+    for builtin in BUILTIN {
+        if builtin.normal.chars().count()!=LAYOUT_KEYS.len()-1||builtin.shift.chars().count()!=LAYOUT_KEYS.len()-1 {
+            return Err("a built in layout has the wrong number of keys");
+        }
+    }
+    let de=find("de").ok_or("no German layout")?;
+    let mut mods=Modifiers::default();
+    if de.translate(KeyCode::Y,&mods,CtrlHandling::Map)!=Some('z') {return Err("German Y key doesn't type z")}
+    mods.right_alt=true;
+    if de.translate(KeyCode::Q,&mods,CtrlHandling::Map)!=Some('@') {return Err("AltGr+Q doesn't type @")}
+    mods=Modifiers{caps_lock:true,..Modifiers::default()};
+    if de.translate(KeyCode::SemiColon,&mods,CtrlHandling::Map)!=Some('Ö') {return Err("Caps Lock doesn't shift ö")}
+    if de.translate(KeyCode::Key1,&mods,CtrlHandling::Map)!=Some('1') {return Err("Caps Lock shifts digits")}
+    let dvorak=find("dvorak").ok_or("no Dvorak layout")?;
+    mods=Modifiers{left_ctrl:true,..Modifiers::default()};
+    if dvorak.translate(KeyCode::I,&mods,CtrlHandling::Map)!=Some('\x03') {return Err("Dvorak Ctrl+C isn't ^C")}
+    if dvorak.translate(KeyCode::I,&mods,CtrlHandling::Ignore)!=Some('c') {return Err("ignored Ctrl still changed the key")}
+    let custom=Keymap::parse("test","base us\naltgr\nQ a A U+00E6 # comment\nZ none")
+        .map_err(|_|"keymap file didn't parse")?;
+    mods=Modifiers{right_alt:true,..Modifiers::default()};
+    if custom.translate(KeyCode::Q,&mods,CtrlHandling::Map)!=Some('æ') {return Err("keymap file AltGr key is wrong")}
+    if custom.translate(KeyCode::Z,&Modifiers::default(),CtrlHandling::Map).is_some() {return Err("`none` key still types")}
+    if custom.translate(KeyCode::W,&Modifiers::default(),CtrlHandling::Map)!=Some('w') {return Err("base layout wasn't copied")}
+    if Keymap::parse("test","Nope a").is_ok() {return Err("unknown key name was accepted")}
+    return Ok(());
+}
@@ -0,0 +1,530 @@
+//! Keyboard and mouse input. The interrupts only push raw bytes into lock-free rings and wake the
+//! input task, which decodes them into [`KeyEvent`]s and [`MouseEvent`]s and hands a copy of each
+//! to every [`Subscription`]. Subscribers read events as they like:
+//! ```ignore
+//! let keys=input::subscribe();
+//! let event=keys.read().await;       // in a task
+//! let event=keys.read_blocking();     // anywhere else with interrupts on
+//! let mouse=input::subscribe_mouse();
+//! ```
+//! The layout is picked with `keyboard.layout` (see [`keymap`]) and can be changed at runtime with
+//! [`set_layout`] or the shell's `kbd` command.
+
+
+use alloc::{
+    collections::VecDeque,
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    task::{
+        Poll,
+        Waker,
+    },
+    sync::atomic::{
+        AtomicBool,
+        AtomicU64,
+        Ordering,
+    },
+    time::Duration,
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::{
+    config::{
+        self,
+        Opt,
+        Kind,
+    },
+    shell::{
+        self,
+        Command,
+    },
+    time::timer,
+    println,
+    info,
+    warn,
+};
+use ring::ByteRing;
+use scancode::{
+    ScancodeDecoder,
+    ScancodeSet,
+};
+use ps2::Ps2Port;
+use mouse::{
+    MouseState,
+    PacketDecoder,
+    Packet,
+};
+
+pub use mouse::MouseEvent;
+use keymap::{
+    Keymap,
+    KeymapError,
+    CtrlHandling,
+};
+
+pub use pc_keyboard::KeyCode;
+
+
+pub mod ring;
+pub mod scancode;
+pub mod keymap;
+pub mod ps2;
+pub mod mouse;
+
+
+pub static LAYOUT:Opt=Opt::new("keyboard.layout",Kind::Str,"us","Keyboard layout: us, uk, de, fr, dvorak, colemak or a keymap file in the initrd");
+pub static CTRL:Opt=Opt::new("keyboard.ctrl",Kind::Str,"map","What Ctrl+letter types: `map` for control characters, `ignore` for the letter");
+pub static SCANCODE_SET:Opt=Opt::new("keyboard.scancode_set",Kind::Int,"1","Scancode set to use, 2 turns off the controller's translation");
+pub static REPEAT_RATE:Opt=Opt::new("keyboard.repeat_rate",Kind::Int,"30","Times per second a held key repeats, 2 to 30");
+pub static REPEAT_DELAY:Opt=Opt::new("keyboard.repeat_delay",Kind::Int,"500","Milliseconds before a held key starts repeating: 250, 500, 750 or 1000");
+pub static PS2:Opt=Opt::new("ps2",Kind::Bool,"true","Reset and set up the PS/2 controller instead of using it as the firmware left it");
+pub static MOUSE:Opt=Opt::new("mouse",Kind::Bool,"true","Use a PS/2 mouse if there is one");
+pub static MOUSE_RATE:Opt=Opt::new("mouse.rate",Kind::Int,"100","Mouse samples per second: 10, 20, 40, 60, 80, 100 or 200");
+
+
+static KBD:Command=Command::new("kbd","[layout <name|file> | ctrl <map|ignore> | repeat <rate> <delay>]","Show or change the keyboard settings",kbd);
+
+
+/// Scancodes from the keyboard interrupt, waiting to be decoded
+static SCANCODES:ByteRing<256>=ByteRing::new();
+/// Bytes from the mouse interrupt
+static MOUSE_BYTES:ByteRing<256>=ByteRing::new();
+/// The input task, waiting for scancodes or mouse bytes. Only locked with interrupts off.
+static SCANCODE_WAKER:Mutex<Option<Waker>>=Mutex::new(None);
+static KEY_SUBSCRIBERS:Mutex<Vec<Arc<Mutex<Queue<KeyEvent>>>>>=Mutex::new(Vec::new());
+static MOUSE_SUBSCRIBERS:Mutex<Vec<Arc<Mutex<Queue<MouseEvent>>>>>=Mutex::new(Vec::new());
+/// Puts PS/2 mouse bytes together, once there is a PS/2 mouse
+static PS2_MOUSE:Mutex<Option<PacketDecoder>>=Mutex::new(None);
+/// Where the mouse is, made on the first packet from any mouse
+static MOUSE_STATE:Mutex<Option<MouseState>>=Mutex::new(None);
+/// How many dropped scancodes have been warned about
+static DROPPED_SEEN:AtomicU64=AtomicU64::new(0);
+/// Ctrl+C was pressed since [`take_ctrl_c`] last looked
+static CTRL_C:AtomicBool=AtomicBool::new(false);
+
+
+lazy_static::lazy_static! {
+    static ref DECODER:Mutex<Decoder>=Mutex::new(Decoder::new());
+}
+
+
+/// Which modifier keys are held and which locks are on
+#[derive(Debug,Copy,Clone,PartialEq,Eq,Default)]
+pub struct Modifiers {
+    pub left_shift:bool,
+    pub right_shift:bool,
+    pub left_ctrl:bool,
+    pub right_ctrl:bool,
+    pub left_alt:bool,
+    /// AltGr on most non-US layouts
+    pub right_alt:bool,
+    pub caps_lock:bool,
+    pub num_lock:bool,
+    pub scroll_lock:bool,
+}
+impl Modifiers {
+    pub fn shift(&self)->bool {
+        self.left_shift||self.right_shift
+    }
+    pub fn ctrl(&self)->bool {
+        self.left_ctrl||self.right_ctrl
+    }
+    pub fn alt(&self)->bool {
+        self.left_alt||self.right_alt
+    }
+    /// Tracks a modifier or lock key
+    fn update(&mut self,code:KeyCode,down:bool) {
+        match code {
+            KeyCode::ShiftLeft=>self.left_shift=down,
+            KeyCode::ShiftRight=>self.right_shift=down,
+            KeyCode::ControlLeft=>self.left_ctrl=down,
+            KeyCode::ControlRight=>self.right_ctrl=down,
+            KeyCode::AltLeft=>self.left_alt=down,
+            KeyCode::AltRight=>self.right_alt=down,
+            KeyCode::CapsLock if down=>self.caps_lock=!self.caps_lock,
+            KeyCode::NumpadLock if down=>self.num_lock=!self.num_lock,
+            KeyCode::ScrollLock if down=>self.scroll_lock=!self.scroll_lock,
+            _=>{},
+        }
+    }
+}
+
+
+/// A key going down or up
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub struct KeyEvent {
+    pub code:KeyCode,
+    pub down:bool,
+    /// The modifiers after this event
+    pub modifiers:Modifiers,
+    /// What the key types with the current layout and modifiers, for key presses that type
+    /// something
+    pub unicode:Option<char>,
+}
+
+
+/// Turns scancodes into events
+struct Decoder {
+    scancodes:ScancodeDecoder,
+    keymap:&'static Keymap,
+    ctrl:CtrlHandling,
+    modifiers:Modifiers,
+}
+impl Decoder {
+    fn new()->Decoder {
+        Decoder {
+            scancodes:ScancodeDecoder::new(ScancodeSet::Set1),
+            keymap:keymap::find("us").unwrap(),
+            ctrl:CtrlHandling::Map,
+            modifiers:Modifiers::default(),
+        }
+    }
+    fn feed(&mut self,scancode:u8)->Option<KeyEvent> {
+        let (code,down)=self.scancodes.feed(scancode)?;
+        Some(self.key(code,down))
+    }
+    /// A key from any keyboard
+    fn key(&mut self,code:KeyCode,down:bool)->KeyEvent {
+        self.modifiers.update(code,down);
+        if down&&code==KeyCode::C&&self.modifiers.ctrl() {
+            CTRL_C.store(true,Ordering::Relaxed);
+        }
+        let unicode=if down {self.keymap.translate(code,&self.modifiers,self.ctrl)} else {None};
+        KeyEvent {
+            code,
+            down,
+            modifiers:self.modifiers,
+            unicode,
+        }
+    }
+}
+
+
+/// Something that can be subscribed to
+pub trait Event:Copy+Send+'static {
+    fn subscribers()->&'static Mutex<Vec<Arc<Mutex<Queue<Self>>>>>;
+}
+impl Event for KeyEvent {
+    fn subscribers()->&'static Mutex<Vec<Arc<Mutex<Queue<Self>>>>> {
+        &KEY_SUBSCRIBERS
+    }
+}
+impl Event for MouseEvent {
+    fn subscribers()->&'static Mutex<Vec<Arc<Mutex<Queue<Self>>>>> {
+        &MOUSE_SUBSCRIBERS
+    }
+}
+
+
+/// Events waiting for one subscriber
+pub struct Queue<T> {
+    events:VecDeque<T>,
+    waker:Option<Waker>,
+}
+
+
+/// Gets a copy of every event of one kind from when it is made until it is dropped
+pub struct Subscription<T:Event> {
+    queue:Arc<Mutex<Queue<T>>>,
+}
+impl<T:Event> Drop for Subscription<T> {
+    fn drop(&mut self) {
+        without_interrupts(||{
+            T::subscribers().lock().retain(|q|!Arc::ptr_eq(q,&self.queue));
+        });
+    }
+}
+#[allow(dead_code)]
+impl<T:Event> Subscription<T> {
+    /// How many events are kept for a subscriber that isn't reading. Older ones are dropped.
+    pub const QUEUE_LIMIT:usize=128;
+    pub fn try_read(&self)->Option<T> {
+        process_pending();
+        without_interrupts(||self.queue.lock().events.pop_front())
+    }
+    /// Waits for the next event
+    pub async fn read(&self)->T {
+        core::future::poll_fn(|cx|{
+            without_interrupts(||{
+                let mut queue=self.queue.lock();
+                match queue.events.pop_front() {
+                    Some(event)=>Poll::Ready(event),
+                    None=>{
+                        queue.waker=Some(cx.waker().clone());
+                        Poll::Pending
+                    },
+                }
+            })
+        }).await
+    }
+    /// Waits for the next event, but no longer than `duration`
+    pub async fn read_timeout(&self,duration:Duration)->Option<T> {
+        timer::timeout(duration,self.read()).await.ok()
+    }
+    /// Halts until there is an event. This doesn't need the input task, so it works before the
+    /// executor is running, but it must not be used from a task.
+    pub fn read_blocking(&self)->T {
+        loop {
+            if let Some(event)=self.try_read() {
+                return event;
+            }
+            x86_64::instructions::interrupts::enable_and_hlt();
+        }
+    }
+}
+
+
+fn subscribe_to<T:Event>()->Subscription<T> {
+    let queue=Arc::new(Mutex::new(Queue {
+        events:VecDeque::new(),
+        waker:None,
+    }));
+    without_interrupts(||{
+        T::subscribers().lock().push(queue.clone());
+    });
+    Subscription{queue}
+}
+/// Key presses and releases
+pub fn subscribe()->Subscription<KeyEvent> {
+    subscribe_to()
+}
+/// Mouse movement, buttons and the wheel
+pub fn subscribe_mouse()->Subscription<MouseEvent> {
+    subscribe_to()
+}
+
+
+/// Called by the keyboard interrupt handler with each byte from the keyboard
+pub fn push_scancode(scancode:u8) {
+    SCANCODES.push(scancode);
+    if let Some(waker)=SCANCODE_WAKER.lock().take() {
+        waker.wake();
+    }
+}
+/// Called by the mouse interrupt handler with each byte from the mouse
+pub fn push_mouse_byte(byte:u8) {
+    MOUSE_BYTES.push(byte);
+    if let Some(waker)=SCANCODE_WAKER.lock().take() {
+        waker.wake();
+    }
+}
+fn dispatch<T:Event>(event:T) {
+    without_interrupts(||{
+        for queue in T::subscribers().lock().iter() {
+            let mut queue=queue.lock();
+            if queue.events.len()>=Subscription::<T>::QUEUE_LIMIT {
+                queue.events.pop_front();
+            }
+            queue.events.push_back(event);
+            if let Some(waker)=queue.waker.take() {
+                waker.wake();
+            }
+        }
+    });
+}
+/// Decodes every scancode and mouse byte that has come in and sends out the events
+pub fn process_pending() {
+    // only one context pops the ring at a time, whoever has the decoder
+    let mut decoder=match DECODER.try_lock() {
+        Some(decoder)=>decoder,
+        None=>return,   // someone else is already on it
+    };
+    let locks=|m:&Modifiers|(m.caps_lock,m.num_lock,m.scroll_lock);
+    let old_locks=locks(&decoder.modifiers);
+    while let Some(scancode)=SCANCODES.pop() {
+        if let Some(event)=decoder.feed(scancode) {
+            dispatch(event);
+        }
+    }
+    let modifiers=decoder.modifiers;
+    without_interrupts(||{
+        if let Some(ps2_mouse)=PS2_MOUSE.lock().as_mut() {
+            while let Some(byte)=MOUSE_BYTES.pop() {
+                if let Some(packet)=ps2_mouse.feed(byte) {
+                    dispatch(mouse_moved(packet));
+                }
+            }
+        }
+    });
+    drop(decoder);
+    if locks(&modifiers)!=old_locks {
+        // the lights are only a nicety, a keyboard that doesn't have them is fine
+        let _=ps2::set_leds(&modifiers);
+    }
+    let dropped=SCANCODES.dropped();
+    if dropped>DROPPED_SEEN.swap(dropped,Ordering::Relaxed) {
+        warn!("Keyboard input came in too fast, {} scancodes dropped so far",dropped);
+    }
+}
+/// Adds a packet to the mouse position. Only called with interrupts off.
+fn mouse_moved(packet:Packet)->MouseEvent {
+    MOUSE_STATE.lock().get_or_insert_with(MouseState::new).apply(packet)
+}
+/// Called by keyboard drivers that already know which key it was, like USB ones. Has to be
+/// called from a task, not an interrupt handler.
+pub fn push_key(code:KeyCode,down:bool) {
+    let event=without_interrupts(||DECODER.lock().key(code,down));
+    dispatch(event);
+    if down&&(code==KeyCode::CapsLock||code==KeyCode::NumpadLock||code==KeyCode::ScrollLock) {
+        let _=ps2::set_leds(&event.modifiers);
+    }
+}
+/// Called by mouse drivers that put their own packets together, like USB ones
+pub fn push_mouse_packet(packet:Packet) {
+    let event=without_interrupts(||mouse_moved(packet));
+    dispatch(event);
+}
+/// Whether Ctrl+C was pressed since the last call, on any keyboard. The key still goes out as
+/// usual.
+pub fn take_ctrl_c()->bool {
+    CTRL_C.swap(false,Ordering::Relaxed)
+}
+/// Which modifiers are held and which locks are on, across every keyboard
+pub fn modifiers()->Modifiers {
+    without_interrupts(||DECODER.lock().modifiers)
+}
+/// Waits for input and decodes it
+pub async fn run() {
+    loop {
+        core::future::poll_fn(|cx|{
+            without_interrupts(||{
+                if SCANCODES.is_empty()&&MOUSE_BYTES.is_empty() {
+                    *SCANCODE_WAKER.lock()=Some(cx.waker().clone());
+                    Poll::Pending
+                } else {
+                    Poll::Ready(())
+                }
+            })
+        }).await;
+        process_pending();
+    }
+}
+
+
+/// Switches to a built in layout or a keymap file from the initrd
+pub fn set_layout(name:&str)->Result<(),KeymapError> {
+    let keymap=keymap::get(name)?;
+    without_interrupts(||DECODER.lock().keymap=keymap);
+    return Ok(());
+}
+pub fn layout()->&'static str {
+    without_interrupts(||DECODER.lock().keymap.name)
+}
+pub fn set_ctrl_handling(ctrl:CtrlHandling) {
+    without_interrupts(||DECODER.lock().ctrl=ctrl);
+}
+pub fn ctrl_handling()->CtrlHandling {
+    without_interrupts(||DECODER.lock().ctrl)
+}
+/// Switches the keyboard and the decoder to another scancode set
+pub fn set_scancode_set(set:ScancodeSet)->Result<(),ps2::Ps2Error> {
+    without_interrupts(||{
+        ps2::set_scancode_set(set)?;
+        // whatever came in before the switch was in the old set
+        process_pending();
+        DECODER.lock().scancodes.set_set(set);
+        Ok(())
+    })
+}
+pub fn scancode_set()->ScancodeSet {
+    without_interrupts(||DECODER.lock().scancodes.set())
+}
+/// Sets how many times a second a held key repeats and after how many milliseconds
+pub fn set_repeat(rate:i64,delay_ms:i64)->Result<(),ps2::Ps2Error> {
+    ps2::set_typematic(rate.clamp(2,30) as u32,delay_ms.clamp(250,1000) as u32)
+}
+
+
+/// Turns on the PS/2 mouse and starts decoding what it sends
+fn enable_mouse() {
+    // the rates a PS/2 mouse takes
+    const RATES:[i64;7]=[10,20,40,60,80,100,200];
+    let wanted=MOUSE_RATE.get_int();
+    let rate=RATES.iter().copied().min_by_key(|r|(r-wanted).abs()).unwrap();
+    match ps2::enable_mouse(rate as u8) {
+        Ok(device)=>{
+            without_interrupts(||*PS2_MOUSE.lock()=Some(PacketDecoder::new(device)));
+            info!("Mouse enabled: {}",device.name());
+        },
+        Err(e)=>warn!("Couldn't turn the mouse on: {:?}",e),
+    }
+}
+
+
+/// Starts the input task and applies the keyboard and mouse options
+pub fn init() {
+    config::register(&[&LAYOUT,&CTRL,&SCANCODE_SET,&REPEAT_RATE,&REPEAT_DELAY,&PS2,&MOUSE,&MOUSE_RATE]);
+    let layout=LAYOUT.get_str();
+    if let Err(e)=set_layout(&layout) {
+        warn!("Couldn't use keyboard layout `{}`: {:?}",layout,e);
+    }
+    match CtrlHandling::parse(&CTRL.get_str()) {
+        Some(ctrl)=>set_ctrl_handling(ctrl),
+        None=>warn!("keyboard.ctrl should be `map` or `ignore`"),
+    }
+    let set=ScancodeSet::from_number(SCANCODE_SET.get_int()).unwrap_or_else(||{
+        warn!("keyboard.scancode_set should be 1 or 2");
+        ScancodeSet::Set1
+    });
+    if PS2.get_bool() {
+        match ps2::init(set) {
+            Ok(())=>{
+                without_interrupts(||DECODER.lock().scancodes.set_set(set));
+                if ps2::device(Ps2Port::First).map(|d|d.is_keyboard()).unwrap_or(false) {
+                    if let Err(e)=set_repeat(REPEAT_RATE.get_int(),REPEAT_DELAY.get_int()) {
+                        warn!("Couldn't set the keyboard repeat rate: {:?}",e);
+                    }
+                    // the firmware may have left Num Lock lit
+                    let _=ps2::set_leds(&Modifiers::default());
+                }
+                if MOUSE.get_bool()&&ps2::device(Ps2Port::Second).map(|d|d.is_mouse()).unwrap_or(false) {
+                    enable_mouse();
+                }
+            },
+            Err(e)=>warn!("The PS/2 controller didn't initialize: {:?}",e),
+        }
+    } else if set!=ScancodeSet::Set1 {  // set 1 is what the firmware leaves us with
+        if let Err(e)=set_scancode_set(set) {
+            warn!("Couldn't switch the keyboard to scancode set {}: {:?}",set.number(),e);
+        }
+    }
+    shell::register(&[&KBD]);
+    crate::task::spawn("input",run());
+}
+
+
+fn kbd(args:&[&str])->Result<(),&'static str> {
+    match args {
+        []=>{
+            println!("Layout: {}",layout());
+            println!("Ctrl: {}",ctrl_handling().name());
+            println!("Scancode set: {}",scancode_set().number());
+            for port in [Ps2Port::First,Ps2Port::Second] {
+                if let Some(device)=ps2::device(port) {
+                    println!("PS/2 {:?} port: {}",port,device.name());
+                }
+            }
+            let mut names=alloc::string::String::new();
+            keymap::for_each_keymap(|k|{
+                names.push(' ');
+                names.push_str(k.name);
+            });
+            println!("Layouts:{}",names);
+        },
+        ["layout",name]=>set_layout(name).map_err(|e|match e {
+            KeymapError::NotFound=>"no such layout or file",
+            KeymapError::UnknownBase=>"the keymap's base layout doesn't exist",
+            _=>"not a keymap file",
+        })?,
+        ["ctrl",ctrl]=>set_ctrl_handling(CtrlHandling::parse(ctrl).ok_or("expected `map` or `ignore`")?),
+        ["repeat",rate,delay]=>{
+            let rate=rate.parse().map_err(|_|"the rate is a number of repeats a second")?;
+            let delay=delay.parse().map_err(|_|"the delay is in milliseconds")?;
+            set_repeat(rate,delay).map_err(|_|"the keyboard didn't take it")?;
+        },
+        _=>return Err("usage: kbd [layout <name|file> | ctrl <map|ignore> | repeat <rate> <delay>]"),
+    }
+    return Ok(());
+}
@@ -0,0 +1,171 @@
+//! Mice. The PS/2 mouse interrupt queues raw bytes like the keyboard's does and the input task
+//! puts them together into packets; USB mice hand over packets directly. Either way they move one
+//! absolute position inside the screen.
+
+
+use crate::bootboot::{
+    BootBootUnpacked,
+    BOOTBOOT_INFO,
+    BOOTBOOT,
+};
+use super::ps2::Device;
+
+
+/// Which buttons are held, one bit each
+#[derive(Debug,Copy,Clone,PartialEq,Eq,Default)]
+pub struct MouseButtons(pub u8);
+#[allow(dead_code)]
+impl MouseButtons {
+    pub const LEFT:u8=0x01;
+    pub const RIGHT:u8=0x02;
+    pub const MIDDLE:u8=0x04;
+    pub const FOURTH:u8=0x08;
+    pub const FIFTH:u8=0x10;
+    pub fn left(&self)->bool {self.0&Self::LEFT!=0}
+    pub fn right(&self)->bool {self.0&Self::RIGHT!=0}
+    pub fn middle(&self)->bool {self.0&Self::MIDDLE!=0}
+    pub fn fourth(&self)->bool {self.0&Self::FOURTH!=0}
+    pub fn fifth(&self)->bool {self.0&Self::FIFTH!=0}
+}
+
+
+/// The mouse moved, scrolled or had a button change
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub struct MouseEvent {
+    /// Movement in pixels, right and down are positive
+    pub dx:i32,
+    pub dy:i32,
+    /// Wheel clicks, down (towards the user) is positive
+    pub wheel:i32,
+    pub buttons:MouseButtons,
+    /// Buttons that went down or up with this event
+    pub changed:MouseButtons,
+    /// Where the mouse is now, inside the screen
+    pub x:usize,
+    pub y:usize,
+}
+
+
+/// One packet, before it is added to the position
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub struct Packet {
+    /// Right and up are positive, like the mouse sends it
+    pub dx:i32,
+    pub dy:i32,
+    pub wheel:i32,
+    pub buttons:MouseButtons,
+}
+
+
+/// Puts bytes from the mouse together into packets
+pub struct PacketDecoder {
+    device:Device,
+    packet:[u8;4],
+    len:usize,
+}
+impl PacketDecoder {
+    pub const fn new(device:Device)->PacketDecoder {
+        PacketDecoder {
+            device,
+            packet:[0;4],
+            len:0,
+        }
+    }
+    fn packet_len(&self)->usize {
+        match self.device {
+            Device::ScrollMouse|Device::FiveButtonMouse=>4,
+            _=>3,
+        }
+    }
+    pub fn feed(&mut self,byte:u8)->Option<Packet> {
+        // bit 3 is always set in the first byte, anything else means we lost our place
+        if self.len==0&&byte&0x08==0 {return None}
+        self.packet[self.len]=byte;
+        self.len+=1;
+        if self.len<self.packet_len() {return None}
+        self.len=0;
+        let [flags,x,y,extra]=self.packet;
+        let overflow=flags&0xC0!=0;
+        // 9 bit two's complement, the sign bits are in the first byte
+        let dx=if overflow {0} else {x as i32-(((flags as i32)<<4)&0x100)};
+        let dy=if overflow {0} else {y as i32-(((flags as i32)<<3)&0x100)};
+        let mut buttons=flags&0x07;
+        let wheel=match self.device {
+            Device::ScrollMouse=>extra as i8 as i32,
+            Device::FiveButtonMouse=>{
+                buttons|=(extra>>1)&0x18;
+                ((extra<<4) as i8>>4) as i32
+            },
+            _=>0,
+        };
+        return Some(Packet {
+            dx,
+            dy,
+            wheel,
+            buttons:MouseButtons(buttons),
+        });
+    }
+}
+
+
+/// Adds packets up into a position
+pub struct MouseState {
+    x:usize,
+    y:usize,
+    buttons:MouseButtons,
+    width:usize,
+    height:usize,
+}
+impl MouseState {
+    /// Starts in the middle of the screen
+    pub fn new()->MouseState {
+        let bootboot:BootBootUnpacked=unsafe{*(BOOTBOOT_INFO as *const BOOTBOOT)}.into();
+        let (width,height)=(bootboot.fb.width as usize,bootboot.fb.height as usize);
+        MouseState {
+            x:width/2,
+            y:height/2,
+            buttons:MouseButtons::default(),
+            width,
+            height,
+        }
+    }
+    pub fn apply(&mut self,packet:Packet)->MouseEvent {
+        let (dx,dy)=(packet.dx,-packet.dy);
+        self.x=(self.x as i64+dx as i64).clamp(0,self.width.saturating_sub(1) as i64) as usize;
+        self.y=(self.y as i64+dy as i64).clamp(0,self.height.saturating_sub(1) as i64) as usize;
+        let changed=MouseButtons(self.buttons.0^packet.buttons.0);
+        self.buttons=packet.buttons;
+        MouseEvent {
+            dx,
+            dy,
+            wheel:packet.wheel,
+            buttons:packet.buttons,
+            changed,
+            x:self.x,
+            y:self.y,
+        }
+    }
+}
+
+
+/// Decodes packets in each of the formats
+pub fn test_packets()->Result<(),&'static str> {
+    let mut standard=PacketDecoder::new(Device::Mouse);
+    // out of sync byte first, then left button with x=-1 y=+2
+    let packets:[u8;4]=[0x00,0x19,0xFF,0x02];
+    let got=packets.iter().filter_map(|b|standard.feed(*b)).next();
+    if got!=Some(Packet{dx:-1,dy:2,wheel:0,buttons:MouseButtons(MouseButtons::LEFT)}) {
+        return Err("standard packet decoded wrong");
+    }
+    let mut scroll=PacketDecoder::new(Device::ScrollMouse);
+    let got=[0x08,0x05,0x00,0xFF].iter().filter_map(|b|scroll.feed(*b)).next();
+    if got!=Some(Packet{dx:5,dy:0,wheel:-1,buttons:MouseButtons(0)}) {
+        return Err("scroll wheel packet decoded wrong");
+    }
+    let mut five=PacketDecoder::new(Device::FiveButtonMouse);
+    let got=[0x08,0x00,0x00,0x21].iter().filter_map(|b|five.feed(*b)).next();
+    if got!=Some(Packet{dx:0,dy:0,wheel:1,buttons:MouseButtons(MouseButtons::FIFTH)}) {
+        return Err("5 button packet decoded wrong");
+    }
+    return Ok(());
+}
@@ -0,0 +1,442 @@
+//! The 8042 PS/2 controller and the keyboards and mice behind it. [`init`] doesn't trust whatever
+//! the firmware left behind: it tests the controller, finds out which ports have something
+//! plugged in, resets and identifies the devices, and only then turns the interrupts back on.
+//!
+//! Commands poll the status register, so after init they run with interrupts off to keep the
+//! keyboard interrupt from taking the replies. Keys typed while a command waits for its reply are
+//! passed on to the input layer.
+
+
+use spin::Mutex;
+use x86_64::instructions::{
+    port::Port,
+    interrupts::without_interrupts,
+};
+use crate::{
+    info,
+    warn,
+};
+use super::{
+    scancode::ScancodeSet,
+    Modifiers,
+};
+
+
+const DATA:u16=0x60;
+/// Status when read, commands when written
+const COMMAND:u16=0x64;
+
+const STATUS_OUTPUT_FULL:u8=0x01;
+const STATUS_INPUT_FULL:u8=0x02;
+/// The byte waiting in the output buffer is from the second port
+const STATUS_AUX:u8=0x20;
+
+const CMD_READ_CONFIG:u8=0x20;
+const CMD_WRITE_CONFIG:u8=0x60;
+const CMD_DISABLE_SECOND:u8=0xA7;
+const CMD_ENABLE_SECOND:u8=0xA8;
+const CMD_TEST_SECOND:u8=0xA9;
+const CMD_SELF_TEST:u8=0xAA;
+const CMD_TEST_FIRST:u8=0xAB;
+const CMD_DISABLE_FIRST:u8=0xAD;
+const CMD_ENABLE_FIRST:u8=0xAE;
+/// The next byte written to the data port goes to the second port
+const CMD_WRITE_SECOND:u8=0xD4;
+
+const CONFIG_FIRST_IRQ:u8=0x01;
+const CONFIG_SECOND_IRQ:u8=0x02;
+const CONFIG_SECOND_CLOCK_OFF:u8=0x20;
+const CONFIG_TRANSLATE:u8=0x40;
+
+const SELF_TEST_PASSED:u8=0x55;
+const PORT_TEST_PASSED:u8=0x00;
+
+const DEV_SET_LEDS:u8=0xED;
+const DEV_SAMPLE_RATE:u8=0xF3;
+const DEV_SET_DEFAULTS:u8=0xF6;
+const DEV_SCANCODE_SET:u8=0xF0;
+const DEV_IDENTIFY:u8=0xF2;
+const DEV_TYPEMATIC:u8=0xF3;
+const DEV_ENABLE_SCANNING:u8=0xF4;
+const DEV_DISABLE_SCANNING:u8=0xF5;
+const DEV_RESET:u8=0xFF;
+const DEV_ACK:u8=0xFA;
+const DEV_RESEND:u8=0xFE;
+const DEV_RESET_PASSED:u8=0xAA;
+
+/// Status reads before giving up on a reply
+const TIMEOUT:usize=100_000;
+/// Devices can take most of a second to reset
+const RESET_TIMEOUT:usize=2_000_000;
+/// How long to wait for the optional bytes of an identify reply
+const ID_TIMEOUT:usize=20_000;
+
+
+static CONTROLLER:Mutex<Controller>=Mutex::new(Controller::new());
+
+
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum Ps2Error {
+    Timeout,
+    /// The device answered something other than ACK
+    NoAck(u8),
+    /// The controller or a port failed its self test, with the result
+    TestFailed(u8),
+    NoDevice,
+}
+
+
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum Ps2Port {
+    First,
+    Second,
+}
+impl Ps2Port {
+    fn index(&self)->usize {
+        match self {
+            Ps2Port::First=>0,
+            Ps2Port::Second=>1,
+        }
+    }
+}
+
+
+/// What [`identify`](DEV_IDENTIFY) says is plugged into a port
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum Device {
+    /// Old AT keyboards don't answer identify at all
+    AtKeyboard,
+    Mf2Keyboard,
+    Mouse,
+    /// IntelliMouse with a scroll wheel
+    ScrollMouse,
+    /// IntelliMouse with a scroll wheel and buttons 4 and 5
+    FiveButtonMouse,
+    Unknown(u8,u8),
+}
+impl Device {
+    fn from_id(id:&[u8])->Device {
+        match id {
+            []=>Device::AtKeyboard,
+            // 0x41 and 0xC1 are what 0x83 looks like through translation
+            [0xAB,0x83|0x41|0xC1]=>Device::Mf2Keyboard,
+            [0x00]=>Device::Mouse,
+            [0x03]=>Device::ScrollMouse,
+            [0x04]=>Device::FiveButtonMouse,
+            [a]=>Device::Unknown(*a,0),
+            [a,b,..]=>Device::Unknown(*a,*b),
+        }
+    }
+    pub fn is_keyboard(&self)->bool {
+        matches!(self,Device::AtKeyboard|Device::Mf2Keyboard)
+    }
+    pub fn is_mouse(&self)->bool {
+        matches!(self,Device::Mouse|Device::ScrollMouse|Device::FiveButtonMouse)
+    }
+    pub fn name(&self)->&'static str {
+        match self {
+            Device::AtKeyboard=>"AT keyboard",
+            Device::Mf2Keyboard=>"MF2 keyboard",
+            Device::Mouse=>"mouse",
+            Device::ScrollMouse=>"mouse with a scroll wheel",
+            Device::FiveButtonMouse=>"5 button mouse",
+            Device::Unknown(..)=>"unknown device",
+        }
+    }
+}
+
+
+struct Controller {
+    /// Passed its self test
+    present:bool,
+    dual:bool,
+    devices:[Option<Device>;2],
+}
+impl Controller {
+    const fn new()->Controller {
+        Controller {
+            present:false,
+            dual:false,
+            devices:[None;2],
+        }
+    }
+}
+
+
+fn status()->u8 {
+    unsafe{Port::<u8>::new(COMMAND).read()}
+}
+fn write(port:u16,byte:u8)->Result<(),Ps2Error> {
+    for _ in 0..TIMEOUT {
+        if status()&STATUS_INPUT_FULL==0 {
+            unsafe{Port::<u8>::new(port).write(byte)};
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    return Err(Ps2Error::Timeout);
+}
+/// Waits for a byte, and says whether it came from the second port
+fn read_from(timeout:usize)->Result<(u8,bool),Ps2Error> {
+    for _ in 0..timeout {
+        let status=status();
+        if status&STATUS_OUTPUT_FULL!=0 {
+            let byte=unsafe{Port::<u8>::new(DATA).read()};
+            return Ok((byte,status&STATUS_AUX!=0));
+        }
+        core::hint::spin_loop();
+    }
+    return Err(Ps2Error::Timeout);
+}
+fn read()->Result<u8,Ps2Error> {
+    read_from(TIMEOUT).map(|(byte,_)|byte)
+}
+/// Throws away whatever is waiting in the output buffer
+fn flush() {
+    for _ in 0..16 {
+        if status()&STATUS_OUTPUT_FULL==0 {break}
+        unsafe{Port::<u8>::new(DATA).read()};
+    }
+}
+fn controller_command(command:u8)->Result<(),Ps2Error> {
+    write(COMMAND,command)
+}
+fn controller_query(command:u8)->Result<u8,Ps2Error> {
+    write(COMMAND,command)?;
+    read()
+}
+fn write_config(config:u8)->Result<(),Ps2Error> {
+    write(COMMAND,CMD_WRITE_CONFIG)?;
+    write(DATA,config)
+}
+fn send(port:Ps2Port,byte:u8)->Result<(),Ps2Error> {
+    if port==Ps2Port::Second {
+        write(COMMAND,CMD_WRITE_SECOND)?;
+    }
+    write(DATA,byte)
+}
+/// Waits for a reply from `port`. Whatever the other port sends first goes to the input layer.
+fn reply(port:Ps2Port,timeout:usize)->Result<u8,Ps2Error> {
+    for _ in 0..16 {
+        let (byte,aux)=read_from(timeout)?;
+        if aux==(port==Ps2Port::Second) {
+            return Ok(byte);
+        }
+        if aux {
+            super::push_mouse_byte(byte);
+        } else {
+            super::push_scancode(byte);
+        }
+    }
+    return Err(Ps2Error::Timeout);
+}
+/// Sends one byte to a device and waits for its ACK, resending a few times if asked to
+fn device_byte(port:Ps2Port,byte:u8)->Result<(),Ps2Error> {
+    for _ in 0..3 {
+        send(port,byte)?;
+        match reply(port,TIMEOUT)? {
+            DEV_ACK=>return Ok(()),
+            DEV_RESEND=>continue,
+            other=>return Err(Ps2Error::NoAck(other)),
+        }
+    }
+    return Err(Ps2Error::NoAck(DEV_RESEND));
+}
+fn device_command(port:Ps2Port,command:u8,arg:Option<u8>)->Result<(),Ps2Error> {
+    device_byte(port,command)?;
+    if let Some(arg)=arg {
+        device_byte(port,arg)?;
+    }
+    return Ok(());
+}
+fn reset(port:Ps2Port)->Result<(),Ps2Error> {
+    device_byte(port,DEV_RESET)?;
+    match reply(port,RESET_TIMEOUT)? {
+        DEV_RESET_PASSED=>{},
+        other=>return Err(Ps2Error::TestFailed(other)),
+    }
+    // mice send their ID after the test result
+    let _=reply(port,ID_TIMEOUT);
+    return Ok(());
+}
+fn identify(port:Ps2Port)->Result<Device,Ps2Error> {
+    device_command(port,DEV_DISABLE_SCANNING,None)?;
+    device_command(port,DEV_IDENTIFY,None)?;
+    let mut id=[0;2];
+    let mut len=0;
+    while len<2 {
+        match reply(port,ID_TIMEOUT) {
+            Ok(byte)=>{
+                id[len]=byte;
+                len+=1;
+            },
+            Err(_)=>break,
+        }
+    }
+    return Ok(Device::from_id(&id[..len]));
+}
+
+
+/// Resets the controller and everything plugged into it. The keyboard is left sending `set` and
+/// scanning, mice are left quiet until a mouse driver turns them on.
+pub fn init(set:ScancodeSet)->Result<(),Ps2Error> {
+    let mut controller=Controller::new();
+    // the firmware may have left the keyboard's IRQ on, and its handler would take the replies,
+    // so keep it out until the IRQs are off in the config byte. After that nothing else reads
+    // the port.
+    let (mut config,maybe_dual)=without_interrupts(||{
+        controller_command(CMD_DISABLE_FIRST)?;
+        controller_command(CMD_DISABLE_SECOND)?;
+        flush();
+        let mut config=controller_query(CMD_READ_CONFIG)?;
+        let maybe_dual=config&CONFIG_SECOND_CLOCK_OFF!=0;
+        config&=!(CONFIG_FIRST_IRQ|CONFIG_SECOND_IRQ|CONFIG_TRANSLATE);
+        write_config(config)?;
+        match controller_query(CMD_SELF_TEST)? {
+            SELF_TEST_PASSED=>{},
+            other=>return Err(Ps2Error::TestFailed(other)),
+        }
+        // some controllers reset themselves during the test
+        write_config(config)?;
+        return Ok((config,maybe_dual));
+    })?;
+    controller.present=true;
+    if maybe_dual {
+        controller_command(CMD_ENABLE_SECOND)?;
+        controller.dual=controller_query(CMD_READ_CONFIG)?&CONFIG_SECOND_CLOCK_OFF==0;
+        controller_command(CMD_DISABLE_SECOND)?;
+    }
+
+    let mut working=[false;2];
+    working[0]=controller_query(CMD_TEST_FIRST)?==PORT_TEST_PASSED;
+    if controller.dual {
+        working[1]=controller_query(CMD_TEST_SECOND)?==PORT_TEST_PASSED;
+    }
+    if working[0] {controller_command(CMD_ENABLE_FIRST)?}
+    if working[1] {controller_command(CMD_ENABLE_SECOND)?}
+
+    for port in [Ps2Port::First,Ps2Port::Second] {
+        if !working[port.index()] {continue}
+        let device=reset(port).and_then(|_|identify(port));
+        match device {
+            Ok(device)=>{
+                info!("PS/2 port {}: {}",port.index()+1,device.name());
+                controller.devices[port.index()]=Some(device);
+            },
+            Err(Ps2Error::Timeout)=>{},     // nothing plugged in
+            Err(e)=>warn!("PS/2 port {} didn't reset: {:?}",port.index()+1,e),
+        }
+    }
+
+    if let Some(device)=controller.devices[0] {
+        if device.is_keyboard() {
+            device_command(Ps2Port::First,DEV_SCANCODE_SET,Some(2))?;
+            device_command(Ps2Port::First,DEV_ENABLE_SCANNING,None)?;
+        }
+        config|=CONFIG_FIRST_IRQ;
+    }
+    if set==ScancodeSet::Set1 {
+        config|=CONFIG_TRANSLATE;
+    }
+    write_config(config)?;
+    without_interrupts(||*CONTROLLER.lock()=controller);
+    return Ok(());
+}
+pub fn device(port:Ps2Port)->Option<Device> {
+    without_interrupts(||CONTROLLER.lock().devices[port.index()])
+}
+pub fn is_present()->bool {
+    without_interrupts(||CONTROLLER.lock().present)
+}
+pub fn is_dual()->bool {
+    without_interrupts(||CONTROLLER.lock().dual)
+}
+/// Runs a command on the keyboard if there is one
+fn keyboard_command(command:u8,arg:Option<u8>)->Result<(),Ps2Error> {
+    without_interrupts(||{
+        let controller=CONTROLLER.lock();
+        match controller.devices[0] {
+            Some(device) if device.is_keyboard()=>device_command(Ps2Port::First,command,arg),
+            _=>Err(Ps2Error::NoDevice),
+        }
+    })
+}
+
+
+/// Sets the sample rate three times in a row, which is how IntelliMouse features are unlocked
+fn knock(rates:[u8;3])->Result<Device,Ps2Error> {
+    for rate in rates {
+        device_command(Ps2Port::Second,DEV_SAMPLE_RATE,Some(rate))?;
+    }
+    identify(Ps2Port::Second)
+}
+/// Turns on the mouse on the second port with its scroll wheel and extra buttons if it has them,
+/// and starts its interrupt. `rate` is in samples per second.
+pub fn enable_mouse(rate:u8)->Result<Device,Ps2Error> {
+    without_interrupts(||{
+        let mut controller=CONTROLLER.lock();
+        match controller.devices[1] {
+            Some(device) if device.is_mouse()=>{},
+            _=>return Err(Ps2Error::NoDevice),
+        }
+        device_command(Ps2Port::Second,DEV_SET_DEFAULTS,None)?;
+        let mut device=knock([200,100,80])?;
+        if device==Device::ScrollMouse {
+            device=knock([200,200,80])?;
+            if !device.is_mouse() {device=Device::ScrollMouse}
+        }
+        if !device.is_mouse() {device=Device::Mouse}
+        device_command(Ps2Port::Second,DEV_SAMPLE_RATE,Some(rate))?;
+        device_command(Ps2Port::Second,DEV_ENABLE_SCANNING,None)?;
+        let config=controller_query(CMD_READ_CONFIG)?;
+        write_config(config|CONFIG_SECOND_IRQ)?;
+        controller.devices[1]=Some(device);
+        Ok(device)
+    })
+}
+
+
+/// Makes us get `set`. The keyboard always sends set 2, for set 1 the controller translates it.
+pub fn set_scancode_set(set:ScancodeSet)->Result<(),Ps2Error> {
+    keyboard_command(DEV_SCANCODE_SET,Some(2))?;
+    without_interrupts(||{
+        let config=controller_query(CMD_READ_CONFIG)?;
+        let config=match set {
+            ScancodeSet::Set1=>config|CONFIG_TRANSLATE,
+            ScancodeSet::Set2=>config&!CONFIG_TRANSLATE,
+        };
+        write_config(config)
+    })
+}
+/// Turns the keyboard's lock lights on or off to match `mods`
+pub fn set_leds(mods:&Modifiers)->Result<(),Ps2Error> {
+    let leds=mods.scroll_lock as u8|(mods.num_lock as u8)<<1|(mods.caps_lock as u8)<<2;
+    keyboard_command(DEV_SET_LEDS,Some(leds))
+}
+/// Sets how fast a held key repeats (2 to 30 per second) and how long before it starts (250 to
+/// 1000ms, in steps of 250)
+pub fn set_typematic(rate:u32,delay_ms:u32)->Result<(),Ps2Error> {
+    keyboard_command(DEV_TYPEMATIC,Some(typematic_byte(rate,delay_ms)))
+}
+/// The keyboard repeats every `(8+a)*2^b*4.17`ms, for the rate bits `bbaaa`
+fn typematic_byte(rate:u32,delay_ms:u32)->u8 {
+    let target_us=1_000_000/rate.max(1);
+    let rate_bits=(0..32u32).min_by_key(|bits|{
+        let period_us=(8+(bits&7))*(1<<(bits>>3))*4167;
+        (period_us as i64-target_us as i64).abs()
+    }).unwrap();
+    let delay_bits=((delay_ms+125)/250).clamp(1,4)-1;
+    return (delay_bits<<5|rate_bits) as u8;
+}
+
+
+/// Checks the typematic encoding and reading identify replies, neither needs the hardware
+pub fn test_encoding()->Result<(),&'static str> {
+    if typematic_byte(30,250)!=0x00 {return Err("30/s after 250ms should be 0x00")}
+    if typematic_byte(2,1000)!=0x7F {return Err("2/s after 1000ms should be 0x7F")}
+    if typematic_byte(10,500)!=0x2C {return Err("10/s after 500ms should be 0x2C")}
+    if Device::from_id(&[0xAB,0x41])!=Device::Mf2Keyboard {return Err("translated keyboard ID not recognized")}
+    if Device::from_id(&[])!=Device::AtKeyboard {return Err("no ID should be an AT keyboard")}
+    if !Device::from_id(&[0x04]).is_mouse() {return Err("5 button mouse ID not recognized")}
+    return Ok(());
+}
@@ -0,0 +1,64 @@
+//! A lock-free single producer, single consumer ring of bytes. The producer is an interrupt
+//! handler, so pushing can never wait on anything.
+
+
+use core::sync::atomic::{
+    AtomicU8,
+    AtomicU64,
+    AtomicUsize,
+    Ordering,
+};
+
+
+/// `N` has to be a power of two so the indices can wrap around `usize` cleanly
+pub struct ByteRing<const N:usize> {
+    buf:[AtomicU8;N],
+    /// Total bytes pushed. Only the producer writes this.
+    head:AtomicUsize,
+    /// Total bytes popped. Only the consumer writes this.
+    tail:AtomicUsize,
+    dropped:AtomicU64,
+}
+#[allow(dead_code)]
+impl<const N:usize> ByteRing<N> {
+    pub const fn new()->ByteRing<N> {
+        assert!(N.is_power_of_two());
+        ByteRing {
+            buf:[const{AtomicU8::new(0)};N],
+            head:AtomicUsize::new(0),
+            tail:AtomicUsize::new(0),
+            dropped:AtomicU64::new(0),
+        }
+    }
+    /// Adds a byte, or drops it if the ring is full. Only one context may push.
+    pub fn push(&self,b:u8)->bool {
+        let head=self.head.load(Ordering::Relaxed);
+        let tail=self.tail.load(Ordering::Acquire);
+        if head.wrapping_sub(tail)>=N {
+            self.dropped.fetch_add(1,Ordering::Relaxed);
+            return false;
+        }
+        self.buf[head%N].store(b,Ordering::Relaxed);
+        self.head.store(head.wrapping_add(1),Ordering::Release);
+        return true;
+    }
+    /// Takes the oldest byte. Only one context may pop.
+    pub fn pop(&self)->Option<u8> {
+        let tail=self.tail.load(Ordering::Relaxed);
+        let head=self.head.load(Ordering::Acquire);
+        if tail==head {return None}
+        let b=self.buf[tail%N].load(Ordering::Relaxed);
+        self.tail.store(tail.wrapping_add(1),Ordering::Release);
+        return Some(b);
+    }
+    pub fn len(&self)->usize {
+        self.head.load(Ordering::Acquire).wrapping_sub(self.tail.load(Ordering::Acquire))
+    }
+    pub fn is_empty(&self)->bool {
+        self.len()==0
+    }
+    /// Bytes thrown away because the ring was full
+    pub fn dropped(&self)->u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,233 @@
+//! Scancode sets 1 and 2. Set 1 is what the 8042 hands us when it translates (the default), set 2
+//! is what keyboards actually send and what we get with translation turned off.
+
+
+use super::KeyCode;
+
+
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum ScancodeSet {
+    Set1,
+    Set2,
+}
+impl ScancodeSet {
+    pub fn from_number(n:i64)->Option<ScancodeSet> {
+        match n {
+            1=>Some(ScancodeSet::Set1),
+            2=>Some(ScancodeSet::Set2),
+            _=>None,
+        }
+    }
+    pub fn number(&self)->u8 {
+        match self {
+            ScancodeSet::Set1=>1,
+            ScancodeSet::Set2=>2,
+        }
+    }
+}
+
+
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+enum State {
+    Start,
+    /// After `E0`
+    Extended,
+    /// After `F0` (set 2 only)
+    Release,
+    /// After `E0 F0` (set 2 only)
+    ExtendedRelease,
+    /// Inside the Pause sequence, with this many bytes left to skip
+    Pause(u8),
+}
+
+
+/// Turns bytes from the keyboard into `(key,pressed)`
+pub struct ScancodeDecoder {
+    set:ScancodeSet,
+    state:State,
+}
+impl ScancodeDecoder {
+    pub const fn new(set:ScancodeSet)->ScancodeDecoder {
+        ScancodeDecoder {
+            set,
+            state:State::Start,
+        }
+    }
+    pub fn set(&self)->ScancodeSet {
+        self.set
+    }
+    pub fn set_set(&mut self,set:ScancodeSet) {
+        self.set=set;
+        self.state=State::Start;
+    }
+    pub fn feed(&mut self,byte:u8)->Option<(KeyCode,bool)> {
+        match self.set {
+            ScancodeSet::Set1=>self.feed_set1(byte),
+            ScancodeSet::Set2=>self.feed_set2(byte),
+        }
+    }
+    fn feed_set1(&mut self,byte:u8)->Option<(KeyCode,bool)> {
+        match (self.state,byte) {
+            (State::Pause(left),_)=>{
+                // E1 1D 45 E1 9D C5, only the first half is counted as the press
+                self.state=if left>1 {State::Pause(left-1)} else {State::Start};
+                if left==4 {return Some((KeyCode::PauseBreak,true))}
+                None
+            },
+            (State::Start,0xE0)=>{
+                self.state=State::Extended;
+                None
+            },
+            (State::Start,0xE1)=>{
+                self.state=State::Pause(5);
+                None
+            },
+            (State::Start,_)=>set1_key(byte&0x7F).map(|k|(k,byte&0x80==0)),
+            (State::Extended,_)=>{
+                self.state=State::Start;
+                set1_extended_key(byte&0x7F).map(|k|(k,byte&0x80==0))
+            },
+            _=>{
+                self.state=State::Start;
+                None
+            },
+        }
+    }
+    fn feed_set2(&mut self,byte:u8)->Option<(KeyCode,bool)> {
+        match (self.state,byte) {
+            (State::Pause(left),_)=>{
+                // E1 14 77 E1 F0 14 F0 77, no release
+                self.state=if left>1 {State::Pause(left-1)} else {State::Start};
+                if left==7 {return Some((KeyCode::PauseBreak,true))}
+                None
+            },
+            // self test passed, echo, acknowledge and resend aren't keys
+            (State::Start,0xAA|0xEE|0xFA|0xFE|0x00|0xFF)=>None,
+            (State::Start,0xE0)=>{
+                self.state=State::Extended;
+                None
+            },
+            (State::Start,0xE1)=>{
+                self.state=State::Pause(7);
+                None
+            },
+            (State::Start,0xF0)=>{
+                self.state=State::Release;
+                None
+            },
+            (State::Extended,0xF0)=>{
+                self.state=State::ExtendedRelease;
+                None
+            },
+            (State::Start,_)=>set2_key(byte).map(|k|(k,true)),
+            (State::Release,_)=>{
+                self.state=State::Start;
+                set2_key(byte).map(|k|(k,false))
+            },
+            (State::Extended,_)=>{
+                self.state=State::Start;
+                set2_extended_key(byte).map(|k|(k,true))
+            },
+            (State::ExtendedRelease,_)=>{
+                self.state=State::Start;
+                set2_extended_key(byte).map(|k|(k,false))
+            },
+        }
+    }
+}
+
+
+/// pc_keyboard has no name for the extra key left of Z on ISO keyboards, so it is reported as
+/// `HashTilde`. `BackSlash` is always the key above Enter.
+pub const ISO_KEY:KeyCode=KeyCode::HashTilde;
+
+
+fn set1_key(code:u8)->Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x01=>Escape,
+        0x02=>Key1,0x03=>Key2,0x04=>Key3,0x05=>Key4,0x06=>Key5,
+        0x07=>Key6,0x08=>Key7,0x09=>Key8,0x0A=>Key9,0x0B=>Key0,
+        0x0C=>Minus,0x0D=>Equals,0x0E=>Backspace,0x0F=>Tab,
+        0x10=>Q,0x11=>W,0x12=>E,0x13=>R,0x14=>T,0x15=>Y,0x16=>U,0x17=>I,0x18=>O,0x19=>P,
+        0x1A=>BracketSquareLeft,0x1B=>BracketSquareRight,0x1C=>Enter,0x1D=>ControlLeft,
+        0x1E=>A,0x1F=>S,0x20=>D,0x21=>F,0x22=>G,0x23=>H,0x24=>J,0x25=>K,0x26=>L,
+        0x27=>SemiColon,0x28=>Quote,0x29=>BackTick,0x2A=>ShiftLeft,0x2B=>BackSlash,
+        0x2C=>Z,0x2D=>X,0x2E=>C,0x2F=>V,0x30=>B,0x31=>N,0x32=>M,
+        0x33=>Comma,0x34=>Fullstop,0x35=>Slash,0x36=>ShiftRight,0x37=>NumpadStar,
+        0x38=>AltLeft,0x39=>Spacebar,0x3A=>CapsLock,
+        0x3B=>F1,0x3C=>F2,0x3D=>F3,0x3E=>F4,0x3F=>F5,0x40=>F6,0x41=>F7,0x42=>F8,0x43=>F9,0x44=>F10,
+        0x45=>NumpadLock,0x46=>ScrollLock,
+        0x47=>Numpad7,0x48=>Numpad8,0x49=>Numpad9,0x4A=>NumpadMinus,
+        0x4B=>Numpad4,0x4C=>Numpad5,0x4D=>Numpad6,0x4E=>NumpadPlus,
+        0x4F=>Numpad1,0x50=>Numpad2,0x51=>Numpad3,0x52=>Numpad0,0x53=>NumpadPeriod,
+        0x56=>ISO_KEY,0x57=>F11,0x58=>F12,
+        _=>return None,
+    })
+}
+fn set1_extended_key(code:u8)->Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x1C=>NumpadEnter,0x1D=>ControlRight,0x35=>NumpadSlash,0x37=>PrintScreen,0x38=>AltRight,
+        0x47=>Home,0x48=>ArrowUp,0x49=>PageUp,0x4B=>ArrowLeft,0x4D=>ArrowRight,
+        0x4F=>End,0x50=>ArrowDown,0x51=>PageDown,0x52=>Insert,0x53=>Delete,
+        0x5B=>WindowsLeft,0x5C=>WindowsRight,0x5D=>Menus,
+        _=>return None,     // includes the fake shifts around Print Screen
+    })
+}
+fn set2_key(code:u8)->Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x76=>Escape,
+        0x16=>Key1,0x1E=>Key2,0x26=>Key3,0x25=>Key4,0x2E=>Key5,
+        0x36=>Key6,0x3D=>Key7,0x3E=>Key8,0x46=>Key9,0x45=>Key0,
+        0x4E=>Minus,0x55=>Equals,0x66=>Backspace,0x0D=>Tab,
+        0x15=>Q,0x1D=>W,0x24=>E,0x2D=>R,0x2C=>T,0x35=>Y,0x3C=>U,0x43=>I,0x44=>O,0x4D=>P,
+        0x54=>BracketSquareLeft,0x5B=>BracketSquareRight,0x5A=>Enter,0x14=>ControlLeft,
+        0x1C=>A,0x1B=>S,0x23=>D,0x2B=>F,0x34=>G,0x33=>H,0x3B=>J,0x42=>K,0x4B=>L,
+        0x4C=>SemiColon,0x52=>Quote,0x0E=>BackTick,0x12=>ShiftLeft,0x5D=>BackSlash,
+        0x1A=>Z,0x22=>X,0x21=>C,0x2A=>V,0x32=>B,0x31=>N,0x3A=>M,
+        0x41=>Comma,0x49=>Fullstop,0x4A=>Slash,0x59=>ShiftRight,0x7C=>NumpadStar,
+        0x11=>AltLeft,0x29=>Spacebar,0x58=>CapsLock,
+        0x05=>F1,0x06=>F2,0x04=>F3,0x0C=>F4,0x03=>F5,0x0B=>F6,0x83=>F7,0x0A=>F8,0x01=>F9,0x09=>F10,
+        0x77=>NumpadLock,0x7E=>ScrollLock,
+        0x6C=>Numpad7,0x75=>Numpad8,0x7D=>Numpad9,0x7B=>NumpadMinus,
+        0x6B=>Numpad4,0x73=>Numpad5,0x74=>Numpad6,0x79=>NumpadPlus,
+        0x69=>Numpad1,0x72=>Numpad2,0x7A=>Numpad3,0x70=>Numpad0,0x71=>NumpadPeriod,
+        0x61=>ISO_KEY,0x78=>F11,0x07=>F12,
+        _=>return None,
+    })
+}
+fn set2_extended_key(code:u8)->Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x5A=>NumpadEnter,0x14=>ControlRight,0x4A=>NumpadSlash,0x7C=>PrintScreen,0x11=>AltRight,
+        0x6C=>Home,0x75=>ArrowUp,0x7D=>PageUp,0x6B=>ArrowLeft,0x74=>ArrowRight,
+        0x69=>End,0x72=>ArrowDown,0x7A=>PageDown,0x70=>Insert,0x71=>Delete,
+        0x1F=>WindowsLeft,0x27=>WindowsRight,0x2F=>Menus,
+        _=>return None,
+    })
+}
+
+
+/// Feeds a few sequences from both sets through the decoder
+pub fn test_decoder()->Result<(),&'static str> {
+    let run=|set,bytes:&[u8],expected:&[(KeyCode,bool)]|->Result<(),&'static str> {
+        let mut decoder=ScancodeDecoder::new(set);
+        let mut i=0;
+        for b in bytes {
+            if let Some(key)=decoder.feed(*b) {
+                if expected.get(i)!=Some(&key) {return Err("wrong key")}
+                i+=1;
+            }
+        }
+        if i!=expected.len() {return Err("missing keys")}
+        Ok(())
+    };
+    use KeyCode::*;
+    run(ScancodeSet::Set1,&[0x1E,0x9E,0xE0,0x48,0xE0,0xC8,0xE1,0x1D,0x45,0xE1,0x9D,0xC5,0x56],
+        &[(A,true),(A,false),(ArrowUp,true),(ArrowUp,false),(PauseBreak,true),(ISO_KEY,true)]).map_err(|_|"set 1 decoded wrong")?;
+    run(ScancodeSet::Set2,&[0x1C,0xF0,0x1C,0xE0,0x75,0xE0,0xF0,0x75,0xFA,0xE1,0x14,0x77,0xE1,0xF0,0x14,0xF0,0x77,0x83],
+        &[(A,true),(A,false),(ArrowUp,true),(ArrowUp,false),(PauseBreak,true),(F7,true)]).map_err(|_|"set 2 decoded wrong")?;
+    return Ok(());
+}
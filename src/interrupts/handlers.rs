@@ -1,54 +1,91 @@
 use x86_64::{
-    structures::idt::InterruptStackFrame,
+    structures::idt::{
+        InterruptStackFrame,
+        PageFaultErrorCode,
+    },
     instructions::port::Port,
+    registers::control::Cr2,
 };
-use pc_keyboard::{
-    layouts::Us104Key,
-    DecodedKey,
-    HandleControl,
-    Keyboard,
-    ScancodeSet1,
-};
-use spin::Mutex;
-use crate::{print,println,cursor_timer};
+use crate::{cursor_timer,warn,error,input,time,user};
 use super::{
     PICS,
     InterruptID,
+    count_irq,
 };
 
 
-lazy_static::lazy_static! {
-    static ref KEYBOARD: Mutex<Keyboard<Us104Key, ScancodeSet1>> =
-        Mutex::new(Keyboard::new(Us104Key, ScancodeSet1,
-            HandleControl::Ignore)
-        );
-}
-
-
 pub extern "x86-interrupt" fn breakpoint(stack_frame:InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}",stack_frame);
+    warn!("EXCEPTION: BREAKPOINT {:?}",stack_frame);
 }
 pub extern "x86-interrupt" fn double_fault(stack_frame:InterruptStackFrame,error_code:u64)->! {
     panic!("#DF: {}\n{:#?}",error_code,stack_frame);
 }
 pub extern "x86-interrupt" fn general_prot(stack_frame:InterruptStackFrame,error_code:u64) {
-    println!("#GP: {}\n{:#?}",error_code,stack_frame);
+    user::fault(&stack_frame,13,"#GP",Some(error_code),None);
+    error!("#GP: {} {:?}",error_code,stack_frame);
+}
+// faults from ring 3 end the program, see `user::fault`. From the kernel they're bugs.
+pub extern "x86-interrupt" fn divide_error(stack_frame:InterruptStackFrame) {
+    user::fault(&stack_frame,0,"#DE",None,None);
+    panic!("#DE\n{:#?}",stack_frame);
+}
+pub extern "x86-interrupt" fn invalid_opcode(stack_frame:InterruptStackFrame) {
+    user::fault(&stack_frame,6,"#UD",None,None);
+    panic!("#UD\n{:#?}",stack_frame);
+}
+pub extern "x86-interrupt" fn stack_segment(stack_frame:InterruptStackFrame,error_code:u64) {
+    user::fault(&stack_frame,12,"#SS",Some(error_code),None);
+    panic!("#SS: {}\n{:#?}",error_code,stack_frame);
 }
-pub extern "x86-interrupt" fn timer(_stack_frame:InterruptStackFrame) {
-    cursor_timer!();
+pub extern "x86-interrupt" fn page_fault(stack_frame:InterruptStackFrame,error_code:PageFaultErrorCode) {
+    let address=Cr2::read().as_u64();
+    user::fault(&stack_frame,14,"#PF",Some(error_code.bits()),Some(address));
+    panic!("#PF at {:#x}: {:?}\n{:#?}",address,error_code,stack_frame);
+}
+pub extern "x86-interrupt" fn simd_floating_point(stack_frame:InterruptStackFrame) {
+    user::fault(&stack_frame,19,"#XM",None,None);
+    panic!("#XM\n{:#?}",stack_frame);
+}
+pub extern "x86-interrupt" fn timer(stack_frame:InterruptStackFrame) {
+    count_irq(0);
+    let elapsed=time::tick();
+    cursor_timer!(elapsed);
     unsafe{PICS.lock().notify_end_of_interrupt(InterruptID::Timer.into())};
+    // may not come back, if it stops a program
+    user::check_kill(&stack_frame);
+}
+/// The HPET's event comparator went off, in legacy replacement mode
+pub extern "x86-interrupt" fn hpet_timer(_stack_frame:InterruptStackFrame) {
+    count_irq(8);
+    time::timer_fired();
+    unsafe{PICS.lock().notify_end_of_interrupt(InterruptID::HpetTimer.into())};
 }
+/// This core's LAPIC timer went off
+pub extern "x86-interrupt" fn lapic_timer(_stack_frame:InterruptStackFrame) {
+    time::timer_fired();
+    time::lapic::eoi();
+}
+/// The LAPIC sends this when an interrupt went away before it could be delivered. It doesn't
+/// want an EOI.
+pub extern "x86-interrupt" fn spurious(_stack_frame:InterruptStackFrame) {}
+/// Only queues the scancode. Decoding happens in the input task, see [`crate::input`].
 pub extern "x86-interrupt" fn keyboard(_stack_frame:InterruptStackFrame) {
-    let mut keyboard=KEYBOARD.lock();
-    let mut port=Port::new(0x60);
-    let scancode:u8=unsafe{port.read()};
-    if let Ok(Some(key_event))=keyboard.add_byte(scancode) {
-        if let Some(key)=keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character)=>print!("{}",character),
-                DecodedKey::RawKey(key)=>print!("{:?}",key),
-            }
-        }
+    count_irq(1);
+    // the byte may already have been taken by code polling the controller, or be from the mouse
+    let status:u8=unsafe{Port::new(0x64).read()};
+    if status&0x21==0x01 {
+        let scancode:u8=unsafe{Port::new(0x60).read()};
+        input::push_scancode(scancode);
     }
     unsafe{PICS.lock().notify_end_of_interrupt(InterruptID::Keyboard.into())};
 }
+/// Only queues the byte, like the keyboard
+pub extern "x86-interrupt" fn mouse(_stack_frame:InterruptStackFrame) {
+    count_irq(12);
+    let status:u8=unsafe{Port::new(0x64).read()};
+    if status&0x21==0x21 {
+        let byte:u8=unsafe{Port::new(0x60).read()};
+        input::push_mouse_byte(byte);
+    }
+    unsafe{PICS.lock().notify_end_of_interrupt(InterruptID::Mouse.into())};
+}
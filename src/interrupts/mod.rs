@@ -8,6 +8,11 @@ use x86_64::{
 };
 use pic8259::ChainedPics;
 use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use core::sync::atomic::{
+    AtomicU64,
+    Ordering,
+};
 use crate::{
     gdt::DOUBLE_FAULT_IST_INDEX,
 };
@@ -25,13 +30,29 @@ lazy_static::lazy_static! {
         let mut idt=InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(handlers::breakpoint);
         idt.general_protection_fault.set_handler_fn(handlers::general_prot);
+        idt.divide_error.set_handler_fn(handlers::divide_error);
+        idt.invalid_opcode.set_handler_fn(handlers::invalid_opcode);
+        idt.stack_segment_fault.set_handler_fn(handlers::stack_segment);
+        idt.page_fault.set_handler_fn(handlers::page_fault);
+        idt.simd_floating_point.set_handler_fn(handlers::simd_floating_point);
         unsafe{idt.double_fault.set_handler_fn(handlers::double_fault).set_stack_index(DOUBLE_FAULT_IST_INDEX);}
         idt[InterruptID::Timer.into()].set_handler_fn(handlers::timer);
         idt[InterruptID::Keyboard.into()].set_handler_fn(handlers::keyboard);
+        idt[InterruptID::Mouse.into()].set_handler_fn(handlers::mouse);
+        idt[InterruptID::HpetTimer.into()].set_handler_fn(handlers::hpet_timer);
+        idt[InterruptID::LapicTimer.into()].set_handler_fn(handlers::lapic_timer);
+        idt[InterruptID::Spurious.into()].set_handler_fn(handlers::spurious);
         idt
     };
 }
 pub static PICS:Mutex<ChainedPics>=Mutex::new(unsafe{ChainedPics::new(PIC1_OFFSET,PIC2_OFFSET)});
+/// How many times each PIC line has fired
+pub static IRQ_COUNTS:[AtomicU64;16]=[const{AtomicU64::new(0)};16];
+/// What is normally wired to each PIC line on a PC
+pub const IRQ_NAMES:[&str;16]=[
+    "timer","keyboard","cascade","com2","com1","lpt2","floppy","lpt1",
+    "rtc","acpi","free","free","mouse","fpu","ata1","ata2",
+];
 
 
 #[repr(u8)]
@@ -39,11 +60,35 @@ pub static PICS:Mutex<ChainedPics>=Mutex::new(unsafe{ChainedPics::new(PIC1_OFFSE
 pub enum InterruptID {
     Timer=PIC1_OFFSET,
     Keyboard,
+    /// IRQ8, the RTC's line until the HPET takes it, see [`crate::time::hpet`]
+    HpetTimer=PIC2_OFFSET,
+    Mouse=PIC2_OFFSET+4,
+    /// Not from the PICs, see [`crate::time::lapic`]
+    LapicTimer=PIC2_OFFSET+8,
+    Spurious=0xFF,
 }
 impl From<InterruptID> for usize {fn from(id:InterruptID)->usize {id as u8 as usize}}
 impl From<InterruptID> for u8 {fn from(id:InterruptID)->u8 {id as u8}}
 
 
+/// Called by IRQ handlers to count the interrupt
+pub fn count_irq(irq:u8) {
+    IRQ_COUNTS[irq as usize&15].fetch_add(1,Ordering::Relaxed);
+}
+pub fn irq_count(irq:u8)->u64 {
+    IRQ_COUNTS[irq as usize&15].load(Ordering::Relaxed)
+}
+/// Lets a PIC line through
+pub fn unmask_irq(irq:u8) {
+    without_interrupts(||unsafe{
+        let mut pics=PICS.lock();
+        let mut masks=pics.read_masks();
+        masks[(irq as usize>>3)&1]&=!(1<<(irq&7));
+        pics.write_masks(masks[0],masks[1]);
+    });
+}
+
+
 pub fn init(core:usize)->Result<(),()> {
     match core {
         0=>{
@@ -53,7 +98,8 @@ pub fn init(core:usize)->Result<(),()> {
     }
     unsafe {
         PICS.lock().initialize();
-        PICS.lock().write_masks(!3,0xff);
+        // timer, keyboard, the cascade to the second PIC and the mouse on it
+        PICS.lock().write_masks(!0x07,!0x10);
     }
     x86_64::instructions::interrupts::enable();
     return Ok(());
@@ -0,0 +1,554 @@
+//! Kernel logging. Messages go through the `error!`..`trace!` macros, get tagged with their module
+//! and a timestamp, are kept in a fixed size ring buffer (so they can be read back later like
+//! `dmesg`) and are then handed to every registered [`Sink`].
+//!
+//! Filtering is done with a global level plus per-module overrides. Both are read from the
+//! BOOTBOOT environment at boot:
+//! ```text
+//! loglevel=debug
+//! loglevel.memory=trace
+//! loglevel.interrupts=off
+//! logsinks=serial
+//! ```
+//!
+//! Timestamps are kept as uptime and shown as seconds since boot, or as the local date and time
+//! with `logtime=local` (`utc` for UTC) once the wall clock knows the date.
+
+
+use core::{
+    fmt::{
+        self,
+        Write,
+    },
+    sync::atomic::{
+        AtomicU8,
+        AtomicU64,
+        Ordering,
+    },
+};
+use spin::Mutex;
+use embedded_graphics::pixelcolor::Rgb888;
+use crate::{
+    console::{
+        CONSOLE,
+        Console,
+        vt,
+    },
+    serial::SERIAL1,
+    config::{
+        self,
+        Opt,
+        Kind,
+    },
+    time::{
+        self,
+        date::DateTime,
+        wall,
+    },
+};
+
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr,$($arg:tt)*) => ($crate::log::_log($level,module_path!(),format_args!($($arg)*)));
+}
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Error,$($arg)*));
+}
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Warn,$($arg)*));
+}
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Info,$($arg)*));
+}
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Debug,$($arg)*));
+}
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Trace,$($arg)*));
+}
+
+
+pub const RING_ENTRIES:usize=512;
+pub const MODULE_LEN:usize=32;
+pub const MESSAGE_LEN:usize=256;
+pub const MAX_FILTERS:usize=16;
+pub const MAX_SINKS:usize=4;
+/// Stripped from the front of `module_path!()` so tags and filters read `memory::frame`
+const CRATE_PREFIX:&str="homebrew_os::";
+
+
+static MAX_LEVEL:AtomicU8=AtomicU8::new(LevelFilter::Info as u8);
+static TIME_FORMAT:AtomicU8=AtomicU8::new(TimeFormat::Uptime as u8);
+static SEQUENCE:AtomicU64=AtomicU64::new(0);
+static RING:Mutex<LogRing<RING_ENTRIES>>=Mutex::new(LogRing::new());
+static FILTERS:Mutex<[Option<ModuleFilter>;MAX_FILTERS]>=Mutex::new([None;MAX_FILTERS]);
+static SINKS:Mutex<[Option<&'static dyn Sink>;MAX_SINKS]>=Mutex::new([None;MAX_SINKS]);
+
+pub static LOG_LEVEL:Opt=Opt::new("loglevel",Kind::Str,"info","Most verbose level logged: off, error, warn, info, debug or trace");
+pub static LOG_MODULE_LEVEL:Opt=Opt::new("loglevel.*",Kind::Str,"info","Log level for a module and everything under it, e.g. `loglevel.memory=trace`");
+pub static LOG_SINKS:Opt=Opt::new("logsinks",Kind::List,"console,serial","Where log messages are written: console, serial");
+pub static LOG_TIME:Opt=Opt::new("logtime",Kind::Str,"uptime","What log timestamps show: uptime, local or utc");
+
+pub static CONSOLE_SINK:ConsoleSink=ConsoleSink;
+pub static SERIAL_SINK:SerialSink=SerialSink;
+
+
+#[repr(u8)]
+#[derive(Debug,Copy,Clone,PartialEq,Eq,PartialOrd,Ord)]
+pub enum Level {
+    Error=1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+impl Level {
+    pub fn as_str(&self)->&'static str {
+        use Level::*;
+        match self {
+            Error=>"ERROR",
+            Warn=>"WARN",
+            Info=>"INFO",
+            Debug=>"DEBUG",
+            Trace=>"TRACE",
+        }
+    }
+    pub fn color(&self)->Rgb888 {
+        use Level::*;
+        match self {
+            Error=>Rgb888::new(255,85,85),
+            Warn=>Rgb888::new(255,200,60),
+            Info=>Rgb888::new(175,175,175),
+            Debug=>Rgb888::new(100,170,255),
+            Trace=>Rgb888::new(120,120,120),
+        }
+    }
+}
+/// The most verbose level that gets through. `Off` drops everything.
+#[repr(u8)]
+#[derive(Debug,Copy,Clone,PartialEq,Eq,PartialOrd,Ord)]
+pub enum LevelFilter {
+    Off=0,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+impl LevelFilter {
+    pub fn parse(s:&str)->Option<LevelFilter> {
+        use LevelFilter::*;
+        Some(match s.trim() {
+            "off"|"none"|"0"=>Off,
+            "error"|"1"=>Error,
+            "warn"|"warning"|"2"=>Warn,
+            "info"|"3"=>Info,
+            "debug"|"4"=>Debug,
+            "trace"|"5"=>Trace,
+            _=>return None,
+        })
+    }
+    pub fn allows(&self,level:Level)->bool {
+        level as u8<=*self as u8
+    }
+    fn from_u8(level:u8)->LevelFilter {
+        use LevelFilter::*;
+        match level {
+            0=>Off,
+            1=>Error,
+            2=>Warn,
+            3=>Info,
+            4=>Debug,
+            _=>Trace,
+        }
+    }
+}
+
+
+/// A single log message. The strings borrow from wherever the record lives (usually the ring).
+pub struct Record<'a> {
+    pub sequence:u64,
+    /// Nanoseconds since boot
+    pub timestamp:u64,
+    pub level:Level,
+    pub module:&'a str,
+    pub message:&'a str,
+}
+impl<'a> fmt::Display for Record<'a> {
+    fn fmt(&self,f:&mut fmt::Formatter)->fmt::Result {
+        write!(f,"[{}] {:<5} {}: {}",Timestamp(self.timestamp),self.level.as_str(),self.module,self.message)
+    }
+}
+/// How timestamps are shown
+#[repr(u8)]
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum TimeFormat {
+    /// Seconds since boot
+    Uptime,
+    Local,
+    Utc,
+}
+impl TimeFormat {
+    pub fn parse(s:&str)->Option<TimeFormat> {
+        Some(match s.trim() {
+            "uptime"=>TimeFormat::Uptime,
+            "local"=>TimeFormat::Local,
+            "utc"=>TimeFormat::Utc,
+            _=>return None,
+        })
+    }
+}
+/// Seconds since boot to the microsecond like `dmesg`, or the date and time it was
+struct Timestamp(u64);
+impl fmt::Display for Timestamp {
+    fn fmt(&self,f:&mut fmt::Formatter)->fmt::Result {
+        let uptime=core::time::Duration::from_nanos(self.0);
+        match time_format() {
+            // until the date is known the uptime says more
+            _ if !wall::is_known()=>{},
+            TimeFormat::Uptime=>{},
+            TimeFormat::Local=>return write!(f,"{}",wall::local_at(uptime).format("%Y-%m-%d %H:%M:%S.%f")),
+            TimeFormat::Utc=>return write!(f,"{}",DateTime::from_unix_ns(wall::unix_ns_at(uptime)).format("%Y-%m-%d %H:%M:%S.%f")),
+        }
+        write!(f,"{:>5}.{:06}",self.0/1_000_000_000,self.0/1000%1_000_000)
+    }
+}
+
+
+/// Somewhere log messages end up. Sinks are called with interrupts disabled and must not log.
+pub trait Sink:Sync {
+    fn name(&self)->&'static str;
+    fn write(&self,record:&Record);
+}
+pub struct ConsoleSink;
+impl Sink for ConsoleSink {
+    fn name(&self)->&'static str {"console"}
+    fn write(&self,record:&Record) {
+        let mut terminals=CONSOLE.lock();
+        let log_vt=terminals.log_vt();
+        if terminals.log_echo()&&log_vt!=vt::KERNEL_VT {
+            Self::write_to(terminals.kernel_vt(),record);
+        }
+        if let Some(console)=terminals.get(log_vt) {
+            Self::write_to(console,record);
+        }
+    }
+}
+impl ConsoleSink {
+    fn write_to(console:&mut Console,record:&Record) {
+        let (fg,bg)=console.colors();
+        let _=write!(console,"[{}] ",Timestamp(record.timestamp));
+        let _=console.print_str_format(record.level.as_str(),record.level.color(),Some(bg),false);
+        let _=write!(console," {}: ",record.module);
+        let _=console.println_str_format(record.message,fg,Some(bg),false);
+        console.written();
+    }
+}
+pub struct SerialSink;
+impl Sink for SerialSink {
+    fn name(&self)->&'static str {"serial"}
+    fn write(&self,record:&Record) {
+        let _=writeln!(SERIAL1.lock(),"{}",record);
+    }
+}
+
+
+#[derive(Copy,Clone)]
+struct ModuleFilter {
+    module:FixedStr<MODULE_LEN>,
+    level:LevelFilter,
+}
+
+
+/// A string stored inline. Anything that doesn't fit is cut off at a char boundary.
+#[derive(Copy,Clone)]
+struct FixedStr<const N:usize> {
+    len:usize,
+    data:[u8;N],
+}
+impl<const N:usize> Write for FixedStr<N> {
+    fn write_str(&mut self,string:&str)->fmt::Result {
+        let mut end=string.len().min(N-self.len);
+        while !string.is_char_boundary(end) {end-=1}
+        self.data[self.len..self.len+end].copy_from_slice(&string.as_bytes()[..end]);
+        self.len+=end;
+        if end<string.len() {
+            return Err(fmt::Error);
+        }
+        return Ok(());
+    }
+}
+impl<const N:usize> FixedStr<N> {
+    const fn new()->FixedStr<N> {
+        FixedStr {
+            len:0,
+            data:[0;N],
+        }
+    }
+    fn from_str(string:&str)->FixedStr<N> {
+        let mut s=Self::new();
+        let _=s.write_str(string);
+        return s;
+    }
+    fn as_str(&self)->&str {
+        unsafe{core::str::from_utf8_unchecked(&self.data[..self.len])}  // only ever written through `write_str`
+    }
+}
+
+
+#[derive(Copy,Clone)]
+struct Entry {
+    sequence:u64,
+    timestamp:u64,
+    level:Level,
+    module:FixedStr<MODULE_LEN>,
+    message:FixedStr<MESSAGE_LEN>,
+}
+impl Entry {
+    fn record(&self)->Record<'_> {
+        Record {
+            sequence:self.sequence,
+            timestamp:self.timestamp,
+            level:self.level,
+            module:self.module.as_str(),
+            message:self.message.as_str(),
+        }
+    }
+}
+/// Fixed size ring of log entries. Once it is full the oldest entry gets overwritten.
+struct LogRing<const N:usize> {
+    entries:[Option<Entry>;N],
+    next:usize,
+}
+impl<const N:usize> LogRing<N> {
+    const fn new()->LogRing<N> {
+        LogRing {
+            entries:[None;N],
+            next:0,
+        }
+    }
+    fn push(&mut self,entry:Entry)->&Entry {
+        let idx=self.next;
+        self.next=(self.next+1)%N;
+        self.entries[idx]=Some(entry);
+        return self.entries[idx].as_ref().unwrap();
+    }
+    /// Oldest to newest
+    fn iter(&self)->impl Iterator<Item=&Entry> {
+        let (newer,older)=self.entries.split_at(self.next);
+        older.iter().chain(newer.iter()).filter_map(|e|e.as_ref())
+    }
+}
+
+
+pub fn strip_crate(module:&str)->&str {
+    if module==&CRATE_PREFIX[..CRATE_PREFIX.len()-2] {
+        return "kernel";
+    }
+    module.strip_prefix(CRATE_PREFIX).unwrap_or(module)
+}
+fn module_matches(filter:&str,module:&str)->bool {
+    module.starts_with(filter)&&(module.len()==filter.len()||module[filter.len()..].starts_with("::"))
+}
+/// The level for `module` is the one from the most specific matching filter, or the global one.
+pub fn enabled(level:Level,module:&str)->bool {
+    let module=strip_crate(module);
+    x86_64::instructions::interrupts::without_interrupts(||{
+        let filters=FILTERS.lock();
+        let mut best:Option<&ModuleFilter>=None;
+        for filter in filters.iter().flatten() {
+            if module_matches(filter.module.as_str(),module) {
+                if best.map(|b|b.module.len<filter.module.len).unwrap_or(true) {
+                    best=Some(filter);
+                }
+            }
+        }
+        best.map(|f|f.level).unwrap_or(max_level()).allows(level)
+    })
+}
+pub fn max_level()->LevelFilter {
+    LevelFilter::from_u8(MAX_LEVEL.load(Ordering::Relaxed))
+}
+pub fn set_max_level(level:LevelFilter) {
+    MAX_LEVEL.store(level as u8,Ordering::Relaxed);
+}
+pub fn time_format()->TimeFormat {
+    match TIME_FORMAT.load(Ordering::Relaxed) {
+        1=>TimeFormat::Local,
+        2=>TimeFormat::Utc,
+        _=>TimeFormat::Uptime,
+    }
+}
+pub fn set_time_format(format:TimeFormat) {
+    TIME_FORMAT.store(format as u8,Ordering::Relaxed);
+}
+/// Override the level for a module and everything under it. Returns `Err(())` if all the filter
+/// slots are used.
+pub fn set_module_level(module:&str,level:LevelFilter)->Result<(),()> {
+    x86_64::instructions::interrupts::without_interrupts(||{
+        let mut filters=FILTERS.lock();
+        let new=ModuleFilter{module:FixedStr::from_str(module),level};
+        if let Some(slot)=filters.iter_mut().flatten().find(|f|f.module.as_str()==module) {
+            *slot=new;
+            return Ok(());
+        }
+        if let Some(slot)=filters.iter_mut().find(|f|f.is_none()) {
+            *slot=Some(new);
+            return Ok(());
+        }
+        return Err(());
+    })
+}
+pub fn clear_module_level(module:&str) {
+    x86_64::instructions::interrupts::without_interrupts(||{
+        for slot in FILTERS.lock().iter_mut() {
+            if slot.map(|f|f.module.as_str()==module).unwrap_or(false) {
+                *slot=None;
+            }
+        }
+    });
+}
+/// Returns `Err(())` if all the sink slots are used.
+pub fn add_sink(sink:&'static dyn Sink)->Result<(),()> {
+    x86_64::instructions::interrupts::without_interrupts(||{
+        let mut sinks=SINKS.lock();
+        if sinks.iter().flatten().any(|s|s.name()==sink.name()) {
+            return Ok(());
+        }
+        if let Some(slot)=sinks.iter_mut().find(|s|s.is_none()) {
+            *slot=Some(sink);
+            return Ok(());
+        }
+        return Err(());
+    })
+}
+pub fn remove_sink(name:&str) {
+    x86_64::instructions::interrupts::without_interrupts(||{
+        for slot in SINKS.lock().iter_mut() {
+            if slot.map(|s|s.name()==name).unwrap_or(false) {
+                *slot=None;
+            }
+        }
+    });
+}
+/// Calls `f` on every message still in the ring, oldest first. Interrupts are disabled and the ring
+/// is locked while this runs, so `f` can't log.
+pub fn for_each_record<F:FnMut(&Record)>(mut f:F) {
+    x86_64::instructions::interrupts::without_interrupts(||{
+        for entry in RING.lock().iter() {
+            f(&entry.record());
+        }
+    });
+}
+
+
+/// Sets up the sinks, levels and timestamps from the `logsinks`, `loglevel`, `loglevel.<module>`
+/// and `logtime` options.
+pub fn init() {
+    config::register(&[&LOG_LEVEL,&LOG_MODULE_LEVEL,&LOG_SINKS,&LOG_TIME]);
+    match TimeFormat::parse(&LOG_TIME.get_str()) {
+        Some(format)=>set_time_format(format),
+        None=>crate::warn!("Unknown log time format `{}`",LOG_TIME.raw()),
+    }
+    match LevelFilter::parse(&LOG_LEVEL.get_str()) {
+        Some(level)=>set_max_level(level),
+        None=>crate::warn!("Unknown log level `{}`",LOG_LEVEL.raw()),
+    }
+    for (key,value) in config::raw_with_prefix("loglevel.") {
+        let module=&key["loglevel.".len()..];
+        match config::parse_str(&value).ok().and_then(|v|LevelFilter::parse(&v)) {
+            Some(level)=>if let Err(_)=set_module_level(module,level) {
+                crate::warn!("Too many log filters, ignoring `{}`",key);
+            },
+            None=>crate::warn!("Unknown log level `{}` for `{}`",value,key),
+        }
+    }
+    for sink in LOG_SINKS.get_list() {
+        match &sink[..] {
+            "console"=>{
+                // set the console up now, so anything it logs while starting doesn't come back
+                // to a half made console
+                lazy_static::initialize(&CONSOLE);
+                add_sink(&CONSOLE_SINK).unwrap();
+            },
+            "serial"=>if SERIAL1.lock().is_present() {
+                add_sink(&SERIAL_SINK).unwrap();
+            },
+            _=>crate::warn!("Unknown log sink `{}`",sink),
+        }
+    }
+}
+
+
+#[doc(hidden)]
+pub fn _log(level:Level,module:&'static str,args:fmt::Arguments) {
+    if !enabled(level,module) {return}
+    let mut entry=Entry {
+        sequence:SEQUENCE.fetch_add(1,Ordering::Relaxed),
+        timestamp:time::uptime().as_nanos() as u64,
+        level,
+        module:FixedStr::from_str(strip_crate(module)),
+        message:FixedStr::new(),
+    };
+    let _=entry.message.write_fmt(args);    // long messages are truncated
+    x86_64::instructions::interrupts::without_interrupts(||{
+        let mut ring=RING.lock();
+        let record=ring.push(entry).record();
+        for sink in SINKS.lock().iter().flatten() {
+            sink.write(&record);
+        }
+    });
+}
+
+
+/// Ring wraparound, cutting long strings short and per-module levels
+pub fn test_ring()->Result<(),&'static str> {
+    let entry=|sequence:u64|Entry {
+        sequence,
+        timestamp:0,
+        level:Level::Info,
+        module:FixedStr::from_str("test"),
+        message:FixedStr::from_str("message"),
+    };
+    let mut ring=LogRing::<4>::new();
+    if ring.iter().next().is_some() {
+        return Err("a new ring isn't empty");
+    }
+    for sequence in 0..6 {
+        ring.push(entry(sequence));
+    }
+    if !ring.iter().map(|e|e.sequence).eq(2..6) {
+        return Err("the ring didn't keep the newest entries in order");
+    }
+    // "é" is two bytes, and doesn't fit in what's left
+    let mut short=FixedStr::<6>::new();
+    if short.write_str("abcdé").is_ok()||short.as_str()!="abcd" {
+        return Err("a long string wasn't cut at a char boundary");
+    }
+    let mut message=FixedStr::<MESSAGE_LEN>::new();
+    if message.write_fmt(format_args!("{:>1$}","x",MESSAGE_LEN+10)).is_ok()||message.len!=MESSAGE_LEN {
+        return Err("a long message wasn't cut to fit");
+    }
+    // more specific filters win, and only whole path components match
+    let module="homebrew_os::selftest_log::inner::leaf";
+    set_module_level("selftest_log",LevelFilter::Off).map_err(|_|"no room for a log filter")?;
+    set_module_level("selftest_log::inner",LevelFilter::Debug).map_err(|_|"no room for a log filter")?;
+    let filtered=enabled(Level::Debug,module)&&!enabled(Level::Trace,module)
+        &&!enabled(Level::Error,"homebrew_os::selftest_log::other")
+        &&enabled(Level::Error,"homebrew_os::selftest_logger")==max_level().allows(Level::Error);
+    clear_module_level("selftest_log");
+    clear_module_level("selftest_log::inner");
+    if !filtered {
+        return Err("module levels were applied wrong");
+    }
+    if enabled(Level::Error,module)!=max_level().allows(Level::Error) {
+        return Err("a cleared module level is still used");
+    }
+    return Ok(());
+}
@@ -8,8 +8,6 @@
 TODO:
     Allocator,
     Screen as an init module,
-    ?Mouse driver,
-    ?USB driver,
     ?PCI(E) driver
 
 Notes on OSDEV:
@@ -28,8 +26,10 @@ use raw_cpuid::{
 use alloc::{
     vec,
 };
+use core::time::Duration;
 use spin::Mutex;
 use bootboot::*;
+use screen::PixelFormat;
 use memory::frame::{
     PageAllocator,
     print_mmap,
@@ -45,6 +45,19 @@ mod initrd;
 mod interrupts;
 mod gdt;
 mod memory;
+mod serial;
+mod log;
+mod config;
+mod selftest;
+mod task;
+mod shell;
+mod power;
+mod input;
+mod pci;
+mod usb;
+mod time;
+mod acpi;
+mod user;
 
 
 static mut CPUS:Mutex<usize>=Mutex::new(0);
@@ -77,21 +90,35 @@ fn _start()->! {  // This runs on all cores at once
     }
     if core==Some(0) {  // if we are on core0
         let core=core.unwrap()as usize;
-        gdt::init();
+        gdt::init(core);
+        user::init(core);
         interrupts::init(core).unwrap();    // we are core 0, so this will never panic
-        iter_delay(10000);
+        config::init();
+        log::init();
+        acpi::init();
+        time::init();
+        input::init();
+        pci::init();
+        usb::init();
+        console::init();
+        time::sleep(Duration::from_millis(10));    // let the other cores check in
         unsafe{*CPUS.lock()+=1;}
         let vec=vec![10,9,8,7,6,5,4,3,2,1,0];
-        println!("{} logical cores detected\n{} threads/physical core\n{} logical cores checked in",cores,threads,unsafe{CPUS.lock()});
-        println!("Screen resolution: {}x{}",bootboot.fb.width,bootboot.fb.height);
+        info!("{} logical cores detected, {} threads/physical core, {} logical cores checked in",cores,threads,unsafe{CPUS.lock()});
+        info!("Screen resolution: {}x{}",bootboot.fb.width,bootboot.fb.height);
+        match PixelFormat::from_fb_type(bootboot.fb.fb_type) {
+            Some(format)=>info!("Framebuffer pixel format: {:?}",format),
+            None=>warn!("Unknown framebuffer type {}, assuming ARGB",bootboot.fb.fb_type),
+        }
         print_mmap(&bootboot);
         for item in vec {
             println!("Item: {}",item);
         }
+        selftest::run();
+        config::warn_unknown();
         println!("Success!");
-        loop {  // do nothing
-            x86_64::instructions::hlt();
-        }
+        shell::init();
+        task::executor::run();
     } else {    // other cores
         // TODO: load IDT/core and start the cores on the work-fetching.
         // Core 0 is the scheduling core, for now, so we can schedule work easily.
@@ -103,15 +130,14 @@ fn _start()->! {  // This runs on all cores at once
         }
     }
 }
-fn iter_delay(amt:usize) {
-    for _ in 0..amt {}
-}
 
 
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
+    console::_switch(console::vt::KERNEL_VT);   // panics go to VT1, make sure it is showing
     println!("{}",info);
+    console::_flush();
     loop {
         x86_64::instructions::hlt();
     }
@@ -0,0 +1,386 @@
+//! Address spaces. Each program gets a top level page table of its own: the kernel's entries are
+//! copied in from the table BOOTBOOT left us, so the kernel looks the same from every space, and
+//! user memory (PML4 entries 128 to 255, see [`crate::user`]) starts out empty.
+//!
+//! User memory is handed out in regions, [`Vma`]s, which are backed by zeroed frames as soon as
+//! they're mapped. Dropping the space frees the frames and every page table under its user half.
+//!
+//! The kernel's half is copied when a space is made, so the kernel must not grow into top level
+//! entries it hasn't used yet while spaces exist. Its allocations start at 1TB and would have to
+//! pass 512GB for that.
+
+
+use alloc::{
+    collections::BTreeMap,
+    vec::Vec,
+};
+use core::ops::Range;
+use x86_64::{
+    structures::paging::{
+        Page,
+        PageTable,
+        PageTableFlags,
+        PhysFrame,
+        Size4KiB,
+        Mapper,
+        Translate,
+        FrameAllocator,
+        FrameDeallocator,
+        mapper::TranslateResult,
+    },
+    instructions::interrupts::without_interrupts,
+    registers::{
+        control::Cr3,
+        model_specific::{
+            Efer,
+            EferFlags,
+        },
+    },
+    VirtAddr,
+};
+use crate::user::{
+    USER_START,
+    USER_END,
+    is_user_range,
+};
+use super::{
+    frame::{
+        FRAME_ALLOCATOR,
+        table_at,
+    },
+    PAGE_SIZE,
+};
+
+
+/// The top level entries that cover user memory
+const USER_PML4_ENTRIES:Range<usize>=128..256;
+/// Where [`AddressSpace::find_free`] starts looking, leaving the bottom for programs' own segments
+const MMAP_BASE:u64=USER_START+0x1000_0000_0000;
+
+
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum SpaceError {
+    /// The range isn't page aligned and in user memory
+    NotUser,
+    /// Part of the range is mapped already
+    Overlaps,
+    /// Part of the range isn't mapped
+    NotMapped,
+    NoMemory,
+}
+
+
+/// A region of user memory, mapped with the same flags all the way through
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub struct Vma {
+    pub start:VirtAddr,
+    /// Just past the last byte
+    pub end:VirtAddr,
+    /// Always with PRESENT and USER_ACCESSIBLE
+    pub flags:PageTableFlags,
+}
+#[allow(dead_code)]
+impl Vma {
+    pub fn len(&self)->u64 {
+        self.end-self.start
+    }
+    pub fn contains(&self,addr:VirtAddr)->bool {
+        addr>=self.start&&addr<self.end
+    }
+    pub fn pages(&self)->impl Iterator<Item=Page> {
+        Page::range(Page::containing_address(self.start),Page::containing_address(self.end))
+    }
+}
+
+
+pub struct AddressSpace {
+    pml4:PhysFrame,
+    /// By start address
+    vmas:BTreeMap<VirtAddr,Vma>,
+}
+#[allow(dead_code)]
+impl AddressSpace {
+    /// An empty user half with the kernel's half from the table BOOTBOOT left us
+    pub fn new()->Result<AddressSpace,SpaceError> {
+        without_interrupts(||{
+            let mut allocator=FRAME_ALLOCATOR.lock();
+            let pml4=allocator.allocate_frame().ok_or(SpaceError::NoMemory)?;
+            // physical memory is mapped at 0
+            let table=unsafe{&mut *(pml4.start_address().as_u64() as *mut PageTable)};
+            let kernel=unsafe{&*(allocator.kernel_table().start_address().as_u64() as *const PageTable)};
+            table.zero();
+            for i in (0..512).filter(|i|!USER_PML4_ENTRIES.contains(i)) {
+                table[i]=kernel[i].clone();
+            }
+            return Ok(AddressSpace{pml4,vmas:BTreeMap::new()});
+        })
+    }
+    pub fn pml4(&self)->PhysFrame {
+        self.pml4
+    }
+    pub fn is_active(&self)->bool {
+        Cr3::read().0==self.pml4
+    }
+    /// Loads this space's page table. Returns the one that was loaded, to go back to.
+    pub fn activate(&self)->PhysFrame {
+        let (old,flags)=Cr3::read();
+        if old!=self.pml4 {
+            // the kernel's half is the same in both, so this doesn't pull anything out from under us
+            unsafe{Cr3::write(self.pml4,flags)};
+        }
+        return old;
+    }
+    /// Loads the kernel's own page table, if this space's is loaded
+    pub fn deactivate(&self) {
+        if self.is_active() {
+            let kernel=without_interrupts(||FRAME_ALLOCATOR.lock().kernel_table());
+            unsafe{Cr3::write(kernel,Cr3::read().1)};
+        }
+    }
+
+
+    pub fn vmas(&self)->impl Iterator<Item=&Vma> {
+        self.vmas.values()
+    }
+    /// The region `addr` is in
+    pub fn find(&self,addr:VirtAddr)->Option<&Vma> {
+        self.vmas.range(..=addr).next_back().map(|(_,vma)|vma).filter(|vma|vma.contains(addr))
+    }
+    fn overlaps(&self,start:VirtAddr,end:VirtAddr)->bool {
+        self.vmas.range(..end).next_back().map(|(_,vma)|vma.end>start).unwrap_or(false)
+    }
+    /// Somewhere `len` bytes aren't mapped yet, above where programs are usually linked
+    pub fn find_free(&self,len:u64)->Option<VirtAddr> {
+        let len=align_up(len)?;
+        let mut candidate=MMAP_BASE;
+        // regions don't overlap, so they're in order of where they end too
+        for vma in self.vmas.values() {
+            if vma.end.as_u64()<=candidate {continue}
+            if vma.start.as_u64()>=candidate.checked_add(len)? {break}
+            candidate=vma.end.as_u64();
+        }
+        if candidate.checked_add(len)?>USER_END {return None}
+        return Some(VirtAddr::new(candidate));
+    }
+    /// Whether all of `len` bytes at `addr` are mapped, and writable if `write`
+    pub fn check(&self,addr:VirtAddr,len:u64,write:bool)->bool {
+        if len==0 {return true}
+        if !is_user_range(addr,len) {return false}
+        let end=addr+len;
+        let mut at=addr;
+        while at<end {
+            let Some(vma)=self.find(at) else {return false};
+            if write&&!vma.flags.contains(PageTableFlags::WRITABLE) {return false}
+            at=vma.end;
+        }
+        return true;
+    }
+
+
+    /// Maps `len` bytes from `start`, rounded up to pages, as a new region of zeroed memory.
+    /// `flags` can have WRITABLE and NO_EXECUTE.
+    pub fn map(&mut self,start:VirtAddr,len:u64,flags:PageTableFlags)->Result<Vma,SpaceError> {
+        let len=align_up(len).ok_or(SpaceError::NotUser)?;
+        if len==0||!start.is_aligned(PAGE_SIZE)||!is_user_range(start,len) {
+            return Err(SpaceError::NotUser);
+        }
+        let end=start+len;
+        if self.overlaps(start,end) {
+            return Err(SpaceError::Overlaps);
+        }
+        let mut flags=flags|PageTableFlags::PRESENT|PageTableFlags::USER_ACCESSIBLE;
+        // without NXE the bit is reserved, and every access to the page would fault
+        if !Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) {
+            flags.remove(PageTableFlags::NO_EXECUTE);
+        }
+        let vma=Vma{start,end,flags};
+        let parents=PageTableFlags::PRESENT|PageTableFlags::WRITABLE|PageTableFlags::USER_ACCESSIBLE;
+        let mut table=unsafe{table_at(self.pml4)};
+        let mapped=without_interrupts(||{
+            let mut allocator=FRAME_ALLOCATOR.lock();
+            for (i,page) in vma.pages().enumerate() {
+                let frame=match allocator.allocate_frame() {
+                    Some(frame)=>frame,
+                    None=>return Err((i,SpaceError::NoMemory)),
+                };
+                unsafe {
+                    core::ptr::write_bytes(frame.start_address().as_u64() as *mut u8,0,PAGE_SIZE as usize);
+                    // the pages were free, so nothing can be cached for them
+                    match table.map_to_with_table_flags(page,frame,flags,parents,&mut **allocator) {
+                        Ok(flush)=>flush.ignore(),
+                        Err(_)=>{
+                            allocator.deallocate_frame(frame);
+                            return Err((i,SpaceError::NoMemory));
+                        },
+                    }
+                }
+            }
+            return Ok(());
+        });
+        if let Err((done,error))=mapped {
+            self.free_pages(vma.pages().take(done));
+            return Err(error);
+        }
+        self.vmas.insert(start,vma);
+        return Ok(vma);
+    }
+    /// Unmaps `len` bytes from `start`, which have to be mapped, and frees the memory. Regions
+    /// that are only partly covered are cut down.
+    pub fn unmap(&mut self,start:VirtAddr,len:u64)->Result<(),SpaceError> {
+        let len=align_up(len).ok_or(SpaceError::NotUser)?;
+        if !start.is_aligned(PAGE_SIZE)||!is_user_range(start,len) {
+            return Err(SpaceError::NotUser);
+        }
+        if !self.check(start,len,false) {
+            return Err(SpaceError::NotMapped);
+        }
+        let end=start+len;
+        let covered:Vec<Vma>=self.vmas.range(..end).map(|(_,vma)|*vma).filter(|vma|vma.end>start).collect();
+        for vma in covered {
+            self.vmas.remove(&vma.start);
+            if vma.start<start {
+                self.vmas.insert(vma.start,Vma{end:start,..vma});
+            }
+            if vma.end>end {
+                self.vmas.insert(end,Vma{start:end,..vma});
+            }
+        }
+        self.free_pages(Page::range(Page::containing_address(start),Page::containing_address(end)));
+        return Ok(());
+    }
+    fn free_pages(&mut self,pages:impl Iterator<Item=Page>) {
+        let active=self.is_active();
+        let mut table=unsafe{table_at(self.pml4)};
+        without_interrupts(||{
+            let mut allocator=FRAME_ALLOCATOR.lock();
+            for page in pages {
+                if let Ok((frame,flush))=table.unmap(page) {
+                    if active {flush.flush()} else {flush.ignore()}
+                    unsafe{allocator.deallocate_frame(frame)};
+                }
+            }
+        });
+    }
+
+
+    /// Where `addr` is in physical memory
+    pub fn translate(&self,addr:VirtAddr)->Option<(u64,PageTableFlags)> {
+        match unsafe{table_at(self.pml4)}.translate(addr) {
+            TranslateResult::Mapped{frame,offset,flags} if flags.contains(PageTableFlags::USER_ACCESSIBLE)=>{
+                Some((frame.start_address().as_u64()+offset,flags))
+            },
+            _=>None,
+        }
+    }
+    /// Copies `data` into the space at `addr`, whatever the pages' flags. Works when it isn't
+    /// loaded, so it's how programs get their contents.
+    pub fn write(&mut self,addr:VirtAddr,data:&[u8])->Result<(),SpaceError> {
+        if !self.check(addr,data.len() as u64,false) {
+            return Err(SpaceError::NotMapped);
+        }
+        let mut done=0;
+        while done<data.len() {
+            let at=addr+done as u64;
+            let (phys,_)=self.translate(at).ok_or(SpaceError::NotMapped)?;
+            let len=((PAGE_SIZE-at.as_u64()%PAGE_SIZE) as usize).min(data.len()-done);
+            // physical memory is mapped at 0
+            unsafe{core::ptr::copy_nonoverlapping(data[done..].as_ptr(),phys as *mut u8,len)};
+            done+=len;
+        }
+        return Ok(());
+    }
+    /// Copies from the space at `addr` into `data`
+    pub fn read(&self,addr:VirtAddr,data:&mut [u8])->Result<(),SpaceError> {
+        if !self.check(addr,data.len() as u64,false) {
+            return Err(SpaceError::NotMapped);
+        }
+        let mut done=0;
+        while done<data.len() {
+            let at=addr+done as u64;
+            let (phys,_)=self.translate(at).ok_or(SpaceError::NotMapped)?;
+            let len=((PAGE_SIZE-at.as_u64()%PAGE_SIZE) as usize).min(data.len()-done);
+            unsafe{core::ptr::copy_nonoverlapping(phys as *const u8,data[done..].as_mut_ptr(),len)};
+            done+=len;
+        }
+        return Ok(());
+    }
+}
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        self.deactivate();
+        without_interrupts(||unsafe{
+            let mut allocator=FRAME_ALLOCATOR.lock();
+            let table=&*(self.pml4.start_address().as_u64() as *const PageTable);
+            for i in USER_PML4_ENTRIES {
+                if let Ok(frame)=table[i].frame() {
+                    free_table(&mut **allocator,frame,3);
+                }
+            }
+            allocator.deallocate_frame(self.pml4);
+        });
+    }
+}
+
+
+/// Frees a page table `level` levels above the pages, and everything under it
+unsafe fn free_table(allocator:&mut impl FrameDeallocator<Size4KiB>,frame:PhysFrame,level:usize) {
+    let table=&*(frame.start_address().as_u64() as *const PageTable);
+    for entry in table.iter() {
+        match entry.frame() {
+            Ok(child) if level>1=>free_table(allocator,child,level-1),
+            Ok(child)=>allocator.deallocate_frame(child),
+            // huge pages aren't made in user memory
+            Err(_)=>{},
+        }
+    }
+    allocator.deallocate_frame(frame);
+}
+fn align_up(len:u64)->Option<u64> {
+    Some(len.checked_add(PAGE_SIZE-1)?&!(PAGE_SIZE-1))
+}
+
+
+pub fn test_address_space()->Result<(),&'static str> {
+    let mut space=AddressSpace::new().map_err(|_|"couldn't make an address space")?;
+    let base=VirtAddr::new(USER_START+0x20_0000);
+    let rw=PageTableFlags::WRITABLE|PageTableFlags::NO_EXECUTE;
+    space.map(base,4*PAGE_SIZE,rw).map_err(|_|"couldn't map")?;
+    if space.map(base+PAGE_SIZE*3,PAGE_SIZE,rw)!=Err(SpaceError::Overlaps) {
+        return Err("mapped over a region");
+    }
+    if space.map(VirtAddr::new(0x1000),PAGE_SIZE,rw)!=Err(SpaceError::NotUser) {
+        return Err("mapped kernel memory");
+    }
+    space.map(base+PAGE_SIZE*4,PAGE_SIZE,PageTableFlags::empty()).map_err(|_|"couldn't map next to a region")?;
+    if !space.check(base,4*PAGE_SIZE,true)||space.check(base,5*PAGE_SIZE,true)||!space.check(base,5*PAGE_SIZE,false) {
+        return Err("checked the permissions wrong");
+    }
+    // written from outside, then read through the page table once it's loaded
+    let message=b"across a page boundary";
+    let at=base+PAGE_SIZE-4u64;
+    space.write(at,message).map_err(|_|"couldn't write")?;
+    let old=space.activate();
+    let seen=unsafe{core::slice::from_raw_parts(at.as_ptr::<u8>(),message.len())}==message;
+    unsafe{Cr3::write(old,Cr3::read().1)};
+    if !seen {
+        return Err("the space didn't have what was written");
+    }
+    // cutting a page out of the middle leaves a region either side
+    space.unmap(base+PAGE_SIZE,PAGE_SIZE).map_err(|_|"couldn't unmap")?;
+    if space.vmas().count()!=3||space.find(base+PAGE_SIZE).is_some()||space.translate(base+PAGE_SIZE).is_some() {
+        return Err("unmapping didn't split the region");
+    }
+    let mut back=[0;4];
+    space.read(at,&mut back).map_err(|_|"couldn't read")?;
+    if &back!=b"acro" {
+        return Err("read back something else");
+    }
+    let free=space.find_free(PAGE_SIZE).ok_or("nowhere free")?;
+    if space.map(free,PAGE_SIZE,rw).is_err() {
+        return Err("find_free found somewhere taken");
+    }
+    if space.unmap(base+PAGE_SIZE,PAGE_SIZE)!=Err(SpaceError::NotMapped) {
+        return Err("unmapped a hole");
+    }
+    return Ok(());
+}
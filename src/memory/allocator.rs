@@ -7,14 +7,48 @@ use alloc::alloc::{
     Layout,
 };
 use x86_64::addr::VirtAddr;
-use core::ptr::null_mut;
+use core::{
+    ptr::null_mut,
+    sync::atomic::{
+        AtomicUsize,
+        Ordering,
+    },
+};
+use x86_64::instructions::interrupts::without_interrupts;
 use super::{
-    frame::FRAME_ALLOCATOR,
+    frame::{
+        FRAME_ALLOCATOR,
+        PageAllocator,
+    },
 };
 
 
 #[global_allocator]
 pub static GLOBAL_ALLOCATOR:GlobalAllocator=GlobalAllocator;
+static ALLOCATIONS:AtomicUsize=AtomicUsize::new(0);
+static DEALLOCATIONS:AtomicUsize=AtomicUsize::new(0);
+static BYTES_IN_USE:AtomicUsize=AtomicUsize::new(0);
+static PAGES_IN_USE:AtomicUsize=AtomicUsize::new(0);
+
+
+/// What the heap has been up to
+#[derive(Debug,Copy,Clone)]
+pub struct AllocStats {
+    pub allocations:usize,
+    pub deallocations:usize,
+    /// Bytes asked for and not freed yet
+    pub bytes_in_use:usize,
+    /// Pages backing those bytes. Every allocation gets whole pages for now.
+    pub pages_in_use:usize,
+}
+pub fn stats()->AllocStats {
+    AllocStats {
+        allocations:ALLOCATIONS.load(Ordering::Relaxed),
+        deallocations:DEALLOCATIONS.load(Ordering::Relaxed),
+        bytes_in_use:BYTES_IN_USE.load(Ordering::Relaxed),
+        pages_in_use:PAGES_IN_USE.load(Ordering::Relaxed),
+    }
+}
 
 
 #[cfg(not(test))]
@@ -26,9 +60,14 @@ fn alloc_error_handler(layout:Layout)->! {
 
 pub struct GlobalAllocator;
 unsafe impl GlobalAlloc for GlobalAllocator {
+    // interrupt handlers allocate too (waking tasks, queueing input), so the lock is never held
+    // with interrupts on
     unsafe fn alloc(&self,layout:Layout)->*mut u8 {
         if layout.size()>0 {
-            if let Ok(ptr)=FRAME_ALLOCATOR.lock().allocate(layout.size()) {
+            if let Ok(ptr)=without_interrupts(||FRAME_ALLOCATOR.lock().allocate(layout.size())) {
+                ALLOCATIONS.fetch_add(1,Ordering::Relaxed);
+                BYTES_IN_USE.fetch_add(layout.size(),Ordering::Relaxed);
+                PAGES_IN_USE.fetch_add(PageAllocator::min_frames_from_size(layout.size()),Ordering::Relaxed);
                 return ptr.as_u64() as *mut u8;
             }
         }
@@ -36,7 +75,10 @@ unsafe impl GlobalAlloc for GlobalAllocator {
     }
     unsafe fn dealloc(&self,ptr:*mut u8,layout:Layout) {
         if layout.size()>0 {
-            FRAME_ALLOCATOR.lock().deallocate(VirtAddr::new(ptr as u64),layout.size()).unwrap();
+            without_interrupts(||FRAME_ALLOCATOR.lock().deallocate(VirtAddr::new(ptr as u64),layout.size())).unwrap();
+            DEALLOCATIONS.fetch_add(1,Ordering::Relaxed);
+            BYTES_IN_USE.fetch_sub(layout.size(),Ordering::Relaxed);
+            PAGES_IN_USE.fetch_sub(PageAllocator::min_frames_from_size(layout.size()),Ordering::Relaxed);
         }
     }
 }
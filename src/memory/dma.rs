@@ -0,0 +1,68 @@
+//! Memory for devices to read and write on their own. Devices only know physical addresses, so
+//! this hands out single frames straight from the frame allocator and uses them through BOOTBOOT's
+//! identity mapping, where the virtual address is the physical one.
+
+
+use x86_64::{
+    addr::PhysAddr,
+    instructions::interrupts::without_interrupts,
+    structures::paging::{
+        FrameAllocator as FrameAllocatorTrait,
+        FrameDeallocator as FrameDeallocatorTrait,
+        PhysFrame,
+    },
+};
+use super::{
+    frame::FRAME_ALLOCATOR,
+    PAGE_SIZE,
+};
+
+
+/// One zeroed, page aligned page a device can use. It goes back to the allocator when dropped.
+pub struct DmaPage {
+    frame:PhysFrame,
+}
+#[allow(dead_code)]
+impl DmaPage {
+    pub const SIZE:usize=PAGE_SIZE as usize;
+    pub fn new()->Option<DmaPage> {
+        let frame=without_interrupts(||FRAME_ALLOCATOR.lock().allocate_frame())?;
+        let page=DmaPage{frame};
+        unsafe{core::ptr::write_bytes(page.ptr::<u8>(),0,Self::SIZE)};
+        Some(page)
+    }
+    pub fn phys(&self)->u64 {
+        self.frame.start_address().as_u64()
+    }
+    /// The address of byte `offset`, as the device sees it
+    pub fn phys_at(&self,offset:usize)->u64 {
+        self.phys()+offset as u64
+    }
+    pub fn ptr<T>(&self)->*mut T {
+        self.phys() as *mut T
+    }
+    pub fn ptr_at<T>(&self,offset:usize)->*mut T {
+        self.phys_at(offset) as *mut T
+    }
+    pub fn as_slice(&self)->&[u8] {
+        unsafe{core::slice::from_raw_parts(self.ptr(),Self::SIZE)}
+    }
+    pub fn as_mut_slice(&mut self)->&mut [u8] {
+        unsafe{core::slice::from_raw_parts_mut(self.ptr(),Self::SIZE)}
+    }
+    pub fn zero(&mut self) {
+        self.as_mut_slice().fill(0);
+    }
+}
+impl Drop for DmaPage {
+    fn drop(&mut self) {
+        without_interrupts(||unsafe{FRAME_ALLOCATOR.lock().deallocate_frame(self.frame)});
+    }
+}
+
+
+/// Maps device registers, see [`PageAllocator::map_mmio`](super::frame::PageAllocator::map_mmio)
+pub fn map_mmio(phys:u64,size:usize)->Result<*mut u8,()> {
+    let virt=without_interrupts(||FRAME_ALLOCATOR.lock().map_mmio(PhysAddr::new(phys),size))?;
+    Ok(virt.as_mut_ptr())
+}
@@ -68,6 +68,7 @@ pub enum FrameAllocateError {
 pub struct PageAllocator {
     frame:FrameAllocator,
     page:OffsetPageTable<'static>,
+    kernel_pml4:PhysFrame,
 }
 impl PageAllocator {
     pub fn new(bb:&BootBootUnpacked)->PageAllocator {
@@ -75,7 +76,8 @@ impl PageAllocator {
         let page_directory=unsafe{(cr3.start_address().as_u64() as *mut u8 as *mut PageTable).as_mut().unwrap()};
         PageAllocator {
             frame:FrameAllocator::new(&bb),
-            page:unsafe{OffsetPageTable::new(page_directory,VirtAddr::new(0))}
+            page:unsafe{OffsetPageTable::new(page_directory,VirtAddr::new(0))},
+            kernel_pml4:cr3,
         }
     }
     pub fn min_frames_from_size(size:usize)->usize {
@@ -132,6 +134,25 @@ impl PageAllocator {
         }
         return Err(());
     }
+    /// Maps device registers at `phys` uncached, somewhere new in virtual memory. The frames don't
+    /// come from the allocator and never go back to it.
+    pub fn map_mmio(&mut self,phys:PhysAddr,size:usize)->Result<VirtAddr,()> {
+        let start=phys.align_down(PAGE_SIZE);
+        let frames=Self::min_frames_from_size(size+(phys-start) as usize);
+        let virt=VirtAddr::new(self.memory_allocate_offset);
+        self.memory_allocate_offset+=frames as u64*PAGE_SIZE;
+        let flags=PageTableFlags::PRESENT|PageTableFlags::WRITABLE|PageTableFlags::NO_CACHE|PageTableFlags::WRITE_THROUGH;
+        for i in 0..frames as u64 {
+            let page=Page::<Size4KiB>::from_start_address(virt+i*PAGE_SIZE).unwrap();
+            let frame=PhysFrame::<Size4KiB>::from_start_address(start+i*PAGE_SIZE).unwrap();
+            unsafe{self.page.map_to(page,frame,flags,&mut self.frame)}.map_err(|_|())?.flush();
+        }
+        return Ok(virt+(phys-start));
+    }
+    /// The page table BOOTBOOT left us, which every address space copies the kernel's half from
+    pub fn kernel_table(&self)->PhysFrame {
+        self.kernel_pml4
+    }
     /// Accepts pointers to continuous (virtual) memory. Physical memory may/may not be contiguous
     pub unsafe fn deallocate(&mut self,ptr:VirtAddr,size:usize)->Result<(),()> {
         let frames=Self::min_frames_from_size(size);
@@ -258,6 +279,12 @@ impl FrameDeallocatorTrait<Size4KiB> for FrameAllocator {
 }
 
 
+/// The page table at `pml4`, through the identity mapping
+pub unsafe fn table_at(pml4:PhysFrame)->OffsetPageTable<'static> {
+    OffsetPageTable::new(&mut *(pml4.start_address().as_u64() as *mut PageTable),VirtAddr::new(0))
+}
+
+
 pub fn print_mmap(bb:&BootBootUnpacked) {
     println!("{} MMAP entries",bb.mmio_count);
     let mut ram=0;
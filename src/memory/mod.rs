@@ -1,5 +1,7 @@
 pub mod frame;
 pub mod allocator;
+pub mod dma;
+pub mod address_space;
 
 
 pub const FREE_MARKER:u64=0x1C31C3BABEEEEEEE;   // LOL
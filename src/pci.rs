@@ -0,0 +1,215 @@
+//! PCI configuration space through the legacy `0xCF8`/`0xCFC` ports, and finding devices by
+//! walking every bus.
+
+
+use alloc::vec::Vec;
+use x86_64::instructions::{
+    port::Port,
+    interrupts::without_interrupts,
+};
+use spin::Mutex;
+use crate::{
+    shell::{
+        self,
+        Command,
+    },
+    println,
+};
+
+
+const CONFIG_ADDRESS:u16=0xCF8;
+const CONFIG_DATA:u16=0xCFC;
+
+const REG_ID:u8=0x00;
+const REG_COMMAND:u8=0x04;
+const REG_CLASS:u8=0x08;
+const REG_HEADER:u8=0x0C;
+const REG_BAR0:u8=0x10;
+const REG_INTERRUPT:u8=0x3C;
+
+const COMMAND_IO:u16=0x01;
+const COMMAND_MEMORY:u16=0x02;
+const COMMAND_BUS_MASTER:u16=0x04;
+
+const HEADER_MULTIFUNCTION:u8=0x80;
+
+
+/// The port pair is shared by every config access, so one at a time
+static CONFIG_LOCK:Mutex<()>=Mutex::new(());
+
+
+static LSPCI:Command=Command::new("lspci","","List PCI devices",lspci);
+
+
+/// Where a BAR points
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum Bar {
+    Memory{addr:u64,size:u64,prefetchable:bool},
+    Io{port:u16,size:u16},
+}
+
+
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub struct PciDevice {
+    pub bus:u8,
+    pub device:u8,
+    pub function:u8,
+    pub vendor_id:u16,
+    pub device_id:u16,
+    pub class:u8,
+    pub subclass:u8,
+    pub prog_if:u8,
+}
+#[allow(dead_code)]
+impl PciDevice {
+    fn at(bus:u8,device:u8,function:u8)->Option<PciDevice> {
+        let id=read(bus,device,function,REG_ID);
+        if id&0xFFFF==0xFFFF {return None}
+        let class=read(bus,device,function,REG_CLASS);
+        Some(PciDevice {
+            bus,device,function,
+            vendor_id:id as u16,
+            device_id:(id>>16) as u16,
+            class:(class>>24) as u8,
+            subclass:(class>>16) as u8,
+            prog_if:(class>>8) as u8,
+        })
+    }
+    pub fn read(&self,offset:u8)->u32 {
+        read(self.bus,self.device,self.function,offset)
+    }
+    pub fn write(&self,offset:u8,value:u32) {
+        write(self.bus,self.device,self.function,offset,value)
+    }
+    fn header_type(&self)->u8 {
+        (self.read(REG_HEADER)>>16) as u8
+    }
+    /// Lets the device answer memory and I/O accesses and do DMA
+    pub fn enable(&self) {
+        let command=self.read(REG_COMMAND);
+        self.write(REG_COMMAND,command|(COMMAND_IO|COMMAND_MEMORY|COMMAND_BUS_MASTER) as u32);
+    }
+    /// The legacy interrupt line the firmware routed the device to
+    pub fn interrupt_line(&self)->Option<u8> {
+        match self.read(REG_INTERRUPT) as u8 {
+            0xFF=>None,
+            line=>Some(line),
+        }
+    }
+    /// Reads BAR `n`, sizing it by writing all ones and seeing which bits stick. A 64 bit memory
+    /// BAR takes up `n` and `n+1`.
+    pub fn bar(&self,n:u8)->Option<Bar> {
+        if n>5 {return None}
+        let offset=REG_BAR0+n*4;
+        let command=self.read(REG_COMMAND);
+        // decoding off while the BAR holds garbage
+        self.write(REG_COMMAND,command&!((COMMAND_IO|COMMAND_MEMORY) as u32));
+        let low=self.read(offset);
+        self.write(offset,0xFFFF_FFFF);
+        let low_mask=self.read(offset);
+        self.write(offset,low);
+        let bar=if low&1==1 {
+            let size=(!(low_mask&!0x3)).wrapping_add(1) as u16;
+            Some(Bar::Io{port:(low&!0x3) as u16,size})
+        } else if (low>>1)&3==2&&n<5 {   // 64 bit
+            let high=self.read(offset+4);
+            self.write(offset+4,0xFFFF_FFFF);
+            let high_mask=self.read(offset+4);
+            self.write(offset+4,high);
+            let mask=(high_mask as u64)<<32|(low_mask&!0xF) as u64;
+            Some(Bar::Memory{addr:(high as u64)<<32|(low&!0xF) as u64,size:(!mask).wrapping_add(1),prefetchable:low&8!=0})
+        } else {
+            let size=(!(low_mask&!0xF)).wrapping_add(1) as u64;
+            Some(Bar::Memory{addr:(low&!0xF) as u64,size,prefetchable:low&8!=0})
+        };
+        self.write(REG_COMMAND,command);
+        if low==0&&low_mask==0 {return None}     // not implemented
+        return bar;
+    }
+    /// A rough name for the class, for listing
+    pub fn class_name(&self)->&'static str {
+        match (self.class,self.subclass) {
+            (0x01,0x01)=>"IDE controller",
+            (0x01,0x06)=>"SATA controller",
+            (0x01,0x08)=>"NVMe controller",
+            (0x01,_)=>"storage controller",
+            (0x02,_)=>"network controller",
+            (0x03,_)=>"display controller",
+            (0x04,_)=>"multimedia device",
+            (0x06,0x00)=>"host bridge",
+            (0x06,0x01)=>"ISA bridge",
+            (0x06,0x04)=>"PCI bridge",
+            (0x06,_)=>"bridge",
+            (0x0C,0x03)=>match self.prog_if {
+                0x00=>"USB UHCI controller",
+                0x10=>"USB OHCI controller",
+                0x20=>"USB EHCI controller",
+                0x30=>"USB xHCI controller",
+                _=>"USB controller",
+            },
+            (0x0C,0x05)=>"SMBus controller",
+            (0x0C,_)=>"serial bus controller",
+            _=>"device",
+        }
+    }
+}
+
+
+fn address(bus:u8,device:u8,function:u8,offset:u8)->u32 {
+    0x8000_0000|(bus as u32)<<16|(device as u32)<<11|(function as u32)<<8|(offset&0xFC) as u32
+}
+pub fn read(bus:u8,device:u8,function:u8,offset:u8)->u32 {
+    without_interrupts(||{
+        let _lock=CONFIG_LOCK.lock();
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(address(bus,device,function,offset));
+            Port::<u32>::new(CONFIG_DATA).read()
+        }
+    })
+}
+pub fn write(bus:u8,device:u8,function:u8,offset:u8,value:u32) {
+    without_interrupts(||{
+        let _lock=CONFIG_LOCK.lock();
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(address(bus,device,function,offset));
+            Port::<u32>::new(CONFIG_DATA).write(value);
+        }
+    });
+}
+
+
+/// Every function on every bus
+pub fn devices()->Vec<PciDevice> {
+    let mut found=Vec::new();
+    for bus in 0..=255u8 {
+        for device in 0..32 {
+            let first=match PciDevice::at(bus,device,0) {
+                Some(first)=>first,
+                None=>continue,
+            };
+            let functions=if first.header_type()&HEADER_MULTIFUNCTION!=0 {8} else {1};
+            found.push(first);
+            for function in 1..functions {
+                if let Some(dev)=PciDevice::at(bus,device,function) {
+                    found.push(dev);
+                }
+            }
+        }
+    }
+    return found;
+}
+/// Devices with this class, subclass and programming interface
+pub fn find(class:u8,subclass:u8,prog_if:u8)->Vec<PciDevice> {
+    devices().into_iter().filter(|d|d.class==class&&d.subclass==subclass&&d.prog_if==prog_if).collect()
+}
+
+
+pub fn init() {
+    shell::register(&[&LSPCI]);
+}
+fn lspci(_args:&[&str])->Result<(),&'static str> {
+    for dev in devices() {
+        println!("  {:02x}:{:02x}.{} {:04x}:{:04x} {}",dev.bus,dev.device,dev.function,dev.vendor_id,dev.device_id,dev.class_name());
+    }
+    return Ok(());
+}
@@ -0,0 +1,53 @@
+//! Rebooting and turning the machine off. The ACPI tables can be found, but sleeping through ACPI
+//! needs AML, so this uses the 8042's reset line and the shutdown ports emulators provide.
+
+
+use x86_64::{
+    instructions::port::Port,
+    structures::DescriptorTablePointer,
+    VirtAddr,
+};
+use crate::{
+    console,
+    warn,
+};
+
+
+/// `(port,value)` pairs that power off QEMU, Bochs and VirtualBox
+const SHUTDOWN_PORTS:[(u16,u16);3]=[
+    (0x604,0x2000),
+    (0xB004,0x2000),
+    (0x4004,0x3400),
+];
+/// Cloud Hypervisor's ACPI sleep control register, which is a single byte
+const SLEEP_CONTROL:(u16,u8)=(0x600,0x34);
+
+
+/// Resets the machine with the keyboard controller, or failing that a triple fault
+pub fn reboot()->! {
+    console::_flush();
+    x86_64::instructions::interrupts::disable();
+    unsafe {
+        let mut status:Port<u8>=Port::new(0x64);
+        for _ in 0..0x10000 {   // wait for the input buffer to empty
+            if status.read()&0x02==0 {break}
+        }
+        status.write(0xFE);     // pulse the reset line
+        // still here, so fault with no IDT to catch it
+        let idt=DescriptorTablePointer{limit:0,base:VirtAddr::new(0)};
+        x86_64::instructions::tables::lidt(&idt);
+        core::arch::asm!("int3",options(nomem,nostack));
+    }
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+/// Turns the machine off. This only works on emulators for now and returns if it didn't.
+pub fn shutdown() {
+    console::_flush();
+    for (port,value) in SHUTDOWN_PORTS {
+        unsafe{Port::<u16>::new(port).write(value)};
+    }
+    unsafe{Port::<u8>::new(SLEEP_CONTROL.0).write(SLEEP_CONTROL.1)};
+    warn!("Couldn't power off, ACPI power off isn't supported yet");
+}
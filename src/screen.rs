@@ -1,6 +1,21 @@
 use crate::{
     math::Point,
+    config::{
+        Opt,
+        Kind,
+    },
+    bootboot::{
+        FB_ARGB,
+        FB_RGBA,
+        FB_ABGR,
+        FB_BGRA,
+    },
+};
+use alloc::{
+    vec,
+    vec::Vec,
 };
+use core::ops::Range;
 use embedded_graphics::{
     draw_target::DrawTarget,
     pixelcolor::{
@@ -12,17 +27,146 @@ use embedded_graphics::{
         Point as EGPoint,
         Size,
     },
-    primitives::Rectangle,
+    primitives::{
+        Rectangle,
+        PointsIter,
+    },
     Pixel,
 };
 
 
-#[derive(Default)]
+pub static BACK_BUFFER:Opt=Opt::new("fb.backbuffer",Kind::Bool,"true","Draw into a copy of the framebuffer in RAM and flush changes to the screen");
+pub static FLUSH_TICKS:Opt=Opt::new("fb.flush_ticks",Kind::Int,"0","With a back buffer, flush every N timer ticks instead of after every console write (0)");
+
+
+/// How a pixel is laid out in a 32 bit framebuffer word, named from the most significant byte down
+/// (this is BOOTBOOT's `fb_type`).
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum PixelFormat {
+    Argb,
+    Rgba,
+    Abgr,
+    Bgra,
+}
+impl PixelFormat {
+    pub const ALL:[PixelFormat;4]=[PixelFormat::Argb,PixelFormat::Rgba,PixelFormat::Abgr,PixelFormat::Bgra];
+    pub fn from_fb_type(fb_type:u8)->Option<PixelFormat> {
+        use PixelFormat::*;
+        match fb_type as u32 {
+            FB_ARGB=>Some(Argb),
+            FB_RGBA=>Some(Rgba),
+            FB_ABGR=>Some(Abgr),
+            FB_BGRA=>Some(Bgra),
+            _=>None,
+        }
+    }
+    pub fn encoder(&self)->fn(Rgb888)->u32 {
+        use PixelFormat::*;
+        match self {
+            Argb=>encode_argb,
+            Rgba=>encode_rgba,
+            Abgr=>encode_abgr,
+            Bgra=>encode_bgra,
+        }
+    }
+    pub fn decoder(&self)->fn(u32)->Rgb888 {
+        use PixelFormat::*;
+        match self {
+            Argb=>decode_argb,
+            Rgba=>decode_rgba,
+            Abgr=>decode_abgr,
+            Bgra=>decode_bgra,
+        }
+    }
+}
+#[inline(always)]
+fn encode_argb(c:Rgb888)->u32 {((c.r()as u32)<<16)|((c.g()as u32)<<8)|(c.b()as u32)}
+#[inline(always)]
+fn encode_rgba(c:Rgb888)->u32 {((c.r()as u32)<<24)|((c.g()as u32)<<16)|((c.b()as u32)<<8)}
+#[inline(always)]
+fn encode_abgr(c:Rgb888)->u32 {((c.b()as u32)<<16)|((c.g()as u32)<<8)|(c.r()as u32)}
+#[inline(always)]
+fn encode_bgra(c:Rgb888)->u32 {((c.b()as u32)<<24)|((c.g()as u32)<<16)|((c.r()as u32)<<8)}
+#[inline(always)]
+fn decode_argb(raw:u32)->Rgb888 {Rgb888::new((raw>>16)as u8,(raw>>8)as u8,raw as u8)}
+#[inline(always)]
+fn decode_rgba(raw:u32)->Rgb888 {Rgb888::new((raw>>24)as u8,(raw>>16)as u8,(raw>>8)as u8)}
+#[inline(always)]
+fn decode_abgr(raw:u32)->Rgb888 {Rgb888::new(raw as u8,(raw>>8)as u8,(raw>>16)as u8)}
+#[inline(always)]
+fn decode_bgra(raw:u32)->Rgb888 {Rgb888::new((raw>>8)as u8,(raw>>16)as u8,(raw>>24)as u8)}
+
+
+/// The mouse pointer, `#` is the outline, `o` the inside
+const POINTER_SPRITE:[&str;16]=[
+    "#          ",
+    "##         ",
+    "#o#        ",
+    "#oo#       ",
+    "#ooo#      ",
+    "#oooo#     ",
+    "#ooooo#    ",
+    "#oooooo#   ",
+    "#ooooooo#  ",
+    "#oooooooo# ",
+    "#ooooo#####",
+    "#oo#oo#    ",
+    "#o# #oo#   ",
+    "##  #oo#   ",
+    "#    #oo#  ",
+    "     ####  ",
+];
+const POINTER_W:usize=11;
+const POINTER_H:usize=16;
+
+
+/// A pointer drawn over the framebuffer. It is never in the back buffer, so drawing doesn't have
+/// to know about it: what it covers is saved when it is drawn and put back before anything under
+/// it is flushed or it moves.
+struct Pointer {
+    pos:Option<Point>,
+    /// Where it is drawn right now, if it is
+    drawn_at:Option<Point>,
+    /// The framebuffer pixels under it, `POINTER_W` apart
+    saved:Vec<u32>,
+}
+
+
+/// A copy of the screen in normal RAM. The real framebuffer is usually uncached or write-combining,
+/// so reading it back (like scrolling does) is really slow. With a back buffer all drawing happens
+/// here and only the changed part of each row is copied out by [`Screen::flush`].
+struct BackBuffer {
+    pixels:Vec<u32>,
+    front:usize,
+    front_stride:usize,
+    /// Dirty columns `start..end` of each row. Clean rows have an empty span.
+    dirty:Vec<(u16,u16)>,
+}
+impl BackBuffer {
+    fn mark(&mut self,rows:Range<usize>,cols:Range<usize>) {
+        for span in self.dirty[rows].iter_mut() {
+            if span.0>=span.1 {
+                *span=(cols.start as u16,cols.end as u16);
+            } else {
+                span.0=span.0.min(cols.start as u16);
+                span.1=span.1.max(cols.end as u16);
+            }
+        }
+    }
+}
+
+
 pub struct Screen {
     pub w:usize,
     pub h:usize,
+    /// Where drawing goes, this is the back buffer if there is one
     fb:usize,
     s:usize,
+    format:PixelFormat,
+    encode:fn(Rgb888)->u32,
+    decode:fn(u32)->Rgb888,
+    back:Option<BackBuffer>,
+    pointer:Pointer,
 }
 impl Dimensions for Screen {
     fn bounding_box(&self)->Rectangle {
@@ -34,43 +178,399 @@ impl DrawTarget for Screen {
     type Error=core::convert::Infallible;
     fn draw_iter<I:IntoIterator<Item=Pixel<Self::Color>>>(&mut self,pixels:I)->Result<(),Self::Error> {
         for Pixel(EGPoint{x,y},color) in pixels {
-            self.draw_pixel(Point(x as usize,y as usize),color);
+            if x>=0&&y>=0&&(x as usize)<self.w&&(y as usize)<self.h {
+                self.draw_pixel(Point(x as usize,y as usize),color);
+            }
         }
         return Ok(());
     }
-    fn clear(&mut self,color:Rgb888)->Result<(),Self::Error> {
-        for x in 0..self.w {
-            for y in 0..self.h {
-                self.draw_pixel(Point(x,y),color);
+    fn fill_contiguous<I:IntoIterator<Item=Self::Color>>(&mut self,area:&Rectangle,colors:I)->Result<(),Self::Error> {
+        let clipped=area.intersection(&self.bounding_box());
+        if clipped.is_zero_sized() {return Ok(())}
+        if clipped==*area {   // fast path, every colour lands on the screen
+            let mut colors=colors.into_iter();
+            let encode=self.encode;
+            let x=area.top_left.x as usize;
+            for y in area.rows() {
+                let row=self.row_span(y as usize,x..x+area.size.width as usize);
+                for px in row.iter_mut() {
+                    match colors.next() {
+                        Some(color)=>*px=encode(color),
+                        None=>return Ok(()),
+                    }
+                }
             }
+            return Ok(());
         }
+        let points=area.points();
+        return self.draw_iter(points.zip(colors).map(|(p,c)|Pixel(p,c)));
+    }
+    fn fill_solid(&mut self,area:&Rectangle,color:Rgb888)->Result<(),Self::Error> {
+        self.fill_rect(area,color);
+        return Ok(());
+    }
+    fn clear(&mut self,color:Rgb888)->Result<(),Self::Error> {
+        self.fill_rect(&self.bounding_box(),color);
         return Ok(());
     }
 }
 impl Screen {
-    pub fn new(w:usize,h:usize,s:usize,fb:usize)->Screen {
-        Screen {w,h,s,fb}
+    pub fn new(w:usize,h:usize,s:usize,fb:usize,format:PixelFormat)->Screen {
+        Screen {
+            w,h,s,fb,
+            format,
+            encode:format.encoder(),
+            decode:format.decoder(),
+            back:None,
+            pointer:Pointer {
+                pos:None,
+                drawn_at:None,
+                saved:Vec::new(),
+            },
+        }
+    }
+    pub fn format(&self)->PixelFormat {
+        self.format
+    }
+    /// Moves drawing into a back buffer in RAM. The current contents of the screen are copied so
+    /// nothing changes until the next flush.
+    pub fn enable_back_buffer(&mut self) {
+        if self.back.is_some() {return}
+        let mut pixels=vec![0u32;self.w*self.h];
+        for y in 0..self.h {
+            pixels[y*self.w..(y+1)*self.w].copy_from_slice(self.row(y));
+        }
+        self.back=Some(BackBuffer {
+            front:self.fb,
+            front_stride:self.s,
+            dirty:vec![(0,0);self.h],
+            pixels,
+        });
+        self.fb=self.back.as_ref().unwrap().pixels.as_ptr() as usize;
+        self.s=self.w*4;
+    }
+    /// Flushes and goes back to drawing on the framebuffer directly. The pointer goes away with the
+    /// back buffer.
+    pub fn disable_back_buffer(&mut self) {
+        self.flush();
+        self.hide_pointer();
+        if let Some(back)=self.back.take() {
+            self.fb=back.front;
+            self.s=back.front_stride;
+        }
+    }
+    pub fn has_back_buffer(&self)->bool {
+        self.back.is_some()
+    }
+    /// Copies everything drawn since the last flush to the framebuffer. Does nothing without a back
+    /// buffer.
+    pub fn flush(&mut self) {
+        let w=self.w;
+        let covered=self.pointer_dirty();
+        if covered {self.hide_pointer()}
+        if let Some(back)=self.back.as_mut() {
+            for (y,span) in back.dirty.iter_mut().enumerate() {
+                let (start,end)=(span.0 as usize,span.1 as usize);
+                if start>=end {continue}
+                let src=&back.pixels[y*w+start..y*w+end];
+                let dst=(back.front+back.front_stride*y+start*4) as *mut u32;
+                unsafe{core::ptr::copy_nonoverlapping(src.as_ptr(),dst,end-start);}
+                *span=(0,0);
+            }
+        }
+        if covered {self.show_pointer()}
+    }
+    /// Moves the mouse pointer, or hides it with `None`. It is only drawn with a back buffer:
+    /// without one, drawing would go over it and leave stale pixels behind when it moves.
+    pub fn set_pointer(&mut self,pos:Option<Point>) {
+        let pos=pos.map(|p|Point(p.0.min(self.w.saturating_sub(1)),p.1.min(self.h.saturating_sub(1))));
+        if pos==self.pointer.pos {return}
+        self.hide_pointer();
+        self.pointer.pos=pos;
+        self.show_pointer();
+    }
+    pub fn pointer(&self)->Option<Point> {
+        self.pointer.pos
+    }
+    /// Where the framebuffer really is and its stride, past the back buffer
+    fn front(&self)->(usize,usize) {
+        match self.back.as_ref() {
+            Some(back)=>(back.front,back.front_stride),
+            None=>(self.fb,self.s),
+        }
+    }
+    /// The size of the pointer at `at`, cut off at the edges of the screen
+    fn pointer_size(&self,at:Point)->(usize,usize) {
+        (POINTER_W.min(self.w-at.0),POINTER_H.min(self.h-at.1))
+    }
+    /// Whether the next flush will draw over the pointer
+    fn pointer_dirty(&self)->bool {
+        let (at,back)=match (self.pointer.drawn_at,self.back.as_ref()) {
+            (Some(at),Some(back))=>(at,back),
+            _=>return false,
+        };
+        let (w,h)=self.pointer_size(at);
+        back.dirty[at.1..at.1+h].iter().any(|span|span.0<span.1&&(span.0 as usize)<at.0+w&&(span.1 as usize)>at.0)
+    }
+    fn hide_pointer(&mut self) {
+        let at=match self.pointer.drawn_at.take() {
+            Some(at)=>at,
+            None=>return,
+        };
+        let (front,stride)=self.front();
+        let (w,h)=self.pointer_size(at);
+        for y in 0..h {
+            let dst=(front+stride*(at.1+y)+at.0*4) as *mut u32;
+            unsafe{core::ptr::copy_nonoverlapping(self.pointer.saved[y*POINTER_W..].as_ptr(),dst,w);}
+        }
+    }
+    fn show_pointer(&mut self) {
+        let at=match self.pointer.pos {
+            Some(at) if self.back.is_some()=>at,
+            _=>return,
+        };
+        let (front,stride)=self.front();
+        let (w,h)=self.pointer_size(at);
+        let outline=(self.encode)(Rgb888::BLACK);
+        let inside=(self.encode)(Rgb888::WHITE);
+        self.pointer.saved.resize(POINTER_W*POINTER_H,0);
+        for (y,line) in POINTER_SPRITE.iter().enumerate().take(h) {
+            let row=(front+stride*(at.1+y)+at.0*4) as *mut u32;
+            for (x,c) in line.bytes().enumerate().take(w) {
+                unsafe {
+                    self.pointer.saved[y*POINTER_W+x]=row.add(x).read_volatile();
+                    match c {
+                        b'#'=>row.add(x).write_volatile(outline),
+                        b'o'=>row.add(x).write_volatile(inside),
+                        _=>{},
+                    }
+                }
+            }
+        }
+        self.pointer.drawn_at=Some(at);
+    }
+    /// The bounding box of everything waiting to be flushed
+    pub fn dirty_rect(&self)->Option<Rectangle> {
+        let back=self.back.as_ref()?;
+        let mut rect:Option<(usize,usize,usize,usize)>=None;
+        for (y,span) in back.dirty.iter().enumerate().filter(|(_,s)|s.0<s.1) {
+            let (x0,x1)=(span.0 as usize,span.1 as usize);
+            rect=Some(match rect {
+                Some((rx0,ry0,rx1,_))=>(rx0.min(x0),ry0,rx1.max(x1),y+1),
+                None=>(x0,y,x1,y+1),
+            });
+        }
+        rect.map(|(x0,y0,x1,y1)|Rectangle::new(EGPoint::new(x0 as i32,y0 as i32),Size::new((x1-x0) as u32,(y1-y0) as u32)))
+    }
+    fn mark_dirty(&mut self,rows:Range<usize>,cols:Range<usize>) {
+        if let Some(back)=self.back.as_mut() {
+            back.mark(rows,cols);
+        }
     }
-    pub fn draw_pixel(&self,p:Point,color:Rgb888) {
+    pub fn draw_pixel(&mut self,p:Point,color:Rgb888) {
         let pixel_loc=self.fb+(self.s*p.1)+(p.0*4); // 4 bytes/pixel
-        let color=((color.r()as u32)<<16)|((color.g()as u32)<<8)|(color.b()as u32);
-        unsafe{*(pixel_loc as *mut u32)=color;}
+        unsafe{*(pixel_loc as *mut u32)=(self.encode)(color);}
+        self.mark_dirty(p.1..p.1+1,p.0..p.0+1);
     }
     pub fn get_pixel(&self,p:Point)->Rgb888 {
         let pixel_loc=self.fb+(self.s*p.1)+(p.0*4); // 4 bytes/pixel
         let raw=unsafe{(pixel_loc as *mut u32).read()};
-        Rgb888::new(((raw>>16)&0xFF)as u8,((raw>>8)&0xFF)as u8,(raw&0xFF)as u8)
+        (self.decode)(raw)
     }
-    pub fn move_up(&self,amt:usize,clear_color:Rgb888) {
-        for y in amt..self.h {
-            for x in 0..self.w {
-                self.draw_pixel(Point(x,y-amt),self.get_pixel(Point(x,y)));
+    /// Encodes a colour once so it can be written with [`Screen::blit`] or straight into a row
+    pub fn encode(&self,color:Rgb888)->u32 {
+        (self.encode)(color)
+    }
+    fn row_ptr(&self,y:usize)->*mut u32 {
+        (self.fb+self.s*y) as *mut u32
+    }
+    /// One row of pixels. Panics if `y` is off the screen.
+    pub fn row(&mut self,y:usize)->&mut [u32] {
+        self.row_span(y,0..self.w)
+    }
+    /// Columns `cols.start..cols.end` of a row, which are assumed to be written to. Panics if any of
+    /// it is off the screen.
+    pub fn row_span(&mut self,y:usize,cols:Range<usize>)->&mut [u32] {
+        assert!(y<self.h&&cols.end<=self.w);
+        self.mark_dirty(y..y+1,cols.clone());
+        unsafe{core::slice::from_raw_parts_mut(self.row_ptr(y).add(cols.start),cols.len())}
+    }
+    /// Scrolls the whole screen up by `amt` pixels
+    pub fn move_up(&mut self,amt:usize,clear_color:Rgb888) {
+        self.scroll_up(0..self.h,amt,clear_color);
+    }
+    /// Scrolls rows `rows.start..rows.end` up by `amt` pixels, a row at a time with `memmove`, and
+    /// clears the rows that were uncovered.
+    pub fn scroll_up(&mut self,rows:Range<usize>,amt:usize,clear_color:Rgb888) {
+        let rows=rows.start.min(self.h)..rows.end.min(self.h);
+        let amt=amt.min(rows.len());
+        if amt<rows.len() {
+            if self.s==self.w*4 {  // no padding between rows, so it's all one big copy
+                unsafe{core::ptr::copy(self.row_ptr(rows.start+amt),self.row_ptr(rows.start),(rows.len()-amt)*self.w);}
+            } else {
+                for y in rows.start..rows.end-amt {
+                    unsafe{core::ptr::copy(self.row_ptr(y+amt),self.row_ptr(y),self.w);}
+                }
             }
         }
-        for y in self.h-amt..self.h {
-            for x in 0..self.w {
-                self.draw_pixel(Point(x,y),clear_color);
+        self.mark_dirty(rows.start..rows.end,0..self.w);
+        self.fill_rows(rows.end-amt..rows.end,clear_color);
+    }
+    /// Scrolls rows `rows.start..rows.end` down by `amt` pixels and clears the rows that were
+    /// uncovered.
+    pub fn scroll_down(&mut self,rows:Range<usize>,amt:usize,clear_color:Rgb888) {
+        let rows=rows.start.min(self.h)..rows.end.min(self.h);
+        let amt=amt.min(rows.len());
+        for y in (rows.start+amt..rows.end).rev() {
+            unsafe{core::ptr::copy(self.row_ptr(y-amt),self.row_ptr(y),self.w);}
+        }
+        self.mark_dirty(rows.start..rows.end,0..self.w);
+        self.fill_rows(rows.start..rows.start+amt,clear_color);
+    }
+    fn fill_rows(&mut self,rows:Range<usize>,color:Rgb888) {
+        let raw=(self.encode)(color);
+        for y in rows {
+            self.row(y).fill(raw);
+        }
+    }
+    /// Fills the part of `area` that is on the screen
+    pub fn fill_rect(&mut self,area:&Rectangle,color:Rgb888) {
+        let area=area.intersection(&self.bounding_box());
+        if area.is_zero_sized() {return}
+        let raw=(self.encode)(color);
+        let x=area.top_left.x as usize;
+        let w=area.size.width as usize;
+        for y in area.rows() {
+            self.row_span(y as usize,x..x+w).fill(raw);
+        }
+    }
+    /// Copies already encoded pixels onto the screen. `src` holds rows of `area.size.width` pixels
+    /// that are `src_stride` pixels apart. Anything off the screen is clipped.
+    pub fn blit(&mut self,area:&Rectangle,src:&[u32],src_stride:usize) {
+        let clipped=area.intersection(&self.bounding_box());
+        if clipped.is_zero_sized() {return}
+        let skip_x=(clipped.top_left.x-area.top_left.x) as usize;
+        let skip_y=(clipped.top_left.y-area.top_left.y) as usize;
+        let x=clipped.top_left.x as usize;
+        let w=clipped.size.width as usize;
+        for (i,y) in clipped.rows().enumerate() {
+            let start=(skip_y+i)*src_stride+skip_x;
+            self.row_span(y as usize,x..x+w).copy_from_slice(&src[start..start+w]);
+        }
+    }
+}
+
+
+/// Round-trips colours through every pixel format, both on their own and through `draw_pixel` and
+/// `get_pixel` on a screen in normal memory.
+pub fn test_pixel_formats()->Result<(),&'static str> {
+    let expected=[
+        (PixelFormat::Argb,0x00123456),
+        (PixelFormat::Rgba,0x12345600),
+        (PixelFormat::Abgr,0x00563412),
+        (PixelFormat::Bgra,0x56341200),
+    ];
+    for (format,raw) in expected.iter() {
+        if (format.encoder())(Rgb888::new(0x12,0x34,0x56))!=*raw {
+            return Err("a colour was encoded with the channels in the wrong place");
+        }
+    }
+    const W:usize=16;
+    let mut buffer=[0u32;W*W];
+    for format in PixelFormat::ALL.iter() {
+        let mut screen=Screen::new(W,W,W*4,buffer.as_mut_ptr() as usize,*format);
+        for v in 0..=255u8 {
+            let colors=[
+                Rgb888::new(v,0,0),
+                Rgb888::new(0,v,0),
+                Rgb888::new(0,0,v),
+                Rgb888::new(v,v.wrapping_mul(7),!v),
+            ];
+            for (i,color) in colors.iter().enumerate() {
+                if (format.decoder())((format.encoder())(*color))!=*color {
+                    return Err("a colour didn't survive encoding and decoding");
+                }
+                let p=Point(v as usize%W,(v as usize/W+i)%W);
+                screen.draw_pixel(p,*color);
+                if screen.get_pixel(p)!=*color {
+                    return Err("a colour didn't survive `draw_pixel` and `get_pixel`");
+                }
             }
         }
     }
+    return Ok(());
+}
+/// Scrolling, filling and blitting on a screen in normal memory with padding at the end of each
+/// row, which must never be touched.
+pub fn test_scroll_and_blit()->Result<(),&'static str> {
+    const W:usize=8;
+    const H:usize=6;
+    const STRIDE:usize=W+2;
+    const PAD:u32=0xDEADBEEF;
+    let mut buffer=[PAD;STRIDE*H];
+    let mut screen=Screen::new(W,H,STRIDE*4,buffer.as_mut_ptr() as usize,PixelFormat::Argb);
+    let color=|y:usize|Rgb888::new(y as u8,0,0);
+    for y in 0..H {
+        screen.fill_rect(&Rectangle::new(EGPoint::new(0,y as i32),Size::new(W as u32,1)),color(y));
+    }
+    screen.move_up(2,Rgb888::new(0,0,255));
+    for y in 0..H {
+        let expected=if y<H-2 {color(y+2)} else {Rgb888::new(0,0,255)};
+        if screen.get_pixel(Point(W-1,y))!=expected {
+            return Err("`move_up` left the wrong rows behind");
+        }
+    }
+    screen.scroll_down(1..H,1,Rgb888::new(0,255,0));
+    if screen.get_pixel(Point(0,1))!=Rgb888::new(0,255,0)||screen.get_pixel(Point(0,2))!=color(3) {
+        return Err("`scroll_down` left the wrong rows behind");
+    }
+    let src=[1,2,3,4];
+    screen.blit(&Rectangle::new(EGPoint::new(W as i32-1,H as i32-1),Size::new(2,2)),&src,2);
+    if screen.get_pixel(Point(W-1,H-1))!=(screen.decode)(1) {
+        return Err("`blit` put the wrong pixel in the corner");
+    }
+    for y in 0..H {
+        if buffer[y*STRIDE+W..(y+1)*STRIDE].iter().any(|p|*p!=PAD) {
+            return Err("the padding after a row was overwritten");
+        }
+    }
+    return Ok(());
+}
+/// Draws through a back buffer and checks only the dirty part reaches the "framebuffer" on flush.
+pub fn test_back_buffer()->Result<(),&'static str> {
+    const W:usize=8;
+    const H:usize=4;
+    let mut front=[0u32;W*H];
+    let mut screen=Screen::new(W,H,W*4,front.as_mut_ptr() as usize,PixelFormat::Argb);
+    screen.enable_back_buffer();
+    screen.draw_pixel(Point(2,1),Rgb888::new(0,0,1));
+    screen.fill_rect(&Rectangle::new(EGPoint::new(5,3),Size::new(2,1)),Rgb888::new(0,0,2));
+    if front.iter().any(|p|*p!=0) {
+        return Err("drawing reached the framebuffer before a flush");
+    }
+    if screen.dirty_rect()!=Some(Rectangle::new(EGPoint::new(2,1),Size::new(5,3))) {
+        return Err("the dirty rectangle is wrong");
+    }
+    screen.flush();
+    if screen.dirty_rect().is_some() {
+        return Err("flushing didn't clear the dirty rows");
+    }
+    let expected=|x:usize,y:usize|match (x,y) {
+        (2,1)=>1,
+        (5,3)|(6,3)=>2,
+        _=>0,
+    };
+    for y in 0..H {
+        for x in 0..W {
+            if front[y*W+x]!=expected(x,y) {
+                return Err("the framebuffer doesn't match what was drawn");
+            }
+        }
+    }
+    screen.move_up(1,Rgb888::new(0,0,3));
+    screen.disable_back_buffer();
+    if front[0]!=0||front[W*2+2]!=0||front[W*2+5]!=2||front[W*3]!=3 {
+        return Err("scrolling in the back buffer didn't reach the framebuffer");
+    }
+    return Ok(());
 }
@@ -0,0 +1,86 @@
+//! Tests that have to run on real (or emulated) hardware. There is no test harness for the kernel,
+//! so these are picked with the `selftest` option and run at boot:
+//! ```text
+//! selftest=all
+//! selftest=screen.pixel_format
+//! ```
+
+
+use crate::{
+    config::{
+        self,
+        Opt,
+        Kind,
+    },
+    screen,
+    console,
+    log,
+    input,
+    shell,
+    usb,
+    time,
+    memory,
+    user,
+    info,
+    error,
+};
+
+
+pub static SELFTEST:Opt=Opt::new("selftest",Kind::List,"","Self tests to run at boot, or `all`");
+
+
+/// Every self test. Names are `<module>.<test>`.
+pub static TESTS:&[(&str,fn()->Result<(),&'static str>)]=&[
+    ("config.parse",config::test_parse),
+    ("log.ring",log::test_ring),
+    ("screen.pixel_format",screen::test_pixel_formats),
+    ("screen.scroll_and_blit",screen::test_scroll_and_blit),
+    ("screen.back_buffer",screen::test_back_buffer),
+    ("console.ansi",console::ansi::test_parser),
+    ("console.layout",console::test_layout),
+    ("console.scrollback",console::test_scrollback),
+    ("console.psf",console::font::test_psf),
+    ("shell.editor",shell::test_editor),
+    ("shell.prompt",shell::test_prompt),
+    ("input.scancodes",input::scancode::test_decoder),
+    ("input.keymaps",input::keymap::test_keymaps),
+    ("input.ps2",input::ps2::test_encoding),
+    ("input.mouse",input::mouse::test_packets),
+    ("usb.descriptors",usb::test_descriptors),
+    ("usb.hid",usb::hid::test_reports),
+    ("time.pit",time::test_pit),
+    ("time.clock",time::test_clock),
+    ("time.hpet",time::test_hpet),
+    ("time.date",time::test_date),
+    ("time.timers",time::test_timers),
+    ("memory.address_space",memory::address_space::test_address_space),
+    ("user.ring3",user::test_ring3),
+    ("user.syscalls",user::syscall::test_syscalls),
+    ("user.elf",user::elf::test_elf),
+];
+
+
+/// Runs the selected tests and returns how many failed
+pub fn run()->usize {
+    config::register(&[&SELFTEST]);
+    let selected=SELFTEST.get_list();
+    let all=selected.iter().any(|s|s=="all");
+    for name in selected.iter().filter(|s|*s!="all") {
+        if !TESTS.iter().any(|(test,_)|test==name||test.split('.').next()==Some(name)) {
+            error!("No self test called `{}`",name);
+        }
+    }
+    let mut failed=0;
+    for (name,test) in TESTS.iter() {
+        let module=name.split('.').next().unwrap();
+        if !all&&!selected.iter().any(|s|s==name||s==module) {continue}
+        match test() {
+            Ok(())=>info!("{} ... ok",name),
+            Err(e)=>{
+                error!("{} ... FAILED: {}",name,e);
+                failed+=1;
+            },
+        }
+    }
+    return failed;
+}
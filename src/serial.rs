@@ -0,0 +1,110 @@
+//! A tiny polling driver for 16550 compatible UARTs. This is mostly used as a log sink since it
+//! keeps working when the screen doesn't (and QEMU can dump it straight to a file).
+
+
+use x86_64::instructions::port::Port;
+use spin::Mutex;
+use core::fmt::{
+    self,
+    Write,
+};
+use crate::config::{
+    self,
+    Opt,
+    Kind,
+};
+
+
+pub const UART_CLOCK:u32=115200;
+
+
+pub static PORT:Opt=Opt::new("serial.port",Kind::Int,"0x3f8","I/O port of the serial port used for logging");
+pub static BAUD:Opt=Opt::new("serial.baud",Kind::Int,"38400","Baud rate of the serial port");
+
+
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => ($crate::serial::_print(format_args!($($arg)*)));
+}
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($($arg:tt)*) => ($crate::serial_print!("{}\n", format_args!($($arg)*)));
+}
+
+
+lazy_static::lazy_static! {
+    pub static ref SERIAL1:Mutex<SerialPort>={
+        config::register(&[&PORT,&BAUD]);
+        let mut port=SerialPort::new(PORT.get_int() as u16);
+        port.init(BAUD.get_int() as u32);
+        Mutex::new(port)
+    };
+}
+
+
+#[doc(hidden)]
+pub fn _print(args:fmt::Arguments) {
+    x86_64::instructions::interrupts::without_interrupts(||{
+        SERIAL1.lock().write_fmt(args).unwrap();
+    });
+}
+
+
+pub struct SerialPort {
+    base:u16,
+    present:bool,
+}
+impl Write for SerialPort {
+    fn write_str(&mut self,string:&str)->fmt::Result {
+        for b in string.bytes() {
+            if b==b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(b);
+        }
+        return Ok(());
+    }
+}
+impl SerialPort {
+    /// Line status register bit that says the transmit holding register is empty
+    const LSR_THRE:u8=1<<5;
+    pub const fn new(base:u16)->SerialPort {
+        SerialPort {
+            base,
+            present:false,
+        }
+    }
+    fn port(&self,offset:u16)->Port<u8> {
+        Port::new(self.base+offset)
+    }
+    /// Sets the port to 8N1 with FIFOs enabled and checks the chip is actually there with the
+    /// loopback test. If it isn't, all writes are dropped.
+    pub fn init(&mut self,baud:u32) {
+        let divisor=(UART_CLOCK/baud.max(1)).max(1) as u16;
+        unsafe {
+            self.port(1).write(0x00);   // no interrupts, we poll
+            self.port(3).write(0x80);   // DLAB on
+            self.port(0).write(divisor as u8);
+            self.port(1).write((divisor>>8) as u8);
+            self.port(3).write(0x03);   // DLAB off, 8 bits, no parity, one stop bit
+            self.port(2).write(0xC7);   // enable and clear FIFOs, 14 byte threshold
+            self.port(4).write(0x1E);   // loopback mode for the self test
+            self.port(0).write(0xAE);
+            self.present=self.port(0).read()==0xAE;
+            self.port(4).write(0x0F);   // back to normal operation
+        }
+    }
+    pub fn is_present(&self)->bool {
+        self.present
+    }
+    pub fn write_byte(&mut self,b:u8) {
+        if !self.present {return}
+        unsafe {
+            while self.port(5).read()&Self::LSR_THRE==0 {
+                core::hint::spin_loop();
+            }
+            self.port(0).write(b);
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! The commands the shell comes with
+
+
+use alloc::{
+    string::String,
+    vec::Vec,
+    format,
+};
+use raw_cpuid::{
+    CpuId,
+    TopologyType,
+};
+use crate::{
+    bootboot::{
+        BootBootUnpacked,
+        BOOTBOOT_INFO,
+        BOOTBOOT,
+    },
+    memory::{
+        allocator,
+        frame::print_mmap,
+    },
+    interrupts::{
+        self,
+        IRQ_NAMES,
+    },
+    log::{
+        self,
+        LevelFilter,
+    },
+    console,
+    initrd,
+    time,
+    power,
+    print,
+    println,
+};
+use super::{
+    Command,
+    register,
+    for_each_command,
+};
+
+
+static HELP:Command=Command::new("help","","List the commands",help);
+static CLEAR:Command=Command::new("clear","","Clear the screen",clear);
+static MEM:Command=Command::new("mem","","Heap statistics and the memory map",mem);
+static CPUS:Command=Command::new("cpus","","Processor and topology information",cpus);
+static IRQ:Command=Command::new("irq","","How many times each interrupt line has fired",irq);
+static LS:Command=Command::new("ls","[dir]","List the files in the initrd",ls);
+static CAT:Command=Command::new("cat","<file>...","Print files from the initrd",cat);
+static LOG:Command=Command::new("log","[count] | level [module] <level>","Show recent log messages or change log levels",log_cmd);
+static FONT:Command=Command::new("font","[file]","Switch to a PSF font from the initrd, or the built in one",font);
+static REBOOT:Command=Command::new("reboot","","Restart the machine",reboot);
+static SHUTDOWN:Command=Command::new("shutdown","","Turn the machine off",shutdown);
+
+
+pub fn init() {
+    register(&[&HELP,&CLEAR,&MEM,&CPUS,&IRQ,&LS,&CAT,&LOG,&FONT,&REBOOT,&SHUTDOWN]);
+}
+
+
+fn help(_args:&[&str])->Result<(),&'static str> {
+    for_each_command(|command|{
+        let usage=format!("{} {}",command.name,command.usage);
+        println!("  {:<36} {}",usage,command.help);
+    });
+    return Ok(());
+}
+fn clear(_args:&[&str])->Result<(),&'static str> {
+    print!("\x1b[2J\x1b[H");
+    return Ok(());
+}
+fn mem(_args:&[&str])->Result<(),&'static str> {
+    let stats=allocator::stats();
+    println!("Heap: {} bytes in {} pages, {} allocations, {} frees",stats.bytes_in_use,stats.pages_in_use,stats.allocations,stats.deallocations);
+    let bootboot:BootBootUnpacked=unsafe{*(BOOTBOOT_INFO as *const BOOTBOOT)}.into();
+    print_mmap(&bootboot);
+    return Ok(());
+}
+fn cpus(_args:&[&str])->Result<(),&'static str> {
+    let cpuid=CpuId::new();
+    if let Some(vendor)=cpuid.get_vendor_info() {
+        println!("Vendor: {}",vendor.as_str());
+    }
+    if let Some(brand)=cpuid.get_processor_brand_string() {
+        println!("Model: {}",brand.as_str().trim());
+    }
+    if let Some(info)=cpuid.get_feature_info() {
+        println!("Family {} model {} stepping {}",info.family_id(),info.model_id(),info.stepping_id());
+    }
+    let bootboot:BootBootUnpacked=unsafe{*(BOOTBOOT_INFO as *const BOOTBOOT)}.into();
+    println!("Cores reported by BOOTBOOT: {}, bootstrap core {}",bootboot.numcores,bootboot.bspid);
+    if let Some(levels)=cpuid.get_extended_topology_info() {
+        for level in levels {
+            let kind=match level.level_type() {
+                TopologyType::SMT=>"SMT",
+                TopologyType::Core=>"core",
+                _=>"other",
+            };
+            println!("Topology level {}: {} logical processors at the {} level",level.level_number(),level.processors(),kind);
+        }
+    }
+    return Ok(());
+}
+fn irq(_args:&[&str])->Result<(),&'static str> {
+    for (irq,name) in IRQ_NAMES.iter().enumerate() {
+        let count=interrupts::irq_count(irq as u8);
+        if count>0 {
+            println!("  {:>2} {:<10} {}",irq,name,count);
+        }
+    }
+    println!("Timer ticks: {}",time::ticks());
+    return Ok(());
+}
+fn ls(args:&[&str])->Result<(),&'static str> {
+    let dir=args.first().map(|d|d.trim_start_matches("./").trim_matches('/')).unwrap_or("");
+    let mut found=false;
+    for entry in initrd::files() {
+        let name=entry.name().trim_start_matches("./");
+        let in_dir=dir.is_empty()||name.strip_prefix(dir).map(|rest|rest.starts_with('/')).unwrap_or(false);
+        if in_dir&&name!="." {
+            println!("  {:>8} {}",entry.file().len(),name);
+            found=true;
+        }
+    }
+    if !found&&!dir.is_empty() {
+        return Err("no such directory");
+    }
+    return Ok(());
+}
+fn cat(args:&[&str])->Result<(),&'static str> {
+    if args.is_empty() {
+        return Err("which file?");
+    }
+    for path in args {
+        let data=initrd::find(path).ok_or("no such file")?;
+        let text=core::str::from_utf8(data).map_err(|_|"not a text file")?;
+        print!("{}",text);
+        if !text.ends_with('\n')&&!text.is_empty() {
+            println!();
+        }
+    }
+    return Ok(());
+}
+fn log_cmd(args:&[&str])->Result<(),&'static str> {
+    match args {
+        ["level"]=>println!("{:?}",log::max_level()),
+        ["level",level]=>log::set_max_level(LevelFilter::parse(level).ok_or("unknown level")?),
+        ["level",module,level]=>{
+            let level=LevelFilter::parse(level).ok_or("unknown level")?;
+            log::set_module_level(module,level).map_err(|_|"too many module levels")?;
+        },
+        [count,..] if count.parse::<usize>().is_err()=>return Err("usage: log [count] | log level [module] <level>"),
+        _=>{
+            let count=args.first().map(|c|c.parse().unwrap()).unwrap_or(20);
+            // copied out first, printing while the log is locked would stop anything else logging
+            let mut lines:Vec<String>=Vec::new();
+            log::for_each_record(|record|lines.push(format!("{}",record)));
+            for line in lines.iter().skip(lines.len().saturating_sub(count)) {
+                println!("{}",line);
+            }
+        },
+    }
+    return Ok(());
+}
+fn font(args:&[&str])->Result<(),&'static str> {
+    console::set_font(args.first().copied().unwrap_or("")).map_err(|e|match e {
+        console::font::FontError::NotFound=>"no such file",
+        _=>"not a PSF font",
+    })
+}
+fn reboot(_args:&[&str])->Result<(),&'static str> {
+    power::reboot();
+}
+fn shutdown(_args:&[&str])->Result<(),&'static str> {
+    power::shutdown();
+    return Err("couldn't turn the machine off");
+}
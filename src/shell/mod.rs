@@ -0,0 +1,434 @@
+//! The kernel shell. It runs as a task on VT1 and reads lines with a small line editor (history
+//! with Up/Down, Left/Right/Home/End, Tab completion), then runs the command named by the first
+//! word. Drivers can add their own commands with [`register`]:
+//! ```ignore
+//! static LSPCI:Command=Command::new("lspci","","List PCI devices",lspci);
+//! shell::register(&[&LSPCI]);
+//! ```
+
+
+use alloc::{
+    string::String,
+    vec::Vec,
+    collections::VecDeque,
+};
+use spin::Mutex;
+use crate::{
+    config::{
+        self,
+        Opt,
+        Kind,
+    },
+    console::{
+        self,
+        vt::KERNEL_VT,
+        unicode::char_width,
+    },
+    initrd,
+    print,
+    println,
+    warn,
+};
+
+
+pub mod commands;
+
+
+pub static SHELL:Opt=Opt::new("shell",Kind::Bool,"true","Start the kernel shell on VT1");
+pub static PROMPT:Opt=Opt::new("shell.prompt",Kind::Str,"\"kernel> \"","What the shell prints before each line");
+pub static HISTORY:Opt=Opt::new("shell.history",Kind::Int,"100","Lines of shell history to keep");
+
+
+/// Every command the shell knows about, in the order they were registered
+static COMMANDS:Mutex<Vec<&'static Command>>=Mutex::new(Vec::new());
+
+
+pub type CommandFn=fn(args:&[&str])->Result<(),&'static str>;
+
+
+/// A shell command. `run` gets the words after the command's name and prints whatever it wants;
+/// an `Err` is shown as `<name>: <error>`.
+pub struct Command {
+    pub name:&'static str,
+    /// Arguments, like `<file>...`
+    pub usage:&'static str,
+    pub help:&'static str,
+    pub run:CommandFn,
+}
+impl Command {
+    pub const fn new(name:&'static str,usage:&'static str,help:&'static str,run:CommandFn)->Command {
+        Command {
+            name,
+            usage,
+            help,
+            run,
+        }
+    }
+}
+
+
+/// Adds commands to the shell. A command with the same name as an existing one replaces it.
+pub fn register(commands:&[&'static Command]) {
+    let mut replaced=Vec::new();
+    x86_64::instructions::interrupts::without_interrupts(||{
+        let mut registered=COMMANDS.lock();
+        for command in commands.iter() {
+            match registered.iter_mut().find(|c|c.name==command.name) {
+                Some(old)=>{
+                    *old=command;
+                    replaced.push(command.name);
+                },
+                None=>registered.push(command),
+            }
+        }
+    });
+    for name in replaced {
+        warn!("Shell command `{}` was registered twice",name);
+    }
+}
+pub fn find(name:&str)->Option<&'static Command> {
+    x86_64::instructions::interrupts::without_interrupts(||{
+        COMMANDS.lock().iter().find(|c|c.name==name).copied()
+    })
+}
+pub fn for_each_command<F:FnMut(&'static Command)>(mut f:F) {
+    let commands=x86_64::instructions::interrupts::without_interrupts(||COMMANDS.lock().clone());
+    for command in commands {
+        f(command);
+    }
+}
+
+
+/// Runs one line of input
+pub fn execute(line:&str) {
+    let args:Vec<&str>=line.split_whitespace().collect();
+    let (name,args)=match args.split_first() {
+        Some(split)=>split,
+        None=>return,
+    };
+    match find(name) {
+        Some(command)=>if let Err(e)=(command.run)(args) {
+            println!("{}: {}",name,e);
+        },
+        None=>println!("Unknown command `{}`, try `help`",name),
+    }
+}
+
+
+/// Starts the shell task, unless `shell=false`
+pub fn init() {
+    config::register(&[&SHELL,&PROMPT,&HISTORY]);
+    commands::init();
+    if SHELL.get_bool() {
+        crate::task::spawn("shell",run());
+    }
+}
+/// The shell task
+pub async fn run() {
+    console::set_echo(KERNEL_VT,false);
+    let mut editor=LineEditor::new(PROMPT.get_str(),HISTORY.get_int().max(0) as usize);
+    println!("Kernel shell, type `help` for a list of commands");
+    loop {
+        let line=editor.read_line().await;
+        execute(&line);
+    }
+}
+
+
+/// Where we are in an escape sequence typed by the user
+#[derive(Copy,Clone,PartialEq,Eq)]
+enum Escape {
+    None,
+    Esc,
+    /// `ESC [`, with the number so far
+    Csi(u16),
+}
+
+
+/// Reads a line from VT1, redrawing it as it is edited
+pub struct LineEditor {
+    prompt:String,
+    line:Vec<char>,
+    /// Cursor position in `line`
+    pos:usize,
+    history:VecDeque<String>,
+    history_limit:usize,
+    /// Which history line is being shown, 0 being the newest
+    history_pos:Option<usize>,
+    /// The line being typed before going into the history
+    unsent:Vec<char>,
+    escape:Escape,
+    /// Rows between the prompt and the cursor, to find the start of the line when redrawing
+    cursor_row:usize,
+    /// Whether edits are drawn. Only off for the self test.
+    echo:bool,
+}
+#[allow(dead_code)]
+impl LineEditor {
+    pub fn new(prompt:String,history_limit:usize)->LineEditor {
+        LineEditor {
+            prompt,
+            line:Vec::new(),
+            pos:0,
+            history:VecDeque::new(),
+            history_limit,
+            history_pos:None,
+            unsent:Vec::new(),
+            escape:Escape::None,
+            cursor_row:0,
+            echo:true,
+        }
+    }
+    pub fn set_prompt(&mut self,prompt:String) {
+        self.prompt=prompt;
+    }
+    pub async fn read_line(&mut self)->String {
+        self.line.clear();
+        self.pos=0;
+        self.history_pos=None;
+        self.escape=Escape::None;
+        self.cursor_row=0;
+        print!("{}",self.prompt);
+        loop {
+            let c=console::read_char(KERNEL_VT).await;
+            if self.key(c) {
+                break;
+            }
+        }
+        self.pos=self.line.len();
+        self.redraw();
+        println!();
+        let line:String=self.line.iter().collect();
+        self.remember(&line);
+        return line;
+    }
+    /// Handles one typed character. Returns `true` when the line is done.
+    fn key(&mut self,c:char)->bool {
+        match (self.escape,c) {
+            (Escape::None,'\x1b')=>self.escape=Escape::Esc,
+            (Escape::Esc,'[')=>self.escape=Escape::Csi(0),
+            (Escape::Esc,c)=>{  // just Escape, then some other key
+                self.escape=Escape::None;
+                return self.key(c);
+            },
+            (Escape::Csi(n),'0'..='9')=>self.escape=Escape::Csi(n.saturating_mul(10).saturating_add(c as u16-'0' as u16)),
+            (Escape::Csi(n),c)=>{
+                self.escape=Escape::None;
+                self.csi(n,c);
+            },
+            (_,'\n'|'\r')=>return true,
+            (_,'\x08')=>if self.pos>0 {
+                self.pos-=1;
+                self.line.remove(self.pos);
+                self.redraw();
+            },
+            (_,'\x7f')=>self.delete(),
+            (_,'\t')=>self.complete(),
+            (_,c) if c>=' '=>{
+                self.line.insert(self.pos,c);
+                self.pos+=1;
+                self.redraw();
+            },
+            _=>{},
+        }
+        return false;
+    }
+    fn csi(&mut self,n:u16,final_char:char) {
+        match (n,final_char) {
+            (_,'A')=>self.history_move(1),
+            (_,'B')=>self.history_move(-1),
+            (_,'C') if self.pos<self.line.len()=>self.pos+=1,
+            (_,'D') if self.pos>0=>self.pos-=1,
+            (_,'H')=>self.pos=0,
+            (_,'F')=>self.pos=self.line.len(),
+            (3,'~')=>self.delete(),
+            _=>return,
+        }
+        self.redraw();
+    }
+    fn delete(&mut self) {
+        if self.pos<self.line.len() {
+            self.line.remove(self.pos);
+            self.redraw();
+        }
+    }
+    /// Goes `by` lines back into the history, or forwards for negative `by`
+    fn history_move(&mut self,by:isize) {
+        let current=self.history_pos.map(|p|p as isize).unwrap_or(-1);
+        let next=(current+by).min(self.history.len() as isize-1).max(-1);
+        if next==current {return}
+        if self.history_pos.is_none() {
+            self.unsent=self.line.clone();
+        }
+        if next<0 {
+            self.history_pos=None;
+            self.line=core::mem::take(&mut self.unsent);
+        } else {
+            self.history_pos=Some(next as usize);
+            let idx=self.history.len()-1-next as usize;
+            self.line=self.history[idx].chars().collect();
+        }
+        self.pos=self.line.len();
+    }
+    fn remember(&mut self,line:&str) {
+        if line.trim().is_empty()||self.history_limit==0 {return}
+        if self.history.back().map(|l|l==line).unwrap_or(false) {return}
+        if self.history.len()>=self.history_limit {
+            self.history.pop_front();
+        }
+        self.history.push_back(line.into());
+    }
+    /// Completes the word before the cursor: command names for the first word, initrd paths for
+    /// the rest. With several choices it fills in what they share, or lists them.
+    fn complete(&mut self) {
+        let start=self.line[..self.pos].iter().rposition(|c|*c==' ').map(|i|i+1).unwrap_or(0);
+        let word:String=self.line[start..self.pos].iter().collect();
+        let first_word=self.line[..start].iter().all(|c|*c==' ');
+        let mut choices:Vec<String>=Vec::new();
+        if first_word {
+            for_each_command(|c|if c.name.starts_with(&word[..]) {
+                choices.push(c.name.into());
+            });
+        } else {
+            for entry in initrd::files() {
+                let name=entry.name().trim_start_matches("./");
+                if name.starts_with(&word[..]) {
+                    choices.push(name.into());
+                }
+            }
+        }
+        self.complete_with(&word,&choices);
+    }
+    /// Completes `word`, which is right before the cursor, from `choices`, which all start with it
+    fn complete_with(&mut self,word:&str,choices:&[String]) {
+        let mut common=match choices.first() {
+            Some(first)=>first.clone(),
+            None=>return,
+        };
+        for choice in choices.iter() {
+            let len=common.chars().zip(choice.chars()).take_while(|(a,b)|a==b).map(|(a,_)|a.len_utf8()).sum();
+            common.truncate(len);
+        }
+        let mut insert:Vec<char>=common[word.len()..].chars().collect();
+        if choices.len()==1 {
+            insert.push(' ');
+        } else if insert.is_empty() {
+            if !self.echo {return}
+            println!();
+            for choice in choices.iter() {
+                print!("{}  ",choice);
+            }
+            println!();
+            print!("{}",self.prompt);
+            self.redraw_from_prompt();
+            return;
+        }
+        let count=insert.len();
+        self.line.splice(self.pos..self.pos,insert);
+        self.pos+=count;
+        self.redraw();
+    }
+    /// Display columns of some characters
+    fn width(chars:&[char])->usize {
+        chars.iter().map(|c|char_width(*c)).sum()
+    }
+    /// Goes back to the prompt and draws the line again, putting the cursor at `pos`
+    fn redraw(&mut self) {
+        if !self.echo {return}
+        if self.cursor_row>0 {
+            print!("\x1b[{}A",self.cursor_row);
+        }
+        print!("\r{}",self.prompt);
+        self.redraw_from_prompt();
+    }
+    /// Draws the line after a freshly printed prompt
+    fn redraw_from_prompt(&mut self) {
+        let cols=console::size(KERNEL_VT).0.max(1);
+        let prompt=self.prompt.chars().map(char_width).sum::<usize>();
+        let line:String=self.line.iter().collect();
+        print!("{}\x1b[J",line);
+        // the cursor is at the end of the line, possibly waiting to wrap in the last column
+        let end=prompt+Self::width(&self.line);
+        let end_row=if end>0&&end%cols==0 {end/cols-1} else {end/cols};
+        let target=prompt+Self::width(&self.line[..self.pos]);
+        if self.pos==self.line.len() {
+            self.cursor_row=end_row;
+            return;
+        }
+        let (row,col)=(target/cols,target%cols);
+        if end_row>row {
+            print!("\x1b[{}A",end_row-row);
+        }
+        print!("\r");
+        if col>0 {
+            print!("\x1b[{}C",col);
+        }
+        self.cursor_row=row;
+    }
+}
+
+
+/// Editing keys and escape sequences, the history and completion, without drawing anything
+pub fn test_editor()->Result<(),&'static str> {
+    let mut editor=LineEditor::new(String::new(),2);
+    editor.echo=false;
+    let feed=|editor:&mut LineEditor,keys:&str|keys.chars().map(|c|editor.key(c)).last().unwrap_or(false);
+    let state=|editor:&LineEditor|(editor.line.iter().collect::<String>(),editor.pos);
+    feed(&mut editor,"hello\x1b[D\x1b[DX");
+    if state(&editor)!=("helXlo".into(),4) {
+        return Err("left or typing in the middle went wrong");
+    }
+    feed(&mut editor,"\x1b[H\x1b[3~\x1b[F\x08");
+    if state(&editor)!=("elXl".into(),4) {
+        return Err("home, delete, end or backspace went wrong");
+    }
+    // a lone Escape doesn't eat the key after it, and a long count doesn't overflow
+    feed(&mut editor,"\x1bY\x1b[99999999C");
+    if state(&editor)!=("elXlY".into(),5) {
+        return Err("escape sequences were read wrong");
+    }
+    if !feed(&mut editor,"\r") {
+        return Err("enter didn't end the line");
+    }
+    for line in ["one","two","two","  ","three"] {
+        editor.remember(line);
+    }
+    if editor.history!=["two","three"] {
+        return Err("the history kept the wrong lines");
+    }
+    editor.line=alloc::vec!['n','e','w'];
+    editor.pos=3;
+    feed(&mut editor,"\x1b[A\x1b[A\x1b[A");
+    if state(&editor)!=("two".into(),3) {
+        return Err("up went through the history wrong");
+    }
+    feed(&mut editor,"\x1b[B\x1b[B");
+    if state(&editor)!=("new".into(),3)||editor.history_pos.is_some() {
+        return Err("down didn't come back to the unsent line");
+    }
+    editor.line="cat fo".chars().collect();
+    editor.pos=6;
+    editor.complete_with("fo",&["foo.txt".into(),"foobar".into(),"foo.bin".into()]);
+    if state(&editor)!=("cat foo".into(),7) {
+        return Err("completion didn't fill in the common prefix");
+    }
+    editor.complete_with("foo",&["foo.txt".into(),"foobar".into()]);
+    if state(&editor)!=("cat foo".into(),7) {
+        return Err("completion with nothing in common changed the line");
+    }
+    editor.complete_with("foo",&["foobar".into()]);
+    if state(&editor)!=("cat foobar ".into(),11) {
+        return Err("completing the only choice went wrong");
+    }
+    return Ok(());
+}
+/// The prompt keeps its trailing space, which an unquoted string would lose
+pub fn test_prompt()->Result<(),&'static str> {
+    if config::parse_str(PROMPT.default).as_deref()!=Ok("kernel> ") {
+        return Err("the default prompt lost its space");
+    }
+    if config::raw(PROMPT.name).is_none()&&PROMPT.get_str()!="kernel> " {
+        return Err("the prompt isn't the default");
+    }
+    return Ok(());
+}
@@ -0,0 +1,104 @@
+//! Runs the tasks. Each task gets a waker that puts its ID back on the ready queue, and when
+//! nothing is ready the core halts until the next interrupt.
+
+
+use alloc::{
+    collections::BTreeMap,
+    sync::Arc,
+    task::Wake,
+};
+use core::task::{
+    Context,
+    Poll,
+    Waker,
+};
+use x86_64::instructions::interrupts;
+use crate::trace;
+use super::{
+    Task,
+    TaskId,
+    SPAWNED,
+    READY,
+    wake,
+};
+
+
+struct TaskWaker(TaskId);
+impl Wake for TaskWaker {
+    fn wake(self:Arc<Self>) {
+        wake(self.0);
+    }
+    fn wake_by_ref(self:&Arc<Self>) {
+        wake(self.0);
+    }
+}
+
+
+pub struct Executor {
+    tasks:BTreeMap<TaskId,Task>,
+    wakers:BTreeMap<TaskId,Waker>,
+}
+#[allow(dead_code)]
+impl Executor {
+    pub fn new()->Executor {
+        Executor {
+            tasks:BTreeMap::new(),
+            wakers:BTreeMap::new(),
+        }
+    }
+    /// How many tasks haven't finished
+    pub fn len(&self)->usize {
+        self.tasks.len()
+    }
+    /// Moves newly spawned tasks in and marks them ready
+    fn take_spawned(&mut self) {
+        let spawned=interrupts::without_interrupts(||core::mem::take(&mut *SPAWNED.lock()));
+        for task in spawned {
+            let id=task.id;
+            trace!("Starting task {} `{}`",id.0,task.name);
+            self.tasks.insert(id,task);
+            wake(id);
+        }
+    }
+    /// Polls every ready task once. Returns `false` if there was nothing to do.
+    pub fn run_ready(&mut self)->bool {
+        self.take_spawned();
+        let mut ran=false;
+        while let Some(id)=interrupts::without_interrupts(||READY.lock().pop_front()) {
+            ran=true;
+            let task=match self.tasks.get_mut(&id) {
+                Some(task)=>task,
+                None=>continue,     // finished already
+            };
+            let waker=self.wakers.entry(id).or_insert_with(||Waker::from(Arc::new(TaskWaker(id))));
+            let mut cx=Context::from_waker(waker);
+            if let Poll::Ready(())=task.poll(&mut cx) {
+                trace!("Task {} `{}` finished",id.0,task.name);
+                self.tasks.remove(&id);
+                self.wakers.remove(&id);
+            }
+        }
+        return ran;
+    }
+    /// Runs tasks forever, halting when none are ready
+    pub fn run(&mut self)->! {
+        loop {
+            if !self.run_ready() {
+                interrupts::disable();
+                // an interrupt could have woken something since we looked
+                let idle=READY.lock().is_empty()&&SPAWNED.lock().is_empty();
+                if idle {
+                    interrupts::enable_and_hlt();
+                } else {
+                    interrupts::enable();
+                }
+            }
+        }
+    }
+}
+
+
+/// Runs the executor on this core. Only core 0 does this for now.
+pub fn run()->! {
+    Executor::new().run()
+}
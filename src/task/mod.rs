@@ -0,0 +1,108 @@
+//! Kernel tasks. A task is a future that gets polled by the [executor] on core 0 whenever it is
+//! woken, so tasks share the core cooperatively: one only gives it up at an `.await`.
+//!
+//! Interrupt handlers can't run tasks, but they can wake them, which is how the shell waits for
+//! key presses without spinning.
+
+
+use alloc::{
+    boxed::Box,
+    collections::VecDeque,
+    vec::Vec,
+};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+    sync::atomic::{
+        AtomicU64,
+        Ordering,
+    },
+};
+use spin::Mutex;
+
+
+pub mod executor;
+
+
+static NEXT_ID:AtomicU64=AtomicU64::new(1);
+/// Tasks that have been spawned but not picked up by the executor yet
+static SPAWNED:Mutex<Vec<Task>>=Mutex::new(Vec::new());
+/// Tasks that were woken and need polling
+static READY:Mutex<VecDeque<TaskId>>=Mutex::new(VecDeque::new());
+
+
+#[derive(Debug,Copy,Clone,PartialEq,Eq,PartialOrd,Ord)]
+pub struct TaskId(u64);
+impl TaskId {
+    fn new()->TaskId {
+        TaskId(NEXT_ID.fetch_add(1,Ordering::Relaxed))
+    }
+    pub fn as_u64(&self)->u64 {
+        self.0
+    }
+}
+
+
+pub struct Task {
+    id:TaskId,
+    name:&'static str,
+    future:Pin<Box<dyn Future<Output=()>+Send>>,
+}
+impl Task {
+    pub fn new(name:&'static str,future:impl Future<Output=()>+Send+'static)->Task {
+        Task {
+            id:TaskId::new(),
+            name,
+            future:Box::pin(future),
+        }
+    }
+    pub fn id(&self)->TaskId {
+        self.id
+    }
+    pub fn name(&self)->&'static str {
+        self.name
+    }
+    fn poll(&mut self,cx:&mut Context)->Poll<()> {
+        self.future.as_mut().poll(cx)
+    }
+}
+
+
+/// Starts running `future` as a task. It is first polled the next time the executor looks for
+/// work.
+pub fn spawn(name:&'static str,future:impl Future<Output=()>+Send+'static)->TaskId {
+    let task=Task::new(name,future);
+    let id=task.id;
+    x86_64::instructions::interrupts::without_interrupts(||{
+        SPAWNED.lock().push(task);
+    });
+    return id;
+}
+/// Queues a task to be polled. Safe to call from interrupt handlers.
+fn wake(id:TaskId) {
+    x86_64::instructions::interrupts::without_interrupts(||{
+        let mut ready=READY.lock();
+        if !ready.contains(&id) {
+            ready.push_back(id);
+        }
+    });
+}
+
+
+/// Lets the other tasks run before carrying on
+pub async fn yield_now() {
+    let mut yielded=false;
+    core::future::poll_fn(|cx|{
+        if yielded {
+            Poll::Ready(())
+        } else {
+            yielded=true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }).await
+}
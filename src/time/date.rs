@@ -0,0 +1,208 @@
+//! Calendar dates and times, converting to and from seconds since 1970 and formatting. Everything
+//! is proleptic Gregorian with no leap seconds, like Unix time.
+
+
+use core::fmt::{
+    self,
+    Write,
+};
+
+
+const NS_PER_SEC:u64=1_000_000_000;
+const SECS_PER_DAY:i64=86400;
+
+const WEEKDAYS:[&str;7]=["Sun","Mon","Tue","Wed","Thu","Fri","Sat"];
+const MONTHS:[&str;12]=["Jan","Feb","Mar","Apr","May","Jun","Jul","Aug","Sep","Oct","Nov","Dec"];
+
+
+/// A date and time of day, `offset` minutes ahead of UTC
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub struct DateTime {
+    pub year:u16,
+    /// 1 to 12
+    pub month:u8,
+    /// 1 to 31
+    pub day:u8,
+    pub hour:u8,
+    pub minute:u8,
+    pub second:u8,
+    pub nanosecond:u32,
+    pub offset:i16,
+}
+#[allow(dead_code)]
+impl DateTime {
+    pub const EPOCH:DateTime=DateTime{year:1970,month:1,day:1,hour:0,minute:0,second:0,nanosecond:0,offset:0};
+    /// UTC, `ns` nanoseconds after 1970
+    pub fn from_unix_ns(ns:u64)->DateTime {
+        let secs=(ns/NS_PER_SEC) as i64;
+        let (year,month,day)=civil_from_days(secs.div_euclid(SECS_PER_DAY));
+        let time=secs.rem_euclid(SECS_PER_DAY);
+        DateTime {
+            year:year as u16,
+            month,
+            day,
+            hour:(time/3600) as u8,
+            minute:(time/60%60) as u8,
+            second:(time%60) as u8,
+            nanosecond:(ns%NS_PER_SEC) as u32,
+            offset:0,
+        }
+    }
+    /// Nanoseconds after 1970 in UTC, or `None` before 1970
+    pub fn to_unix_ns(&self)->Option<u64> {
+        let days=days_from_civil(self.year as i64,self.month,self.day);
+        let secs=days*SECS_PER_DAY+self.hour as i64*3600+self.minute as i64*60+self.second as i64-self.offset as i64*60;
+        if secs<0 {return None}
+        Some(secs as u64*NS_PER_SEC+self.nanosecond as u64)
+    }
+    /// The same moment `offset` minutes ahead of UTC
+    pub fn with_offset(&self,offset:i16)->DateTime {
+        let Some(ns)=self.to_unix_ns() else {return *self};
+        let shifted=ns as i64+offset as i64*60*NS_PER_SEC as i64;
+        let mut local=DateTime::from_unix_ns(shifted.max(0) as u64);
+        local.offset=offset;
+        return local;
+    }
+    pub fn is_valid(&self)->bool {
+        (1..=12).contains(&self.month)
+            &&self.day>=1&&self.day<=days_in_month(self.year,self.month)
+            &&self.hour<24&&self.minute<60&&self.second<60
+            &&self.nanosecond<NS_PER_SEC as u32
+    }
+    /// 0 is Sunday
+    pub fn weekday(&self)->u8 {
+        // 1970-01-01 was a Thursday
+        (days_from_civil(self.year as i64,self.month,self.day)+4).rem_euclid(7) as u8
+    }
+    /// BOOTBOOT's `datetime`: BCD `yyyymmddhhiiss` in UTC, then a byte we don't use. `None` if
+    /// it's zero or doesn't make sense.
+    pub fn from_bootboot(datetime:&[u8;8])->Option<DateTime> {
+        let century=bcd(datetime[0])?;
+        let date=DateTime {
+            year:century as u16*100+bcd(datetime[1])? as u16,
+            month:bcd(datetime[2])?,
+            day:bcd(datetime[3])?,
+            hour:bcd(datetime[4])?,
+            minute:bcd(datetime[5])?,
+            second:bcd(datetime[6])?,
+            nanosecond:0,
+            offset:0,
+        };
+        if century==0||!date.is_valid() {return None}
+        return Some(date);
+    }
+    /// Parses `YYYY-MM-DD HH:MM:SS` (or with a `T` in the middle), as UTC
+    pub fn parse(s:&str)->Option<DateTime> {
+        let (date,time)=s.trim().split_once(|c|c==' '||c=='T')?;
+        let mut date=date.splitn(3,'-');
+        let mut time=time.trim().splitn(3,':');
+        let parsed=DateTime {
+            year:date.next()?.parse().ok()?,
+            month:date.next()?.parse().ok()?,
+            day:date.next()?.parse().ok()?,
+            hour:time.next()?.parse().ok()?,
+            minute:time.next()?.parse().ok()?,
+            second:time.next().unwrap_or("0").parse().ok()?,
+            nanosecond:0,
+            offset:0,
+        };
+        if parsed.year<1970||!parsed.is_valid() {return None}
+        return Some(parsed);
+    }
+    /// Formats like `strftime`, with `%Y %m %d %H %M %S %f %a %b %z %%`. `%f` is microseconds.
+    pub fn format<'a>(&'a self,pattern:&'a str)->Formatted<'a> {
+        Formatted{date:self,pattern}
+    }
+}
+/// `2026-10-19 12:34:56`
+impl fmt::Display for DateTime {
+    fn fmt(&self,f:&mut fmt::Formatter)->fmt::Result {
+        write!(f,"{:04}-{:02}-{:02} {:02}:{:02}:{:02}",self.year,self.month,self.day,self.hour,self.minute,self.second)
+    }
+}
+
+
+/// A [`DateTime`] waiting to be formatted, see [`DateTime::format`]
+pub struct Formatted<'a> {
+    date:&'a DateTime,
+    pattern:&'a str,
+}
+impl<'a> fmt::Display for Formatted<'a> {
+    fn fmt(&self,f:&mut fmt::Formatter)->fmt::Result {
+        let d=self.date;
+        let mut chars=self.pattern.chars();
+        while let Some(c)=chars.next() {
+            if c!='%' {
+                f.write_char(c)?;
+                continue;
+            }
+            match chars.next() {
+                Some('Y')=>write!(f,"{:04}",d.year)?,
+                Some('m')=>write!(f,"{:02}",d.month)?,
+                Some('d')=>write!(f,"{:02}",d.day)?,
+                Some('H')=>write!(f,"{:02}",d.hour)?,
+                Some('M')=>write!(f,"{:02}",d.minute)?,
+                Some('S')=>write!(f,"{:02}",d.second)?,
+                Some('f')=>write!(f,"{:06}",d.nanosecond/1000)?,
+                Some('a')=>f.write_str(WEEKDAYS[d.weekday() as usize])?,
+                Some('b')=>f.write_str(MONTHS[(d.month as usize).clamp(1,12)-1])?,
+                Some('z')=>{
+                    let sign=if d.offset<0 {'-'} else {'+'};
+                    let offset=d.offset.unsigned_abs();
+                    write!(f,"{}{:02}{:02}",sign,offset/60,offset%60)?;
+                },
+                Some('%')=>f.write_char('%')?,
+                Some(other)=>{
+                    f.write_char('%')?;
+                    f.write_char(other)?;
+                },
+                None=>f.write_char('%')?,
+            }
+        }
+        return Ok(());
+    }
+}
+
+
+/// A BCD byte, `None` if a digit isn't one
+pub fn bcd(byte:u8)->Option<u8> {
+    if byte&0x0F>9||byte>>4>9 {return None}
+    Some((byte>>4)*10+(byte&0x0F))
+}
+pub fn to_bcd(value:u8)->u8 {
+    (value/10)<<4|value%10
+}
+pub fn is_leap_year(year:u16)->bool {
+    year%4==0&&(year%100!=0||year%400==0)
+}
+pub fn days_in_month(year:u16,month:u8)->u8 {
+    match month {
+        2 if is_leap_year(year)=>29,
+        2=>28,
+        4|6|9|11=>30,
+        _=>31,
+    }
+}
+/// Days since 1970-01-01, from Howard Hinnant's `days_from_civil`
+fn days_from_civil(year:i64,month:u8,day:u8)->i64 {
+    let year=if month<=2 {year-1} else {year};
+    let era=year.div_euclid(400);
+    let year_of_era=year-era*400;
+    let month=month as i64;
+    let day_of_year=(153*(if month>2 {month-3} else {month+9})+2)/5+day as i64-1;
+    let day_of_era=year_of_era*365+year_of_era/4-year_of_era/100+day_of_year;
+    era*146097+day_of_era-719468
+}
+/// The other way around
+fn civil_from_days(days:i64)->(i64,u8,u8) {
+    let days=days+719468;
+    let era=days.div_euclid(146097);
+    let day_of_era=days-era*146097;
+    let year_of_era=(day_of_era-day_of_era/1460+day_of_era/36524-day_of_era/146096)/365;
+    let day_of_year=day_of_era-(365*year_of_era+year_of_era/4-year_of_era/100);
+    let mp=(5*day_of_year+2)/153;
+    let day=(day_of_year-(153*mp+2)/5+1) as u8;
+    let month=(if mp<10 {mp+3} else {mp-9}) as u8;
+    let year=year_of_era+era*400+if month<=2 {1} else {0};
+    (year,month,day)
+}
@@ -0,0 +1,258 @@
+//! The high precision event timer, found through its ACPI table. Its main counter runs at a fixed
+//! rate of at least 10MHz, which makes it a finer clock than the ticks and a better yardstick than
+//! the PIT for timing the TSC and the LAPIC timer. It also has a few comparators that interrupt
+//! when the counter gets to them. Without an I/O APIC the only way their interrupts reach the PICs
+//! is legacy replacement mode, where comparator 0 takes over the PIT's IRQ0 and comparator 1 the
+//! RTC's IRQ8.
+
+
+use core::{
+    convert::TryInto,
+    sync::atomic::{
+        AtomicBool,
+        AtomicU64,
+        Ordering,
+    },
+    time::Duration,
+};
+use crate::{
+    acpi,
+    memory::dma,
+};
+
+
+const REG_CAPABILITIES:usize=0x00;
+const REG_CONFIG:usize=0x10;
+const REG_COUNTER:usize=0xF0;
+const fn reg_timer_config(timer:u8)->usize {0x100+0x20*timer as usize}
+const fn reg_timer_comparator(timer:u8)->usize {0x108+0x20*timer as usize}
+
+const CAP_COUNTER_64:u64=1<<13;
+const CAP_LEGACY:u64=1<<15;
+const CONFIG_ENABLE:u64=1<<0;
+const CONFIG_LEGACY:u64=1<<1;
+
+const TIMER_INT_ENABLE:u64=1<<2;
+const TIMER_PERIODIC:u64=1<<3;
+const TIMER_PERIODIC_CAP:u64=1<<4;
+const TIMER_64BIT_CAP:u64=1<<5;
+const TIMER_VALUE_SET:u64=1<<6;
+const TIMER_32BIT:u64=1<<8;
+
+/// The spec says a period over 100ns (under 10MHz) is broken
+const MAX_PERIOD_FS:u64=100_000_000;
+/// A one-shot closer than this could be missed before the comparator is written
+const MIN_ONE_SHOT_NS:u64=10_000;
+
+/// Comparator 0 drives IRQ0 in legacy replacement mode
+pub const TICK_TIMER:u8=0;
+/// Comparator 1 drives IRQ8 in legacy replacement mode
+pub const EVENT_TIMER:u8=1;
+
+
+/// Where the registers are mapped, 0 without an HPET
+static BASE:AtomicU64=AtomicU64::new(0);
+/// How long one count is in femtoseconds
+static PERIOD_FS:AtomicU64=AtomicU64::new(0);
+static WIDE:AtomicBool=AtomicBool::new(false);
+/// The counter and the uptime when the HPET became the clock, `u64::MAX` if it isn't the clock
+static START_COUNTER:AtomicU64=AtomicU64::new(u64::MAX);
+static START_NS:AtomicU64=AtomicU64::new(0);
+/// Set once legacy replacement is on and comparator 0 is the tick
+static TICKING:AtomicBool=AtomicBool::new(false);
+/// Comparator 0's period in nanoseconds, as close as the counter gets
+static TICK_NS:AtomicU64=AtomicU64::new(0);
+
+
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum HpetError {
+    /// No HPET table, or one that doesn't parse
+    NoTable,
+    /// Mapping the registers failed
+    Mapping,
+    /// The registers say something impossible
+    Broken,
+    /// The HPET can't do what was asked
+    Unsupported,
+}
+
+
+/// What the ACPI table says
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub struct Table {
+    pub address:u64,
+    pub vendor:u16,
+    pub comparators:u8,
+    pub legacy:bool,
+    /// The smallest period worth asking for in periodic mode, in counts
+    pub minimum_tick:u16,
+}
+impl Table {
+    /// Parses the table, header included
+    pub fn parse(bytes:&[u8])->Option<Table> {
+        if bytes.len()<56||&bytes[0..4]!=b"HPET" {return None}
+        let id=u32::from_le_bytes(bytes[36..40].try_into().unwrap());
+        // the registers have to be in memory, not I/O space
+        if bytes[40]!=0 {return None}
+        Some(Table {
+            address:u64::from_le_bytes(bytes[44..52].try_into().unwrap()),
+            vendor:(id>>16) as u16,
+            comparators:((id>>8)&0x1F) as u8+1,
+            legacy:id&(1<<15)!=0,
+            minimum_tick:u16::from_le_bytes([bytes[53],bytes[54]]),
+        })
+    }
+}
+
+
+/// What [`init`] found
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub struct Info {
+    pub hz:u64,
+    pub comparators:u8,
+    pub wide:bool,
+    pub legacy:bool,
+}
+
+
+fn read(reg:usize)->u64 {
+    let base=BASE.load(Ordering::Relaxed);
+    unsafe{core::ptr::read_volatile((base as usize+reg) as *const u64)}
+}
+fn write(reg:usize,value:u64) {
+    let base=BASE.load(Ordering::Relaxed);
+    unsafe{core::ptr::write_volatile((base as usize+reg) as *mut u64,value)};
+}
+pub fn is_present()->bool {
+    BASE.load(Ordering::Relaxed)!=0
+}
+/// The main counter. Only the low 32 bits count on an HPET without a 64 bit counter.
+pub fn counter()->u64 {
+    read(REG_COUNTER)
+}
+/// Counts a second
+pub fn frequency()->Option<u64> {
+    match PERIOD_FS.load(Ordering::Relaxed) {
+        0=>None,
+        period=>Some(1_000_000_000_000_000/period),
+    }
+}
+pub fn counts_to_ns(counts:u64)->u64 {
+    (counts as u128*PERIOD_FS.load(Ordering::Relaxed) as u128/1_000_000) as u64
+}
+pub fn ns_to_counts(ns:u64)->u64 {
+    match PERIOD_FS.load(Ordering::Relaxed) {
+        0=>0,
+        period=>(ns as u128*1_000_000/period as u128) as u64,
+    }
+}
+/// Counts between `start` and `end`, across a wrap of a 32 bit counter
+fn elapsed(start:u64,end:u64)->u64 {
+    if WIDE.load(Ordering::Relaxed) {end.wrapping_sub(start)} else {(end as u32).wrapping_sub(start as u32) as u64}
+}
+/// Spins for `ns` nanoseconds. A 32 bit counter wraps every few minutes, which is plenty for this.
+pub fn spin_ns(ns:u64) {
+    let counts=ns_to_counts(ns);
+    let start=counter();
+    while elapsed(start,counter())<counts {
+        core::hint::spin_loop();
+    }
+}
+
+
+/// Finds the HPET, maps it and starts the counter from 0 with every comparator off
+pub fn init()->Result<Info,HpetError> {
+    let table=acpi::find(b"HPET").and_then(|table|Table::parse(table.bytes)).ok_or(HpetError::NoTable)?;
+    let virt=dma::map_mmio(table.address,1024).map_err(|_|HpetError::Mapping)?;
+    BASE.store(virt as u64,Ordering::Relaxed);
+    let capabilities=read(REG_CAPABILITIES);
+    let period=capabilities>>32;
+    if period==0||period>MAX_PERIOD_FS {
+        BASE.store(0,Ordering::Relaxed);
+        return Err(HpetError::Broken);
+    }
+    let comparators=((capabilities>>8)&0x1F) as u8+1;
+    write(REG_CONFIG,read(REG_CONFIG)&!(CONFIG_ENABLE|CONFIG_LEGACY));
+    for timer in 0..comparators {
+        write(reg_timer_config(timer),read(reg_timer_config(timer))&!(TIMER_INT_ENABLE|TIMER_PERIODIC));
+    }
+    write(REG_COUNTER,0);
+    PERIOD_FS.store(period,Ordering::Relaxed);
+    WIDE.store(capabilities&CAP_COUNTER_64!=0,Ordering::Relaxed);
+    write(REG_CONFIG,read(REG_CONFIG)|CONFIG_ENABLE);
+    return Ok(Info {
+        hz:frequency().unwrap_or(0),
+        comparators,
+        wide:capabilities&CAP_COUNTER_64!=0,
+        legacy:capabilities&CAP_LEGACY!=0,
+    });
+}
+
+
+/// Makes the HPET the clock, going on from `uptime_ns`. Only a 64 bit counter is used as the
+/// clock, a 32 bit one wraps too soon.
+pub fn start_clock(uptime_ns:u64)->Result<(),HpetError> {
+    if !is_present()||!WIDE.load(Ordering::Relaxed) {return Err(HpetError::Unsupported)}
+    START_NS.store(uptime_ns,Ordering::Relaxed);
+    START_COUNTER.store(counter(),Ordering::Release);
+    return Ok(());
+}
+/// Uptime from the HPET, if it is the clock
+pub fn uptime_ns()->Option<u64> {
+    let start=START_COUNTER.load(Ordering::Acquire);
+    if start==u64::MAX {return None}
+    Some(START_NS.load(Ordering::Relaxed)+counts_to_ns(counter().saturating_sub(start)))
+}
+
+
+/// Turns on legacy replacement mode, which cuts the PIT and the RTC off from the PICs, and makes
+/// comparator 0 interrupt every `period` in the PIT's place
+pub fn take_over_tick(period:Duration)->Result<(),HpetError> {
+    if !is_present() {return Err(HpetError::Unsupported)}
+    if read(REG_CAPABILITIES)&CAP_LEGACY==0 {return Err(HpetError::Unsupported)}
+    set_periodic(TICK_TIMER,period)?;
+    write(REG_CONFIG,read(REG_CONFIG)|CONFIG_LEGACY);
+    TICKING.store(true,Ordering::Relaxed);
+    return Ok(());
+}
+/// Whether comparator 0 drives the timer interrupt
+pub fn is_ticking()->bool {
+    TICKING.load(Ordering::Relaxed)
+}
+/// How long a tick is when [`is_ticking`]
+pub fn tick_ns()->u64 {
+    TICK_NS.load(Ordering::Relaxed)
+}
+/// Makes `timer` interrupt every `period`, starting one period from now
+pub fn set_periodic(timer:u8,period:Duration)->Result<(),HpetError> {
+    let config=read(reg_timer_config(timer));
+    if config&TIMER_PERIODIC_CAP==0 {return Err(HpetError::Unsupported)}
+    let counts=ns_to_counts(period.as_nanos() as u64).max(1);
+    write(reg_timer_config(timer),(config&!TIMER_32BIT)|TIMER_INT_ENABLE|TIMER_PERIODIC|TIMER_VALUE_SET);
+    // with the value-set bit the first write is when, the second how often
+    write(reg_timer_comparator(timer),counter()+counts);
+    write(reg_timer_comparator(timer),counts);
+    if timer==TICK_TIMER {
+        TICK_NS.store(counts_to_ns(counts),Ordering::Relaxed);
+    }
+    return Ok(());
+}
+/// Makes `timer` interrupt once, after `delay`. A delay longer than a 32 bit comparator reaches
+/// fires early, and whoever set it sets it again. So does a periodic `timer`, once the delay is
+/// up.
+pub fn set_one_shot(timer:u8,delay:Duration) {
+    if !is_present() {return}
+    let config=read(reg_timer_config(timer));
+    let ns=(delay.as_nanos().min(u64::MAX as u128) as u64).max(MIN_ONE_SHOT_NS);
+    let mut counts=ns_to_counts(ns).max(1);
+    if config&TIMER_64BIT_CAP==0||!WIDE.load(Ordering::Relaxed) {
+        counts=counts.min(u32::MAX as u64/2);
+    }
+    write(reg_timer_config(timer),(config&!TIMER_PERIODIC)|TIMER_INT_ENABLE);
+    write(reg_timer_comparator(timer),counter().wrapping_add(counts));
+}
+/// Stops `timer` from interrupting
+pub fn stop(timer:u8) {
+    if !is_present() {return}
+    write(reg_timer_config(timer),read(reg_timer_config(timer))&!(TIMER_INT_ENABLE|TIMER_PERIODIC));
+}
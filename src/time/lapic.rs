@@ -0,0 +1,137 @@
+//! The local APIC timer. Every core has its own LAPIC at the same physical address, and its
+//! timer can interrupt just that core once, either after counting down from a number (timed
+//! against the HPET or the PIT at boot) or, with TSC-deadline mode, when the TSC reaches a value.
+//!
+//! The PICs stay in charge of device interrupts; the LAPIC is only switched on for its timer.
+
+
+use core::{
+    sync::atomic::{
+        AtomicBool,
+        AtomicU64,
+        Ordering,
+    },
+    time::Duration,
+};
+use raw_cpuid::CpuId;
+use x86_64::registers::model_specific::Msr;
+use crate::{
+    interrupts::InterruptID,
+    memory::dma,
+};
+use super::tsc;
+
+
+const REG_ID:usize=0x20;
+const REG_EOI:usize=0xB0;
+const REG_SPURIOUS:usize=0xF0;
+const REG_LVT_TIMER:usize=0x320;
+const REG_INITIAL_COUNT:usize=0x380;
+const REG_CURRENT_COUNT:usize=0x390;
+const REG_DIVIDE:usize=0x3E0;
+
+const MSR_APIC_BASE:u32=0x1B;
+const MSR_TSC_DEADLINE:u32=0x6E0;
+const APIC_BASE_ENABLE:u64=1<<11;
+
+const SPURIOUS_ENABLE:u32=1<<8;
+const LVT_MASKED:u32=1<<16;
+const TIMER_ONE_SHOT:u32=0;
+const TIMER_TSC_DEADLINE:u32=2<<17;
+/// The timer counts down at the bus clock divided by 16
+const DIVIDE_16:u32=0x3;
+
+
+/// Where the registers are mapped, 0 before [`init`]
+static BASE:AtomicU64=AtomicU64::new(0);
+/// Timer counts a second, after the divider
+static HZ:AtomicU64=AtomicU64::new(0);
+static TSC_DEADLINE:AtomicBool=AtomicBool::new(false);
+
+
+/// How the timer is run
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum Mode {
+    OneShot{hz:u64},
+    TscDeadline,
+}
+
+
+fn read(reg:usize)->u32 {
+    let base=BASE.load(Ordering::Relaxed);
+    unsafe{core::ptr::read_volatile((base as usize+reg) as *const u32)}
+}
+fn write(reg:usize,value:u32) {
+    let base=BASE.load(Ordering::Relaxed);
+    unsafe{core::ptr::write_volatile((base as usize+reg) as *mut u32,value)};
+}
+pub fn is_enabled()->bool {
+    BASE.load(Ordering::Relaxed)!=0
+}
+/// This core's LAPIC ID, 0 before [`init`]
+pub fn id()->u32 {
+    if !is_enabled() {return 0}
+    read(REG_ID)>>24
+}
+/// Tells this core's LAPIC the interrupt is handled
+pub fn eoi() {
+    write(REG_EOI,0);
+}
+
+
+/// Maps the LAPIC, turns on this core's and works out how its timer will be run
+pub fn init(cpuid:&CpuId)->Result<Mode,()> {
+    let features=cpuid.get_feature_info().ok_or(())?;
+    if !features.has_apic() {return Err(())}
+    let mut msr=Msr::new(MSR_APIC_BASE);
+    let apic_base=unsafe{msr.read()};
+    unsafe{msr.write(apic_base|APIC_BASE_ENABLE)};
+    let virt=dma::map_mmio(apic_base&0x000F_FFFF_FFFF_F000,4096)?;
+    BASE.store(virt as u64,Ordering::Relaxed);
+    init_core();
+    // deadline mode counts in TSC ticks, so the TSC's rate has to be trusted
+    if features.has_tsc_deadline()&&tsc::is_invariant()&&tsc::frequency().is_some() {
+        TSC_DEADLINE.store(true,Ordering::Relaxed);
+        return Ok(Mode::TscDeadline);
+    }
+    write(REG_INITIAL_COUNT,u32::MAX);
+    super::reference_delay(10);
+    let counted=u32::MAX-read(REG_CURRENT_COUNT);
+    write(REG_INITIAL_COUNT,0);
+    let hz=counted as u64*100;
+    HZ.store(hz,Ordering::Relaxed);
+    return Ok(Mode::OneShot{hz});
+}
+/// Software-enables this core's LAPIC with its timer masked. Every core that uses the timer runs
+/// this once.
+pub fn init_core() {
+    write(REG_SPURIOUS,SPURIOUS_ENABLE|u8::from(InterruptID::Spurious) as u32);
+    write(REG_DIVIDE,DIVIDE_16);
+    write(REG_LVT_TIMER,LVT_MASKED|u8::from(InterruptID::LapicTimer) as u32);
+}
+/// Interrupts this core once `delay` has passed, replacing whatever was set before
+pub fn arm(delay:Duration) {
+    if !is_enabled() {return}
+    let ns=delay.as_nanos().min(u64::MAX as u128) as u64;
+    let vector=u8::from(InterruptID::LapicTimer) as u32;
+    if TSC_DEADLINE.load(Ordering::Relaxed) {
+        let Some(hz)=tsc::frequency() else {return};
+        write(REG_LVT_TIMER,vector|TIMER_TSC_DEADLINE);
+        let deadline=tsc::read()+tsc::ns_to_ticks(ns,hz).max(1);
+        unsafe{Msr::new(MSR_TSC_DEADLINE).write(deadline)};
+    } else {
+        // a delay too long for the counter fires early, and whoever armed it arms it again
+        let counts=(ns as u128*HZ.load(Ordering::Relaxed) as u128/1_000_000_000).clamp(1,u32::MAX as u128) as u32;
+        write(REG_LVT_TIMER,vector|TIMER_ONE_SHOT);
+        write(REG_INITIAL_COUNT,counts);
+    }
+}
+/// Stops this core's timer
+pub fn disarm() {
+    if !is_enabled() {return}
+    if TSC_DEADLINE.load(Ordering::Relaxed) {
+        unsafe{Msr::new(MSR_TSC_DEADLINE).write(0)};
+    } else {
+        write(REG_INITIAL_COUNT,0);
+    }
+}
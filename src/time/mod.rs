@@ -0,0 +1,422 @@
+//! Time since boot. The PIT fires the timer interrupt `timer.frequency` times a second; each tick
+//! bumps the tick counter and adds the tick's length to the tick clock, so it stays monotonic even
+//! if the frequency is changed later. Once the [`tsc`] is known to be steady it takes over as the
+//! clock from where the ticks got to, which gives [`uptime`] nanosecond resolution. Without one
+//! the [`hpet`]'s counter does the same at a coarser resolution.
+//!
+//! [`udelay`] spins, [`sleep`] halts the core until the time is up. The [`lapic`] timer wakes it
+//! right on time instead of at the next tick, or the HPET's second comparator on machines
+//! without a LAPIC. The HPET drives the tick then too, since it takes over the PIT's IRQ to get
+//! its own interrupts through.
+//!
+//! The date and time of day are kept by [`wall`], which counts on from the date at boot with
+//! [`uptime`]; [`now`] is the current date in UTC.
+//!
+//! Things that have to happen at a certain time go on a [`timer`]: [`timer::call_after`] and
+//! [`timer::call_every`] for code run from the timer interrupt, [`timer::delay`] and
+//! [`timer::timeout`] for tasks.
+
+
+use alloc::vec::Vec;
+use core::{
+    sync::atomic::{
+        AtomicU64,
+        Ordering,
+    },
+    task::{
+        Poll,
+        Waker,
+    },
+    time::Duration,
+};
+use spin::Mutex;
+use raw_cpuid::CpuId;
+use date::DateTime;
+use x86_64::instructions::interrupts::{
+    self,
+    without_interrupts,
+};
+use crate::{
+    bootboot::{
+        BootBootUnpacked,
+        BOOTBOOT_INFO,
+        BOOTBOOT,
+    },
+    config::{
+        self,
+        Opt,
+        Kind,
+    },
+    shell::{
+        self,
+        Command,
+    },
+    println,
+    info,
+    warn,
+};
+
+
+pub mod pit;
+pub mod tsc;
+pub mod lapic;
+pub mod hpet;
+pub mod date;
+pub mod rtc;
+pub mod wall;
+pub mod timer;
+
+
+pub use wall::now;
+
+
+pub static FREQUENCY:Opt=Opt::new("timer.frequency",Kind::Int,"100","Timer interrupts a second, 19 to 10000");
+pub static TSC:Opt=Opt::new("clock.tsc",Kind::Bool,"false","Use the TSC as the clock even if the CPU doesn't say its rate is invariant");
+pub static HPET:Opt=Opt::new("timer.hpet",Kind::Bool,"false","Take the timer interrupt from the HPET instead of the PIT");
+pub static TIMEZONE:Opt=Opt::new("clock.timezone",Kind::Int,"0","Minutes ahead of UTC for local time, BOOTBOOT's timezone if not set");
+pub static LOCAL_RTC:Opt=Opt::new("clock.local_rtc",Kind::Bool,"false","The CMOS clock keeps local time instead of UTC");
+
+
+static UPTIME:Command=Command::new("uptime","","Time since boot and the timer frequency",uptime_cmd);
+static DATE:Command=Command::new("date","[YYYY-MM-DD HH:MM:SS]","Show the date, or set it in UTC and save it to the CMOS clock",date_cmd);
+
+
+/// Timer interrupts since boot
+static TICKS:AtomicU64=AtomicU64::new(0);
+/// Nanoseconds since boot, as of the last tick
+static TICK_NS:AtomicU64=AtomicU64::new(0);
+/// Tasks waiting for the next tick. Only locked with interrupts off.
+static TICK_WAKERS:Mutex<Vec<Waker>>=Mutex::new(Vec::new());
+
+
+/// Programs the PIT, or the HPET if it has the tick, for about `hz` interrupts a second and
+/// returns what it really got
+pub fn set_frequency(hz:u32)->u32 {
+    if hpet::is_ticking() {
+        let _=hpet::set_periodic(hpet::TICK_TIMER,Duration::from_nanos(1_000_000_000/hz.max(1) as u64));
+    } else {
+        pit::set_divisor(pit::divisor_for(hz));
+    }
+    return frequency();
+}
+/// Timer interrupts a second, rounded
+pub fn frequency()->u32 {
+    let period=tick_ns();
+    ((1_000_000_000+period/2)/period) as u32
+}
+/// How long one tick is now
+pub fn tick_period()->Duration {
+    Duration::from_nanos(tick_ns())
+}
+fn tick_ns()->u64 {
+    if hpet::is_ticking() {hpet::tick_ns()} else {pit::period_ns(pit::divisor())}
+}
+pub fn ticks()->u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+/// Time since boot, from the TSC or the HPET, or else to the last tick
+pub fn uptime()->Duration {
+    let ns=tsc::uptime_ns()
+        .or_else(hpet::uptime_ns)
+        .unwrap_or_else(||TICK_NS.load(Ordering::Relaxed));
+    Duration::from_nanos(ns)
+}
+/// How many ticks at the current frequency cover `duration`, rounded up
+pub fn duration_to_ticks(duration:Duration)->u64 {
+    let period=tick_ns();
+    (duration.as_nanos() as u64).div_ceil(period)
+}
+
+
+/// Waits for the next timer interrupt, for tasks that have to poll hardware
+pub async fn next_tick() {
+    let start=ticks();
+    core::future::poll_fn(|cx|{
+        without_interrupts(||{
+            if ticks()!=start {
+                return Poll::Ready(());
+            }
+            TICK_WAKERS.lock().push(cx.waker().clone());
+            Poll::Pending
+        })
+    }).await
+}
+/// Spins for `us` microseconds, on the TSC if its rate is known, or the HPET's counter, or the
+/// PIT's count
+pub fn udelay(us:u64) {
+    match tsc::frequency() {
+        Some(hz)=>{
+            let end=tsc::read()+tsc::ns_to_ticks(us*1000,hz);
+            while tsc::read()<end {
+                core::hint::spin_loop();
+            }
+        },
+        None if hpet::is_present()=>hpet::spin_ns(us*1000),
+        None=>pit::spin_counts(us*pit::BASE_FREQUENCY/1_000_000),
+    }
+}
+/// Spins for `ms` milliseconds of the HPET, or the PIT without one, to time other clocks against
+fn reference_delay(ms:u64) {
+    if hpet::is_present() {
+        hpet::spin_ns(ms*1_000_000);
+    } else {
+        pit::spin_counts(pit::BASE_FREQUENCY*ms/1000);
+    }
+}
+/// Halts until `duration` has passed. Spins instead with interrupts off, since nothing would
+/// wake it.
+pub fn sleep(duration:Duration) {
+    if !interrupts::are_enabled() {
+        udelay(duration.as_micros() as u64);
+        return;
+    }
+    let end=uptime()+duration;
+    // nothing to do but wake this core with the LAPIC
+    let wake=timer::call_after(duration,||{});
+    loop {
+        interrupts::disable();
+        if uptime()>=end {break}
+        interrupts::enable_and_hlt();
+    }
+    interrupts::enable();
+    timer::cancel(wake);
+}
+
+
+/// Called by the timer interrupt. Returns how long the tick was.
+pub fn tick()->Duration {
+    let period=tick_ns();
+    TICKS.fetch_add(1,Ordering::Relaxed);
+    TICK_NS.fetch_add(period,Ordering::Relaxed);
+    for waker in TICK_WAKERS.lock().drain(..) {
+        waker.wake();
+    }
+    timer::run();
+    return Duration::from_nanos(period);
+}
+/// Called by the LAPIC timer interrupt and the HPET's event comparator
+pub fn timer_fired() {
+    timer::run();
+}
+
+
+/// Programs the PIT with `timer.frequency`, then finds out about the HPET, the TSC and the LAPIC
+/// timer, and picks the best of them for the clock and for timed calls
+pub fn init() {
+    config::register(&[&FREQUENCY,&TSC,&HPET,&TIMEZONE,&LOCAL_RTC]);
+    shell::register(&[&UPTIME,&DATE]);
+    let wanted=FREQUENCY.get_int();
+    let wanted=if (19..=10000).contains(&wanted) {wanted as u32} else {
+        warn!("timer.frequency should be 19 to 10000, using 100");
+        100
+    };
+    let got=set_frequency(wanted);
+    if got!=wanted {
+        warn!("The timer runs at {}Hz, as close as the PIT gets to {}Hz",got,wanted);
+    }
+    match hpet::init() {
+        Ok(info)=>info!("HPET runs at {}.{:03}MHz with {} comparators{}",info.hz/1_000_000,info.hz/1000%1000,info.comparators,if info.wide {", 64 bit"} else {", 32 bit"}),
+        Err(err)=>info!("No HPET ({:?})",err),
+    }
+    let cpuid=CpuId::new();
+    let tsc_clock=match tsc::init(&cpuid) {
+        Some((hz,source))=>{
+            info!("TSC runs at {}.{:03}MHz ({:?}){}",hz/1_000_000,hz/1000%1000,source,if tsc::is_invariant() {", invariant"} else {""});
+            if tsc::is_invariant()||TSC.get_bool() {
+                without_interrupts(||tsc::start_clock(TICK_NS.load(Ordering::Relaxed)));
+            }
+            tsc::uptime_ns().is_some()
+        },
+        None=>false,
+    };
+    if !tsc_clock&&without_interrupts(||hpet::start_clock(TICK_NS.load(Ordering::Relaxed))).is_err() {
+        warn!("Neither the TSC nor the HPET can be the clock, it only moves with timer ticks");
+    }
+    let lapic=lapic::init(&cpuid);
+    match lapic {
+        Ok(lapic::Mode::OneShot{hz})=>info!("LAPIC timer counts at {}kHz",hz/1000),
+        Ok(lapic::Mode::TscDeadline)=>info!("LAPIC timer runs in TSC-deadline mode"),
+        Err(())=>info!("No LAPIC timer"),
+    }
+    if HPET.get_bool()||lapic.is_err() {
+        match without_interrupts(||hpet::take_over_tick(tick_period())) {
+            Ok(())=>{
+                crate::interrupts::unmask_irq(8);
+                info!("The HPET drives the timer interrupt at {}Hz",frequency());
+            },
+            Err(_) if lapic.is_err()=>warn!("No LAPIC or HPET timer, timed calls happen on timer ticks"),
+            Err(_)=>warn!("The HPET can't drive the timer interrupt, the PIT keeps it"),
+        }
+    }
+    init_wall_clock();
+}
+/// Sets the timezone and starts the wall clock from BOOTBOOT's date or the RTC
+fn init_wall_clock() {
+    let bootboot:BootBootUnpacked=unsafe{*(BOOTBOOT_INFO as *const BOOTBOOT)}.into();
+    match config::raw(TIMEZONE.name) {
+        Some(_)=>wall::set_timezone(TIMEZONE.get_int().clamp(-1440,1440) as i16),
+        None=>wall::set_timezone(bootboot.timezone),
+    }
+    rtc::init();
+    match wall::init(DateTime::from_bootboot(&bootboot.datetime),LOCAL_RTC.get_bool()) {
+        Some((time,source))=>info!("The date is {} UTC, from {:?}",time.with_offset(0),source),
+        None=>warn!("Neither BOOTBOOT nor the CMOS clock know the date, counting from 1970"),
+    }
+}
+fn date_cmd(args:&[&str])->Result<(),&'static str> {
+    if !args.is_empty() {
+        let time=DateTime::parse(&args.join(" ")).ok_or("expected YYYY-MM-DD HH:MM:SS")?;
+        wall::set_and_save(&time,LOCAL_RTC.get_bool());
+    }
+    println!("{}",wall::local_now().format("%a %Y-%m-%d %H:%M:%S %z"));
+    if wall::timezone()!=0 {
+        println!("{}",now().format("%a %Y-%m-%d %H:%M:%S UTC"));
+    }
+    if !wall::is_known() {
+        println!("The date isn't known, this counts from boot");
+    }
+    return Ok(());
+}
+fn uptime_cmd(_args:&[&str])->Result<(),&'static str> {
+    let up=uptime();
+    let secs=up.as_secs();
+    println!("Up {}:{:02}:{:02}.{:03}",secs/3600,secs/60%60,secs%60,up.subsec_millis());
+    println!("Timer: {} ticks at {}Hz from the {}",ticks(),frequency(),if hpet::is_ticking() {"HPET"} else {"PIT"});
+    let clock=if tsc::uptime_ns().is_some() {"TSC"} else if hpet::uptime_ns().is_some() {"HPET"} else {"timer ticks"};
+    match tsc::frequency() {
+        Some(hz)=>println!("Clock: {}, TSC at {}kHz{}",clock,hz/1000,if tsc::is_invariant() {", invariant"} else {""}),
+        None=>println!("Clock: {}",clock),
+    }
+    return Ok(());
+}
+
+
+/// PIT divisors and tick conversions
+pub fn test_pit()->Result<(),&'static str> {
+    if pit::divisor_for(1000)!=1193||pit::divisor_for(1)!=pit::MAX_DIVISOR||pit::divisor_for(10_000_000)!=1 {
+        return Err("PIT divisors worked out wrong");
+    }
+    if pit::period_ns(pit::MAX_DIVISOR)/1000!=54925 {
+        return Err("the firmware's tick length worked out wrong");
+    }
+    let period=pit::period_ns(pit::divisor());
+    if duration_to_ticks(Duration::from_nanos(period))!=1||duration_to_ticks(Duration::from_nanos(period+1))!=2 {
+        return Err("durations rounded to ticks wrong");
+    }
+    return Ok(());
+}
+/// TSC conversions, and that the clock and the delays agree
+pub fn test_clock()->Result<(),&'static str> {
+    if tsc::ns_to_ticks(1_500,2_000_000_000)!=3000||tsc::ticks_to_ns(3000,2_000_000_000)!=1_500 {
+        return Err("TSC conversions worked out wrong");
+    }
+    // only the TSC clock moves between ticks
+    if tsc::uptime_ns().is_some() {
+        let start=uptime();
+        udelay(2000);
+        let took=uptime()-start;
+        if took<Duration::from_micros(2000)||took>Duration::from_millis(50) {
+            return Err("udelay and the clock disagree");
+        }
+    }
+    let start=uptime();
+    sleep(Duration::from_millis(20));
+    if uptime()-start<Duration::from_millis(20) {
+        return Err("sleep woke up early");
+    }
+    return Ok(());
+}
+/// Parsing the ACPI table and the counter conversions
+pub fn test_hpet()->Result<(),&'static str> {
+    let mut table=[0u8;56];
+    table[0..4].copy_from_slice(b"HPET");
+    table[36..40].copy_from_slice(&(0x8086_A201u32).to_le_bytes());
+    table[44..52].copy_from_slice(&0xFED0_0000u64.to_le_bytes());
+    table[53..55].copy_from_slice(&0x80u16.to_le_bytes());
+    let parsed=hpet::Table::parse(&table).ok_or("the HPET table didn't parse")?;
+    if parsed!=(hpet::Table{address:0xFED0_0000,vendor:0x8086,comparators:3,legacy:true,minimum_tick:0x80}) {
+        return Err("the HPET table parsed wrong");
+    }
+    table[40]=1;    // registers in I/O space
+    if hpet::Table::parse(&table).is_some() {
+        return Err("an HPET in I/O space was taken");
+    }
+    if let Some(hz)=hpet::frequency() {
+        let second=hpet::ns_to_counts(1_000_000_000);
+        if second.abs_diff(hz)>1||hpet::counts_to_ns(second).abs_diff(1_000_000_000)>1000 {
+            return Err("HPET conversions worked out wrong");
+        }
+    }
+    return Ok(());
+}
+/// Calendar conversions, BOOTBOOT's date and the RTC's formats
+pub fn test_date()->Result<(),&'static str> {
+    let leap=DateTime{year:2024,month:2,day:29,hour:23,minute:59,second:58,nanosecond:0,offset:0};
+    if leap.to_unix_ns()!=Some(1_709_251_198_000_000_000)||DateTime::from_unix_ns(1_709_251_198_000_000_000)!=leap {
+        return Err("unix time conversions worked out wrong");
+    }
+    if DateTime::EPOCH.weekday()!=4||leap.weekday()!=4 {
+        return Err("weekdays worked out wrong");
+    }
+    let local=leap.with_offset(-90);
+    if (local.hour,local.minute,local.offset)!=(22,29,-90)||local.to_unix_ns()!=leap.to_unix_ns() {
+        return Err("timezone offsets worked out wrong");
+    }
+    if leap.with_offset(120).day!=1||leap.with_offset(120).month!=3 {
+        return Err("an offset past midnight didn't change the date");
+    }
+    if DateTime::from_bootboot(&[0x20,0x24,0x02,0x29,0x23,0x59,0x58,0])!=Some(leap)||DateTime::from_bootboot(&[0;8]).is_some() {
+        return Err("BOOTBOOT's date decoded wrong");
+    }
+    if DateTime::parse("2024-02-29 23:59:58")!=Some(leap)||DateTime::parse("2023-02-29 00:00:00").is_some() {
+        return Err("dates parsed wrong");
+    }
+    if alloc::format!("{}",leap.with_offset(330).format("%a %b %d %H:%M:%S.%f %z %%"))!="Fri Mar 01 05:29:58.000000 +0530 %" {
+        return Err("dates formatted wrong");
+    }
+    // 11:59:58 PM in BCD 12 hour time, then binary 24 hour time
+    let bcd=rtc::Registers{second:0x58,minute:0x59,hour:0x91,day:0x29,month:0x02,year:0x24,century:0x20,status_b:0};
+    let binary=rtc::Registers{second:58,minute:59,hour:23,day:29,month:2,year:24,century:0,status_b:0x06};
+    if bcd.decode()!=Some(leap)||binary.decode()!=Some(leap) {
+        return Err("RTC registers decoded wrong");
+    }
+    if rtc::Registers::encode(&leap,0,true)!=bcd||rtc::Registers::encode(&leap,0x06,false)!=binary {
+        return Err("RTC registers encoded wrong");
+    }
+    let midnight=rtc::Registers{hour:0x12,..bcd};
+    if midnight.decode().map(|t|t.hour)!=Some(0) {
+        return Err("12 AM decoded wrong");
+    }
+    return Ok(());
+}
+/// Timers go off in order, can be cancelled, and periodic ones keep going
+pub fn test_timers()->Result<(),&'static str> {
+    static ORDER:AtomicU64=AtomicU64::new(0);
+    static PERIODIC:AtomicU64=AtomicU64::new(0);
+    fn push(n:u64) {
+        let _=ORDER.fetch_update(Ordering::Relaxed,Ordering::Relaxed,|order|Some(order*10+n));
+    }
+    ORDER.store(0,Ordering::Relaxed);
+    PERIODIC.store(0,Ordering::Relaxed);
+    timer::call_after(Duration::from_millis(10),||push(2));
+    timer::call_after(Duration::from_millis(5),||push(1));
+    let cancelled=timer::call_after(Duration::from_millis(5),||push(9));
+    if !timer::cancel(cancelled)||timer::cancel(cancelled) {
+        return Err("cancelling a timer twice didn't work out");
+    }
+    let periodic=timer::call_every(Duration::from_millis(2),||{PERIODIC.fetch_add(1,Ordering::Relaxed);});
+    sleep(Duration::from_millis(30));
+    timer::cancel(periodic);
+    let runs=PERIODIC.load(Ordering::Relaxed);
+    if ORDER.load(Ordering::Relaxed)!=12 {
+        return Err("timers went off out of order, or a cancelled one went off");
+    }
+    if runs==0 {
+        return Err("a periodic timer never went off");
+    }
+    sleep(Duration::from_millis(10));
+    if PERIODIC.load(Ordering::Relaxed)!=runs {
+        return Err("a cancelled periodic timer kept going");
+    }
+    return Ok(());
+}
@@ -0,0 +1,87 @@
+//! The 8253/8254 programmable interval timer. Channel 0 is wired to IRQ0 and drives the timer
+//! interrupt: it counts down from a divisor of its 1.193182MHz clock and fires at zero.
+
+
+use core::sync::atomic::{
+    AtomicU32,
+    Ordering,
+};
+use spin::Mutex;
+use x86_64::instructions::{
+    port::Port,
+    interrupts::without_interrupts,
+};
+
+
+const CHANNEL0:u16=0x40;
+const MODE:u16=0x43;
+
+/// Channel 0, low byte then high byte, mode 2 (rate generator), binary
+const MODE_RATE_GENERATOR:u8=0x34;
+/// Channel 0, latch the count so both bytes are from the same moment
+const MODE_LATCH:u8=0x00;
+
+/// What the PIT counts at, in Hz
+pub const BASE_FREQUENCY:u64=1_193_182;
+/// The divisor is 16 bits and 0 means 65536, which is what the firmware leaves (~18.2Hz)
+pub const MAX_DIVISOR:u32=65536;
+
+
+/// The mode and channel ports are shared between programming and reading the count
+static PORTS:Mutex<()>=Mutex::new(());
+/// What channel 0 is programmed with. The firmware leaves the largest divisor.
+static DIVISOR:AtomicU32=AtomicU32::new(MAX_DIVISOR);
+
+
+/// The divisor closest to `hz` interrupts a second
+pub fn divisor_for(hz:u32)->u32 {
+    let hz=hz.max(1) as u64;
+    ((BASE_FREQUENCY+hz/2)/hz).clamp(1,MAX_DIVISOR as u64) as u32
+}
+/// How long one interrupt period is with `divisor`
+pub fn period_ns(divisor:u32)->u64 {
+    divisor as u64*1_000_000_000/BASE_FREQUENCY
+}
+/// Starts channel 0 firing every `divisor` counts
+pub fn set_divisor(divisor:u32) {
+    let divisor=divisor.clamp(1,MAX_DIVISOR);
+    let bytes=(divisor as u16).to_le_bytes();   // 65536 wraps to 0, like the PIT wants
+    without_interrupts(||{
+        let _lock=PORTS.lock();
+        unsafe {
+            Port::<u8>::new(MODE).write(MODE_RATE_GENERATOR);
+            Port::<u8>::new(CHANNEL0).write(bytes[0]);
+            Port::<u8>::new(CHANNEL0).write(bytes[1]);
+        }
+        DIVISOR.store(divisor,Ordering::Relaxed);
+    });
+}
+pub fn divisor()->u32 {
+    DIVISOR.load(Ordering::Relaxed)
+}
+/// Where channel 0 is in its countdown
+pub fn count()->u16 {
+    without_interrupts(||{
+        let _lock=PORTS.lock();
+        unsafe {
+            Port::<u8>::new(MODE).write(MODE_LATCH);
+            let low=Port::<u8>::new(CHANNEL0).read();
+            let high=Port::<u8>::new(CHANNEL0).read();
+            u16::from_le_bytes([low,high])
+        }
+    })
+}
+/// Spins for `counts` of the PIT's clock by watching channel 0 count down, for calibrating other
+/// clocks against. Has to read the count at least once a period, so a long interrupt handler in
+/// the middle makes it run long.
+pub fn spin_counts(counts:u64) {
+    let divisor=divisor() as u64;
+    let mut last=count() as u64;
+    let mut elapsed=0;
+    while elapsed<counts {
+        let now=count() as u64;
+        // it counts down and reloads with the divisor at the bottom
+        elapsed+=if now<=last {last-now} else {last+divisor-now};
+        last=now;
+    }
+}
@@ -0,0 +1,196 @@
+//! The CMOS real time clock, which keeps the date through power off. Depending on how the firmware
+//! set it up it counts in BCD or binary and in 12 or 24 hour time, and it can be caught halfway
+//! through updating, so a read is only trusted once two in a row agree. The century is in a CMOS
+//! register only if the ACPI FADT says which one.
+
+
+use core::sync::atomic::{
+    AtomicU8,
+    Ordering,
+};
+use spin::Mutex;
+use x86_64::instructions::{
+    port::Port,
+    interrupts::without_interrupts,
+};
+use crate::acpi;
+use super::date::{
+    self,
+    DateTime,
+};
+
+
+const CMOS_ADDRESS:u16=0x70;
+const CMOS_DATA:u16=0x71;
+
+const REG_SECONDS:u8=0x00;
+const REG_MINUTES:u8=0x02;
+const REG_HOURS:u8=0x04;
+const REG_DAY:u8=0x07;
+const REG_MONTH:u8=0x08;
+const REG_YEAR:u8=0x09;
+const REG_STATUS_A:u8=0x0A;
+const REG_STATUS_B:u8=0x0B;
+
+const STATUS_A_UPDATING:u8=0x80;
+const STATUS_B_SET:u8=0x80;
+const STATUS_B_BINARY:u8=0x04;
+const STATUS_B_24_HOUR:u8=0x02;
+/// In 12 hour mode the top bit of the hour is PM
+const HOUR_PM:u8=0x80;
+
+/// Where the FADT keeps the CMOS century register's index
+const FADT_CENTURY:usize=108;
+/// Reads that can disagree before giving up
+const MAX_READS:usize=8;
+/// How long to wait for an update to finish, in reads of status A. Updates take under 2ms.
+const MAX_UPDATE_SPINS:usize=100_000;
+
+
+/// The index and data ports are used as a pair
+static PORTS:Mutex<()>=Mutex::new(());
+/// The CMOS register with the century, 0 without one
+static CENTURY:AtomicU8=AtomicU8::new(0);
+
+
+/// The clock registers as they are in the CMOS
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub struct Registers {
+    pub second:u8,
+    pub minute:u8,
+    pub hour:u8,
+    pub day:u8,
+    pub month:u8,
+    pub year:u8,
+    /// 0 without a century register
+    pub century:u8,
+    pub status_b:u8,
+}
+impl Registers {
+    /// Turns what the CMOS says into a date. Without a century the year is taken to be 20xx.
+    pub fn decode(&self)->Option<DateTime> {
+        let binary=self.status_b&STATUS_B_BINARY!=0;
+        let value=|byte:u8|if binary {Some(byte)} else {date::bcd(byte)};
+        let pm=self.hour&HOUR_PM!=0;
+        let mut hour=value(self.hour&!HOUR_PM)?;
+        if self.status_b&STATUS_B_24_HOUR==0 {
+            // 12 hour time goes 12, 1, .. 11
+            if !(1..=12).contains(&hour) {return None}
+            hour=hour%12+if pm {12} else {0};
+        }
+        let century=if self.century==0 {20} else {value(self.century)?};
+        let decoded=DateTime {
+            year:century as u16*100+value(self.year)? as u16,
+            month:value(self.month)?,
+            day:value(self.day)?,
+            hour,
+            minute:value(self.minute)?,
+            second:value(self.second)?,
+            nanosecond:0,
+            offset:0,
+        };
+        if !decoded.is_valid() {return None}
+        return Some(decoded);
+    }
+    /// Turns a date into what the CMOS wants, in the format `status_b` says
+    pub fn encode(time:&DateTime,status_b:u8,has_century:bool)->Registers {
+        let binary=status_b&STATUS_B_BINARY!=0;
+        let value=|n:u8|if binary {n} else {date::to_bcd(n)};
+        let hour=if status_b&STATUS_B_24_HOUR!=0 {value(time.hour)} else {
+            let pm=if time.hour>=12 {HOUR_PM} else {0};
+            value((time.hour+11)%12+1)|pm
+        };
+        Registers {
+            second:value(time.second),
+            minute:value(time.minute),
+            hour,
+            day:value(time.day),
+            month:value(time.month),
+            year:value((time.year%100) as u8),
+            century:if has_century {value((time.year/100) as u8)} else {0},
+            status_b,
+        }
+    }
+}
+
+
+fn read_register(reg:u8)->u8 {
+    unsafe {
+        Port::<u8>::new(CMOS_ADDRESS).write(reg);
+        Port::<u8>::new(CMOS_DATA).read()
+    }
+}
+fn write_register(reg:u8,value:u8) {
+    unsafe {
+        Port::<u8>::new(CMOS_ADDRESS).write(reg);
+        Port::<u8>::new(CMOS_DATA).write(value);
+    }
+}
+/// Waits for an update in progress to finish. Returns `false` if it never did.
+fn wait_for_update()->bool {
+    for _ in 0..MAX_UPDATE_SPINS {
+        if read_register(REG_STATUS_A)&STATUS_A_UPDATING==0 {return true}
+        core::hint::spin_loop();
+    }
+    return false;
+}
+fn read_registers()->Registers {
+    let century=CENTURY.load(Ordering::Relaxed);
+    Registers {
+        second:read_register(REG_SECONDS),
+        minute:read_register(REG_MINUTES),
+        hour:read_register(REG_HOURS),
+        day:read_register(REG_DAY),
+        month:read_register(REG_MONTH),
+        year:read_register(REG_YEAR),
+        century:if century==0 {0} else {read_register(century)},
+        status_b:read_register(REG_STATUS_B),
+    }
+}
+
+
+/// Looks for the century register in the FADT
+pub fn init() {
+    let century=acpi::find(b"FACP").and_then(|fadt|fadt.bytes.get(FADT_CENTURY).copied()).unwrap_or(0);
+    // the first 14 registers are the clock itself
+    if century>=0x0E&&century<0x80 {
+        CENTURY.store(century,Ordering::Relaxed);
+    }
+}
+/// The date and time the RTC holds, `None` if it never settled or makes no sense
+pub fn read()->Option<DateTime> {
+    without_interrupts(||{
+        let _lock=PORTS.lock();
+        let mut last=None;
+        for _ in 0..MAX_READS {
+            if !wait_for_update() {return None}
+            let registers=read_registers();
+            if last==Some(registers) {
+                return registers.decode();
+            }
+            last=Some(registers);
+        }
+        return None;
+    })
+}
+/// Sets the RTC, in whatever format it already uses
+pub fn write(time:&DateTime) {
+    without_interrupts(||{
+        let _lock=PORTS.lock();
+        let status_b=read_register(REG_STATUS_B);
+        let century=CENTURY.load(Ordering::Relaxed);
+        let registers=Registers::encode(time,status_b,century!=0);
+        // stop it updating while the registers are half written
+        write_register(REG_STATUS_B,status_b|STATUS_B_SET);
+        write_register(REG_SECONDS,registers.second);
+        write_register(REG_MINUTES,registers.minute);
+        write_register(REG_HOURS,registers.hour);
+        write_register(REG_DAY,registers.day);
+        write_register(REG_MONTH,registers.month);
+        write_register(REG_YEAR,registers.year);
+        if century!=0 {
+            write_register(century,registers.century);
+        }
+        write_register(REG_STATUS_B,status_b&!STATUS_B_SET);
+    });
+}
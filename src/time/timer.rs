@@ -0,0 +1,228 @@
+//! Timers: something to do once a point on the monotonic clock is reached, once or every period.
+//! Each core has its own queue, ordered by when things are due, and runs it from its timer
+//! interrupt: the LAPIC timer, armed for whatever is due first, or failing that the tick. Only
+//! core 0 takes interrupts so far, so only its queue runs.
+//!
+//! A timer either calls a function inside the interrupt handler, which must be quick and must not
+//! take locks that are held with interrupts on, or wakes a task. Tasks mostly use [`delay`] and
+//! [`timeout`] instead of making timers themselves.
+
+
+use alloc::collections::BTreeMap;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{
+        AtomicU64,
+        Ordering,
+    },
+    task::{
+        Context,
+        Poll,
+        Waker,
+    },
+    time::Duration,
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::gdt::{
+    current_core,
+    MAX_CORES,
+};
+use super::{
+    hpet,
+    lapic,
+    uptime,
+};
+
+
+/// Only locked with interrupts off
+static QUEUES:[Mutex<TimerQueue>;MAX_CORES]=[const{Mutex::new(TimerQueue::new())};MAX_CORES];
+static NEXT_ID:AtomicU64=AtomicU64::new(1);
+
+
+/// Returned when a timer is made, to [`cancel`] it
+#[derive(Debug,Copy,Clone,PartialEq,Eq,PartialOrd,Ord)]
+pub struct TimerId {
+    core:usize,
+    id:u64,
+}
+
+
+/// What [`timeout`] gives back when the time ran out first
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub struct TimedOut;
+
+
+#[derive(Clone)]
+enum Action {
+    Call(fn()),
+    Wake(Waker),
+}
+
+
+struct Timer {
+    period:Option<u64>,
+    action:Action,
+}
+
+
+/// One core's timers
+struct TimerQueue {
+    /// By uptime in nanoseconds they're due at, then by ID so timers due together go in order
+    timers:BTreeMap<(u64,u64),Timer>,
+    /// When each timer is due, to find it again
+    due:BTreeMap<u64,u64>,
+}
+impl TimerQueue {
+    const fn new()->TimerQueue {
+        TimerQueue {
+            timers:BTreeMap::new(),
+            due:BTreeMap::new(),
+        }
+    }
+    fn insert(&mut self,id:u64,due:u64,timer:Timer) {
+        self.timers.insert((due,id),timer);
+        self.due.insert(id,due);
+    }
+    fn remove(&mut self,id:u64)->bool {
+        match self.due.remove(&id) {
+            Some(due)=>self.timers.remove(&(due,id)).is_some(),
+            None=>false,
+        }
+    }
+    fn next_due(&self)->Option<u64> {
+        self.timers.keys().next().map(|(due,_)|*due)
+    }
+    /// Takes the first timer if it's due by `now`. A periodic one goes back in for its next
+    /// period; one that fell behind skips the periods it missed.
+    fn pop_due(&mut self,now:u64)->Option<Action> {
+        let (&(due,id),_)=self.timers.iter().next()?;
+        if due>now {return None}
+        let timer=self.timers.remove(&(due,id))?;
+        self.due.remove(&id);
+        if let Some(period)=timer.period {
+            let action=timer.action.clone();
+            self.insert(id,(due+period).max(now+1),timer);
+            return Some(action);
+        }
+        return Some(timer.action);
+    }
+}
+
+
+fn schedule(delay:Duration,period:Option<u64>,action:Action)->TimerId {
+    let core=current_core();
+    let id=NEXT_ID.fetch_add(1,Ordering::Relaxed);
+    let due=(uptime()+delay).as_nanos() as u64;
+    without_interrupts(||{
+        QUEUES[core].lock().insert(id,due,Timer{period,action});
+        arm();
+    });
+    return TimerId{core,id};
+}
+
+
+/// Calls `callback` from the timer interrupt once `delay` has passed
+pub fn call_after(delay:Duration,callback:fn())->TimerId {
+    schedule(delay,None,Action::Call(callback))
+}
+/// Calls `callback` from the timer interrupt every `period`, starting one period from now
+pub fn call_every(period:Duration,callback:fn())->TimerId {
+    let period_ns=(period.as_nanos() as u64).max(1);
+    schedule(period,Some(period_ns),Action::Call(callback))
+}
+/// Wakes `waker` once `delay` has passed
+pub fn wake_after(delay:Duration,waker:Waker)->TimerId {
+    schedule(delay,None,Action::Wake(waker))
+}
+/// Stops a timer from going off (again). Returns whether it was still waiting.
+pub fn cancel(id:TimerId)->bool {
+    without_interrupts(||QUEUES[id.core].lock().remove(id.id))
+}
+/// When the first timer on this core is due, as uptime
+pub fn next_due()->Option<Duration> {
+    without_interrupts(||QUEUES[current_core()].lock().next_due()).map(Duration::from_nanos)
+}
+
+
+/// Sets this core's LAPIC timer, or the HPET's event comparator without one, for the first timer,
+/// so it goes off on time instead of at the next tick. Called with interrupts off.
+fn arm() {
+    let Some(due)=QUEUES[current_core()].lock().next_due() else {return};
+    let now=uptime().as_nanos() as u64;
+    let delay=Duration::from_nanos(due.saturating_sub(now));
+    if lapic::is_enabled() {
+        lapic::arm(delay);
+    } else if hpet::is_ticking() {
+        hpet::set_one_shot(hpet::EVENT_TIMER,delay);
+    }
+}
+/// Runs whatever is due on this core, one at a time so timers can make or cancel others, then
+/// arms the timer interrupt for the next one. Called from timer interrupts.
+pub fn run() {
+    let queue=&QUEUES[current_core()];
+    loop {
+        let now=uptime().as_nanos() as u64;
+        let Some(action)=queue.lock().pop_due(now) else {break};
+        match action {
+            Action::Call(callback)=>callback(),
+            Action::Wake(waker)=>waker.wake(),
+        }
+    }
+    arm();
+}
+
+
+/// Done once `due` is reached. Made by [`delay`] and [`delay_until`].
+pub struct Delay {
+    /// Uptime in nanoseconds
+    due:u64,
+    timer:Option<TimerId>,
+}
+impl Future for Delay {
+    type Output=();
+    fn poll(mut self:Pin<&mut Self>,cx:&mut Context)->Poll<()> {
+        if let Some(timer)=self.timer.take() {
+            cancel(timer);
+        }
+        let now=uptime().as_nanos() as u64;
+        if now>=self.due {
+            return Poll::Ready(());
+        }
+        // the waker may have changed since the last poll, so it gets a new timer every time
+        self.timer=Some(wake_after(Duration::from_nanos(self.due-now),cx.waker().clone()));
+        Poll::Pending
+    }
+}
+impl Drop for Delay {
+    fn drop(&mut self) {
+        if let Some(timer)=self.timer.take() {
+            cancel(timer);
+        }
+    }
+}
+
+
+/// For tasks: waits until `duration` has passed without holding up the core
+pub fn delay(duration:Duration)->Delay {
+    delay_until(uptime()+duration)
+}
+/// Waits until the uptime is `deadline`
+pub fn delay_until(deadline:Duration)->Delay {
+    Delay{due:deadline.as_nanos() as u64,timer:None}
+}
+/// Gives up on `future` if it hasn't finished within `duration`
+pub async fn timeout<F:Future>(duration:Duration,future:F)->Result<F::Output,TimedOut> {
+    let mut future=core::pin::pin!(future);
+    let mut delay=delay(duration);
+    core::future::poll_fn(|cx|{
+        if let Poll::Ready(output)=future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        if Pin::new(&mut delay).poll(cx).is_ready() {
+            return Poll::Ready(Err(TimedOut));
+        }
+        Poll::Pending
+    }).await
+}
@@ -0,0 +1,94 @@
+//! The time stamp counter, which counts up by some fixed amount every cycle of something. CPUID
+//! leaf 0x15 (crystal clock and ratio) or 0x16 (base frequency) can say how fast; otherwise it is
+//! timed against the HPET or the PIT. Only an invariant TSC keeps the same rate through power states, so
+//! only that one is used as the clock unless `clock.tsc` says otherwise.
+
+
+use core::sync::atomic::{
+    AtomicBool,
+    AtomicU64,
+    Ordering,
+};
+use raw_cpuid::CpuId;
+use super::hpet;
+
+
+/// TSC counts a second, 0 until it's known
+static HZ:AtomicU64=AtomicU64::new(0);
+static INVARIANT:AtomicBool=AtomicBool::new(false);
+/// The TSC and the uptime when the TSC became the clock, 0 for a TSC that isn't the clock
+static START_TSC:AtomicU64=AtomicU64::new(0);
+static START_NS:AtomicU64=AtomicU64::new(0);
+
+
+/// Where the frequency came from
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum Source {
+    /// Leaf 0x15, exact
+    Crystal,
+    /// Leaf 0x16, the nominal base frequency
+    BaseFrequency,
+    /// Counted against the HPET
+    Hpet,
+    /// Counted against the PIT
+    Pit,
+}
+
+
+pub fn read()->u64 {
+    unsafe{core::arch::x86_64::_rdtsc()}
+}
+pub fn frequency()->Option<u64> {
+    match HZ.load(Ordering::Relaxed) {
+        0=>None,
+        hz=>Some(hz),
+    }
+}
+pub fn is_invariant()->bool {
+    INVARIANT.load(Ordering::Relaxed)
+}
+pub fn ticks_to_ns(ticks:u64,hz:u64)->u64 {
+    (ticks as u128*1_000_000_000/hz as u128) as u64
+}
+pub fn ns_to_ticks(ns:u64,hz:u64)->u64 {
+    (ns as u128*hz as u128/1_000_000_000) as u64
+}
+
+
+/// What CPUID says the TSC runs at
+fn cpuid_frequency(cpuid:&CpuId)->Option<(u64,Source)> {
+    if let Some(hz)=cpuid.get_tsc_info().and_then(|info|info.tsc_frequency()) {
+        return Some((hz,Source::Crystal));
+    }
+    let mhz=cpuid.get_processor_frequency_info()?.processor_base_frequency();
+    if mhz==0 {return None}
+    return Some((mhz as u64*1_000_000,Source::BaseFrequency));
+}
+/// Counts TSC ticks over `ms` milliseconds of the HPET, or the PIT without one
+pub fn calibrate(ms:u64)->(u64,Source) {
+    let source=if hpet::is_present() {Source::Hpet} else {Source::Pit};
+    let start=read();
+    super::reference_delay(ms);
+    let end=read();
+    return ((end-start)*1000/ms,source);
+}
+/// Works out the frequency. Returns it and where it came from, or `None` without a TSC.
+pub fn init(cpuid:&CpuId)->Option<(u64,Source)> {
+    if !cpuid.get_feature_info().map(|f|f.has_tsc()).unwrap_or(false) {return None}
+    INVARIANT.store(cpuid.get_advanced_power_mgmt_info().map(|a|a.has_invariant_tsc()).unwrap_or(false),Ordering::Relaxed);
+    let (hz,source)=cpuid_frequency(cpuid).unwrap_or_else(||calibrate(50));
+    HZ.store(hz,Ordering::Relaxed);
+    return Some((hz,source));
+}
+/// Makes the TSC the clock, going on from `uptime_ns`
+pub fn start_clock(uptime_ns:u64) {
+    START_NS.store(uptime_ns,Ordering::Relaxed);
+    START_TSC.store(read(),Ordering::Release);
+}
+/// Uptime from the TSC, if it is the clock
+pub fn uptime_ns()->Option<u64> {
+    let start=START_TSC.load(Ordering::Acquire);
+    if start==0 {return None}
+    let hz=frequency()?;
+    Some(START_NS.load(Ordering::Relaxed)+ticks_to_ns(read().saturating_sub(start),hz))
+}
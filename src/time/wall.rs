@@ -0,0 +1,96 @@
+//! Wall clock time. At boot the date comes from BOOTBOOT, or from the [`rtc`](super::rtc) if
+//! BOOTBOOT didn't have one, and is pinned to the uptime it was read at. From then on it moves
+//! with [`uptime`], so it is as steady as the clock is. Local time is UTC plus a timezone offset
+//! in minutes.
+
+
+use core::{
+    sync::atomic::{
+        AtomicBool,
+        AtomicI32,
+        AtomicU64,
+        Ordering,
+    },
+    time::Duration,
+};
+use super::{
+    date::DateTime,
+    rtc,
+    uptime,
+};
+
+
+/// Nanoseconds after 1970 in UTC when the uptime was 0
+static BOOT_UNIX_NS:AtomicU64=AtomicU64::new(0);
+static KNOWN:AtomicBool=AtomicBool::new(false);
+/// Minutes ahead of UTC
+static TIMEZONE:AtomicI32=AtomicI32::new(0);
+
+
+/// Where the time came from
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum Source {
+    Bootboot,
+    Rtc,
+}
+
+
+/// Whether the date is known. Until it is, [`now`] counts from 1970.
+pub fn is_known()->bool {
+    KNOWN.load(Ordering::Relaxed)
+}
+/// Nanoseconds after 1970 in UTC at `uptime`
+pub fn unix_ns_at(uptime:Duration)->u64 {
+    BOOT_UNIX_NS.load(Ordering::Relaxed)+uptime.as_nanos() as u64
+}
+pub fn unix_ns()->u64 {
+    unix_ns_at(uptime())
+}
+/// The date and time in UTC
+pub fn now()->DateTime {
+    DateTime::from_unix_ns(unix_ns())
+}
+/// The date and time in the local timezone
+pub fn local_now()->DateTime {
+    now().with_offset(timezone())
+}
+/// The local date and time at `uptime`, for log timestamps
+pub fn local_at(uptime:Duration)->DateTime {
+    DateTime::from_unix_ns(unix_ns_at(uptime)).with_offset(timezone())
+}
+pub fn timezone()->i16 {
+    TIMEZONE.load(Ordering::Relaxed) as i16
+}
+/// Clamped to a day either way
+pub fn set_timezone(minutes:i16) {
+    TIMEZONE.store(minutes.clamp(-1440,1440) as i32,Ordering::Relaxed);
+}
+/// Makes it `time` now, in whatever zone its `offset` says
+pub fn set(time:&DateTime) {
+    let Some(ns)=time.to_unix_ns() else {return};
+    let up=uptime().as_nanos() as u64;
+    BOOT_UNIX_NS.store(ns.saturating_sub(up),Ordering::Relaxed);
+    KNOWN.store(true,Ordering::Relaxed);
+}
+/// Sets the clock and the RTC. The RTC gets local time if `local_rtc`.
+pub fn set_and_save(time:&DateTime,local_rtc:bool) {
+    set(time);
+    let now=if local_rtc {local_now()} else {now()};
+    rtc::write(&now);
+}
+
+
+/// Starts the clock from BOOTBOOT's date or the RTC. `local_rtc` says whether the RTC keeps local
+/// time rather than UTC.
+pub fn init(bootboot:Option<DateTime>,local_rtc:bool)->Option<(DateTime,Source)> {
+    if let Some(time)=bootboot {
+        set(&time);
+        return Some((time,Source::Bootboot));
+    }
+    let mut time=rtc::read()?;
+    if local_rtc {
+        time.offset=timezone();
+    }
+    set(&time);
+    return Some((time,Source::Rtc));
+}
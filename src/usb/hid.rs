@@ -0,0 +1,200 @@
+//! HID keyboards and mice, in the boot protocol so there are no report descriptors to parse. A boot
+//! keyboard report is the modifier bits, a reserved byte and up to 6 held keys; we compare it with
+//! the last one to find presses and releases. A boot mouse report is buttons, x, y and maybe a
+//! wheel.
+
+
+use crate::input::{
+    self,
+    KeyCode,
+    Modifiers,
+    mouse::{
+        MouseButtons,
+        Packet,
+    },
+    scancode::ISO_KEY,
+};
+
+
+/// Modifier bits in the first byte of a keyboard report, lowest first
+const MODIFIER_KEYS:[KeyCode;8]=[
+    KeyCode::ControlLeft,KeyCode::ShiftLeft,KeyCode::AltLeft,KeyCode::WindowsLeft,
+    KeyCode::ControlRight,KeyCode::ShiftRight,KeyCode::AltRight,KeyCode::WindowsRight,
+];
+/// Sent in every key slot when more keys are held than the report has room for
+const ERROR_ROLLOVER:u8=0x01;
+
+
+/// Which boot protocol an interface speaks, from its protocol byte
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum Kind {
+    Keyboard=1,
+    Mouse=2,
+}
+impl Kind {
+    pub fn from_protocol(protocol:u8)->Option<Kind> {
+        match protocol {
+            1=>Some(Kind::Keyboard),
+            2=>Some(Kind::Mouse),
+            _=>None,
+        }
+    }
+}
+
+
+pub enum Driver {
+    Keyboard(Keyboard),
+    Mouse(Mouse),
+}
+impl Driver {
+    pub fn new(kind:Kind)->Driver {
+        match kind {
+            Kind::Keyboard=>Driver::Keyboard(Keyboard::new()),
+            Kind::Mouse=>Driver::Mouse(Mouse),
+        }
+    }
+    pub fn name(&self)->&'static str {
+        match self {
+            Driver::Keyboard(_)=>"keyboard",
+            Driver::Mouse(_)=>"mouse",
+        }
+    }
+    pub fn report(&mut self,report:&[u8]) {
+        match self {
+            Driver::Keyboard(keyboard)=>keyboard.report(report),
+            Driver::Mouse(mouse)=>mouse.report(report),
+        }
+    }
+    /// The device is gone, let go of anything it was holding down
+    pub fn disconnect(&mut self) {
+        if let Driver::Keyboard(keyboard)=self {
+            keyboard.report(&[0;8]);
+        }
+    }
+}
+
+
+pub struct Keyboard {
+    last:[u8;8],
+    /// What the LEDs were last set to, `None` until the first time
+    pub leds:Option<u8>,
+}
+impl Keyboard {
+    pub fn new()->Keyboard {
+        Keyboard {
+            last:[0;8],
+            leds:None,
+        }
+    }
+    pub fn report(&mut self,report:&[u8]) {
+        if report.len()<8||report[2]==ERROR_ROLLOVER {return}
+        let mut new=[0;8];
+        new.copy_from_slice(&report[..8]);
+        diff(&self.last,&new,input::push_key);
+        self.last=new;
+    }
+}
+
+
+pub struct Mouse;
+impl Mouse {
+    pub fn report(&mut self,report:&[u8]) {
+        if let Some(packet)=mouse_packet(report) {
+            input::push_mouse_packet(packet);
+        }
+    }
+}
+
+
+/// Turns a mouse report into a packet the way PS/2 mice send them: up is positive for y and
+/// towards the user is positive for the wheel, HID has both the other way around
+fn mouse_packet(report:&[u8])->Option<Packet> {
+    if report.len()<3 {return None}
+    let wheel=report.get(3).map(|w|*w as i8 as i32).unwrap_or(0);
+    Some(Packet {
+        dx:report[1] as i8 as i32,
+        dy:-(report[2] as i8 as i32),
+        wheel:-wheel,
+        buttons:MouseButtons(report[0]&0x1F),
+    })
+}
+
+
+/// Calls `key` for each key that went down or up between two keyboard reports
+fn diff(old:&[u8;8],new:&[u8;8],mut key:impl FnMut(KeyCode,bool)) {
+    for (bit,code) in MODIFIER_KEYS.iter().enumerate() {
+        let (was,is)=(old[0]>>bit&1==1,new[0]>>bit&1==1);
+        if was!=is {
+            key(*code,is);
+        }
+    }
+    for usage in old[2..].iter().filter(|u|!new[2..].contains(u)) {
+        if let Some(code)=usage_to_key(*usage) {
+            key(code,false);
+        }
+    }
+    for usage in new[2..].iter().filter(|u|!old[2..].contains(u)) {
+        if let Some(code)=usage_to_key(*usage) {
+            key(code,true);
+        }
+    }
+}
+
+
+/// The LED output report: Num Lock, Caps Lock and Scroll Lock from the lowest bit
+pub fn leds(mods:&Modifiers)->u8 {
+    mods.num_lock as u8|(mods.caps_lock as u8)<<1|(mods.scroll_lock as u8)<<2
+}
+
+
+/// Keyboard page usages. Both the US backslash and the ISO hash key are the key above Enter, like
+/// PS/2 sends them.
+fn usage_to_key(usage:u8)->Option<KeyCode> {
+    use KeyCode::*;
+    const LETTERS:[KeyCode;26]=[A,B,C,D,E,F,G,H,I,J,K,L,M,N,O,P,Q,R,S,T,U,V,W,X,Y,Z];
+    const DIGITS:[KeyCode;10]=[Key1,Key2,Key3,Key4,Key5,Key6,Key7,Key8,Key9,Key0];
+    const FKEYS:[KeyCode;12]=[F1,F2,F3,F4,F5,F6,F7,F8,F9,F10,F11,F12];
+    const NUMPAD:[KeyCode;10]=[Numpad1,Numpad2,Numpad3,Numpad4,Numpad5,Numpad6,Numpad7,Numpad8,Numpad9,Numpad0];
+    Some(match usage {
+        0x04..=0x1D=>LETTERS[usage as usize-0x04],
+        0x1E..=0x27=>DIGITS[usage as usize-0x1E],
+        0x28=>Enter,0x29=>Escape,0x2A=>Backspace,0x2B=>Tab,0x2C=>Spacebar,
+        0x2D=>Minus,0x2E=>Equals,0x2F=>BracketSquareLeft,0x30=>BracketSquareRight,
+        0x31|0x32=>BackSlash,0x33=>SemiColon,0x34=>Quote,0x35=>BackTick,
+        0x36=>Comma,0x37=>Fullstop,0x38=>Slash,0x39=>CapsLock,
+        0x3A..=0x45=>FKEYS[usage as usize-0x3A],
+        0x46=>PrintScreen,0x47=>ScrollLock,0x48=>PauseBreak,
+        0x49=>Insert,0x4A=>Home,0x4B=>PageUp,0x4C=>Delete,0x4D=>End,0x4E=>PageDown,
+        0x4F=>ArrowRight,0x50=>ArrowLeft,0x51=>ArrowDown,0x52=>ArrowUp,
+        0x53=>NumpadLock,0x54=>NumpadSlash,0x55=>NumpadStar,0x56=>NumpadMinus,
+        0x57=>NumpadPlus,0x58=>NumpadEnter,
+        0x59..=0x62=>NUMPAD[usage as usize-0x59],
+        0x63=>NumpadPeriod,0x64=>ISO_KEY,0x65=>Menus,
+        0x7F=>Mute,0x80=>VolumeUp,0x81=>VolumeDown,
+        _=>return None,
+    })
+}
+
+
+/// Report diffing and mouse packets
+pub fn test_reports()->Result<(),&'static str> {
+    use alloc::vec::Vec;
+    let mut keys=Vec::new();
+    // Left Shift and A down, then A up and B down
+    diff(&[0;8],&[0x02,0,0x04,0,0,0,0,0],|c,d|keys.push((c,d)));
+    diff(&[0x02,0,0x04,0,0,0,0,0],&[0x02,0,0,0x05,0,0,0,0],|c,d|keys.push((c,d)));
+    if keys!=[(KeyCode::ShiftLeft,true),(KeyCode::A,true),(KeyCode::A,false),(KeyCode::B,true)] {
+        return Err("keyboard reports diffed wrong");
+    }
+    if usage_to_key(0x64)!=Some(ISO_KEY)||usage_to_key(0x32)!=Some(KeyCode::BackSlash) {
+        return Err("ISO keys mapped wrong");
+    }
+    if mouse_packet(&[0x01,0xFF,0x02,0x01])!=Some(Packet{dx:-1,dy:-2,wheel:-1,buttons:MouseButtons(MouseButtons::LEFT)}) {
+        return Err("mouse report turned into the wrong packet");
+    }
+    let mods=Modifiers{caps_lock:true,..Modifiers::default()};
+    if leds(&mods)!=0x02 {
+        return Err("LED report packed wrong");
+    }
+    return Ok(());
+}
@@ -0,0 +1,396 @@
+//! USB through xHCI controllers. Each controller found on PCI gets a task that looks at its event
+//! ring every timer tick; the controller's own interrupts aren't used. Devices are enumerated when
+//! their port says something is plugged in, hubs included, and keyboards and mice that speak the
+//! HID boot protocol are handed to [`hid`], which feeds [`crate::input`] like the PS/2 drivers do.
+//!
+//! Everything the controller reads or writes lives in single
+//! [`DmaPage`](crate::memory::dma::DmaPage)s.
+
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::{
+    config::{
+        self,
+        Opt,
+        Kind,
+    },
+    shell::{
+        self,
+        Command,
+    },
+    pci,
+    println,
+    info,
+    warn,
+};
+
+
+pub mod xhci;
+pub mod hid;
+
+
+pub static USB:Opt=Opt::new("usb",Kind::Bool,"true","Use xHCI USB controllers and the keyboards and mice on them");
+
+
+static LSUSB:Command=Command::new("lsusb","","List USB devices",lsusb);
+
+
+/// What each controller has plugged in, for `lsusb`
+static DEVICES:Mutex<Vec<DeviceInfo>>=Mutex::new(Vec::new());
+
+
+pub const DESC_DEVICE:u8=1;
+pub const DESC_CONFIG:u8=2;
+pub const DESC_STRING:u8=3;
+pub const DESC_INTERFACE:u8=4;
+pub const DESC_ENDPOINT:u8=5;
+pub const DESC_HUB:u8=0x29;
+
+pub const REQ_GET_STATUS:u8=0;
+pub const REQ_CLEAR_FEATURE:u8=1;
+pub const REQ_SET_FEATURE:u8=3;
+pub const REQ_GET_DESCRIPTOR:u8=6;
+pub const REQ_SET_CONFIGURATION:u8=9;
+pub const REQ_SET_REPORT:u8=9;
+pub const REQ_SET_IDLE:u8=0x0A;
+pub const REQ_SET_PROTOCOL:u8=0x0B;
+
+pub const CLASS_HID:u8=3;
+pub const CLASS_HUB:u8=9;
+
+
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum UsbError {
+    /// The controller didn't answer in time
+    Timeout,
+    /// A command or transfer finished with this xHCI completion code
+    Completion(u8),
+    /// The device sent something that doesn't parse
+    BadDescriptor,
+    /// Out of DMA pages, slots or ring space
+    NoResources,
+    /// The controller is halted or never came out of reset
+    Controller,
+    Unsupported,
+}
+
+
+/// How fast a device talks, with xHCI's default protocol speed IDs
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum Speed {
+    Full=1,
+    Low=2,
+    High=3,
+    Super=4,
+    SuperPlus=5,
+}
+impl Speed {
+    pub fn from_id(id:u8)->Option<Speed> {
+        Some(match id {
+            1=>Speed::Full,
+            2=>Speed::Low,
+            3=>Speed::High,
+            4=>Speed::Super,
+            5=>Speed::SuperPlus,
+            _=>return None,
+        })
+    }
+    pub fn id(&self)->u8 {
+        *self as u8
+    }
+    /// What endpoint 0 takes before the device descriptor says otherwise
+    pub fn default_max_packet(&self)->u16 {
+        match self {
+            Speed::Low|Speed::Full=>8,
+            Speed::High=>64,
+            Speed::Super|Speed::SuperPlus=>512,
+        }
+    }
+    /// Low and full speed devices behind a high speed hub go through its transaction translator
+    pub fn is_slow(&self)->bool {
+        matches!(self,Speed::Low|Speed::Full)
+    }
+    pub fn name(&self)->&'static str {
+        match self {
+            Speed::Low=>"1.5Mb/s",
+            Speed::Full=>"12Mb/s",
+            Speed::High=>"480Mb/s",
+            Speed::Super=>"5Gb/s",
+            Speed::SuperPlus=>"10Gb/s",
+        }
+    }
+}
+
+
+/// The 8 bytes that start every control transfer
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub struct SetupPacket {
+    pub request_type:u8,
+    pub request:u8,
+    pub value:u16,
+    pub index:u16,
+    pub length:u16,
+}
+#[allow(dead_code)]
+impl SetupPacket {
+    /// Device to host
+    pub const IN:u8=0x80;
+    pub const CLASS:u8=0x20;
+    pub const TO_INTERFACE:u8=0x01;
+    pub const TO_OTHER:u8=0x03;
+    pub fn is_in(&self)->bool {
+        self.request_type&Self::IN!=0
+    }
+    pub fn as_u64(&self)->u64 {
+        self.request_type as u64|(self.request as u64)<<8|(self.value as u64)<<16|(self.index as u64)<<32|(self.length as u64)<<48
+    }
+    pub fn get_descriptor(kind:u8,index:u8,length:u16)->SetupPacket {
+        SetupPacket{request_type:Self::IN,request:REQ_GET_DESCRIPTOR,value:(kind as u16)<<8|index as u16,index:0,length}
+    }
+    pub fn set_configuration(value:u8)->SetupPacket {
+        SetupPacket{request_type:0,request:REQ_SET_CONFIGURATION,value:value as u16,index:0,length:0}
+    }
+    pub fn hub_descriptor(length:u16)->SetupPacket {
+        SetupPacket{request_type:Self::IN|Self::CLASS,request:REQ_GET_DESCRIPTOR,value:(DESC_HUB as u16)<<8,index:0,length}
+    }
+    pub fn port_status(port:u8)->SetupPacket {
+        SetupPacket{request_type:Self::IN|Self::CLASS|Self::TO_OTHER,request:REQ_GET_STATUS,value:0,index:port as u16,length:4}
+    }
+    pub fn set_port_feature(port:u8,feature:u16)->SetupPacket {
+        SetupPacket{request_type:Self::CLASS|Self::TO_OTHER,request:REQ_SET_FEATURE,value:feature,index:port as u16,length:0}
+    }
+    pub fn clear_port_feature(port:u8,feature:u16)->SetupPacket {
+        SetupPacket{request_type:Self::CLASS|Self::TO_OTHER,request:REQ_CLEAR_FEATURE,value:feature,index:port as u16,length:0}
+    }
+    /// 0 is the boot protocol
+    pub fn set_protocol(interface:u8,protocol:u16)->SetupPacket {
+        SetupPacket{request_type:Self::CLASS|Self::TO_INTERFACE,request:REQ_SET_PROTOCOL,value:protocol,index:interface as u16,length:0}
+    }
+    /// Only report when something changes
+    pub fn set_idle(interface:u8)->SetupPacket {
+        SetupPacket{request_type:Self::CLASS|Self::TO_INTERFACE,request:REQ_SET_IDLE,value:0,index:interface as u16,length:0}
+    }
+    /// An output report, the keyboard LEDs
+    pub fn set_report(interface:u8,length:u16)->SetupPacket {
+        SetupPacket{request_type:Self::CLASS|Self::TO_INTERFACE,request:REQ_SET_REPORT,value:0x0200,index:interface as u16,length}
+    }
+}
+
+
+#[derive(Debug,Copy,Clone,PartialEq,Eq,Default)]
+pub struct DeviceDescriptor {
+    pub usb_version:u16,
+    pub class:u8,
+    pub subclass:u8,
+    pub protocol:u8,
+    pub max_packet0:u8,
+    pub vendor_id:u16,
+    pub product_id:u16,
+    pub manufacturer:u8,
+    pub product:u8,
+    pub configurations:u8,
+}
+impl DeviceDescriptor {
+    pub const LEN:usize=18;
+    /// The first 8 bytes are enough for [`max_packet0`](Self::max_packet0)
+    pub fn parse(bytes:&[u8])->Result<DeviceDescriptor,UsbError> {
+        if bytes.len()<8||bytes[1]!=DESC_DEVICE {return Err(UsbError::BadDescriptor)}
+        let word=|i:usize|bytes.get(i..i+2).map(|b|u16::from_le_bytes([b[0],b[1]])).unwrap_or(0);
+        let byte=|i:usize|bytes.get(i).copied().unwrap_or(0);
+        Ok(DeviceDescriptor {
+            usb_version:word(2),
+            class:bytes[4],
+            subclass:bytes[5],
+            protocol:bytes[6],
+            max_packet0:bytes[7],
+            vendor_id:word(8),
+            product_id:word(10),
+            manufacturer:byte(14),
+            product:byte(15),
+            configurations:byte(17),
+        })
+    }
+}
+
+
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub struct Endpoint {
+    /// Bit 7 set for IN
+    pub address:u8,
+    pub attributes:u8,
+    pub max_packet:u16,
+    pub interval:u8,
+}
+#[allow(dead_code)]
+impl Endpoint {
+    pub const CONTROL:u8=0;
+    pub const ISOCHRONOUS:u8=1;
+    pub const BULK:u8=2;
+    pub const INTERRUPT:u8=3;
+    pub fn number(&self)->u8 {
+        self.address&0x0F
+    }
+    pub fn is_in(&self)->bool {
+        self.address&0x80!=0
+    }
+    pub fn kind(&self)->u8 {
+        self.attributes&3
+    }
+}
+
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Interface {
+    pub number:u8,
+    pub alternate:u8,
+    pub class:u8,
+    pub subclass:u8,
+    pub protocol:u8,
+    pub endpoints:Vec<Endpoint>,
+}
+
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Configuration {
+    pub value:u8,
+    pub interfaces:Vec<Interface>,
+}
+impl Configuration {
+    /// The whole configuration, with the interface and endpoint descriptors that follow it.
+    /// Descriptors this doesn't know (HID, class specific) are skipped.
+    pub fn parse(bytes:&[u8])->Result<Configuration,UsbError> {
+        if bytes.len()<9||bytes[1]!=DESC_CONFIG {return Err(UsbError::BadDescriptor)}
+        let total=(u16::from_le_bytes([bytes[2],bytes[3]]) as usize).min(bytes.len());
+        let mut config=Configuration{value:bytes[5],interfaces:Vec::new()};
+        let mut i=bytes[0] as usize;
+        while i+2<=total {
+            let len=bytes[i] as usize;
+            if len<2||i+len>total {return Err(UsbError::BadDescriptor)}
+            let desc=&bytes[i..i+len];
+            match desc[1] {
+                DESC_INTERFACE if len>=9=>config.interfaces.push(Interface {
+                    number:desc[2],
+                    alternate:desc[3],
+                    class:desc[5],
+                    subclass:desc[6],
+                    protocol:desc[7],
+                    endpoints:Vec::new(),
+                }),
+                DESC_ENDPOINT if len>=7=>{
+                    let interface=config.interfaces.last_mut().ok_or(UsbError::BadDescriptor)?;
+                    interface.endpoints.push(Endpoint {
+                        address:desc[2],
+                        attributes:desc[3],
+                        max_packet:u16::from_le_bytes([desc[4],desc[5]])&0x7FF,
+                        interval:desc[6],
+                    });
+                },
+                _=>{},
+            }
+            i+=len;
+        }
+        return Ok(config);
+    }
+}
+
+
+/// A string descriptor is UTF-16 after a 2 byte header
+pub fn parse_string(bytes:&[u8])->Option<String> {
+    if bytes.len()<2||bytes[1]!=DESC_STRING {return None}
+    let len=(bytes[0] as usize).min(bytes.len());
+    let units=bytes[2..len].chunks_exact(2).map(|c|u16::from_le_bytes([c[0],c[1]]));
+    Some(char::decode_utf16(units).map(|c|c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect())
+}
+
+
+/// One line of `lsusb`
+#[derive(Debug,Clone)]
+pub struct DeviceInfo {
+    pub controller:usize,
+    pub slot:u8,
+    pub root_port:u8,
+    pub route:u32,
+    pub speed:Speed,
+    pub descriptor:DeviceDescriptor,
+    pub product:String,
+    /// What the kernel does with it, like `keyboard` or `hub`
+    pub driver:&'static str,
+}
+
+
+pub fn add_device(info:DeviceInfo) {
+    without_interrupts(||DEVICES.lock().push(info));
+}
+pub fn remove_device(controller:usize,slot:u8) {
+    without_interrupts(||DEVICES.lock().retain(|d|!(d.controller==controller&&d.slot==slot)));
+}
+pub fn devices()->Vec<DeviceInfo> {
+    without_interrupts(||DEVICES.lock().clone())
+}
+
+
+/// Takes over every xHCI controller and starts a task for each
+pub fn init() {
+    config::register(&[&USB]);
+    shell::register(&[&LSUSB]);
+    if !USB.get_bool() {return}
+    for (index,dev) in pci::find(0x0C,0x03,0x30).into_iter().enumerate() {
+        match xhci::Controller::new(index,dev) {
+            Ok(controller)=>{
+                info!("xHCI controller at {:02x}:{:02x}.{} has {} ports",dev.bus,dev.device,dev.function,controller.ports());
+                crate::task::spawn("usb",controller.run());
+            },
+            Err(e)=>warn!("xHCI controller at {:02x}:{:02x}.{} didn't start: {:?}",dev.bus,dev.device,dev.function,e),
+        }
+    }
+}
+fn lsusb(_args:&[&str])->Result<(),&'static str> {
+    for dev in devices() {
+        println!("  {}:{} port {} route {:05x} {:04x}:{:04x} {} {} ({})",
+            dev.controller,dev.slot,dev.root_port,dev.route,
+            dev.descriptor.vendor_id,dev.descriptor.product_id,
+            dev.speed.name(),dev.product,dev.driver);
+    }
+    return Ok(());
+}
+
+
+/// Parses a keyboard's configuration, HID and endpoint descriptors included
+pub fn test_descriptors()->Result<(),&'static str> {
+    const CONFIG:[u8;34]=[
+        9,2,34,0,1,1,0,0xA0,50,
+        9,4,0,0,1,3,1,1,0,
+        9,0x21,0x11,1,0,1,0x22,63,0,    // HID, skipped
+        7,5,0x81,3,8,0,10,
+    ];
+    let config=Configuration::parse(&CONFIG).map_err(|_|"config descriptor didn't parse")?;
+    if config.value!=1||config.interfaces.len()!=1 {
+        return Err("wrong configuration value or interface count");
+    }
+    let interface=&config.interfaces[0];
+    if (interface.class,interface.subclass,interface.protocol)!=(CLASS_HID,1,1) {
+        return Err("interface class parsed wrong");
+    }
+    if interface.endpoints!=[Endpoint{address:0x81,attributes:3,max_packet:8,interval:10}] {
+        return Err("endpoint parsed wrong");
+    }
+    if Configuration::parse(&CONFIG[..12]).is_ok() {
+        return Err("a cut off descriptor parsed");
+    }
+    let device=DeviceDescriptor::parse(&[18,1,0,2,0,0,0,64]).map_err(|_|"device descriptor didn't parse")?;
+    if device.max_packet0!=64||device.usb_version!=0x200 {
+        return Err("device descriptor parsed wrong");
+    }
+    if parse_string(&[8,3,b'U',0,b'S',0,b'B',0]).as_deref()!=Some("USB") {
+        return Err("string descriptor parsed wrong");
+    }
+    if SetupPacket::get_descriptor(DESC_DEVICE,0,18).as_u64()!=0x0012_0000_0100_0680 {
+        return Err("setup packet packed wrong");
+    }
+    return Ok(());
+}
@@ -0,0 +1,1017 @@
+//! The xHCI host controller. Setting it up is: take it from the BIOS, reset it, give it the device
+//! context array, a command ring and an event ring, and start it. After that the kernel puts
+//! commands and transfers on rings as TRBs (16 byte transfer request blocks), rings a doorbell, and
+//! the controller answers on the event ring.
+//!
+//! Commands and control transfers are waited for by looking at the event ring once a timer tick, so
+//! the usb task never holds up the others; anything else that shows up meanwhile is kept for
+//! [`Controller::poll`]. Interrupt transfers are queued up front and requeued as they complete, so
+//! reading keyboards and mice never waits.
+
+
+use alloc::{
+    collections::{
+        BTreeMap,
+        VecDeque,
+    },
+    string::String,
+    vec::Vec,
+};
+use core::{
+    hint::spin_loop,
+    time::Duration,
+    ptr::{
+        read_volatile,
+        write_volatile,
+    },
+    sync::atomic::{
+        fence,
+        Ordering,
+    },
+};
+use crate::{
+    time::{
+        timer,
+        uptime,
+        next_tick,
+    },
+    memory::dma::{
+        self,
+        DmaPage,
+    },
+    pci::{
+        Bar,
+        PciDevice,
+    },
+    input,
+    info,
+    warn,
+    error,
+};
+use super::{
+    hid,
+    Configuration,
+    DeviceDescriptor,
+    DeviceInfo,
+    Endpoint,
+    Interface,
+    SetupPacket,
+    Speed,
+    UsbError,
+    CLASS_HID,
+    CLASS_HUB,
+    DESC_CONFIG,
+    DESC_DEVICE,
+    DESC_STRING,
+};
+
+
+const CAP_LENGTH:usize=0x00;
+const CAP_HCSPARAMS1:usize=0x04;
+const CAP_HCSPARAMS2:usize=0x08;
+const CAP_HCCPARAMS1:usize=0x10;
+const CAP_DBOFF:usize=0x14;
+const CAP_RTSOFF:usize=0x18;
+
+const HCC_CONTEXT_64:u32=1<<2;
+
+const OP_USBCMD:usize=0x00;
+const OP_USBSTS:usize=0x04;
+const OP_CRCR:usize=0x18;
+const OP_DCBAAP:usize=0x30;
+const OP_CONFIG:usize=0x38;
+const OP_PORTS:usize=0x400;
+
+const CMD_RUN:u32=1<<0;
+const CMD_RESET:u32=1<<1;
+const STS_HALTED:u32=1<<0;
+const STS_HOST_ERROR:u32=1<<2;
+const STS_NOT_READY:u32=1<<11;
+
+const PORTSC_CONNECTED:u32=1<<0;
+/// Writing 1 turns the port off, so it is never written back
+const PORTSC_ENABLED:u32=1<<1;
+const PORTSC_RESET:u32=1<<4;
+const PORTSC_POWER:u32=1<<9;
+const PORTSC_RESET_CHANGE:u32=1<<21;
+/// Connect, enable, warm reset, overcurrent, reset, link state and config error changes, all
+/// cleared by writing 1
+const PORTSC_CHANGES:u32=0x7F<<17;
+
+/// Interrupter 0's registers in the runtime space
+const IR0_ERSTSZ:usize=0x28;
+const IR0_ERSTBA:usize=0x30;
+const IR0_ERDP:usize=0x38;
+/// Event handler busy, cleared by writing 1 with the dequeue pointer
+const ERDP_BUSY:u64=1<<3;
+
+const XCAP_LEGACY:u32=1;
+const LEGACY_BIOS_OWNED:u32=1<<16;
+const LEGACY_OS_OWNED:u32=1<<24;
+
+const TRB_NORMAL:u32=1;
+const TRB_SETUP:u32=2;
+const TRB_DATA:u32=3;
+const TRB_STATUS:u32=4;
+const TRB_LINK:u32=6;
+const TRB_ENABLE_SLOT:u32=9;
+const TRB_DISABLE_SLOT:u32=10;
+const TRB_ADDRESS_DEVICE:u32=11;
+const TRB_CONFIGURE_ENDPOINT:u32=12;
+const TRB_EVALUATE_CONTEXT:u32=13;
+const TRB_RESET_ENDPOINT:u32=14;
+const TRB_SET_DEQUEUE:u32=16;
+const TRB_TRANSFER_EVENT:u8=32;
+const TRB_COMMAND_COMPLETION:u8=33;
+const TRB_PORT_STATUS_CHANGE:u8=34;
+
+const TRB_CYCLE:u32=1<<0;
+const TRB_TOGGLE_CYCLE:u32=1<<1;
+const TRB_SHORT_OK:u32=1<<2;
+const TRB_IOC:u32=1<<5;
+const TRB_IMMEDIATE:u32=1<<6;
+/// For data and status stages
+const TRB_DIR_IN:u32=1<<16;
+
+const CC_SUCCESS:u8=1;
+const CC_SHORT_PACKET:u8=13;
+
+const EP_CONTROL:u32=4;
+const EP_INTERRUPT_IN:u32=7;
+
+const SLOT_MULTI_TT:u32=1<<25;
+const SLOT_HUB:u32=1<<26;
+
+/// Hub port status and change bits and features
+const HUB_PORT_CONNECTION:u16=1<<0;
+const HUB_PORT_LOW_SPEED:u16=1<<9;
+const HUB_PORT_HIGH_SPEED:u16=1<<10;
+const HUB_C_CONNECTION:u16=1<<0;
+const HUB_C_RESET:u16=1<<4;
+const FEATURE_PORT_RESET:u16=4;
+const FEATURE_PORT_POWER:u16=8;
+const FEATURE_C_PORT_CONNECTION:u16=16;
+const FEATURE_C_PORT_RESET:u16=20;
+
+/// How long commands, transfers and resets get before giving up
+const TIMEOUT:Duration=Duration::from_millis(500);
+/// Hubs are asked about their ports once a second, they have no events of their own here
+const HUB_POLL:Duration=Duration::from_secs(1);
+/// Interrupt transfers kept queued on each HID endpoint
+const REPORTS:usize=8;
+const REPORT_SIZE:usize=64;
+
+
+#[repr(C)]
+#[derive(Debug,Copy,Clone,Default)]
+struct Trb {
+    parameter:u64,
+    status:u32,
+    control:u32,
+}
+impl Trb {
+    fn new(kind:u32,parameter:u64,status:u32,flags:u32)->Trb {
+        Trb{parameter,status,control:kind<<10|flags}
+    }
+    fn kind(&self)->u8 {
+        (self.control>>10&0x3F) as u8
+    }
+    fn completion(&self)->u8 {
+        (self.status>>24) as u8
+    }
+    /// Bytes of a transfer that were not sent
+    fn residual(&self)->usize {
+        (self.status&0xFF_FFFF) as usize
+    }
+    fn slot(&self)->u8 {
+        (self.control>>24) as u8
+    }
+    fn endpoint(&self)->u8 {
+        (self.control>>16&0x1F) as u8
+    }
+}
+
+
+/// A ring the kernel puts TRBs on, for commands and transfers. The last TRB links back to the
+/// start and tells the controller to flip its cycle bit.
+struct Ring {
+    page:DmaPage,
+    enqueue:usize,
+    cycle:bool,
+}
+impl Ring {
+    const LEN:usize=DmaPage::SIZE/16;
+    fn new()->Result<Ring,UsbError> {
+        let page=DmaPage::new().ok_or(UsbError::NoResources)?;
+        Ok(Ring{page,enqueue:0,cycle:true})
+    }
+    fn phys(&self)->u64 {
+        self.page.phys()
+    }
+    /// Where the controller should pick up next, with the cycle bit it should expect
+    fn dequeue_pointer(&self)->u64 {
+        self.page.phys_at(self.enqueue*16)|self.cycle as u64
+    }
+    /// Writes the TRB with the wrong cycle bit, then flips it once the rest is there
+    fn write(&mut self,index:usize,mut trb:Trb) {
+        let control=trb.control&!TRB_CYCLE|self.cycle as u32;
+        trb.control=control^TRB_CYCLE;
+        let ptr=self.page.ptr_at::<Trb>(index*16);
+        unsafe{write_volatile(ptr,trb)};
+        fence(Ordering::SeqCst);
+        unsafe{write_volatile(core::ptr::addr_of_mut!((*ptr).control),control)};
+    }
+    /// Returns the TRB's physical address, which is what its events point at
+    fn push(&mut self,trb:Trb)->u64 {
+        let phys=self.page.phys_at(self.enqueue*16);
+        self.write(self.enqueue,trb);
+        self.enqueue+=1;
+        if self.enqueue==Self::LEN-1 {
+            let link=Trb::new(TRB_LINK,self.phys(),0,TRB_TOGGLE_CYCLE);
+            self.write(self.enqueue,link);
+            self.enqueue=0;
+            self.cycle=!self.cycle;
+        }
+        return phys;
+    }
+}
+
+
+/// The one segment event ring and its segment table
+struct EventRing {
+    page:DmaPage,
+    table:DmaPage,
+    dequeue:usize,
+    cycle:bool,
+}
+impl EventRing {
+    fn new()->Result<EventRing,UsbError> {
+        let page=DmaPage::new().ok_or(UsbError::NoResources)?;
+        let table=DmaPage::new().ok_or(UsbError::NoResources)?;
+        unsafe {
+            write_volatile(table.ptr::<u64>(),page.phys());
+            write_volatile(table.ptr_at::<u32>(8),Ring::LEN as u32);
+        }
+        Ok(EventRing{page,table,dequeue:0,cycle:true})
+    }
+    fn dequeue_phys(&self)->u64 {
+        self.page.phys_at(self.dequeue*16)
+    }
+    fn pop(&mut self)->Option<Trb> {
+        let trb=unsafe{read_volatile(self.page.ptr_at::<Trb>(self.dequeue*16))};
+        if (trb.control&TRB_CYCLE==TRB_CYCLE)!=self.cycle {return None}
+        self.dequeue+=1;
+        if self.dequeue==Ring::LEN {
+            self.dequeue=0;
+            self.cycle=!self.cycle;
+        }
+        return Some(trb);
+    }
+}
+
+
+/// An interrupt IN endpoint with reports queued on it
+struct HidEndpoint {
+    dci:u8,
+    interface:u8,
+    ring:Ring,
+    buffers:DmaPage,
+    report_len:usize,
+    driver:hid::Driver,
+}
+impl HidEndpoint {
+    /// Puts a transfer for every report buffer on the ring
+    fn queue_reports(&mut self) {
+        for i in 0..REPORTS {
+            let buffer=self.buffers.phys_at(i*REPORT_SIZE);
+            self.ring.push(Trb::new(TRB_NORMAL,buffer,self.report_len as u32,TRB_IOC|TRB_SHORT_OK));
+        }
+    }
+}
+
+
+struct Hub {
+    ports:u8,
+    /// One bit per port, set while we have a device enumerated there
+    connected:u32,
+}
+
+
+/// Where a device is plugged in
+#[derive(Debug,Copy,Clone)]
+struct Attach {
+    root_port:u8,
+    /// Hub port numbers from the root down, 4 bits each
+    route:u32,
+    depth:u8,
+    speed:Speed,
+    /// The high speed hub slot and port a slow device is translated by
+    tt:Option<(u8,u8)>,
+    /// The hub slot and port, `None` on a root port
+    parent:Option<(u8,u8)>,
+}
+
+
+struct Device {
+    slot:u8,
+    at:Attach,
+    input:DmaPage,
+    output:DmaPage,
+    ep0:Ring,
+    /// For control transfer data
+    buffer:DmaPage,
+    max_packet0:u16,
+    hub:Option<Hub>,
+    hid:Vec<HidEndpoint>,
+}
+
+
+pub struct Controller {
+    index:usize,
+    op:*mut u8,
+    runtime:*mut u8,
+    doorbells:*mut u32,
+    max_ports:u8,
+    context_size:usize,
+    dcbaa:DmaPage,
+    _scratchpad:Vec<DmaPage>,
+    commands:Ring,
+    events:EventRing,
+    devices:BTreeMap<u8,Device>,
+    /// Events that came in while a command or control transfer was being waited for
+    deferred:VecDeque<Trb>,
+    last_hub_poll:Duration,
+}
+// the registers are only touched by whoever owns the controller, which is the one usb task
+unsafe impl Send for Controller {}
+#[allow(dead_code)]
+impl Controller {
+    /// Takes the controller from the firmware, resets it and starts it. What is already plugged in
+    /// is enumerated once [`run`](Self::run) starts.
+    pub fn new(index:usize,pci:PciDevice)->Result<Controller,UsbError> {
+        pci.enable();
+        let (addr,size)=match pci.bar(0) {
+            Some(Bar::Memory{addr,size,..})=>(addr,size as usize),
+            _=>return Err(UsbError::Unsupported),
+        };
+        let cap=dma::map_mmio(addr,size).map_err(|_|UsbError::NoResources)?;
+        let cap_length=unsafe{read_volatile(cap.add(CAP_LENGTH))} as usize;
+        let hcs1=read32(cap,CAP_HCSPARAMS1);
+        let hcs2=read32(cap,CAP_HCSPARAMS2);
+        let hcc1=read32(cap,CAP_HCCPARAMS1);
+        let op=unsafe{cap.add(cap_length)};
+        let runtime=unsafe{cap.add(read32(cap,CAP_RTSOFF) as usize&!0x1F)};
+        let doorbells=unsafe{cap.add(read32(cap,CAP_DBOFF) as usize&!0x3)} as *mut u32;
+        let max_slots=hcs1 as u8;
+        let max_ports=(hcs1>>24) as u8;
+        bios_handoff(cap,hcc1);
+        // stop, then reset
+        write32(op,OP_USBCMD,read32(op,OP_USBCMD)&!CMD_RUN);
+        wait_until(||read32(op,OP_USBSTS)&STS_HALTED!=0)?;
+        write32(op,OP_USBCMD,CMD_RESET);
+        wait_until(||read32(op,OP_USBCMD)&CMD_RESET==0&&read32(op,OP_USBSTS)&STS_NOT_READY==0)?;
+        write32(op,OP_CONFIG,max_slots as u32);
+        let dcbaa=DmaPage::new().ok_or(UsbError::NoResources)?;
+        let scratchpad=scratchpad(&dcbaa,hcs2)?;
+        write64(op,OP_DCBAAP,dcbaa.phys());
+        let commands=Ring::new()?;
+        write64(op,OP_CRCR,commands.phys()|1);
+        let events=EventRing::new()?;
+        write32(runtime,IR0_ERSTSZ,1);
+        write64(runtime,IR0_ERDP,events.dequeue_phys());
+        write64(runtime,IR0_ERSTBA,events.table.phys());
+        write32(op,OP_USBCMD,CMD_RUN);
+        wait_until(||read32(op,OP_USBSTS)&STS_HALTED==0)?;
+        let controller=Controller {
+            index,
+            op,
+            runtime,
+            doorbells,
+            max_ports,
+            context_size:if hcc1&HCC_CONTEXT_64!=0 {64} else {32},
+            dcbaa,
+            _scratchpad:scratchpad,
+            commands,
+            events,
+            devices:BTreeMap::new(),
+            deferred:VecDeque::new(),
+            last_hub_poll:uptime(),
+        };
+        // ports may come out of the reset switched off
+        for port in 1..=max_ports {
+            if controller.portsc(port)&PORTSC_POWER==0 {
+                controller.set_portsc(port,PORTSC_POWER);
+            }
+        }
+        return Ok(controller);
+    }
+    pub fn ports(&self)->u8 {
+        self.max_ports
+    }
+    /// Enumerates what is plugged in, then handles events every timer tick until the controller
+    /// dies
+    pub async fn run(mut self) {
+        // ports that were just powered take a moment to see what's on them
+        timer::delay(Duration::from_millis(20)).await;
+        for port in 1..=self.max_ports {
+            if self.portsc(port)&PORTSC_CONNECTED!=0 {
+                self.root_port_changed(port).await;
+            }
+        }
+        loop {
+            next_tick().await;
+            let status=read32(self.op,OP_USBSTS);
+            if status&(STS_HALTED|STS_HOST_ERROR)!=0 {
+                error!("xHCI controller {} stopped, status {:#x}",self.index,status);
+                return;
+            }
+            self.poll().await;
+        }
+    }
+    /// Deals with port changes and reports, sets keyboard LEDs and looks at hub ports
+    pub async fn poll(&mut self) {
+        while let Some(trb)=self.deferred.pop_front().or_else(||self.next_event()) {
+            match trb.kind() {
+                TRB_PORT_STATUS_CHANGE=>self.root_port_changed((trb.parameter>>24) as u8).await,
+                TRB_TRANSFER_EVENT=>self.report(trb).await,
+                _=>{},
+            }
+        }
+        self.update_leds().await;
+        if uptime()-self.last_hub_poll>=HUB_POLL {
+            self.last_hub_poll=uptime();
+            let hubs:Vec<u8>=self.devices.values().filter(|d|d.hub.is_some()).map(|d|d.slot).collect();
+            for hub in hubs {
+                if let Err(e)=self.poll_hub(hub).await {
+                    warn!("USB hub in slot {} didn't answer: {:?}",hub,e);
+                }
+            }
+        }
+    }
+
+
+    fn portsc(&self,port:u8)->u32 {
+        read32(self.op,OP_PORTS+0x10*(port as usize-1))
+    }
+    /// Sets `bits` without turning the port off or clearing changes it didn't mean to
+    fn set_portsc(&self,port:u8,bits:u32) {
+        let value=self.portsc(port)&!(PORTSC_ENABLED|PORTSC_CHANGES);
+        write32(self.op,OP_PORTS+0x10*(port as usize-1),value|bits);
+    }
+    fn ring_doorbell(&self,slot:u8,target:u8) {
+        fence(Ordering::SeqCst);
+        unsafe{write_volatile(self.doorbells.add(slot as usize),target as u32)};
+    }
+    fn next_event(&mut self)->Option<Trb> {
+        let trb=self.events.pop()?;
+        write64(self.runtime,IR0_ERDP,self.events.dequeue_phys()|ERDP_BUSY);
+        Some(trb)
+    }
+    /// Waits for the event `matches` picks out, keeping the others for [`poll`](Self::poll)
+    async fn wait_event(&mut self,mut matches:impl FnMut(&Trb)->bool)->Result<Trb,UsbError> {
+        let start=uptime();
+        loop {
+            while let Some(trb)=self.next_event() {
+                if matches(&trb) {return Ok(trb)}
+                self.deferred.push_back(trb);
+            }
+            if uptime()-start>TIMEOUT {return Err(UsbError::Timeout)}
+            next_tick().await;
+        }
+    }
+    async fn command(&mut self,trb:Trb)->Result<Trb,UsbError> {
+        let phys=self.commands.push(trb);
+        self.ring_doorbell(0,0);
+        let event=self.wait_event(|e|e.kind()==TRB_COMMAND_COMPLETION&&e.parameter==phys).await?;
+        match event.completion() {
+            CC_SUCCESS=>Ok(event),
+            code=>Err(UsbError::Completion(code)),
+        }
+    }
+
+
+    /// Runs a control transfer on endpoint 0. `out` is the data for host to device requests; for
+    /// device to host ones the bytes that came back are returned.
+    async fn control(&mut self,slot:u8,setup:SetupPacket,out:&[u8])->Result<Vec<u8>,UsbError> {
+        let len=setup.length as usize;
+        if len>DmaPage::SIZE||(!setup.is_in()&&out.len()!=len) {return Err(UsbError::Unsupported)}
+        let device=self.devices.get_mut(&slot).ok_or(UsbError::Controller)?;
+        if !setup.is_in() {
+            device.buffer.as_mut_slice()[..len].copy_from_slice(out);
+        }
+        let transfer_type=match (len,setup.is_in()) {
+            (0,_)=>0,
+            (_,false)=>2,
+            (_,true)=>3,
+        };
+        device.ep0.push(Trb::new(TRB_SETUP,setup.as_u64(),8,TRB_IMMEDIATE|transfer_type<<16));
+        let data=if len>0 {
+            let dir=if setup.is_in() {TRB_DIR_IN} else {0};
+            Some(device.ep0.push(Trb::new(TRB_DATA,device.buffer.phys(),len as u32,dir|TRB_SHORT_OK)))
+        } else {None};
+        let status_dir=if len==0||!setup.is_in() {TRB_DIR_IN} else {0};
+        device.ep0.push(Trb::new(TRB_STATUS,0,0,status_dir|TRB_IOC));
+        self.ring_doorbell(slot,1);
+        let is_ep0=|e:&Trb|e.kind()==TRB_TRANSFER_EVENT&&e.slot()==slot&&e.endpoint()==1;
+        let mut event=self.wait_event(is_ep0).await?;
+        let mut received=len;
+        if event.completion()==CC_SHORT_PACKET&&Some(event.parameter)==data {
+            received=len-event.residual().min(len);
+            event=self.wait_event(is_ep0).await?;
+        }
+        if event.completion()!=CC_SUCCESS {
+            self.recover_endpoint(slot,1).await?;
+            return Err(UsbError::Completion(event.completion()));
+        }
+        let device=self.devices.get(&slot).ok_or(UsbError::Controller)?;
+        if setup.is_in() {
+            return Ok(device.buffer.as_slice()[..received].to_vec());
+        }
+        return Ok(Vec::new());
+    }
+    /// Gets an endpoint going again after a stall, skipping whatever was left on its ring
+    async fn recover_endpoint(&mut self,slot:u8,dci:u8)->Result<(),UsbError> {
+        let target=(slot as u32)<<24|(dci as u32)<<16;
+        self.command(Trb::new(TRB_RESET_ENDPOINT,0,0,target)).await?;
+        let device=self.devices.get(&slot).ok_or(UsbError::Controller)?;
+        let dequeue=if dci==1 {
+            device.ep0.dequeue_pointer()
+        } else {
+            device.hid.iter().find(|h|h.dci==dci).ok_or(UsbError::Controller)?.ring.dequeue_pointer()
+        };
+        self.command(Trb::new(TRB_SET_DEQUEUE,dequeue,0,target)).await?;
+        return Ok(());
+    }
+
+
+    /// A pointer to context `index` in a device's input context. 0 is the input control context,
+    /// 1 the slot context and 2 onwards endpoint contexts by DCI.
+    fn input_context(&self,device:&Device,index:usize)->*mut u32 {
+        device.input.ptr_at(index*self.context_size)
+    }
+    fn output_context(&self,device:&Device,index:usize)->*mut u32 {
+        device.output.ptr_at(index*self.context_size)
+    }
+    /// Starts a fresh input context that adds the contexts in `add`, with the slot context copied
+    /// from what the controller has now
+    fn prepare_input(&self,device:&mut Device,add:u32) {
+        device.input.zero();
+        unsafe {
+            write_volatile(self.input_context(device,0).add(1),add);
+            core::ptr::copy_nonoverlapping(self.output_context(device,0),self.input_context(device,1),4);
+        }
+    }
+
+
+    async fn root_port_changed(&mut self,port:u8) {
+        if port==0||port>self.max_ports {return}
+        let status=self.portsc(port);
+        self.set_portsc(port,status&PORTSC_CHANGES);
+        let attached=self.devices.values().find(|d|d.at.parent.is_none()&&d.at.root_port==port).map(|d|d.slot);
+        match (status&PORTSC_CONNECTED!=0,attached) {
+            (true,None)=>if let Err(e)=self.attach_root(port).await {
+                warn!("USB device on port {} didn't enumerate: {:?}",port,e);
+            },
+            (false,Some(slot))=>self.detach(slot).await,
+            _=>{},
+        }
+    }
+    async fn attach_root(&mut self,port:u8)->Result<u8,UsbError> {
+        // USB 3 ports enable themselves once the link is up, USB 2 ones need a reset
+        if self.portsc(port)&PORTSC_ENABLED==0 {
+            self.set_portsc(port,PORTSC_RESET);
+            let start=uptime();
+            while self.portsc(port)&PORTSC_RESET_CHANGE==0 {
+                if uptime()-start>TIMEOUT {return Err(UsbError::Timeout)}
+                next_tick().await;
+            }
+            self.set_portsc(port,PORTSC_RESET_CHANGE);
+        }
+        let status=self.portsc(port);
+        if status&PORTSC_ENABLED==0 {return Err(UsbError::Controller)}
+        let speed=Speed::from_id((status>>10&0xF) as u8).ok_or(UsbError::Unsupported)?;
+        self.attach(Attach{root_port:port,route:0,depth:0,speed,tt:None,parent:None}).await
+    }
+    /// Gives the device a slot and an address, then reads its descriptors and finds it a driver
+    async fn attach(&mut self,at:Attach)->Result<u8,UsbError> {
+        let slot=self.command(Trb::new(TRB_ENABLE_SLOT,0,0,0)).await?.slot();
+        let device=Device {
+            slot,
+            at,
+            input:DmaPage::new().ok_or(UsbError::NoResources)?,
+            output:DmaPage::new().ok_or(UsbError::NoResources)?,
+            ep0:Ring::new()?,
+            buffer:DmaPage::new().ok_or(UsbError::NoResources)?,
+            max_packet0:at.speed.default_max_packet(),
+            hub:None,
+            hid:Vec::new(),
+        };
+        let (tt_slot,tt_port)=at.tt.unwrap_or((0,0));
+        unsafe {
+            write_volatile(self.input_context(&device,0).add(1),0b11);
+            let slot_ctx=self.input_context(&device,1);
+            write_volatile(slot_ctx,at.route|(at.speed.id() as u32)<<20|1<<27);
+            write_volatile(slot_ctx.add(1),(at.root_port as u32)<<16);
+            write_volatile(slot_ctx.add(2),tt_slot as u32|(tt_port as u32)<<8);
+            let ep0=self.input_context(&device,2);
+            write_volatile(ep0.add(1),3<<1|EP_CONTROL<<3|(device.max_packet0 as u32)<<16);
+            write_volatile(ep0.add(2) as *mut u64,device.ep0.phys()|1);
+            write_volatile(ep0.add(4),8);
+            write_volatile(self.dcbaa.ptr_at::<u64>(slot as usize*8),device.output.phys());
+        }
+        let input=device.input.phys();
+        self.devices.insert(slot,device);
+        let mut result=self.command(Trb::new(TRB_ADDRESS_DEVICE,input,0,(slot as u32)<<24)).await.map(|_|());
+        if result.is_ok() {
+            result=self.setup_device(slot).await;
+        }
+        if let Err(e)=result {
+            self.detach(slot).await;
+            return Err(e);
+        }
+        return Ok(slot);
+    }
+    async fn setup_device(&mut self,slot:u8)->Result<(),UsbError> {
+        let head=self.control(slot,SetupPacket::get_descriptor(DESC_DEVICE,0,8),&[]).await?;
+        let head=DeviceDescriptor::parse(&head)?;
+        let speed=self.devices[&slot].at.speed;
+        let max_packet0=match speed {
+            Speed::Super|Speed::SuperPlus=>1u16<<head.max_packet0.min(9),
+            _=>head.max_packet0 as u16,
+        };
+        if max_packet0!=self.devices[&slot].max_packet0 {
+            self.set_max_packet0(slot,max_packet0).await?;
+        }
+        let bytes=self.control(slot,SetupPacket::get_descriptor(DESC_DEVICE,0,DeviceDescriptor::LEN as u16),&[]).await?;
+        let descriptor=DeviceDescriptor::parse(&bytes)?;
+        let product=match descriptor.product {
+            0=>String::new(),
+            index=>self.string(slot,index).await.unwrap_or_default(),
+        };
+        let head=self.control(slot,SetupPacket::get_descriptor(DESC_CONFIG,0,9),&[]).await?;
+        if head.len()<4 {return Err(UsbError::BadDescriptor)}
+        let total=u16::from_le_bytes([head[2],head[3]]).min(DmaPage::SIZE as u16);
+        let bytes=self.control(slot,SetupPacket::get_descriptor(DESC_CONFIG,0,total),&[]).await?;
+        let config=Configuration::parse(&bytes)?;
+        self.control(slot,SetupPacket::set_configuration(config.value),&[]).await?;
+        let mut driver="none";
+        if descriptor.class==CLASS_HUB {
+            self.setup_hub(slot,descriptor.protocol).await?;
+            driver="hub";
+        } else {
+            for interface in config.interfaces.iter().filter(|i|i.alternate==0) {
+                if interface.class==CLASS_HID&&interface.subclass==1 {
+                    match self.setup_hid(slot,interface).await {
+                        Ok(name)=>driver=name,
+                        Err(e)=>warn!("USB HID interface {} in slot {} didn't start: {:?}",interface.number,slot,e),
+                    }
+                }
+            }
+        }
+        let at=self.devices[&slot].at;
+        info!("USB device {:04x}:{:04x} {} at {} on port {}, driver {}",descriptor.vendor_id,descriptor.product_id,product,speed.name(),at.root_port,driver);
+        super::add_device(DeviceInfo {
+            controller:self.index,
+            slot,
+            root_port:at.root_port,
+            route:at.route,
+            speed,
+            descriptor,
+            product,
+            driver,
+        });
+        return Ok(());
+    }
+    /// Full speed devices only say how big their endpoint 0 packets are once asked
+    async fn set_max_packet0(&mut self,slot:u8,max_packet:u16)->Result<(),UsbError> {
+        let mut device=self.devices.remove(&slot).ok_or(UsbError::Controller)?;
+        self.prepare_input(&mut device,1<<1);
+        unsafe {
+            let ep0=self.input_context(&device,2);
+            core::ptr::copy_nonoverlapping(self.output_context(&device,1),ep0,5);
+            let dword=read_volatile(ep0.add(1));
+            write_volatile(ep0.add(1),dword&0xFFFF|(max_packet as u32)<<16);
+        }
+        device.max_packet0=max_packet;
+        let input=device.input.phys();
+        self.devices.insert(slot,device);
+        self.command(Trb::new(TRB_EVALUATE_CONTEXT,input,0,(slot as u32)<<24)).await?;
+        return Ok(());
+    }
+    /// A string descriptor in US English
+    async fn string(&mut self,slot:u8,index:u8)->Result<String,UsbError> {
+        let mut setup=SetupPacket::get_descriptor(DESC_STRING,index,255);
+        setup.index=0x0409;
+        let bytes=self.control(slot,setup,&[]).await?;
+        super::parse_string(&bytes).ok_or(UsbError::BadDescriptor)
+    }
+
+
+    /// Switches a boot keyboard or mouse to the boot protocol and starts reading its reports
+    async fn setup_hid(&mut self,slot:u8,interface:&Interface)->Result<&'static str,UsbError> {
+        let kind=hid::Kind::from_protocol(interface.protocol).ok_or(UsbError::Unsupported)?;
+        let endpoint=interface.endpoints.iter()
+            .find(|e|e.is_in()&&e.kind()==Endpoint::INTERRUPT)
+            .copied()
+            .ok_or(UsbError::BadDescriptor)?;
+        self.control(slot,SetupPacket::set_protocol(interface.number,0),&[]).await?;
+        if kind==hid::Kind::Keyboard {
+            // some keyboards stall this, which is fine
+            let _=self.control(slot,SetupPacket::set_idle(interface.number),&[]).await;
+        }
+        let dci=endpoint.number()*2+1;
+        let mut hid=HidEndpoint {
+            dci,
+            interface:interface.number,
+            ring:Ring::new()?,
+            buffers:DmaPage::new().ok_or(UsbError::NoResources)?,
+            report_len:(endpoint.max_packet as usize).clamp(1,REPORT_SIZE),
+            driver:hid::Driver::new(kind),
+        };
+        self.configure_interrupt_in(slot,&endpoint,&hid.ring).await?;
+        hid.queue_reports();
+        let name=hid.driver.name();
+        self.devices.get_mut(&slot).ok_or(UsbError::Controller)?.hid.push(hid);
+        self.ring_doorbell(slot,dci);
+        return Ok(name);
+    }
+    async fn configure_interrupt_in(&mut self,slot:u8,endpoint:&Endpoint,ring:&Ring)->Result<(),UsbError> {
+        let dci=endpoint.number()*2+1;
+        let mut device=self.devices.remove(&slot).ok_or(UsbError::Controller)?;
+        let speed=device.at.speed;
+        // in 125us frames as a power of two; full and low speed intervals are in milliseconds
+        let interval=if speed.is_slow() {
+            (endpoint.interval.max(1) as u32*8).ilog2().clamp(3,10)
+        } else {
+            (endpoint.interval.clamp(1,16)-1) as u32
+        };
+        self.prepare_input(&mut device,1|1<<dci);
+        unsafe {
+            let slot_ctx=self.input_context(&device,1);
+            let entries=(read_volatile(slot_ctx)>>27).max(dci as u32);
+            write_volatile(slot_ctx,read_volatile(slot_ctx)&0x07FF_FFFF|entries<<27);
+            let ep=self.input_context(&device,dci as usize+1);
+            let max_packet=endpoint.max_packet as u32;
+            write_volatile(ep,interval<<16);
+            write_volatile(ep.add(1),3<<1|EP_INTERRUPT_IN<<3|max_packet<<16);
+            write_volatile(ep.add(2) as *mut u64,ring.phys()|1);
+            write_volatile(ep.add(4),max_packet|max_packet<<16);
+        }
+        let input=device.input.phys();
+        self.devices.insert(slot,device);
+        self.command(Trb::new(TRB_CONFIGURE_ENDPOINT,input,0,(slot as u32)<<24)).await?;
+        return Ok(());
+    }
+    /// A report came in on an interrupt endpoint: hand it to the driver and queue the buffer again.
+    /// An endpoint that stopped on an error is reset and gets all its buffers queued afresh, since
+    /// the reset skips whatever was still on its ring.
+    async fn report(&mut self,event:Trb) {
+        let (slot,dci)=(event.slot(),event.endpoint());
+        if dci==1 {return}  // a control transfer we stopped waiting for
+        let Some(hid)=self.hid_endpoint(slot,dci) else {return};
+        match event.completion() {
+            CC_SUCCESS|CC_SHORT_PACKET=>{},
+            code=>{
+                warn!("USB {} in slot {} stopped with completion code {}, resetting it",hid.driver.name(),slot,code);
+                if let Err(e)=self.recover_endpoint(slot,dci).await {
+                    warn!("USB endpoint {} in slot {} didn't reset: {:?}",dci,slot,e);
+                    return;
+                }
+                let Some(hid)=self.hid_endpoint(slot,dci) else {return};
+                hid.queue_reports();
+                self.ring_doorbell(slot,dci);
+                return;
+            },
+        }
+        let trb=unsafe{read_volatile(event.parameter as *const Trb)};
+        let Some(offset)=trb.parameter.checked_sub(hid.buffers.phys()) else {return};
+        let offset=offset as usize;
+        if offset+hid.report_len>DmaPage::SIZE {return}
+        let len=hid.report_len-event.residual().min(hid.report_len);
+        hid.driver.report(&hid.buffers.as_slice()[offset..offset+len]);
+        hid.ring.push(Trb::new(TRB_NORMAL,trb.parameter,hid.report_len as u32,TRB_IOC|TRB_SHORT_OK));
+        self.ring_doorbell(slot,dci);
+    }
+    fn hid_endpoint(&mut self,slot:u8,dci:u8)->Option<&mut HidEndpoint> {
+        self.devices.get_mut(&slot)?.hid.iter_mut().find(|h|h.dci==dci)
+    }
+    /// Keeps every keyboard's lock LEDs the same as the shared lock state
+    async fn update_leds(&mut self) {
+        let wanted=hid::leds(&input::modifiers());
+        let mut stale=Vec::new();
+        for device in self.devices.values() {
+            for hid in device.hid.iter() {
+                if let hid::Driver::Keyboard(keyboard)=&hid.driver {
+                    if keyboard.leds!=Some(wanted) {
+                        stale.push((device.slot,hid.interface));
+                    }
+                }
+            }
+        }
+        for (slot,interface) in stale {
+            let _=self.control(slot,SetupPacket::set_report(interface,1),&[wanted]).await;
+            // even if it failed, so a keyboard without LEDs isn't asked again every tick
+            let hid=self.devices.get_mut(&slot).and_then(|d|d.hid.iter_mut().find(|h|h.interface==interface));
+            if let Some(hid::Driver::Keyboard(keyboard))=hid.map(|h|&mut h.driver) {
+                keyboard.leds=Some(wanted);
+            }
+        }
+    }
+
+
+    /// Tells the controller the device is a hub and powers its ports; what's on them is enumerated
+    /// on the next [`poll`](Self::poll). Only USB 2 hubs, USB 3 ones need a different descriptor
+    /// and hub depth. Protocol 2 is a high speed hub with a transaction translator per port.
+    async fn setup_hub(&mut self,slot:u8,protocol:u8)->Result<(),UsbError> {
+        if !matches!(self.devices[&slot].at.speed,Speed::Low|Speed::Full|Speed::High) {
+            return Err(UsbError::Unsupported);
+        }
+        let desc=self.control(slot,SetupPacket::hub_descriptor(9),&[]).await?;
+        if desc.len()<7 {return Err(UsbError::BadDescriptor)}
+        let (ports,power_on_ms)=(desc[2].min(15),desc[5] as u64*2);
+        let mut device=self.devices.remove(&slot).ok_or(UsbError::Controller)?;
+        self.prepare_input(&mut device,1);
+        unsafe {
+            let slot_ctx=self.input_context(&device,1);
+            write_volatile(slot_ctx,read_volatile(slot_ctx)|SLOT_HUB|SLOT_MULTI_TT*(protocol==2) as u32);
+            write_volatile(slot_ctx.add(1),read_volatile(slot_ctx.add(1))&0x00FF_FFFF|(ports as u32)<<24);
+        }
+        device.hub=Some(Hub{ports,connected:0});
+        let input=device.input.phys();
+        self.devices.insert(slot,device);
+        self.command(Trb::new(TRB_CONFIGURE_ENDPOINT,input,0,(slot as u32)<<24)).await?;
+        for port in 1..=ports {
+            self.control(slot,SetupPacket::set_port_feature(port,FEATURE_PORT_POWER),&[]).await?;
+        }
+        timer::delay(Duration::from_millis(power_on_ms)).await;
+        // hubs are only polled from poll, which also keeps enumeration from recursing
+        self.last_hub_poll=Duration::ZERO;
+        return Ok(());
+    }
+    async fn hub_port_status(&mut self,slot:u8,port:u8)->Result<(u16,u16),UsbError> {
+        let bytes=self.control(slot,SetupPacket::port_status(port),&[]).await?;
+        if bytes.len()<4 {return Err(UsbError::BadDescriptor)}
+        Ok((u16::from_le_bytes([bytes[0],bytes[1]]),u16::from_le_bytes([bytes[2],bytes[3]])))
+    }
+    /// Enumerates devices plugged into a hub since last time and drops the ones unplugged
+    async fn poll_hub(&mut self,slot:u8)->Result<(),UsbError> {
+        let Some(ports)=self.devices.get(&slot).and_then(|d|d.hub.as_ref()).map(|h|h.ports) else {return Ok(())};
+        for port in 1..=ports {
+            let (status,change)=self.hub_port_status(slot,port).await?;
+            if change&HUB_C_CONNECTION!=0 {
+                self.control(slot,SetupPacket::clear_port_feature(port,FEATURE_C_PORT_CONNECTION),&[]).await?;
+            }
+            let Some(hub)=self.devices.get(&slot).and_then(|d|d.hub.as_ref()) else {return Ok(())};
+            let was=hub.connected&1<<port!=0;
+            let is=status&HUB_PORT_CONNECTION!=0;
+            // a quick unplug and replug only shows up as a change
+            if was&&(!is||change&HUB_C_CONNECTION!=0) {
+                self.detach_hub_port(slot,port).await;
+            }
+            if is&&(!was||change&HUB_C_CONNECTION!=0) {
+                if let Err(e)=self.attach_hub_port(slot,port).await {
+                    warn!("USB device on hub {} port {} didn't enumerate: {:?}",slot,port,e);
+                }
+            }
+        }
+        return Ok(());
+    }
+    async fn attach_hub_port(&mut self,hub:u8,port:u8)->Result<(),UsbError> {
+        self.control(hub,SetupPacket::set_port_feature(port,FEATURE_PORT_RESET),&[]).await?;
+        let start=uptime();
+        let status=loop {
+            let (status,change)=self.hub_port_status(hub,port).await?;
+            if change&HUB_C_RESET!=0 {break status}
+            if uptime()-start>TIMEOUT {return Err(UsbError::Timeout)}
+            next_tick().await;
+        };
+        self.control(hub,SetupPacket::clear_port_feature(port,FEATURE_C_PORT_RESET),&[]).await?;
+        // reset recovery
+        timer::delay(Duration::from_millis(10)).await;
+        let speed=if status&HUB_PORT_LOW_SPEED!=0 {
+            Speed::Low
+        } else if status&HUB_PORT_HIGH_SPEED!=0 {
+            Speed::High
+        } else {
+            Speed::Full
+        };
+        let parent=self.devices.get(&hub).ok_or(UsbError::Controller)?.at;
+        let tt=match (parent.speed,speed.is_slow()) {
+            (Speed::High,true)=>Some((hub,port)),
+            _=>parent.tt,
+        };
+        let at=Attach {
+            root_port:parent.root_port,
+            route:parent.route|(port as u32)<<(4*parent.depth),
+            depth:parent.depth+1,
+            speed,
+            tt,
+            parent:Some((hub,port)),
+        };
+        if at.depth>5 {return Err(UsbError::Unsupported)}
+        // marked first so a failed device isn't retried every poll
+        if let Some(h)=self.devices.get_mut(&hub).and_then(|d|d.hub.as_mut()) {
+            h.connected|=1<<port;
+        }
+        self.attach(at).await?;
+        return Ok(());
+    }
+    async fn detach_hub_port(&mut self,hub:u8,port:u8) {
+        if let Some(h)=self.devices.get_mut(&hub).and_then(|d|d.hub.as_mut()) {
+            h.connected&=!(1<<port);
+        }
+        let child=self.devices.values().find(|d|d.at.parent==Some((hub,port))).map(|d|d.slot);
+        if let Some(child)=child {
+            self.detach(child).await;
+        }
+    }
+    /// Forgets a device and everything plugged into it, and gives its slot back
+    async fn detach(&mut self,slot:u8) {
+        for slot in self.subtree(slot) {
+            if let Some(mut device)=self.devices.remove(&slot) {
+                for hid in device.hid.iter_mut() {
+                    hid.driver.disconnect();
+                }
+                if super::devices().iter().any(|d|d.controller==self.index&&d.slot==slot) {
+                    info!("USB device in slot {} unplugged",slot);
+                }
+                // the pages can only go back once the controller is done with the slot
+                let _=self.command(Trb::new(TRB_DISABLE_SLOT,0,0,(slot as u32)<<24)).await;
+                unsafe{write_volatile(self.dcbaa.ptr_at::<u64>(slot as usize*8),0)};
+            }
+            super::remove_device(self.index,slot);
+        }
+    }
+    /// A device and everything plugged into it, the ones furthest down first
+    fn subtree(&self,slot:u8)->Vec<u8> {
+        let mut slots=Vec::new();
+        for child in self.devices.values().filter(|d|d.at.parent.map(|p|p.0)==Some(slot)) {
+            slots.extend(self.subtree(child.slot));
+        }
+        slots.push(slot);
+        return slots;
+    }
+}
+
+
+/// Asks the BIOS to let go of the controller, if it has it for legacy keyboard emulation
+fn bios_handoff(cap:*mut u8,hcc1:u32) {
+    let mut offset=((hcc1>>16) as usize)*4;
+    while offset!=0 {
+        let header=read32(cap,offset);
+        if header&0xFF==XCAP_LEGACY {
+            if header&LEGACY_BIOS_OWNED!=0 {
+                write32(cap,offset,header|LEGACY_OS_OWNED);
+                if wait_until(||read32(cap,offset)&LEGACY_BIOS_OWNED==0).is_err() {
+                    warn!("The BIOS didn't hand over the xHCI controller, taking it anyway");
+                    write32(cap,offset,read32(cap,offset)&!LEGACY_BIOS_OWNED|LEGACY_OS_OWNED);
+                }
+            }
+            // no more SMIs for USB events
+            write32(cap,offset+4,read32(cap,offset+4)&0x000E_1FEE);
+            return;
+        }
+        let next=((header>>8)&0xFF) as usize*4;
+        if next==0 {return}
+        offset+=next;
+    }
+}
+/// Scratchpad pages the controller asked for, with their array in slot 0 of the DCBAA
+fn scratchpad(dcbaa:&DmaPage,hcs2:u32)->Result<Vec<DmaPage>,UsbError> {
+    let count=((hcs2>>27)&0x1F|((hcs2>>21)&0x1F)<<5) as usize;
+    if count==0 {return Ok(Vec::new())}
+    if count>DmaPage::SIZE/8 {return Err(UsbError::Unsupported)}
+    let array=DmaPage::new().ok_or(UsbError::NoResources)?;
+    let mut pages=Vec::with_capacity(count+1);
+    for i in 0..count {
+        let page=DmaPage::new().ok_or(UsbError::NoResources)?;
+        unsafe{write_volatile(array.ptr_at::<u64>(i*8),page.phys())};
+        pages.push(page);
+    }
+    unsafe{write_volatile(dcbaa.ptr::<u64>(),array.phys())};
+    pages.push(array);
+    return Ok(pages);
+}
+
+
+fn read32(base:*mut u8,offset:usize)->u32 {
+    unsafe{read_volatile(base.add(offset) as *const u32)}
+}
+fn write32(base:*mut u8,offset:usize,value:u32) {
+    unsafe{write_volatile(base.add(offset) as *mut u32,value)}
+}
+/// Low half first, controllers without 64 bit access take it that way
+fn write64(base:*mut u8,offset:usize,value:u64) {
+    write32(base,offset,value as u32);
+    write32(base,offset+4,(value>>32) as u32);
+}
+/// Spins until `done` says so, or gives up after [`TIMEOUT`]. Only for bringing the controller up
+/// at boot, before its task runs.
+fn wait_until(mut done:impl FnMut()->bool)->Result<(),UsbError> {
+    let start=uptime();
+    while !done() {
+        if uptime()-start>TIMEOUT {return Err(UsbError::Timeout)}
+        spin_loop();
+    }
+    return Ok(());
+}
@@ -0,0 +1,419 @@
+//! Loads programs: static ELF64 executables for x86_64, like the initrd's servers. Each one gets a
+//! [`AddressSpace`] of its own, its `PT_LOAD` segments mapped with the
+//! permissions they ask for, and a stack laid out like the SysV ABI says: `argc`, then `argv`,
+//! `envp` and the auxiliary vector, with the strings above them.
+//!
+//! Programs have to be linked somewhere in user memory, see [`crate::user`]. Anything needing an
+//! interpreter or relocations (`PT_INTERP`, `ET_DYN`) is refused.
+
+
+use alloc::{
+    collections::BTreeMap,
+    vec::Vec,
+};
+use core::{
+    convert::TryInto,
+    fmt,
+};
+use x86_64::{
+    structures::paging::{
+        Page,
+        PageTableFlags,
+    },
+    VirtAddr,
+};
+use crate::{
+    memory::{
+        address_space::{
+            AddressSpace,
+            SpaceError,
+        },
+        PAGE_SIZE,
+    },
+    initrd,
+};
+use super::{
+    Exit,
+    EnterError,
+    Return,
+    USER_START,
+    USER_END,
+    is_user_range,
+};
+
+
+const ELF_MAGIC:&[u8;4]=b"\x7FELF";
+const CLASS_64:u8=2;
+const DATA_LITTLE_ENDIAN:u8=1;
+const ET_EXEC:u16=2;
+const ET_DYN:u16=3;
+const EM_X86_64:u16=62;
+const HEADER_SIZE:usize=64;
+const PROGRAM_HEADER_SIZE:usize=56;
+const MAX_PROGRAM_HEADERS:usize=64;
+
+const PT_LOAD:u32=1;
+const PT_INTERP:u32=3;
+const PF_X:u32=1;
+const PF_W:u32=2;
+
+const AT_NULL:u64=0;
+const AT_PHDR:u64=3;
+const AT_PHENT:u64=4;
+const AT_PHNUM:u64=5;
+const AT_PAGESZ:u64=6;
+const AT_ENTRY:u64=9;
+const AT_RANDOM:u64=25;
+
+/// Where the stack starts, with a page of nothing between it and the end of user memory
+const STACK_TOP:u64=USER_END-PAGE_SIZE;
+const STACK_SIZE:u64=64*1024;
+/// How much of the stack `argv`, `envp` and the rest can take
+const MAX_ARGS_SIZE:u64=STACK_SIZE/2;
+
+
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum ElfError {
+    NotFound,
+    /// Shorter than its headers say
+    Truncated,
+    NotElf,
+    /// A valid ELF file, but not one this loads
+    Unsupported(&'static str),
+    /// A segment or the entry point isn't in user memory, or doesn't make sense
+    BadSegment,
+    ArgsTooBig,
+    NoMemory,
+    Enter(EnterError),
+}
+impl fmt::Display for ElfError {
+    fn fmt(&self,f:&mut fmt::Formatter)->fmt::Result {
+        match self {
+            ElfError::NotFound=>write!(f,"no such file in the initrd"),
+            ElfError::Truncated=>write!(f,"the file is cut short"),
+            ElfError::NotElf=>write!(f,"not an ELF file"),
+            ElfError::Unsupported(what)=>write!(f,"unsupported: {}",what),
+            ElfError::BadSegment=>write!(f,"a segment or the entry point is outside user memory"),
+            ElfError::ArgsTooBig=>write!(f,"the arguments don't fit on the stack"),
+            ElfError::NoMemory=>write!(f,"out of memory"),
+            ElfError::Enter(error)=>write!(f,"couldn't enter ring 3: {:?}",error),
+        }
+    }
+}
+impl From<SpaceError> for ElfError {
+    fn from(error:SpaceError)->ElfError {
+        match error {
+            SpaceError::NoMemory=>ElfError::NoMemory,
+            // segments are checked when parsing, so this is one running into the stack
+            SpaceError::NotUser|SpaceError::Overlaps|SpaceError::NotMapped=>ElfError::BadSegment,
+        }
+    }
+}
+impl From<EnterError> for ElfError {
+    fn from(error:EnterError)->ElfError {
+        ElfError::Enter(error)
+    }
+}
+
+
+/// A `PT_LOAD` program header
+#[derive(Debug,Copy,Clone)]
+pub struct Segment {
+    pub offset:u64,
+    pub vaddr:u64,
+    pub file_size:u64,
+    pub mem_size:u64,
+    pub flags:u32,
+}
+impl Segment {
+    /// Every segment can be read, x86 has no other way
+    fn page_flags(&self)->PageTableFlags {
+        let mut flags=PageTableFlags::empty();
+        if self.flags&PF_W!=0 {flags|=PageTableFlags::WRITABLE}
+        if self.flags&PF_X==0 {flags|=PageTableFlags::NO_EXECUTE}
+        return flags;
+    }
+}
+
+
+/// A checked ELF executable
+#[derive(Debug)]
+pub struct Elf<'a> {
+    data:&'a [u8],
+    pub entry:u64,
+    program_headers:u64,
+    program_header_count:usize,
+    pub segments:Vec<Segment>,
+}
+impl<'a> Elf<'a> {
+    pub fn parse(data:&'a [u8])->Result<Elf<'a>,ElfError> {
+        if data.len()<HEADER_SIZE {
+            return Err(if data.starts_with(ELF_MAGIC) {ElfError::Truncated} else {ElfError::NotElf});
+        }
+        if &data[0..4]!=ELF_MAGIC {return Err(ElfError::NotElf)}
+        if data[4]!=CLASS_64 {return Err(ElfError::Unsupported("not 64 bit"))}
+        if data[5]!=DATA_LITTLE_ENDIAN {return Err(ElfError::Unsupported("not little endian"))}
+        match u16_at(data,16) {
+            ET_EXEC=>{},
+            ET_DYN=>return Err(ElfError::Unsupported("position independent or a shared library")),
+            _=>return Err(ElfError::Unsupported("not an executable")),
+        }
+        if u16_at(data,18)!=EM_X86_64 {return Err(ElfError::Unsupported("not x86_64"))}
+        let entry=u64_at(data,24);
+        let program_headers=u64_at(data,32);
+        let entry_size=u16_at(data,54) as usize;
+        let count=u16_at(data,56) as usize;
+        if entry_size!=PROGRAM_HEADER_SIZE||count>MAX_PROGRAM_HEADERS {
+            return Err(ElfError::Unsupported("odd program headers"));
+        }
+        let table_end=program_headers.checked_add((count*PROGRAM_HEADER_SIZE) as u64).ok_or(ElfError::Truncated)?;
+        if table_end>data.len() as u64 {return Err(ElfError::Truncated)}
+        let mut segments=Vec::new();
+        for i in 0..count {
+            let header=&data[program_headers as usize+i*PROGRAM_HEADER_SIZE..][..PROGRAM_HEADER_SIZE];
+            match u32_at(header,0) {
+                PT_LOAD=>{},
+                PT_INTERP=>return Err(ElfError::Unsupported("dynamically linked")),
+                _=>continue,
+            }
+            let segment=Segment {
+                flags:u32_at(header,4),
+                offset:u64_at(header,8),
+                vaddr:u64_at(header,16),
+                file_size:u64_at(header,32),
+                mem_size:u64_at(header,40),
+            };
+            let file_end=segment.offset.checked_add(segment.file_size).ok_or(ElfError::Truncated)?;
+            if file_end>data.len() as u64 {return Err(ElfError::Truncated)}
+            if segment.file_size>segment.mem_size {return Err(ElfError::BadSegment)}
+            let in_user=VirtAddr::try_new(segment.vaddr).map(|vaddr|is_user_range(vaddr,segment.mem_size)).unwrap_or(false);
+            // the stack goes at the top
+            if !in_user||segment.vaddr+segment.mem_size>STACK_TOP-STACK_SIZE {
+                return Err(ElfError::BadSegment);
+            }
+            segments.push(segment);
+        }
+        if segments.is_empty() {return Err(ElfError::Unsupported("nothing to load"))}
+        let entry_mapped=segments.iter().any(|s|s.flags&PF_X!=0&&entry>=s.vaddr&&entry<s.vaddr+s.mem_size);
+        if !entry_mapped {return Err(ElfError::BadSegment)}
+        return Ok(Elf{data,entry,program_headers,program_header_count:count,segments});
+    }
+    /// Where the program headers end up in memory, if a segment loads them
+    fn program_headers_addr(&self)->Option<u64> {
+        let len=(self.program_header_count*PROGRAM_HEADER_SIZE) as u64;
+        self.segments.iter()
+            .find(|s|self.program_headers>=s.offset&&self.program_headers+len<=s.offset+s.file_size)
+            .map(|s|s.vaddr+(self.program_headers-s.offset))
+    }
+}
+
+
+/// Works out what every page the segments touch needs. Pages two segments share get both their
+/// permissions.
+fn segment_pages(elf:&Elf)->BTreeMap<Page,PageTableFlags> {
+    let mut pages=BTreeMap::new();
+    for segment in elf.segments.iter().filter(|s|s.mem_size>0) {
+        let flags=segment.page_flags();
+        let first=Page::containing_address(VirtAddr::new(segment.vaddr));
+        let last=Page::containing_address(VirtAddr::new(segment.vaddr+segment.mem_size-1));
+        for page in Page::range_inclusive(first,last) {
+            let existing=pages.entry(page).or_insert(flags);
+            let no_execute=existing.contains(PageTableFlags::NO_EXECUTE)&&flags.contains(PageTableFlags::NO_EXECUTE);
+            *existing|=flags;
+            existing.set(PageTableFlags::NO_EXECUTE,no_execute);
+        }
+    }
+    return pages;
+}
+
+
+/// A loaded program, ready to [`run`](Program::run). Its memory is freed when it's dropped.
+pub struct Program {
+    space:AddressSpace,
+    entry:VirtAddr,
+    stack:VirtAddr,
+    argc:u64,
+    argv:u64,
+}
+impl Program {
+    /// Sets up the program's memory: its segments, and a stack with `argv` and `envp` on it
+    pub fn load(elf:&Elf,argv:&[&str],envp:&[&str])->Result<Program,ElfError> {
+        let mut space=AddressSpace::new()?;
+        // a region for each run of pages with the same permissions
+        let mut run:Option<(Page,u64,PageTableFlags)>=None;
+        for (page,flags) in segment_pages(elf) {
+            match run {
+                Some((start,count,run_flags)) if start+count==page&&run_flags==flags=>run=Some((start,count+1,flags)),
+                _=>{
+                    if let Some((start,count,flags))=run {
+                        space.map(start.start_address(),count*PAGE_SIZE,flags)?;
+                    }
+                    run=Some((page,1,flags));
+                },
+            }
+        }
+        if let Some((start,count,flags))=run {
+            space.map(start.start_address(),count*PAGE_SIZE,flags)?;
+        }
+        for segment in elf.segments.iter() {
+            space.write(VirtAddr::new(segment.vaddr),&elf.data[segment.offset as usize..][..segment.file_size as usize])?;
+        }
+        space.map(VirtAddr::new(STACK_TOP-STACK_SIZE),STACK_SIZE,PageTableFlags::WRITABLE|PageTableFlags::NO_EXECUTE)?;
+        let (stack,argv_addr)=build_stack(&mut space,elf,argv,envp)?;
+        return Ok(Program {
+            space,
+            entry:VirtAddr::new(elf.entry),
+            stack:VirtAddr::new(stack),
+            argc:argv.len() as u64,
+            argv:argv_addr,
+        });
+    }
+    /// Switches to the program's address space and runs it until it exits. `rdi` and `rsi` have
+    /// `argc` and `argv` as well, for programs without a startup routine.
+    pub fn run(mut self)->Result<Exit,ElfError> {
+        let exit=super::run(&mut self.space,self.entry,self.stack,[self.argc,self.argv,0],Return::Sysret)?;
+        return Ok(exit);
+    }
+}
+
+
+/// Lays out the stack the SysV ABI way. Returns the stack pointer, at `argc`, and where `argv` is.
+fn build_stack(space:&mut AddressSpace,elf:&Elf,argv:&[&str],envp:&[&str])->Result<(u64,u64),ElfError> {
+    // the strings go at the top, then 16 random bytes for AT_RANDOM
+    let mut strings=Vec::new();
+    let mut offsets=Vec::new();
+    for s in argv.iter().chain(envp.iter()) {
+        offsets.push(strings.len() as u64);
+        strings.extend_from_slice(s.as_bytes());
+        strings.push(0);
+    }
+    let random_offset=strings.len() as u64;
+    strings.extend_from_slice(&random_bytes());
+    let strings_addr=(STACK_TOP-strings.len() as u64)&!15;
+    let pointer=|i:usize|strings_addr+offsets[i];
+    let mut auxv=alloc::vec![
+        (AT_PHENT,PROGRAM_HEADER_SIZE as u64),
+        (AT_PHNUM,elf.program_header_count as u64),
+        (AT_PAGESZ,PAGE_SIZE),
+        (AT_ENTRY,elf.entry),
+        (AT_RANDOM,strings_addr+random_offset),
+    ];
+    if let Some(addr)=elf.program_headers_addr() {
+        auxv.push((AT_PHDR,addr));
+    }
+    auxv.push((AT_NULL,0));
+    let mut words=Vec::new();
+    words.push(argv.len() as u64);
+    words.extend((0..argv.len()).map(pointer));
+    words.push(0);
+    words.extend((argv.len()..argv.len()+envp.len()).map(pointer));
+    words.push(0);
+    for (key,value) in auxv {
+        words.push(key);
+        words.push(value);
+    }
+    // `rsp` is 16 byte aligned at the entry point
+    let stack=(strings_addr-words.len() as u64*8)&!15;
+    if STACK_TOP-stack>MAX_ARGS_SIZE {
+        return Err(ElfError::ArgsTooBig);
+    }
+    space.write(VirtAddr::new(strings_addr),&strings)?;
+    let bytes:Vec<u8>=words.iter().flat_map(|word|word.to_le_bytes()).collect();
+    space.write(VirtAddr::new(stack),&bytes)?;
+    return Ok((stack,stack+8));
+}
+/// For AT_RANDOM, which C libraries seed stack canaries from. From the TSC, so not secret.
+fn random_bytes()->[u8;16] {
+    let mut state=unsafe{core::arch::x86_64::_rdtsc()}|1;
+    let mut bytes=[0;16];
+    for chunk in bytes.chunks_mut(8) {
+        // xorshift64
+        state^=state<<13;
+        state^=state>>7;
+        state^=state<<17;
+        chunk.copy_from_slice(&state.to_le_bytes());
+    }
+    return bytes;
+}
+
+
+/// Loads the program at `path` in the initrd and runs it until it exits
+pub fn exec(path:&str,argv:&[&str],envp:&[&str])->Result<Exit,ElfError> {
+    let data=initrd::find(path).ok_or(ElfError::NotFound)?;
+    let elf=Elf::parse(data)?;
+    Program::load(&elf,argv,envp)?.run()
+}
+
+
+fn u16_at(data:&[u8],at:usize)->u16 {
+    u16::from_le_bytes(data[at..at+2].try_into().unwrap())
+}
+fn u32_at(data:&[u8],at:usize)->u32 {
+    u32::from_le_bytes(data[at..at+4].try_into().unwrap())
+}
+fn u64_at(data:&[u8],at:usize)->u64 {
+    u64::from_le_bytes(data[at..at+8].try_into().unwrap())
+}
+
+
+pub fn test_elf()->Result<(),&'static str> {
+    const BASE:u64=USER_START+0x40_0000;
+    const CODE_OFFSET:usize=64+56;
+    // exits with argc*256 plus the first byte of argv[1], straight from the stack
+    const CODE:&[u8]=&[
+        0x48,0x8B,0x44,0x24,0x10,           // mov rax,[rsp+16]
+        0x0F,0xB6,0x38,                     // movzx edi,byte [rax]
+        0x48,0x8B,0x0C,0x24,                // mov rcx,[rsp]
+        0x48,0xC1,0xE1,0x08,                // shl rcx,8
+        0x48,0x01,0xCF,                     // add rdi,rcx
+        0x31,0xF6,                          // xor esi,esi
+        0x31,0xD2,                          // xor edx,edx
+        0x31,0xC0,                          // xor eax,eax
+        0x0F,0x05,                          // syscall
+    ];
+    let mut file=alloc::vec![0u8;CODE_OFFSET];
+    file[0..8].copy_from_slice(&[0x7F,b'E',b'L',b'F',2,1,1,0]);
+    let len=(CODE_OFFSET+CODE.len()) as u64;
+    let mut put=|at:usize,bytes:&[u8]|file[at..at+bytes.len()].copy_from_slice(bytes);
+    put(16,&2u16.to_le_bytes());                        // ET_EXEC
+    put(18,&62u16.to_le_bytes());                       // x86_64
+    put(20,&1u32.to_le_bytes());
+    put(24,&(BASE+CODE_OFFSET as u64).to_le_bytes());   // entry
+    put(32,&64u64.to_le_bytes());                       // program headers
+    put(52,&64u16.to_le_bytes());
+    put(54,&56u16.to_le_bytes());
+    put(56,&1u16.to_le_bytes());
+    put(64,&1u32.to_le_bytes());                        // PT_LOAD
+    put(68,&5u32.to_le_bytes());                        // R+X
+    put(80,&BASE.to_le_bytes());
+    put(96,&len.to_le_bytes());
+    put(104,&(len+2*PAGE_SIZE).to_le_bytes());          // and some bss
+    put(112,&PAGE_SIZE.to_le_bytes());
+    file.extend_from_slice(CODE);
+    let mut bad=file.clone();
+    bad[0]=0;
+    if Elf::parse(&bad).err()!=Some(ElfError::NotElf) {
+        return Err("took a file without the ELF magic");
+    }
+    let mut bad=file.clone();
+    bad[16]=3;
+    if !matches!(Elf::parse(&bad),Err(ElfError::Unsupported(_))) {
+        return Err("took a position independent executable");
+    }
+    let mut bad=file.clone();
+    bad[80..88].copy_from_slice(&0x1000u64.to_le_bytes());
+    if !matches!(Elf::parse(&bad),Err(ElfError::BadSegment)) {
+        return Err("took a segment in kernel memory");
+    }
+    if Elf::parse(&file[..100]).err()!=Some(ElfError::Truncated) {
+        return Err("took a truncated file");
+    }
+    let elf=Elf::parse(&file).map_err(|_|"couldn't parse the test program")?;
+    let program=Program::load(&elf,&["test","A"],&["HOME=/"]).map_err(|_|"couldn't load the test program")?;
+    match program.run() {
+        Ok(Exit::Exited(code)) if code==2*256+b'A' as i64=>{},
+        Ok(_)=>return Err("the program didn't see its arguments"),
+        Err(_)=>return Err("couldn't run the test program"),
+    }
+    return Ok(());
+}
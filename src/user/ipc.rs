@@ -0,0 +1,91 @@
+//! Message passing. A channel is just a number: anyone, a program or the kernel, can send to it,
+//! and messages wait in its queue, oldest first, until someone receives them. Channels with
+//! nothing queued don't take any memory.
+
+
+use alloc::{
+    collections::{
+        BTreeMap,
+        VecDeque,
+    },
+    vec::Vec,
+};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::time;
+
+
+/// The biggest message, in bytes
+pub const MAX_MESSAGE:usize=4096;
+/// Messages a channel holds before sends fail
+pub const MAX_QUEUED:usize=64;
+/// How often a waiting receive looks again
+const RECV_POLL:Duration=Duration::from_millis(1);
+
+
+/// Only locked with interrupts off
+static CHANNELS:Mutex<BTreeMap<u64,VecDeque<Vec<u8>>>>=Mutex::new(BTreeMap::new());
+
+
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum IpcError {
+    /// The message is over [`MAX_MESSAGE`], or on receiving, over the buffer. It stays queued.
+    TooBig(usize),
+    /// The channel already has [`MAX_QUEUED`] messages
+    Full,
+    /// Nothing came
+    Empty,
+}
+
+
+/// Queues `message` on `channel`
+pub fn send(channel:u64,message:&[u8])->Result<(),IpcError> {
+    if message.len()>MAX_MESSAGE {
+        return Err(IpcError::TooBig(message.len()));
+    }
+    without_interrupts(||{
+        let mut channels=CHANNELS.lock();
+        let queue=channels.entry(channel).or_default();
+        if queue.len()>=MAX_QUEUED {
+            return Err(IpcError::Full);
+        }
+        queue.push_back(message.to_vec());
+        return Ok(());
+    })
+}
+/// Takes the oldest message on `channel`, if it's no longer than `max`
+pub fn try_recv(channel:u64,max:usize)->Result<Vec<u8>,IpcError> {
+    without_interrupts(||{
+        let mut channels=CHANNELS.lock();
+        let queue=channels.get_mut(&channel).ok_or(IpcError::Empty)?;
+        let len=queue.front().map(|message|message.len()).ok_or(IpcError::Empty)?;
+        if len>max {
+            return Err(IpcError::TooBig(len));
+        }
+        let message=queue.pop_front().ok_or(IpcError::Empty)?;
+        if queue.is_empty() {
+            channels.remove(&channel);
+        }
+        return Ok(message);
+    })
+}
+/// Like [`try_recv`], but waits up to `timeout` for a message, halting in between
+pub fn recv_timeout(channel:u64,max:usize,timeout:Duration)->Result<Vec<u8>,IpcError> {
+    let end=time::uptime()+timeout;
+    loop {
+        match try_recv(channel,max) {
+            Err(IpcError::Empty)=>{},
+            result=>return result,
+        }
+        let now=time::uptime();
+        if now>=end {
+            return Err(IpcError::Empty);
+        }
+        time::sleep((end-now).min(RECV_POLL));
+    }
+}
+/// How many messages are waiting on `channel`
+pub fn queued(channel:u64)->usize {
+    without_interrupts(||CHANNELS.lock().get(&channel).map(|queue|queue.len()).unwrap_or(0))
+}
@@ -0,0 +1,390 @@
+//! Running code in ring 3. [`run`] saves the kernel's callee-saved registers and stack, drops to
+//! ring 3 with `iretq` or `sysretq`, and only comes back once the program is done: [`exit`] puts
+//! the saved registers back and returns from `run` as if the program had been a function call.
+//! Faults in ring 3 end the program the same way instead of taking the kernel down.
+//!
+//! While it runs, interrupts and faults from ring 3 land on the core's RSP0 stack, see
+//! [`gdt::set_kernel_stack`](crate::gdt::set_kernel_stack). Interrupts go back to the program with
+//! `iretq` like any other, so the program has the core until it exits or faults, and no tasks run
+//! meanwhile. The one way out is Ctrl+C, which the timer interrupt looks for, see [`check_kill`].
+//!
+//! Programs talk to the kernel with system calls, see [`syscall`].
+//!
+//! User memory is the top half of the lower half, PML4 entries 128 to 255. Everything below is
+//! the kernel's: physical memory is mapped at 0 and the kernel's own allocations start at 1TB.
+//! Each program has its own, see [`AddressSpace`].
+
+
+use core::{
+    arch::global_asm,
+    ptr::null_mut,
+    sync::atomic::{
+        AtomicPtr,
+        Ordering,
+    },
+};
+use x86_64::{
+    structures::{
+        idt::InterruptStackFrame,
+        paging::PageTableFlags,
+    },
+    instructions::interrupts,
+    registers::{
+        control::Cr3,
+        model_specific::{
+            Efer,
+            EferFlags,
+        },
+    },
+    VirtAddr,
+};
+use raw_cpuid::CpuId;
+use crate::{
+    gdt::{
+        self,
+        MAX_CORES,
+        USER_CODE,
+        USER_DATA,
+    },
+    memory::{
+        address_space::AddressSpace,
+        PAGE_SIZE,
+    },
+    shell::{
+        self,
+        Command,
+    },
+    input,
+    debug,
+    println,
+};
+
+
+pub mod syscall;
+pub mod ipc;
+pub mod elf;
+
+
+/// The first byte of user memory
+pub const USER_START:u64=0x0000_4000_0000_0000;
+/// Just past the last byte of user memory. The page below the non-canonical hole is left out: a
+/// `syscall` at its very end would have SYSRET go back to a non-canonical address, in ring 0.
+pub const USER_END:u64=0x0000_7FFF_FFFF_F000;
+/// Interrupts on, and the bit that is always set
+const USER_RFLAGS:u64=0x202;
+/// The kernel stack each program gets for its system calls, interrupts and faults
+const THREAD_STACK_SIZE:usize=4096*8;
+
+
+/// The kernel's side of [`run`], saved on the way into ring 3 and restored by [`exit`]. The order
+/// is used by the assembly.
+#[repr(C)]
+#[derive(Default)]
+struct KernelContext {
+    rbx:u64,
+    rbp:u64,
+    r12:u64,
+    r13:u64,
+    r14:u64,
+    r15:u64,
+    rsp:u64,
+}
+
+
+/// Where and how to enter ring 3. The order is used by the assembly.
+#[repr(C)]
+struct UserFrame {
+    rip:u64,
+    rsp:u64,
+    rflags:u64,
+    cs:u64,
+    ss:u64,
+    /// 0 for `iretq`, anything else for `sysretq`
+    sysret:u64,
+    /// In `rdi`, `rsi` and `rdx`
+    args:[u64;3],
+}
+
+
+/// Whatever is running in ring 3 on a core
+struct Running {
+    context:KernelContext,
+    exit:Option<Exit>,
+    /// Its memory, for system calls
+    space:*mut AddressSpace,
+}
+
+
+static EXEC:Command=Command::new("exec","<path> [args..]","Run a program from the initrd. Nothing else runs until it exits or Ctrl+C stops it.",exec_cmd);
+
+
+/// Each core's running program, null when there isn't one
+static RUNNING:[AtomicPtr<Running>;MAX_CORES]=[const{AtomicPtr::new(null_mut())};MAX_CORES];
+
+
+/// How to get into ring 3. `sysretq` is quicker, `iretq` can go anywhere.
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum Return {
+    Iret,
+    Sysret,
+}
+
+
+/// Why [`run`] couldn't start a program
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum EnterError {
+    /// The entry point or stack isn't mapped in user memory
+    NotUser,
+    /// Something is already running in ring 3 on this core
+    Busy,
+}
+
+
+/// A CPU exception that came from ring 3
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub struct Fault {
+    pub vector:u8,
+    /// Like `#GP`
+    pub name:&'static str,
+    pub rip:u64,
+    pub error_code:Option<u64>,
+    /// The address a page fault was about
+    pub address:Option<u64>,
+}
+
+
+/// How a program stopped
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum Exit {
+    /// It asked to, with an exit code
+    Exited(i64),
+    Faulted(Fault),
+    /// Stopped with Ctrl+C
+    Killed,
+}
+
+
+extern "C" {
+    fn user_enter(context:*mut KernelContext,frame:*const UserFrame);
+    fn user_resume(context:*const KernelContext)->!;
+}
+global_asm!(r#"
+.global user_enter
+user_enter:
+    mov [rdi+0x00],rbx
+    mov [rdi+0x08],rbp
+    mov [rdi+0x10],r12
+    mov [rdi+0x18],r13
+    mov [rdi+0x20],r14
+    mov [rdi+0x28],r15
+    mov [rdi+0x30],rsp
+    cmp qword ptr [rsi+0x28],0
+    jne 2f
+    push qword ptr [rsi+0x20]
+    push qword ptr [rsi+0x08]
+    push qword ptr [rsi+0x10]
+    push qword ptr [rsi+0x18]
+    push qword ptr [rsi+0x00]
+    xor ecx,ecx
+    xor r11d,r11d
+    jmp 3f
+2:
+    cli
+    mov rcx,[rsi+0x00]
+    mov r11,[rsi+0x10]
+    mov rsp,[rsi+0x08]
+3:
+    mov rdi,[rsi+0x30]
+    mov rdx,[rsi+0x40]
+    mov rsi,[rsi+0x38]
+    xor eax,eax
+    xor ebx,ebx
+    xor ebp,ebp
+    xor r8d,r8d
+    xor r9d,r9d
+    xor r10d,r10d
+    xor r12d,r12d
+    xor r13d,r13d
+    xor r14d,r14d
+    xor r15d,r15d
+    test rcx,rcx
+    jz 4f
+    sysretq
+4:
+    iretq
+
+.global user_resume
+user_resume:
+    mov rbx,[rdi+0x00]
+    mov rbp,[rdi+0x08]
+    mov r12,[rdi+0x10]
+    mov r13,[rdi+0x18]
+    mov r14,[rdi+0x20]
+    mov r15,[rdi+0x28]
+    mov rsp,[rdi+0x30]
+    cld
+    ret
+"#);
+
+
+/// Whether `len` bytes from `start` are all in user memory
+pub fn is_user_range(start:VirtAddr,len:u64)->bool {
+    let start=start.as_u64();
+    match start.checked_add(len) {
+        Some(end)=>start>=USER_START&&end<=USER_END,
+        None=>false,
+    }
+}
+/// Whether an interrupt or fault came from ring 3
+pub fn from_user(stack_frame:&InterruptStackFrame)->bool {
+    stack_frame.code_segment&3==3
+}
+/// Whether something is running in ring 3 on this core
+pub fn is_running()->bool {
+    !RUNNING[gdt::current_core()].load(Ordering::Acquire).is_null()
+}
+
+
+/// Switches to `space` and runs code in ring 3 at `entry` with the stack at `stack` and `args` in
+/// `rdi`, `rsi` and `rdx`, until it exits or faults. Interrupts are on while it runs, and as they
+/// were after.
+pub fn run(space:&mut AddressSpace,entry:VirtAddr,stack:VirtAddr,args:[u64;3],how:Return)->Result<Exit,EnterError> {
+    if !space.check(entry,1,false)||!space.check(stack-8u64,8,true) {
+        return Err(EnterError::NotUser);
+    }
+    let core=gdt::current_core();
+    let mut running=Running{context:KernelContext::default(),exit:None,space};
+    let running_ptr:*mut Running=&mut running;
+    if RUNNING[core].compare_exchange(null_mut(),running_ptr,Ordering::AcqRel,Ordering::Acquire).is_err() {
+        return Err(EnterError::Busy);
+    }
+    let frame=UserFrame {
+        rip:entry.as_u64(),
+        rsp:stack.as_u64(),
+        rflags:USER_RFLAGS,
+        cs:USER_CODE.0 as u64,
+        ss:USER_DATA.0 as u64,
+        sysret:(how==Return::Sysret) as u64,
+        args,
+    };
+    let kernel_stack=alloc::vec![0u8;THREAD_STACK_SIZE];
+    let kernel_stack_top=(VirtAddr::from_ptr(kernel_stack.as_ptr())+THREAD_STACK_SIZE).align_down(16u64);
+    let enabled=interrupts::are_enabled();
+    // a Ctrl+C from before it started isn't for it
+    input::take_ctrl_c();
+    let old_stack=gdt::set_kernel_stack(kernel_stack_top);
+    let (old_table,cr3_flags)=Cr3::read();
+    unsafe {
+        (*(*running_ptr).space).activate();
+        // comes back through `user_resume`, on the same stack
+        user_enter(&mut (*running_ptr).context,&frame);
+        Cr3::write(old_table,cr3_flags);
+    }
+    gdt::set_kernel_stack(old_stack);
+    RUNNING[core].store(null_mut(),Ordering::Release);
+    drop(kernel_stack);
+    if enabled {
+        interrupts::enable();
+    }
+    let exit=unsafe{(*running_ptr).exit.take()}.expect("ring 3 came back without exiting");
+    debug!("Ring 3 program exited: {:?}",exit);
+    return Ok(exit);
+}
+/// Ends what's running in ring 3 on this core and returns `exit` from its [`run`]. Called on
+/// behalf of the program, from a system call or fault handler.
+pub fn exit(exit:Exit)->! {
+    let running=RUNNING[gdt::current_core()].load(Ordering::Acquire);
+    assert!(!running.is_null(),"nothing is running in ring 3 on this core");
+    interrupts::disable();
+    unsafe {
+        (*running).exit=Some(exit);
+        user_resume(&(*running).context);
+    }
+}
+/// Calls `f` with the address space of what's running in ring 3 on this core. For system calls.
+pub fn with_space<R>(f:impl FnOnce(&mut AddressSpace)->R)->Option<R> {
+    let running=RUNNING[gdt::current_core()].load(Ordering::Acquire);
+    if running.is_null() {return None}
+    // only this core's system calls get here, one at a time
+    return Some(f(unsafe{&mut *(*running).space}));
+}
+/// Called by fault handlers. Ends the program if the fault came from ring 3, returns if it didn't.
+pub fn fault(stack_frame:&InterruptStackFrame,vector:u8,name:&'static str,error_code:Option<u64>,address:Option<u64>) {
+    if !from_user(stack_frame)||!is_running() {return}
+    exit(Exit::Faulted(Fault {
+        vector,
+        name,
+        rip:stack_frame.instruction_pointer.as_u64(),
+        error_code,
+        address,
+    }));
+}
+
+
+/// Called from the timer interrupt, after the EOI. Ends the program if the interrupt came from
+/// ring 3 and Ctrl+C was pressed. The input task can't run while a program has the core, so the
+/// keys that came in are decoded here; USB keyboards are only read by their task, so it takes a
+/// PS/2 one.
+pub fn check_kill(stack_frame:&InterruptStackFrame) {
+    if !from_user(stack_frame)||!is_running() {return}
+    input::process_pending();
+    if input::take_ctrl_c() {
+        exit(Exit::Killed);
+    }
+}
+
+
+/// Sets up system calls and no-execute pages on this core. Needs [`gdt::init`] first.
+pub fn init(core:usize) {
+    let nx=CpuId::new().get_extended_processor_and_feature_identifiers().map(|info|info.has_execute_disable()).unwrap_or(false);
+    if nx {
+        unsafe{Efer::update(|flags|flags.insert(EferFlags::NO_EXECUTE_ENABLE))};
+    }
+    syscall::init(core);
+    if core==0 {
+        shell::register(&[&EXEC]);
+    }
+}
+fn exec_cmd(args:&[&str])->Result<(),&'static str> {
+    let path=*args.first().ok_or("expected a path")?;
+    match elf::exec(path,args,&[]) {
+        Ok(Exit::Exited(code))=>println!("{} exited with {}",path,code),
+        Ok(Exit::Faulted(fault))=>println!("{} died of {} at {:#x}",path,fault.name,fault.rip),
+        Ok(Exit::Killed)=>println!("{} was stopped",path),
+        Err(error)=>println!("{}: {}",path,error),
+    }
+    return Ok(());
+}
+
+
+/// Runs `code` in ring 3 at [`USER_START`] with one page of stack, in a space of its own. For
+/// self tests.
+pub fn run_code(code:&[u8],args:[u64;3],how:Return)->Result<Exit,&'static str> {
+    let code_at=VirtAddr::new(USER_START);
+    let stack_at=code_at+PAGE_SIZE;
+    let mut space=AddressSpace::new().map_err(|_|"couldn't make an address space")?;
+    space.map(code_at,PAGE_SIZE,PageTableFlags::empty()).map_err(|_|"couldn't map the code")?;
+    space.map(stack_at,PAGE_SIZE,PageTableFlags::WRITABLE|PageTableFlags::NO_EXECUTE).map_err(|_|"couldn't map the stack")?;
+    space.write(code_at,code).map_err(|_|"couldn't write the code")?;
+    return run(&mut space,code_at,stack_at+PAGE_SIZE,args,how).map_err(|_|"couldn't enter ring 3");
+}
+pub fn test_ring3()->Result<(),&'static str> {
+    const HLT:&[u8]=&[0xF4];
+    const LOAD_RDI:&[u8]=&[0x48,0x8B,0x07];   // mov rax,[rdi]
+    for how in [Return::Iret,Return::Sysret] {
+        // hlt is privileged, so it has to #GP in ring 3
+        match run_code(HLT,[0;3],how)? {
+            Exit::Faulted(Fault{vector:13,rip:USER_START,..})=>{},
+            _=>return Err("hlt didn't #GP in ring 3"),
+        }
+    }
+    // kernel memory isn't reachable, and the arguments arrive in registers
+    match run_code(LOAD_RDI,[0x1000,0,0],Return::Sysret)? {
+        Exit::Faulted(Fault{vector:14,address:Some(0x1000),error_code:Some(code),..}) if code&0x04!=0=>{},
+        _=>return Err("reading kernel memory didn't page fault"),
+    }
+    if is_running() {
+        return Err("ring 3 is still marked as running");
+    }
+    return Ok(());
+}
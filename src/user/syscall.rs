@@ -0,0 +1,459 @@
+//! System calls. Programs use `syscall` with the call's number in `rax` and up to six arguments in
+//! `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`, like Linux. The result comes back in `rax`, either a
+//! value or a negative [`SyscallError`], and every other register but `rcx` and `r11` is kept.
+//!
+//! The entry stub finds the thread's kernel stack through the core's RSP0 (its address is in the
+//! area `swapgs` brings in), saves the user's registers there as a [`SyscallFrame`] and calls
+//! [`dispatch`], which looks the call up in [`SYSCALLS`] by number.
+
+
+use alloc::vec::Vec;
+use core::{
+    arch::global_asm,
+    sync::atomic::{
+        AtomicU64,
+        Ordering,
+    },
+    time::Duration,
+};
+use x86_64::{
+    structures::paging::{
+        Page,
+        PageTableFlags,
+    },
+    instructions::interrupts,
+    registers::{
+        model_specific::{
+            KernelGsBase,
+            LStar,
+            SFMask,
+        },
+        rflags::RFlags,
+    },
+    VirtAddr,
+};
+use crate::{
+    gdt::{
+        self,
+        MAX_CORES,
+    },
+    memory::{
+        address_space::SpaceError,
+        PAGE_SIZE,
+    },
+    print,
+    time,
+    trace,
+};
+use super::{
+    ipc::{
+        self,
+        IpcError,
+    },
+    Exit,
+    Return,
+    USER_START,
+    is_user_range,
+    run_code,
+};
+
+
+/// The most `write` prints at once
+const MAX_WRITE:usize=4096;
+/// The most `map` maps at once
+const MAX_MAP:u64=64*1024*1024;
+
+/// `map` flags
+pub const MAP_WRITE:u64=1;
+pub const MAP_EXECUTE:u64=2;
+
+
+/// What `swapgs` points at on each core: the address of the core's RSP0, then somewhere to keep
+/// the user's stack pointer while switching. The order is used by the assembly.
+static AREAS:[[AtomicU64;2];MAX_CORES]=[const{[AtomicU64::new(0),AtomicU64::new(0)]};MAX_CORES];
+
+
+/// Why a system call failed. Programs get it negated in `rax`.
+#[repr(i64)]
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum SyscallError {
+    NoSuchCall=1,
+    /// An argument is out of range
+    BadArgument=2,
+    /// A pointer isn't to user memory that's mapped, or writable when it has to be
+    BadPointer=3,
+    NoMemory=4,
+    TooBig=5,
+    /// The memory is already mapped, or the channel is full
+    InUse=6,
+    /// Nothing was there to receive
+    Empty=7,
+}
+impl From<IpcError> for SyscallError {
+    fn from(error:IpcError)->SyscallError {
+        match error {
+            IpcError::TooBig(_)=>SyscallError::TooBig,
+            IpcError::Full=>SyscallError::InUse,
+            IpcError::Empty=>SyscallError::Empty,
+        }
+    }
+}
+
+
+/// The user's registers as the entry stub saves them. The order is used by the assembly.
+#[repr(C)]
+#[derive(Debug)]
+pub struct SyscallFrame {
+    pub number:u64,
+    pub rdi:u64,
+    pub rsi:u64,
+    pub rdx:u64,
+    pub r10:u64,
+    pub r8:u64,
+    pub r9:u64,
+    pub rflags:u64,
+    pub rip:u64,
+    pub rsp:u64,
+}
+
+
+/// A system call's arguments, with ways to read them as what the call expects
+#[derive(Debug,Copy,Clone)]
+pub struct Args([u64;6]);
+#[allow(dead_code)]
+impl Args {
+    pub fn raw(&self,i:usize)->u64 {
+        self.0[i]
+    }
+    pub fn int(&self,i:usize)->i64 {
+        self.0[i] as i64
+    }
+    /// Fails if it's over `max`
+    pub fn size(&self,i:usize,max:usize)->Result<usize,SyscallError> {
+        match self.0[i] {
+            n if n>max as u64=>Err(SyscallError::TooBig),
+            n=>Ok(n as usize),
+        }
+    }
+    /// Nanoseconds
+    pub fn duration(&self,i:usize)->Duration {
+        Duration::from_nanos(self.0[i])
+    }
+    /// A pointer in argument `i` and a length in `i+1`, checked to be in user memory
+    pub fn buffer(&self,i:usize)->Result<UserBuffer,SyscallError> {
+        let addr=VirtAddr::try_new(self.0[i]).map_err(|_|SyscallError::BadPointer)?;
+        let len=self.0[i+1];
+        if len>0&&!is_user_range(addr,len) {
+            return Err(SyscallError::BadPointer);
+        }
+        return Ok(UserBuffer{addr,len:len as usize});
+    }
+    /// A page aligned address in user memory
+    pub fn page(&self,i:usize)->Result<Page,SyscallError> {
+        let page=Page::from_start_address(VirtAddr::try_new(self.0[i]).map_err(|_|SyscallError::BadPointer)?)
+            .map_err(|_|SyscallError::BadArgument)?;
+        if !is_user_range(page.start_address(),PAGE_SIZE) {
+            return Err(SyscallError::BadPointer);
+        }
+        return Ok(page);
+    }
+}
+
+
+/// Memory a program passed in. Only the range is checked when it's made; whether it's mapped is
+/// checked on every read and write.
+#[derive(Debug,Copy,Clone)]
+pub struct UserBuffer {
+    pub addr:VirtAddr,
+    pub len:usize,
+}
+impl UserBuffer {
+    pub fn read(&self)->Result<Vec<u8>,SyscallError> {
+        let mut data=alloc::vec![0;self.len];
+        copy_from_user(self.addr,&mut data)?;
+        return Ok(data);
+    }
+    /// Writes as much of `data` as fits and returns how much that was
+    pub fn write(&self,data:&[u8])->Result<usize,SyscallError> {
+        let len=data.len().min(self.len);
+        copy_to_user(self.addr,&data[..len])?;
+        return Ok(len);
+    }
+}
+
+
+/// Checks that all of `len` bytes at `addr` are in the program's memory, and writable if `write`
+pub fn check_user(addr:VirtAddr,len:usize,write:bool)->Result<(),SyscallError> {
+    if len==0 {return Ok(())}
+    if !is_user_range(addr,len as u64) {
+        return Err(SyscallError::BadPointer);
+    }
+    match super::with_space(|space|space.check(addr,len as u64,write)) {
+        Some(true)=>Ok(()),
+        _=>Err(SyscallError::BadPointer),
+    }
+}
+/// Copies `dst.len()` bytes from the program's memory at `src`
+pub fn copy_from_user(src:VirtAddr,dst:&mut [u8])->Result<(),SyscallError> {
+    check_user(src,dst.len(),false)?;
+    unsafe{core::ptr::copy_nonoverlapping(src.as_ptr::<u8>(),dst.as_mut_ptr(),dst.len())};
+    return Ok(());
+}
+/// Copies `src` into the program's memory at `dst`
+pub fn copy_to_user(dst:VirtAddr,src:&[u8])->Result<(),SyscallError> {
+    check_user(dst,src.len(),true)?;
+    unsafe{core::ptr::copy_nonoverlapping(src.as_ptr(),dst.as_mut_ptr::<u8>(),src.len())};
+    return Ok(());
+}
+
+
+/// One entry in [`SYSCALLS`]
+pub struct Syscall {
+    pub name:&'static str,
+    /// How many arguments it takes. The registers for the rest are ignored.
+    pub args:usize,
+    handler:fn(Args)->Result<u64,SyscallError>,
+}
+
+
+/// Every system call, by number
+pub static SYSCALLS:&[Syscall]=&[
+    Syscall{name:"exit",args:1,handler:sys_exit},
+    Syscall{name:"write",args:2,handler:sys_write},
+    Syscall{name:"yield",args:0,handler:sys_yield},
+    Syscall{name:"sleep",args:1,handler:sys_sleep},
+    Syscall{name:"map",args:3,handler:sys_map},
+    Syscall{name:"send",args:3,handler:sys_send},
+    Syscall{name:"recv",args:4,handler:sys_recv},
+];
+
+
+/// `exit(code)`: ends the program
+fn sys_exit(args:Args)->Result<u64,SyscallError> {
+    super::exit(Exit::Exited(args.int(0)));
+}
+/// `write(text,len)`: prints UTF-8 text on the console. Returns how many bytes.
+fn sys_write(args:Args)->Result<u64,SyscallError> {
+    let buffer=args.buffer(0)?;
+    if buffer.len>MAX_WRITE {
+        return Err(SyscallError::TooBig);
+    }
+    let text=buffer.read()?;
+    let text=core::str::from_utf8(&text).map_err(|_|SyscallError::BadArgument)?;
+    print!("{}",text);
+    return Ok(text.len() as u64);
+}
+/// `yield()`: gives up the rest of the timer tick. Nothing else runs on the core while a program
+/// does yet, so the core halts until the tick is over.
+fn sys_yield(_args:Args)->Result<u64,SyscallError> {
+    let start=time::ticks();
+    loop {
+        interrupts::disable();
+        if time::ticks()!=start {break}
+        interrupts::enable_and_hlt();
+    }
+    interrupts::enable();
+    return Ok(0);
+}
+/// `sleep(ns)`
+fn sys_sleep(args:Args)->Result<u64,SyscallError> {
+    time::sleep(args.duration(0));
+    return Ok(0);
+}
+/// `map(addr,len,flags)`: maps zeroed memory at `addr`, which has to be page aligned and not
+/// mapped yet, or anywhere it fits if `addr` is 0. `flags` are [`MAP_WRITE`] and
+/// [`MAP_EXECUTE`]. Returns where it went.
+fn sys_map(args:Args)->Result<u64,SyscallError> {
+    let len=args.size(1,MAX_MAP as usize)? as u64;
+    let flags=args.raw(2);
+    if len==0||flags&!(MAP_WRITE|MAP_EXECUTE)!=0 {
+        return Err(SyscallError::BadArgument);
+    }
+    let mut page_flags=PageTableFlags::empty();
+    if flags&MAP_WRITE!=0 {page_flags|=PageTableFlags::WRITABLE}
+    if flags&MAP_EXECUTE==0 {page_flags|=PageTableFlags::NO_EXECUTE}
+    let start=match args.raw(0) {
+        0=>None,
+        _=>Some(args.page(0)?.start_address()),
+    };
+    let mapped=super::with_space(|space|{
+        let start=match start {
+            Some(start)=>start,
+            None=>space.find_free(len).ok_or(SpaceError::NoMemory)?,
+        };
+        space.map(start,len,page_flags)
+    }).ok_or(SyscallError::BadPointer)?;
+    match mapped {
+        Ok(vma)=>Ok(vma.start.as_u64()),
+        Err(SpaceError::Overlaps)=>Err(SyscallError::InUse),
+        Err(SpaceError::NoMemory)=>Err(SyscallError::NoMemory),
+        Err(SpaceError::NotUser|SpaceError::NotMapped)=>Err(SyscallError::BadPointer),
+    }
+}
+/// `send(channel,message,len)`: queues a message, see [`ipc`]
+fn sys_send(args:Args)->Result<u64,SyscallError> {
+    let buffer=args.buffer(1)?;
+    if buffer.len>ipc::MAX_MESSAGE {
+        return Err(SyscallError::TooBig);
+    }
+    ipc::send(args.raw(0),&buffer.read()?)?;
+    return Ok(0);
+}
+/// `recv(channel,buffer,len,timeout_ns)`: takes the oldest message, waiting up to the timeout for
+/// one. Returns its length. A message that doesn't fit stays queued.
+fn sys_recv(args:Args)->Result<u64,SyscallError> {
+    let buffer=args.buffer(1)?;
+    // the message is gone once it's taken, so find out now if it can't be written
+    check_user(buffer.addr,buffer.len,true)?;
+    let message=ipc::recv_timeout(args.raw(0),buffer.len,args.duration(3))?;
+    return Ok(buffer.write(&message)? as u64);
+}
+
+
+/// Runs system call `number`. Called from the entry stub with interrupts back on.
+pub fn dispatch(number:u64,mut args:Args)->Result<u64,SyscallError> {
+    let syscall=SYSCALLS.get(number as usize).ok_or(SyscallError::NoSuchCall)?;
+    // C library wrappers leave whatever was there in the registers a call doesn't take
+    args.0[syscall.args..].fill(0);
+    trace!("System call `{}` {:x?}",syscall.name,&args.0[..syscall.args]);
+    (syscall.handler)(args)
+}
+#[no_mangle]
+extern "C" fn syscall_dispatch(frame:&mut SyscallFrame)->u64 {
+    interrupts::enable();
+    let args=Args([frame.rdi,frame.rsi,frame.rdx,frame.r10,frame.r8,frame.r9]);
+    match dispatch(frame.number,args) {
+        Ok(value)=>value,
+        Err(error)=>(-(error as i64)) as u64,
+    }
+}
+
+
+extern "C" {
+    fn syscall_entry();
+}
+// interrupts are off on the way in (SFMASK), and `rsp` is still the user's until it's switched
+global_asm!(r#"
+.global syscall_entry
+syscall_entry:
+    swapgs
+    mov gs:[8],rsp
+    mov rsp,gs:[0]
+    mov rsp,[rsp]
+    push qword ptr gs:[8]
+    swapgs
+    push rcx
+    push r11
+    push r9
+    push r8
+    push r10
+    push rdx
+    push rsi
+    push rdi
+    push rax
+    mov rdi,rsp
+    call syscall_dispatch
+    cli
+    add rsp,8
+    pop rdi
+    pop rsi
+    pop rdx
+    pop r10
+    pop r8
+    pop r9
+    pop r11
+    pop rcx
+    pop rsp
+    sysretq
+"#);
+
+
+/// Points `syscall` at the entry stub on this core. [`gdt::init`] has already set up STAR.
+pub fn init(core:usize) {
+    AREAS[core][0].store(gdt::kernel_stack_slot(core) as u64,Ordering::Relaxed);
+    KernelGsBase::write(VirtAddr::from_ptr(&AREAS[core]));
+    LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+    SFMask::write(RFlags::INTERRUPT_FLAG|RFlags::DIRECTION_FLAG|RFlags::TRAP_FLAG|RFlags::ALIGNMENT_CHECK);
+}
+
+
+pub fn test_syscalls()->Result<(),&'static str> {
+    // write(message,18), then exit with what that returned
+    let mut hello=alloc::vec![
+        0xB8,0x01,0x00,0x00,0x00,           // mov eax,1
+        0x48,0x8D,0x3D,0x10,0x00,0x00,0x00, // lea rdi,[rip+16]
+        0xBE,0x12,0x00,0x00,0x00,           // mov esi,18
+        0x0F,0x05,                          // syscall
+        0x48,0x89,0xC7,                     // mov rdi,rax
+        0x31,0xF6,                          // xor esi,esi
+        0x31,0xC0,                          // xor eax,eax
+        0x0F,0x05,                          // syscall
+    ];
+    hello.extend_from_slice(b"hello from ring 3\n");
+    if run_code(&hello,[0;3],Return::Iret)?!=Exit::Exited(18) {
+        return Err("write didn't print the message");
+    }
+    // makes the call in `rdi` with `rsi` and `rdx` as its arguments, then exits with the result
+    const CALL:&[u8]=&[
+        0x48,0x89,0xF8,                     // mov rax,rdi
+        0x48,0x89,0xF7,                     // mov rdi,rsi
+        0x48,0x89,0xD6,                     // mov rsi,rdx
+        0x31,0xD2,                          // xor edx,edx
+        0x0F,0x05,                          // syscall
+        0x48,0x89,0xC7,                     // mov rdi,rax
+        0x31,0xF6,                          // xor esi,esi
+        0x31,0xC0,                          // xor eax,eax
+        0x0F,0x05,                          // syscall
+    ];
+    let cases:[(u64,u64,u64,i64);3]=[
+        (99,0,0,-(SyscallError::NoSuchCall as i64)),
+        (1,0x1000,1,-(SyscallError::BadPointer as i64)),
+        (3,1_000_000,0,0),
+    ];
+    for (number,a,b,expected) in cases {
+        if run_code(CALL,[number,a,b],Return::Sysret)?!=Exit::Exited(expected) {
+            return Err("a system call returned the wrong thing");
+        }
+    }
+    // yield, then exit with what it returned, with junk in every register neither call takes
+    const DIRTY:&[u8]=&[
+        0xB8,0x02,0x00,0x00,0x00,           // mov eax,2
+        0x48,0xC7,0xC7,0xFF,0xFF,0xFF,0xFF, // mov rdi,-1
+        0x48,0x89,0xFE,                     // mov rsi,rdi
+        0x48,0x89,0xFA,                     // mov rdx,rdi
+        0x49,0x89,0xFA,                     // mov r10,rdi
+        0x49,0x89,0xF8,                     // mov r8,rdi
+        0x49,0x89,0xF9,                     // mov r9,rdi
+        0x0F,0x05,                          // syscall
+        0x48,0x89,0xC7,                     // mov rdi,rax
+        0x31,0xC0,                          // xor eax,eax
+        0x0F,0x05,                          // syscall
+    ];
+    if run_code(DIRTY,[0;3],Return::Sysret)?!=Exit::Exited(0) {
+        return Err("a call was refused over registers it doesn't take");
+    }
+    let addr=USER_START+0x10_0000;
+    if run_code(CALL,[4,addr+1,1],Return::Sysret)?!=Exit::Exited(-(SyscallError::BadArgument as i64)) {
+        return Err("map took an address that isn't page aligned");
+    }
+    if run_code(CALL,[4,addr,1],Return::Sysret)?!=Exit::Exited(addr as i64) {
+        return Err("map didn't map a page");
+    }
+    // the code is where USER_START is, so that's taken
+    if run_code(CALL,[4,USER_START,1],Return::Sysret)?!=Exit::Exited(-(SyscallError::InUse as i64)) {
+        return Err("map mapped over the program");
+    }
+    match run_code(CALL,[4,0,1],Return::Sysret)? {
+        Exit::Exited(addr) if is_user_range(VirtAddr::new(addr as u64),PAGE_SIZE)=>{},
+        _=>return Err("map couldn't find somewhere to put a page"),
+    }
+    let channel=0xC0FFEE;
+    ipc::send(channel,b"ping").map_err(|_|"couldn't send")?;
+    if ipc::try_recv(channel,2)!=Err(ipc::IpcError::TooBig(4)) {
+        return Err("a message was received into a buffer too small for it");
+    }
+    if ipc::try_recv(channel,16).as_deref()!=Ok(b"ping".as_slice())||ipc::queued(channel)!=0 {
+        return Err("the message didn't come back out");
+    }
+    return Ok(());
+}